@@ -1,222 +1,176 @@
-use widestring::U16CStr;
-use winapi::{
-  shared::{guiddef::GUID, ifdef::NET_LUID, winerror},
-  um::{ipexport, iphlpapi},
-};
+use std::{net::IpAddr, ops::Deref, sync::Arc};
 
 use crate::{
-  utility::{guid_be_to_ne, guid_from_u128, guid_to_u128, interface_luid_to_guid, parse_guid},
-  wintun_raw::WintunStartSession,
-  IpAndMaskPrefix, RingCapacity, WintunError, WintunResult, MAX_ADAPTER_NAME,
+  backend::{self, AdapterBackend},
+  IpAndMaskPrefix, IpPacketSize, MemoryPeer, Operation, RingCapacity, Route, WintunError,
+  WintunResult,
 };
 
-use super::{
-  session::Session,
-  utility::{encode_utf16, UnsafeHandle},
-  wintun_raw::{
-    WintunCloseAdapter, WintunCreateAdapter, WintunGetAdapterLUID, WintunOpenAdapter,
-    WINTUN_ADAPTER_HANDLE,
-  },
-};
+use super::session::Session;
 
 pub struct Adapter {
-  handle: UnsafeHandle<WINTUN_ADAPTER_HANDLE>,
+  backend: Box<dyn AdapterBackend>,
   name: String,
 }
 
 impl Adapter {
+  /// Creates a new adapter. On Windows this is a Wintun adapter, on Linux a TUN interface
+  /// (`IFF_TUN | IFF_NO_PI`) whose alias stores `tunnel_type` and the guid
   pub fn create(
     name: impl Into<String>,
     tunnel_type: impl AsRef<str>,
     requested_guid: Option<u128>,
   ) -> WintunResult<Self> {
     let name = name.into();
-    let tunnel_type = tunnel_type.as_ref();
-    let name_u16 = encode_utf16(&name, MAX_ADAPTER_NAME - 1)?;
-    let tunnel_type = encode_utf16(tunnel_type, MAX_ADAPTER_NAME - 1)?;
-    //SAFETY: guid is a unique integer so transmuting either all zeroes or the user's preferred
-    //guid to the winapi guid type is safe and will allow the windows kernel to see our GUID
-    let guid_struct: Option<GUID> = requested_guid.map(|guid| unsafe { std::mem::transmute(guid) });
-    //TODO: The guid of the adapter once created might differ from the one provided because of
-    //the byte order of the segments of the GUID struct that are larger than a byte. Verify
-    //that this works as expected
-
-    let guid_ptr = guid_struct
-      .map(|guid| &guid as *const _)
-      .unwrap_or(std::ptr::null());
-    let name_ptr = name_u16.as_ptr();
-    let tunnel_type = tunnel_type.as_ptr();
-    let handle = unsafe { WintunCreateAdapter(name_ptr, tunnel_type, guid_ptr) };
-    if handle == std::ptr::null_mut() {
-      return Err(get_last_error::Win32Error::get_last_error().into());
-    }
-    let handle = UnsafeHandle(handle);
-    Ok(Self { handle, name })
+    let backend = backend::native::create(&name, tunnel_type.as_ref(), requested_guid)
+      .map_err(|err| err.context(Operation::CreateAdapter, Some(&name)))?;
+    Ok(Self { backend, name })
   }
+  /// Creates an adapter that only exists inside this process. Its traffic is exchanged with the
+  /// [`MemoryPeer`] returned by [`memory_peer`](Self::memory_peer), which makes it suitable for
+  /// tests and for running without privileges
+  pub fn create_in_memory(
+    name: impl Into<String>,
+    tunnel_type: impl AsRef<str>,
+    requested_guid: Option<u128>,
+  ) -> WintunResult<Self> {
+    let name = name.into();
+    let backend = backend::memory::create(&name, tunnel_type.as_ref(), requested_guid)
+      .map_err(|err| err.context(Operation::CreateAdapter, Some(&name)))?;
+    Ok(Self { backend, name })
+  }
+  /// Opens an existing adapter. In-memory adapters of this process take precedence over native
+  /// adapters with the same name
   pub fn open(name: impl Into<String>) -> WintunResult<Self> {
     let name = name.into();
-    let name_u16 = encode_utf16(&name, MAX_ADAPTER_NAME)?;
-
-    let handle = unsafe { WintunOpenAdapter(name_u16.as_ptr()) };
-    if handle == std::ptr::null_mut() {
-      return Err(get_last_error::Win32Error::get_last_error().into());
-    }
-    let handle = UnsafeHandle(handle);
-    Ok(Self { handle, name })
+    let backend = match backend::memory::open(&name) {
+      Some(backend) => backend,
+      None => backend::native::open(&name)
+        .map_err(|err| err.context(Operation::OpenAdapter, Some(&name)))?,
+    };
+    Ok(Self { backend, name })
   }
   pub fn close(self) {
     drop(self)
   }
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+  /// Returns the other side of an in-memory adapter, `None` for native adapters
+  pub fn memory_peer(&self) -> Option<MemoryPeer> {
+    self.backend.memory_device().cloned().map(MemoryPeer::new)
+  }
+  pub fn is_in_memory(&self) -> bool {
+    self.backend.memory_device().is_some()
+  }
+  /// Returns the LUID of the adapter. Backends without LUIDs report one built from the interface
+  /// index the way Windows lays it out
   pub fn get_luid(&self) -> WintunResult<u64> {
-    let mut luid = NET_LUID::default();
-    unsafe { WintunGetAdapterLUID(self.handle.0, &mut luid as *mut _) }
-    Ok(luid.Value)
+    self.backend.get_luid().map_err(self.context(Operation::GetLuid))
   }
 
   pub fn get_guid(&self) -> WintunResult<u128> {
-    let guid = interface_luid_to_guid(self.get_luid()?)?;
-    Ok(unsafe { std::mem::transmute(guid) })
+    self.backend.get_guid().map_err(self.context(Operation::GetGuid))
   }
-  pub fn session(&mut self, capacity: RingCapacity) -> WintunResult<Session> {
-    let capacity = capacity.cap();
-    let session = unsafe { WintunStartSession(self.handle.0, capacity) };
-    if session == std::ptr::null_mut() {
-      return Err(get_last_error::Win32Error::get_last_error().into());
-    }
-    Ok(Session::new(session))
+  /// Starts a session borrowing the adapter, which can't be closed or dropped until the session
+  /// and all its halves are gone
+  ///
+  /// ```compile_fail,E0505
+  /// let adapter = wintun2::Adapter::create_in_memory("doc-close", "tunnel_type", None).unwrap();
+  /// let session = adapter.session(wintun2::RingCapacity::min()).unwrap();
+  /// adapter.close();
+  /// let _ = session.recv();
+  /// ```
+  ///
+  /// ```compile_fail,E0597
+  /// let session = {
+  ///   let adapter = wintun2::Adapter::create_in_memory("doc-scope", "tunnel_type", None).unwrap();
+  ///   adapter.session(wintun2::RingCapacity::min()).unwrap()
+  /// };
+  /// let _ = session.recv();
+  /// ```
+  pub fn session(&self, capacity: RingCapacity) -> WintunResult<Session<'_>> {
+    let backend = self
+      .backend
+      .start_session(capacity)
+      .map_err(self.context(Operation::StartSession))?;
+    Ok(Session::new(backend, AdapterRef::Borrowed(self)))
+  }
+  /// Starts a session that takes ownership of the adapter. The adapter is closed once the session
+  /// and all its halves are dropped
+  ///
+  /// ```
+  /// let adapter = wintun2::Adapter::create_in_memory("doc-owned", "tunnel_type", None).unwrap();
+  /// let (reader, writer) = adapter
+  ///   .into_session(wintun2::RingCapacity::min())
+  ///   .unwrap()
+  ///   .split();
+  /// std::thread::spawn(move || drop(reader)).join().unwrap();
+  /// assert_eq!(writer.adapter().name(), "doc-owned");
+  /// ```
+  pub fn into_session(self, capacity: RingCapacity) -> WintunResult<Session<'static>> {
+    let backend = self
+      .backend
+      .start_session(capacity)
+      .map_err(self.context(Operation::StartSession))?;
+    Ok(Session::new(backend, AdapterRef::Owned(Arc::new(self))))
   }
   pub fn set_ip_address(&mut self, internal_ip: IpAndMaskPrefix) -> WintunResult<()> {
-    let mut address_row = winapi::shared::netioapi::MIB_UNICASTIPADDRESS_ROW::default();
-    unsafe {
-      winapi::shared::netioapi::InitializeUnicastIpAddressEntry(&mut address_row as *mut _);
-    }
-    const IP_SUFFIX_ORIGIN_DHCP: winapi::shared::nldef::NL_SUFFIX_ORIGIN = 3;
-    const IP_PREFIX_ORIGIN_DHCP: winapi::shared::nldef::NL_PREFIX_ORIGIN = 3;
-    address_row.SuffixOrigin = IP_SUFFIX_ORIGIN_DHCP;
-    address_row.PrefixOrigin = IP_PREFIX_ORIGIN_DHCP;
-    const LIFETIME_INFINITE: winapi::ctypes::c_ulong = 0xffffffff;
-    address_row.ValidLifetime = LIFETIME_INFINITE;
-    address_row.PreferredLifetime = LIFETIME_INFINITE;
-    address_row.InterfaceLuid = winapi::shared::ifdef::NET_LUID_LH {
-      Value: self.get_luid()?,
-    };
-    match internal_ip {
-      IpAndMaskPrefix::V4 { ip, prefix } => {
-        unsafe {
-          let ipv4 = address_row.Address.Ipv4_mut();
-          ipv4.sin_family = winapi::shared::ws2def::AF_INET as _;
-          *ipv4.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(ip.octets());
-        }
-        address_row.OnLinkPrefixLength = prefix.mask();
-      }
-      IpAndMaskPrefix::V6 { ip, prefix } => {
-        unsafe {
-          let ipv6 = address_row.Address.Ipv6_mut();
-          ipv6.sin6_family = winapi::shared::ws2def::AF_INET as _;
-          *ipv6.sin6_addr.u.Byte_mut() = ip.octets();
-        }
-        address_row.OnLinkPrefixLength = prefix.mask();
-      }
-    }
+    self
+      .backend
+      .set_ip_address(internal_ip)
+      .map_err(self.context(Operation::SetIpAddress))
+  }
+  /// Sets the MTU of the interface. On Windows the IPv6 MTU is only changed when `mtu` is at
+  /// least 1280, the minimum IPv6 allows
+  pub fn set_mtu(&mut self, mtu: IpPacketSize) -> WintunResult<()> {
+    self.backend.set_mtu(mtu).map_err(self.context(Operation::SetMtu))
+  }
+  /// Adds a route through this adapter to the system routing table. On Linux the kernel only
+  /// accepts routes while the interface is up, that is while a session is running, and drops
+  /// them again when the session ends
+  pub fn add_route(&self, route: Route) -> WintunResult<()> {
+    self.backend.add_route(route).map_err(self.context(Operation::AddRoute))
+  }
 
-    address_row.DadState = winapi::shared::nldef::IpDadStatePreferred;
-    let error =
-      unsafe { winapi::shared::netioapi::CreateUnicastIpAddressEntry(&mut address_row as *mut _) };
-    if error != winapi::shared::winerror::ERROR_SUCCESS {
-      return Err(get_last_error::Win32Error::get_last_error().into());
-    }
-    Ok(())
+  /// Replaces the DNS servers used for names resolved through this adapter, an empty list removes
+  /// them. On Linux the servers are handed to systemd-resolved through `resolvectl`
+  pub fn set_dns_servers(&self, servers: &[IpAddr]) -> WintunResult<()> {
+    self
+      .backend
+      .set_dns_servers(servers)
+      .map_err(self.context(Operation::SetDnsServers))
   }
 
   /// Returns the Win32 interface index of this adapter. Useful for specifying the interface
-  /// when executing `netsh interface ip` commands
+  /// when executing `netsh interface ip` commands. On Linux this is the `ifindex`
   pub fn get_adapter_index(&self) -> WintunResult<u32> {
-    let guid = self.get_guid()?;
-    let mut buf_len: u32 = 0;
-    //First figure out the size of the buffer needed to store the adapter info
-    //SAFETY: We are upholding the contract of GetInterfaceInfo. buf_len is a valid pointer to
-    //stack memory
-    let result =
-      unsafe { iphlpapi::GetInterfaceInfo(std::ptr::null_mut(), &mut buf_len as *mut u32) };
-    if result != winerror::NO_ERROR && result != winerror::ERROR_INSUFFICIENT_BUFFER {
-      return Err(get_last_error::Win32Error::get_last_error().into());
-    }
-
-    //Allocate a buffer of the requested size
-    //IP_INTERFACE_INFO must be aligned by at least 4 byte boundaries so use u32 as the
-    //underlying data storage type
-    let buf_elements = buf_len as usize / std::mem::size_of::<u32>() + 1;
-    //Round up incase integer division truncated a byte that filled a partial element
-    let mut buf: Vec<u32> = vec![0; buf_elements];
-
-    let buf_bytes = buf.len() * std::mem::size_of::<u32>();
-    assert!(buf_bytes >= buf_len as usize);
-
-    //SAFETY:
-    //
-    //  1. We are upholding the contract of GetInterfaceInfo.
-    //  2. `final_buf_len` is an aligned, valid pointer to stack memory
-    //  3. buf is a valid, non-null pointer to at least `buf_len` bytes of heap memory,
-    //     aligned to at least 4 byte boundaries
-    //
-    //Get the info
-    let mut final_buf_len: u32 = buf_len;
-    let result = unsafe {
-      iphlpapi::GetInterfaceInfo(
-        buf.as_mut_ptr() as *mut ipexport::IP_INTERFACE_INFO,
-        &mut final_buf_len as *mut u32,
-      )
-    };
-    if result != winerror::NO_ERROR {
-      return Err(get_last_error::Win32Error::get_last_error().into());
-    }
-    let info = buf.as_mut_ptr() as *const ipexport::IP_INTERFACE_INFO;
-    //SAFETY:
-    // info is a valid, non-null, at least 4 byte aligned pointer obtained from
-    // Vec::with_capacity that is readable for up to `buf_len` bytes which is guaranteed to be
-    // larger than on IP_INTERFACE_INFO struct as the kernel would never ask for less memory then
-    // what it will write. The largest type inside IP_INTERFACE_INFO is a u32 therefore
-    // a painter to IP_INTERFACE_INFO requires an alignment of at leant 4 bytes, which
-    // Vec<u32>::as_mut_ptr() provides
-    let adapter_base = unsafe { &*info };
-    let adapter_count = adapter_base.NumAdapters;
-    let first_adapter = &adapter_base.Adapter as *const ipexport::IP_ADAPTER_INDEX_MAP;
+    self
+      .backend
+      .get_adapter_index()
+      .map_err(self.context(Operation::GetAdapterIndex))
+  }
+  /// Wraps errors of `operation` on this adapter into [`WintunError::Context`]
+  fn context(&self, operation: Operation) -> impl FnOnce(WintunError) -> WintunError + '_ {
+    move |err| err.context(operation, Some(&self.name))
+  }
+}
 
-    // SAFETY:
-    //  1. first_adapter is a valid, non null pointer, aligned to at least 4 byte boundaries
-    //     obtained from moving a multiple of 4 offset into the buf given by Vec::with_capacity.
-    //  2. We gave GetInterfaceInfo a buffer of at least least `buf_len` bytes to work with and it
-    //     succeeded in writing the adapter information within the bounds of that buffer, otherwise
-    //     it would've failed. Because the operation succeeded, we know that reading n=NumAdapters
-    //     IP_ADAPTER_INDEX_MAP structs stays within the bounds of buf's buffer
-    let interfaces = unsafe { std::slice::from_raw_parts(first_adapter, adapter_count as usize) };
+/// The adapter a session keeps alive, either borrowed or shared between the session halves
+#[derive(Clone)]
+pub(crate) enum AdapterRef<'adapter> {
+  Borrowed(&'adapter Adapter),
+  Owned(Arc<Adapter>),
+}
 
-    for interface in interfaces {
-      let name = unsafe { U16CStr::from_ptr_str(&interface.Name as *const u16).to_string_lossy() };
-      //Name is something like: \DEVICE\TCPIP_{29C47F55-C7BD-433A-8BF7-408DFD3B3390}
-      //where the GUID is the {29C4...90}, separated by dashes
-      let Some(open) = name.chars().position(|c| c == '{') else {
-        continue;
-      };
-      let Some(close) = name.chars().position(|c| c == '}') else {
-        continue;
-      };
-      let Ok(digits) = parse_guid(&name[open+1..close]) else {
-        continue;
-      };
+impl<'adapter> Deref for AdapterRef<'adapter> {
+  type Target = Adapter;
 
-      let target_guid = guid_to_u128(guid_be_to_ne(guid_from_u128(u128::from_ne_bytes(digits))));
-      if target_guid == guid {
-        return Ok(interface.Index);
-      }
+  fn deref(&self) -> &Adapter {
+    match self {
+      AdapterRef::Borrowed(adapter) => adapter,
+      AdapterRef::Owned(adapter) => adapter,
     }
-    Err(WintunError::InterfaceNotFound)
-  }
-}
-
-impl Drop for Adapter {
-  fn drop(&mut self) {
-    unsafe { WintunCloseAdapter(self.handle.0) };
   }
 }
 
@@ -228,7 +182,14 @@ pub trait TryReopen {
 
 impl TryReopen for Adapter {
   fn try_reopen(&self) -> WintunResult<Self> {
-    Self::open(&self.name)
+    let backend = self
+      .backend
+      .reopen()
+      .map_err(self.context(Operation::ReopenAdapter))?;
+    Ok(Self {
+      backend,
+      name: self.name.clone(),
+    })
   }
 }
 
@@ -240,19 +201,19 @@ mod tests {
 
   #[test]
   fn create_adapter() {
-    let adapter = Adapter::create("name", "tunnel_type", None).unwrap();
+    let adapter = Adapter::create("wt-create", "tunnel_type", None).unwrap();
     adapter.get_luid().unwrap();
   }
   #[test]
   fn create_adapter_and_clone() {
-    let adapter = Adapter::create("name", "tunnel_type", None).unwrap();
+    let adapter = Adapter::create("wt-clone", "tunnel_type", None).unwrap();
     let cloned = adapter.try_reopen().unwrap();
     cloned.close();
     adapter.close();
   }
   #[test]
   fn create_adapter_and_set_ip() {
-    let mut adapter = Adapter::create("name", "tunnel_type", None).unwrap();
+    let mut adapter = Adapter::create("wt-set-ip", "tunnel_type", None).unwrap();
     adapter
       .set_ip_address(crate::IpAndMaskPrefix::V4 {
         ip: Ipv4Addr::new(192, 168, 10, 1),
@@ -262,10 +223,60 @@ mod tests {
     let session = adapter.session(crate::RingCapacity::max()).unwrap();
     session.end();
   }
-  
+
   #[test]
   fn get_index() {
-    let adapter = Adapter::create("name", "tunnel_type", None).unwrap();
+    let adapter = Adapter::create("wt-index", "tunnel_type", None).unwrap();
     adapter.get_adapter_index().unwrap();
   }
+
+  #[test]
+  fn add_route() {
+    let mut adapter = Adapter::create("wt-route", "tunnel_type", None).unwrap();
+    adapter
+      .set_ip_address(crate::IpAndMaskPrefix::V4 {
+        ip: Ipv4Addr::new(192, 168, 12, 1),
+        prefix: 24.try_into().unwrap(),
+      })
+      .unwrap();
+    let session = adapter.session(crate::RingCapacity::max()).unwrap();
+    let mut route = crate::Route::new(crate::IpAndMaskPrefix::V4 {
+      ip: Ipv4Addr::new(10, 99, 0, 0),
+      prefix: 16.try_into().unwrap(),
+    });
+    route.next_hop = Some(Ipv4Addr::new(192, 168, 12, 2).into());
+    adapter.add_route(route).unwrap();
+    session.end();
+  }
+
+  #[test]
+  fn memory_adapter_is_found_by_name() {
+    let adapter = Adapter::create_in_memory("memory-open", "tunnel_type", Some(42)).unwrap();
+    let opened = Adapter::open("memory-open").unwrap();
+    assert!(opened.is_in_memory());
+    assert_eq!(opened.get_guid().unwrap(), 42);
+    assert_eq!(
+      opened.get_adapter_index().unwrap(),
+      adapter.get_adapter_index().unwrap()
+    );
+    assert!(Adapter::create_in_memory("memory-open", "tunnel_type", None).is_err());
+    opened.close();
+    adapter.close();
+    assert!(Adapter::create_in_memory("memory-open", "tunnel_type", None).is_ok());
+  }
+
+  #[test]
+  fn memory_adapter_records_configuration() {
+    let mut adapter = Adapter::create_in_memory("memory-config", "tunnel_type", None).unwrap();
+    let address = crate::IpAndMaskPrefix::V4 {
+      ip: Ipv4Addr::new(10, 8, 0, 1),
+      prefix: 24.try_into().unwrap(),
+    };
+    adapter.set_ip_address(address).unwrap();
+    assert!(adapter.set_ip_address(address).is_err());
+    adapter.set_mtu(1400.try_into().unwrap()).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    assert_eq!(peer.addresses(), vec![address]);
+    assert_eq!(peer.mtu().unwrap().size(), 1400);
+  }
 }
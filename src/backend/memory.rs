@@ -0,0 +1,473 @@
+//! Adapters that live entirely in process memory. Sessions run on the same [rings](crate::RingBuffer)
+//! Wintun shares with its driver: packets are handed out in place and have to be released or
+//! sent, and both directions are bounded by the ring capacity. The other end of the link, where the operating system would sit
+//! for a real adapter, is driven through [`MemoryPeer`]
+
+use std::{
+  collections::hash_map::RandomState,
+  hash::{BuildHasher, Hasher},
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Condvar, Mutex, MutexGuard, Weak,
+  },
+  time::{Duration, Instant},
+};
+
+use crate::{
+  AdapterInfo, AllocatePacketError, IpAndMaskPrefix, IpPacketSize, OsError, ReceivePacketError,
+  RingBuffer, RingCapacity, RingConsumer, RingProducer, Route, WintunError, WintunResult,
+  MAX_ADAPTER_NAME,
+};
+
+use super::{luid_from_index, AdapterBackend, SessionBackend};
+
+/// Adapters that are currently alive, so they can be found by name like real adapters
+static ADAPTERS: Mutex<Vec<Weak<MemoryDevice>>> = Mutex::new(Vec::new());
+/// Interface indices are handed out from a range real interfaces are unlikely to reach
+static NEXT_INDEX: AtomicU32 = AtomicU32::new(0x10000);
+
+#[cfg(windows)]
+fn already_exists() -> OsError {
+  OsError::new(winapi::shared::winerror::ERROR_ALREADY_EXISTS)
+}
+#[cfg(not(windows))]
+fn already_exists() -> OsError {
+  OsError::new(libc::EEXIST)
+}
+
+#[derive(Default)]
+struct LinkState {
+  /// The peer's end of the receive ring of the running session
+  to_session: Option<RingProducer>,
+  /// The peer's end of the send ring of the last session, kept after the session ends so the
+  /// packets it sent can still be read
+  from_session: Option<RingConsumer>,
+  terminated: bool,
+  addresses: Vec<IpAndMaskPrefix>,
+  routes: Vec<Route>,
+  dns_servers: Vec<IpAddr>,
+  mtu: Option<IpPacketSize>,
+}
+
+pub(crate) struct MemoryDevice {
+  name: String,
+  tunnel_type: String,
+  guid: u128,
+  index: u32,
+  state: Mutex<LinkState>,
+  to_session: Condvar,
+  from_session: Condvar,
+}
+
+impl MemoryDevice {
+  fn state(&self) -> MutexGuard<'_, LinkState> {
+    self
+      .state
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+  fn terminate(&self) {
+    let mut state = self.state();
+    state.terminated = true;
+    if let Some(ring) = &state.to_session {
+      ring.ring().terminate();
+    }
+    if let Some(ring) = &state.from_session {
+      ring.terminate();
+    }
+    drop(state);
+    self.to_session.notify_all();
+    self.from_session.notify_all();
+  }
+}
+
+fn random_guid() -> u128 {
+  let state = RandomState::new();
+  let mut high = state.build_hasher();
+  high.write_u8(0);
+  let mut low = state.build_hasher();
+  low.write_u8(1);
+  let guid = ((high.finish() as u128) << 64) | low.finish() as u128;
+  //Mark it as a RFC 9562 version 4 (random) guid
+  (guid & !(0xF << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62)
+}
+
+fn validate_name(name: &str) -> WintunResult<()> {
+  if let Some(pos) = name.encode_utf16().position(|c| c == 0) {
+    return Err(WintunError::ContainsNull(pos));
+  }
+  let len = name.encode_utf16().count();
+  if len >= MAX_ADAPTER_NAME {
+    return Err(WintunError::TooLongName {
+      max: MAX_ADAPTER_NAME,
+      got: len,
+    });
+  }
+  Ok(())
+}
+
+fn find(adapters: &[Weak<MemoryDevice>], name: &str) -> Option<Arc<MemoryDevice>> {
+  adapters
+    .iter()
+    .filter_map(Weak::upgrade)
+    .find(|device| device.name == name && !device.state().terminated)
+}
+
+pub(crate) fn create(
+  name: &str,
+  tunnel_type: &str,
+  requested_guid: Option<u128>,
+) -> WintunResult<Box<dyn AdapterBackend>> {
+  validate_name(name)?;
+  validate_name(tunnel_type)?;
+  let mut adapters = ADAPTERS
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+  adapters.retain(|device| device.strong_count() > 0);
+  if find(&adapters, name).is_some() {
+    return Err(already_exists().into());
+  }
+  let device = Arc::new(MemoryDevice {
+    name: name.to_owned(),
+    tunnel_type: tunnel_type.to_owned(),
+    guid: requested_guid.unwrap_or_else(random_guid),
+    index: NEXT_INDEX.fetch_add(1, Ordering::Relaxed),
+    state: Mutex::default(),
+    to_session: Condvar::new(),
+    from_session: Condvar::new(),
+  });
+  adapters.push(Arc::downgrade(&device));
+  Ok(Box::new(MemoryAdapter {
+    device,
+    owner: true,
+  }))
+}
+
+pub(crate) fn list(tunnel_type: &str) -> Vec<AdapterInfo> {
+  let adapters = ADAPTERS
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+  adapters
+    .iter()
+    .filter_map(Weak::upgrade)
+    .filter(|device| device.tunnel_type == tunnel_type && !device.state().terminated)
+    .map(|device| AdapterInfo {
+      name: device.name.clone(),
+      tunnel_type: device.tunnel_type.clone(),
+      guid: device.guid,
+      index: device.index,
+      in_memory: true,
+    })
+    .collect()
+}
+
+/// Terminates the in-memory adapter described by `adapter`, as if its owner had closed it
+pub(crate) fn remove(adapter: &AdapterInfo) -> WintunResult<()> {
+  let adapters = ADAPTERS
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+  let device = adapters
+    .iter()
+    .filter_map(Weak::upgrade)
+    .find(|device| device.index == adapter.index && !device.state().terminated)
+    .ok_or(WintunError::InterfaceNotFound)?;
+  device.terminate();
+  Ok(())
+}
+
+/// Opens an in-memory adapter by name. Returns `None` if no such adapter exists so the caller can
+/// fall back to the native backend
+pub(crate) fn open(name: &str) -> Option<Box<dyn AdapterBackend>> {
+  let adapters = ADAPTERS
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+  let device = find(&adapters, name)?;
+  Some(Box::new(MemoryAdapter {
+    device,
+    owner: false,
+  }))
+}
+
+pub(crate) struct MemoryAdapter {
+  device: Arc<MemoryDevice>,
+  /// Only the handle that created the adapter removes it, like `WintunCloseAdapter`
+  owner: bool,
+}
+
+impl AdapterBackend for MemoryAdapter {
+  fn get_luid(&self) -> WintunResult<u64> {
+    Ok(luid_from_index(self.device.index))
+  }
+
+  fn get_guid(&self) -> WintunResult<u128> {
+    Ok(self.device.guid)
+  }
+
+  fn get_adapter_index(&self) -> WintunResult<u32> {
+    Ok(self.device.index)
+  }
+
+  fn start_session(&self, capacity: RingCapacity) -> WintunResult<Box<dyn SessionBackend>> {
+    let mut state = self.device.state();
+    if state.terminated {
+      return Err(WintunError::AdapterIsTerminating);
+    }
+    if state.to_session.is_some() {
+      return Err(already_exists().into());
+    }
+    let to_session = Arc::new(RingBuffer::new(capacity));
+    let from_session = Arc::new(RingBuffer::new(capacity));
+    state.to_session = Some(RingProducer::new(to_session.clone()));
+    state.from_session = Some(RingConsumer::new(from_session.clone()));
+    Ok(Box::new(MemorySession {
+      device: self.device.clone(),
+      receive: Mutex::new(RingConsumer::new(to_session)),
+      send: Mutex::new(RingProducer::new(from_session)),
+    }))
+  }
+
+  fn set_ip_address(&self, internal_ip: IpAndMaskPrefix) -> WintunResult<()> {
+    let mut state = self.device.state();
+    if state.addresses.contains(&internal_ip) {
+      return Err(already_exists().into());
+    }
+    state.addresses.push(internal_ip);
+    Ok(())
+  }
+
+  fn set_mtu(&self, mtu: IpPacketSize) -> WintunResult<()> {
+    self.device.state().mtu = Some(mtu);
+    Ok(())
+  }
+
+  fn add_route(&self, route: Route) -> WintunResult<()> {
+    let mut state = self.device.state();
+    if state.routes.contains(&route) {
+      return Err(already_exists().into());
+    }
+    state.routes.push(route);
+    Ok(())
+  }
+
+  fn set_dns_servers(&self, servers: &[IpAddr]) -> WintunResult<()> {
+    self.device.state().dns_servers = servers.to_vec();
+    Ok(())
+  }
+
+  fn reopen(&self) -> WintunResult<Box<dyn AdapterBackend>> {
+    Ok(Box::new(MemoryAdapter {
+      device: self.device.clone(),
+      owner: false,
+    }))
+  }
+
+  fn memory_device(&self) -> Option<&Arc<MemoryDevice>> {
+    Some(&self.device)
+  }
+}
+
+impl Drop for MemoryAdapter {
+  fn drop(&mut self) {
+    if self.owner {
+      self.device.terminate();
+    }
+  }
+}
+
+pub(crate) struct MemorySession {
+  device: Arc<MemoryDevice>,
+  receive: Mutex<RingConsumer>,
+  send: Mutex<RingProducer>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl SessionBackend for MemorySession {
+  fn receive(&self) -> Result<(*mut u8, u32), ReceivePacketError> {
+    lock(&self.receive).receive()
+  }
+
+  fn release(&self, data: *const u8, _size: u32) {
+    unsafe { lock(&self.receive).release(data) }
+  }
+
+  fn allocate(&self, size: u32) -> Result<*mut u8, AllocatePacketError> {
+    lock(&self.send).allocate(size)
+  }
+
+  fn send(&self, data: *const u8, _size: u32) {
+    if unsafe { lock(&self.send).send(data) } {
+      //Taking the lock orders the wakeup after the peer's check for packets
+      let _state = self.device.state();
+      self.device.from_session.notify_all();
+    }
+  }
+
+  fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut state = self.device.state();
+    let ring = lock(&self.receive).ring().clone();
+    ring.set_alertable(true);
+    let readable = loop {
+      if lock(&self.receive).is_ready() {
+        break true;
+      }
+      state = match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            break false;
+          }
+          self
+            .device
+            .to_session
+            .wait_timeout(state, deadline - now)
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .0
+        }
+        None => self
+          .device
+          .to_session
+          .wait(state)
+          .unwrap_or_else(|poisoned| poisoned.into_inner()),
+      };
+    };
+    ring.set_alertable(false);
+    Ok(readable)
+  }
+
+  #[cfg(windows)]
+  fn read_wait_event(&self) -> WintunResult<winapi::shared::ntdef::HANDLE> {
+    Err(OsError::new(winapi::shared::winerror::ERROR_NOT_SUPPORTED).into())
+  }
+}
+
+impl Drop for MemorySession {
+  fn drop(&mut self) {
+    self.device.state().to_session = None;
+  }
+}
+
+/// The far end of an in-memory adapter, standing in for the operating system. Packets injected
+/// here are received by the adapter's session and packets sent by the session are read here
+#[derive(Clone)]
+pub struct MemoryPeer {
+  device: Arc<MemoryDevice>,
+}
+
+impl MemoryPeer {
+  pub(crate) fn new(device: Arc<MemoryDevice>) -> Self {
+    Self { device }
+  }
+  pub fn name(&self) -> &str {
+    &self.device.name
+  }
+  pub fn tunnel_type(&self) -> &str {
+    &self.device.tunnel_type
+  }
+  /// Queues a packet for the session. Like a real adapter the packet is silently dropped while
+  /// no session is running, and the call fails with [`WintunError::WouldBlock`] when the
+  /// session's receive ring is full
+  pub fn inject(&self, packet: &[u8]) -> WintunResult<()> {
+    IpPacketSize::try_from(packet.len() as u32)?;
+    let mut state = self.device.state();
+    if state.terminated {
+      return Err(WintunError::AdapterIsTerminating);
+    }
+    let Some(ring) = &mut state.to_session else {
+      return Ok(());
+    };
+    let data = ring.allocate(packet.len() as u32)?;
+    //SAFETY: the ring handed out room for exactly this many bytes
+    let alertable = unsafe {
+      std::ptr::copy_nonoverlapping(packet.as_ptr(), data, packet.len());
+      ring.send(data)
+    };
+    if alertable {
+      self.device.to_session.notify_all();
+    }
+    Ok(())
+  }
+  /// Takes the oldest packet sent by the session, if any
+  pub fn try_recv(&self) -> Option<Vec<u8>> {
+    Self::take(&mut self.device.state())
+  }
+  fn take(state: &mut LinkState) -> Option<Vec<u8>> {
+    let ring = state.from_session.as_mut()?;
+    let (data, size) = ring.receive().ok()?;
+    //SAFETY: the packet stays valid until it is released
+    let packet = unsafe { std::slice::from_raw_parts(data, size as usize) }.to_vec();
+    unsafe { ring.release(data) };
+    Some(packet)
+  }
+  /// Waits up to `timeout` for a packet sent by the session
+  pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.device.state();
+    loop {
+      //Asking the session to wake us before looking means a packet sent in between isn't missed
+      let ring = state.from_session.as_ref().map(|ring| ring.ring().clone());
+      if let Some(ring) = &ring {
+        ring.set_alertable(true);
+      }
+      let packet = Self::take(&mut state);
+      let now = Instant::now();
+      if packet.is_some() || now >= deadline || state.terminated {
+        if let Some(ring) = &ring {
+          ring.set_alertable(false);
+        }
+        return packet;
+      }
+      state = self
+        .device
+        .from_session
+        .wait_timeout(state, deadline - now)
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .0;
+    }
+  }
+  /// Puts the adapter into the terminating state, every further session operation fails with
+  /// `AdapterIsTerminating`
+  pub fn terminate(&self) {
+    self.device.terminate()
+  }
+  /// Writes a bogus packet header into the session's receive ring, every further receive fails
+  /// with `InvalidData`
+  pub fn corrupt(&self) {
+    if let Some(ring) = &mut self.device.state().to_session {
+      ring.publish_corrupt_header();
+    }
+    self.device.to_session.notify_all();
+  }
+  pub fn is_terminated(&self) -> bool {
+    self.device.state().terminated
+  }
+  pub fn has_session(&self) -> bool {
+    self.device.state().to_session.is_some()
+  }
+  /// Addresses assigned with [`Adapter::set_ip_address`](crate::Adapter::set_ip_address)
+  pub fn addresses(&self) -> Vec<IpAndMaskPrefix> {
+    self.device.state().addresses.clone()
+  }
+  /// MTU assigned with [`Adapter::set_mtu`](crate::Adapter::set_mtu)
+  pub fn mtu(&self) -> Option<IpPacketSize> {
+    self.device.state().mtu
+  }
+  /// Routes added with [`Adapter::add_route`](crate::Adapter::add_route)
+  pub fn routes(&self) -> Vec<Route> {
+    self.device.state().routes.clone()
+  }
+  /// Servers assigned with [`Adapter::set_dns_servers`](crate::Adapter::set_dns_servers)
+  pub fn dns_servers(&self) -> Vec<IpAddr> {
+    self.device.state().dns_servers.clone()
+  }
+  /// Guid the adapter was created with
+  pub fn guid(&self) -> u128 {
+    self.device.guid
+  }
+}
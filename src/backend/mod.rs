@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use crate::{
+  AllocatePacketError, IpAndMaskPrefix, IpPacketSize, ReceivePacketError, RingCapacity, Route,
+  WintunResult,
+};
+
+pub(crate) mod memory;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(target_os = "linux")]
+pub(crate) mod tun;
+#[cfg(windows)]
+pub(crate) mod wintun;
+
+/// Backend used by [`Adapter::create`](crate::Adapter::create) and
+/// [`Adapter::open`](crate::Adapter::open) on the current platform
+#[cfg(target_os = "linux")]
+pub(crate) use tun as native;
+#[cfg(windows)]
+pub(crate) use wintun as native;
+
+/// Operations an adapter implementation has to provide. Every method mirrors the public method of
+/// [`Adapter`](crate::Adapter) with the same name
+pub(crate) trait AdapterBackend: Send + Sync {
+  fn get_luid(&self) -> WintunResult<u64>;
+  fn get_guid(&self) -> WintunResult<u128>;
+  fn get_adapter_index(&self) -> WintunResult<u32>;
+  fn start_session(&self, capacity: RingCapacity) -> WintunResult<Box<dyn SessionBackend>>;
+  fn set_ip_address(&self, internal_ip: IpAndMaskPrefix) -> WintunResult<()>;
+  fn set_mtu(&self, mtu: IpPacketSize) -> WintunResult<()>;
+  fn add_route(&self, route: Route) -> WintunResult<()>;
+  fn set_dns_servers(&self, servers: &[std::net::IpAddr]) -> WintunResult<()>;
+  /// Opens another handle to the same adapter
+  fn reopen(&self) -> WintunResult<Box<dyn AdapterBackend>>;
+  fn memory_device(&self) -> Option<&std::sync::Arc<memory::MemoryDevice>> {
+    None
+  }
+}
+
+/// Operations a session implementation has to provide. Packets are handed out as raw pointers
+/// that stay valid until they are passed back to [`release`](Self::release) or
+/// [`send`](Self::send) respectively, which is exactly the contract of the Wintun ring
+pub(crate) trait SessionBackend: Send + Sync {
+  fn receive(&self) -> Result<(*mut u8, u32), ReceivePacketError>;
+  fn release(&self, data: *const u8, size: u32);
+  fn allocate(&self, size: u32) -> Result<*mut u8, AllocatePacketError>;
+  fn send(&self, data: *const u8, size: u32);
+  /// Blocks until a packet is available or the timeout elapses. Returns `false` on timeout
+  fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool>;
+  #[cfg(windows)]
+  fn read_wait_event(&self) -> WintunResult<winapi::shared::ntdef::HANDLE>;
+}
+
+/// Builds a LUID the way Windows lays it out (`NetLuidIndex` in bits 24..48, `IfType` in bits
+/// 48..64) for backends that only have an interface index
+pub(crate) fn luid_from_index(index: u32) -> u64 {
+  const IF_TYPE_PROP_VIRTUAL: u64 = 53;
+  (IF_TYPE_PROP_VIRTUAL << 48) | ((index as u64 & 0xFF_FFFF) << 24)
+}
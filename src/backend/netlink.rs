@@ -0,0 +1,199 @@
+//! Minimal rtnetlink client covering the handful of requests the TUN backend issues. Messages are
+//! serialized by hand to avoid pulling in a netlink crate for a few fixed layouts
+
+use std::net::IpAddr;
+
+use crate::{Errno, IpAndMaskPrefix, Route, WintunResult};
+
+const NLMSG_HDRLEN: usize = 16;
+
+fn align(len: usize) -> usize {
+  (len + 3) & !3
+}
+
+/// A single rtnetlink request. The body is built with [`push`](Self::push) and
+/// [`attr`](Self::attr) and sent with [`execute`](Self::execute), which waits for the kernel's
+/// acknowledgement
+pub(crate) struct Request {
+  buf: Vec<u8>,
+}
+
+impl Request {
+  pub(crate) fn new(message_type: u16, flags: u16) -> Self {
+    let mut buf = vec![0; NLMSG_HDRLEN];
+    buf[4..6].copy_from_slice(&message_type.to_ne_bytes());
+    let flags = flags | libc::NLM_F_REQUEST as u16 | libc::NLM_F_ACK as u16;
+    buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+    buf[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    Self { buf }
+  }
+
+  /// Appends a fixed size header such as `ifinfomsg` or `ifaddrmsg`
+  pub(crate) fn push(&mut self, bytes: &[u8]) -> &mut Self {
+    self.buf.extend_from_slice(bytes);
+    self.buf.resize(align(self.buf.len()), 0);
+    self
+  }
+
+  /// Appends a `rtattr` with the given payload
+  pub(crate) fn attr(&mut self, attr_type: u16, payload: &[u8]) -> &mut Self {
+    let len = (4 + payload.len()) as u16;
+    self.buf.extend_from_slice(&len.to_ne_bytes());
+    self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+    self.push(payload)
+  }
+
+  pub(crate) fn execute(&mut self) -> WintunResult<()> {
+    let len = self.buf.len() as u32;
+    self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+    let fd = unsafe {
+      libc::socket(
+        libc::AF_NETLINK,
+        libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+        libc::NETLINK_ROUTE,
+      )
+    };
+    if fd < 0 {
+      return Err(Errno::get_last_error().into());
+    }
+    let result = Self::exchange(fd, &self.buf);
+    unsafe { libc::close(fd) };
+    result
+  }
+
+  fn exchange(fd: libc::c_int, request: &[u8]) -> WintunResult<()> {
+    let sent = unsafe { libc::send(fd, request.as_ptr() as *const _, request.len(), 0) };
+    if sent < 0 {
+      return Err(Errno::get_last_error().into());
+    }
+    let mut response = [0u8; 4096];
+    loop {
+      let received = unsafe { libc::recv(fd, response.as_mut_ptr() as *mut _, response.len(), 0) };
+      if received < 0 {
+        return Err(Errno::get_last_error().into());
+      }
+      let mut message = &response[..received as usize];
+      while message.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(message[0..4].try_into().unwrap()) as usize;
+        let message_type = u16::from_ne_bytes(message[4..6].try_into().unwrap());
+        if len < NLMSG_HDRLEN || len > message.len() {
+          return Err(Errno::new(libc::EBADMSG).into());
+        }
+        if message_type == libc::NLMSG_ERROR as u16 && len >= NLMSG_HDRLEN + 4 {
+          let code = i32::from_ne_bytes(message[16..20].try_into().unwrap());
+          if code == 0 {
+            return Ok(());
+          }
+          return Err(Errno::new(-code).into());
+        }
+        message = &message[align(len).min(message.len())..];
+      }
+    }
+  }
+}
+
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> [u8; 16] {
+  let mut header = [0u8; 16];
+  header[0] = libc::AF_UNSPEC as u8;
+  header[4..8].copy_from_slice(&(index as i32).to_ne_bytes());
+  header[8..12].copy_from_slice(&flags.to_ne_bytes());
+  header[12..16].copy_from_slice(&change.to_ne_bytes());
+  header
+}
+
+pub(crate) fn set_link_up(index: u32, up: bool) -> WintunResult<()> {
+  let flags = if up { libc::IFF_UP as u32 } else { 0 };
+  Request::new(libc::RTM_NEWLINK, 0)
+    .push(&ifinfomsg(index, flags, libc::IFF_UP as u32))
+    .execute()
+}
+
+pub(crate) fn set_mtu(index: u32, mtu: u32) -> WintunResult<()> {
+  Request::new(libc::RTM_NEWLINK, 0)
+    .push(&ifinfomsg(index, 0, 0))
+    .attr(libc::IFLA_MTU, &mtu.to_ne_bytes())
+    .execute()
+}
+
+pub(crate) fn delete_link(index: u32) -> WintunResult<()> {
+  Request::new(libc::RTM_DELLINK, 0)
+    .push(&ifinfomsg(index, 0, 0))
+    .execute()
+}
+
+/// Stores a free-form description on the interface. Used to remember the tunnel type the same
+/// way Wintun stores it as the device description
+pub(crate) fn set_alias(index: u32, alias: &str) -> WintunResult<()> {
+  Request::new(libc::RTM_NEWLINK, 0)
+    .push(&ifinfomsg(index, 0, 0))
+    .attr(libc::IFLA_IFALIAS, alias.as_bytes())
+    .execute()
+}
+
+pub(crate) fn add_address(index: u32, address: IpAndMaskPrefix) -> WintunResult<()> {
+  let (family, prefix, octets) = match address {
+    IpAndMaskPrefix::V4 { ip, prefix } => (libc::AF_INET, prefix.mask(), ip.octets().to_vec()),
+    IpAndMaskPrefix::V6 { ip, prefix } => (libc::AF_INET6, prefix.mask(), ip.octets().to_vec()),
+  };
+  let mut header = [0u8; 8];
+  header[0] = family as u8;
+  header[1] = prefix;
+  header[4..8].copy_from_slice(&index.to_ne_bytes());
+  Request::new(
+    libc::RTM_NEWADDR,
+    (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+  )
+  .push(&header)
+  .attr(libc::IFA_LOCAL, &octets)
+  .attr(libc::IFA_ADDRESS, &octets)
+  .execute()
+}
+
+pub(crate) fn add_route(index: u32, route: Route) -> WintunResult<()> {
+  //The kernel rejects destinations with host bits set
+  let (family, prefix, destination) = match route.destination {
+    IpAndMaskPrefix::V4 { ip, prefix } => {
+      let mask = u32::MAX.checked_shl(32 - prefix.mask() as u32).unwrap_or(0);
+      let network = u32::from(ip) & mask;
+      (libc::AF_INET, prefix.mask(), network.to_be_bytes().to_vec())
+    }
+    IpAndMaskPrefix::V6 { ip, prefix } => {
+      let mask = u128::MAX
+        .checked_shl(128 - prefix.mask() as u32)
+        .unwrap_or(0);
+      let network = u128::from(ip) & mask;
+      (
+        libc::AF_INET6,
+        prefix.mask(),
+        network.to_be_bytes().to_vec(),
+      )
+    }
+  };
+  let mut header = [0u8; 12];
+  header[0] = family as u8;
+  header[1] = prefix;
+  header[4] = libc::RT_TABLE_MAIN;
+  header[5] = libc::RTPROT_BOOT;
+  header[6] = match route.next_hop {
+    Some(_) => libc::RT_SCOPE_UNIVERSE,
+    None => libc::RT_SCOPE_LINK,
+  };
+  header[7] = libc::RTN_UNICAST;
+  let mut request = Request::new(
+    libc::RTM_NEWROUTE,
+    (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+  );
+  request
+    .push(&header)
+    .attr(libc::RTA_DST, &destination)
+    .attr(libc::RTA_OIF, &index.to_ne_bytes());
+  match route.next_hop {
+    Some(IpAddr::V4(next_hop)) => request.attr(libc::RTA_GATEWAY, &next_hop.octets()),
+    Some(IpAddr::V6(next_hop)) => request.attr(libc::RTA_GATEWAY, &next_hop.octets()),
+    None => &mut request,
+  };
+  if route.metric != 0 {
+    request.attr(libc::RTA_PRIORITY, &route.metric.to_ne_bytes());
+  }
+  request.execute()
+}
@@ -0,0 +1,563 @@
+use std::{
+  fs::File,
+  io::Write,
+  os::fd::{AsRawFd, FromRawFd, OwnedFd},
+  path::PathBuf,
+  sync::Mutex,
+  time::{Duration, SystemTime},
+};
+
+use crate::{
+  wintun_raw::{WINTUN_LOGGER_LEVEL_WINTUN_LOG_ERR, WINTUN_LOGGER_LEVEL_WINTUN_LOG_INFO},
+  AdapterInfo, AllocatePacketError, DriverVersion, Errno, GetRunningDriverVersionError,
+  IpAndMaskPrefix, IpPacketSize, ReceivePacketError, RingCapacity, Route, WintunError,
+  WintunResult, MAX_IP_PACKET_SIZE,
+};
+
+use super::{luid_from_index, netlink, AdapterBackend, SessionBackend};
+
+const TUN_PATH: &[u8] = b"/dev/net/tun\0";
+/// Every packet buffer is large enough for the biggest IP packet so buffers can be recycled
+/// between receives and sends regardless of their size
+const BUFFER_SIZE: usize = MAX_IP_PACKET_SIZE as usize;
+/// Upper bound on the number of idle buffers kept per session
+const MAX_POOLED_BUFFERS: usize = 16;
+
+/// Longest alias the kernel stores, `IFALIASZ` without the terminator
+const MAX_ALIAS_LEN: usize = 255;
+/// Space and 32 hex digits of the guid stored after the tunnel type
+const ALIAS_GUID_LEN: usize = 33;
+
+pub(crate) struct TunAdapter {
+  /// Detached queue that keeps the interface alive without receiving any of its traffic
+  _fd: OwnedFd,
+  name: String,
+  index: u32,
+  guid: u128,
+}
+
+fn interface_request(name: &str, flags: libc::c_int) -> WintunResult<libc::ifreq> {
+  if let Some(pos) = name.bytes().position(|b| b == 0) {
+    return Err(WintunError::ContainsNull(pos));
+  }
+  if name.len() >= libc::IFNAMSIZ {
+    return Err(WintunError::TooLongName {
+      max: libc::IFNAMSIZ,
+      got: name.len(),
+    });
+  }
+  let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+  for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+    *dst = src as libc::c_char;
+  }
+  request.ifr_ifru.ifru_flags = flags as libc::c_short;
+  Ok(request)
+}
+
+fn interface_index(name: &str) -> u32 {
+  let Ok(name) = std::ffi::CString::new(name) else {
+    return 0;
+  };
+  unsafe { libc::if_nametoindex(name.as_ptr()) }
+}
+
+/// Opens a new queue of the multi-queue TUN interface `name`, creating the interface if needed
+fn attach_queue(name: &str) -> WintunResult<OwnedFd> {
+  let mut request = interface_request(
+    name,
+    libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_MULTI_QUEUE,
+  )?;
+  let fd = unsafe {
+    libc::open(
+      TUN_PATH.as_ptr() as *const _,
+      libc::O_RDWR | libc::O_CLOEXEC,
+    )
+  };
+  if fd < 0 {
+    return Err(Errno::get_last_error().into());
+  }
+  let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+  if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut request as *mut _) } < 0 {
+    return Err(Errno::get_last_error().into());
+  }
+  Ok(fd)
+}
+
+fn detach_queue(fd: &OwnedFd, name: &str) -> WintunResult<()> {
+  let mut request = interface_request(name, libc::IFF_DETACH_QUEUE)?;
+  if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETQUEUE, &mut request as *mut _) } < 0 {
+    return Err(Errno::get_last_error().into());
+  }
+  Ok(())
+}
+
+/// Stable guid for adapters that were not given one, derived from the adapter name so every
+/// process opening the adapter agrees on it
+fn guid_from_name(name: &str) -> u128 {
+  let hash = |seed: u64| {
+    name.bytes().fold(seed, |hash, byte| {
+      (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+  };
+  let guid = ((hash(0xcbf2_9ce4_8422_2325) as u128) << 64) | hash(0x6c62_272e_07bb_0142) as u128;
+  //Mark it as a RFC 9562 version 8 (custom) guid
+  (guid & !(0xF << 76) & !(0x3 << 62)) | (0x8 << 76) | (0x2 << 62)
+}
+
+/// Alias of an interface, which stores the tunnel type and the guid so every process opening the
+/// adapter reports the same ones
+fn alias(tunnel_type: &str, guid: u128) -> String {
+  format!("{tunnel_type} {guid:032x}")
+}
+
+/// Splits an alias written by [`alias`] into the tunnel type and the guid. Interfaces whose alias
+/// is only the tunnel type get no guid
+fn parse_alias(alias: &str) -> (&str, Option<u128>) {
+  match alias.rsplit_once(' ') {
+    Some((tunnel_type, guid))
+      if guid.len() == 32 && guid.bytes().all(|byte| byte.is_ascii_hexdigit()) =>
+    {
+      (tunnel_type, u128::from_str_radix(guid, 16).ok())
+    }
+    _ => (alias, None),
+  }
+}
+
+fn sysfs_path(name: &str, attribute: &str) -> PathBuf {
+  PathBuf::from("/sys/class/net").join(name).join(attribute)
+}
+
+fn io_error(error: std::io::Error) -> WintunError {
+  Errno::new(error.raw_os_error().unwrap_or(libc::EIO)).into()
+}
+
+fn guid_of(name: &str) -> u128 {
+  std::fs::read_to_string(sysfs_path(name, "ifalias"))
+    .ok()
+    .and_then(|alias| parse_alias(alias.trim_end_matches('\n')).1)
+    .unwrap_or_else(|| guid_from_name(name))
+}
+
+fn log_info(message: &str) {
+  crate::log(
+    WINTUN_LOGGER_LEVEL_WINTUN_LOG_INFO,
+    SystemTime::now(),
+    message,
+  )
+}
+
+pub(crate) fn create(
+  name: &str,
+  tunnel_type: &str,
+  requested_guid: Option<u128>,
+) -> WintunResult<Box<dyn AdapterBackend>> {
+  interface_request(name, 0)?;
+  if tunnel_type.len() + ALIAS_GUID_LEN > MAX_ALIAS_LEN {
+    return Err(WintunError::TooLongName {
+      max: MAX_ALIAS_LEN - ALIAS_GUID_LEN,
+      got: tunnel_type.len(),
+    });
+  }
+  if interface_index(name) != 0 {
+    return Err(Errno::new(libc::EEXIST).into());
+  }
+  let fd = attach_queue(name)?;
+  detach_queue(&fd, name)?;
+  let index = interface_index(name);
+  if index == 0 {
+    return Err(Errno::get_last_error().into());
+  }
+  //Whatever a claim left behind belongs to an earlier interface of the same name
+  let _ = std::fs::remove_file(LinkClaim::path(name));
+  let guid = requested_guid.unwrap_or_else(|| guid_from_name(name));
+  netlink::set_alias(index, &alias(tunnel_type, guid))?;
+  log_info(&format!("Created adapter {name}"));
+  Ok(Box::new(TunAdapter {
+    _fd: fd,
+    name: name.to_owned(),
+    index,
+    guid,
+  }))
+}
+
+pub(crate) fn open(name: &str) -> WintunResult<Box<dyn AdapterBackend>> {
+  interface_request(name, 0)?;
+  if interface_index(name) == 0 {
+    return Err(Errno::new(libc::ENODEV).into());
+  }
+  let fd = attach_queue(name)?;
+  detach_queue(&fd, name)?;
+  let index = interface_index(name);
+  Ok(Box::new(TunAdapter {
+    _fd: fd,
+    name: name.to_owned(),
+    index,
+    guid: guid_of(name),
+  }))
+}
+
+/// Lists the TUN interfaces whose alias, set on creation, matches `tunnel_type`
+pub(crate) fn list(tunnel_type: &str) -> WintunResult<Vec<AdapterInfo>> {
+  let entries = std::fs::read_dir("/sys/class/net").map_err(io_error)?;
+  let mut adapters = Vec::new();
+  for entry in entries.flatten() {
+    let path = entry.path();
+    //Only TUN/TAP devices have tun_flags
+    if !path.join("tun_flags").exists() {
+      continue;
+    }
+    let Ok(alias) = std::fs::read_to_string(path.join("ifalias")) else {
+      continue;
+    };
+    let (alias_tunnel_type, guid) = parse_alias(alias.trim_end_matches('\n'));
+    if alias_tunnel_type != tunnel_type {
+      continue;
+    }
+    let name = entry.file_name().to_string_lossy().into_owned();
+    let Some(index) = std::fs::read_to_string(path.join("ifindex"))
+      .ok()
+      .and_then(|index| index.trim().parse().ok())
+    else {
+      continue;
+    };
+    adapters.push(AdapterInfo {
+      guid: guid.unwrap_or_else(|| guid_from_name(&name)),
+      name,
+      tunnel_type: tunnel_type.to_owned(),
+      index,
+      in_memory: false,
+    });
+  }
+  Ok(adapters)
+}
+
+pub(crate) fn remove(adapter: &AdapterInfo) -> WintunResult<()> {
+  //The index may have been reused since the adapter was listed
+  if interface_index(&adapter.name) != adapter.index {
+    return Err(Errno::new(libc::ENODEV).into());
+  }
+  netlink::delete_link(adapter.index)
+}
+
+pub(crate) fn delete_driver() -> WintunResult<()> {
+  //The tun driver belongs to the kernel, there is nothing to uninstall
+  Err(Errno::new(libc::EOPNOTSUPP).into())
+}
+
+pub(crate) fn get_running_driver_version() -> Result<DriverVersion, GetRunningDriverVersionError> {
+  let version = match std::fs::read_to_string("/sys/module/tun/version") {
+    Ok(version) => version,
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+      return Err(GetRunningDriverVersionError::WintunNotLoaded)
+    }
+    Err(err) => {
+      return Err(GetRunningDriverVersionError::Other(Errno::new(
+        err.raw_os_error().unwrap_or(libc::EIO),
+      )))
+    }
+  };
+  version
+    .trim()
+    .parse()
+    .map_err(|_| GetRunningDriverVersionError::Other(Errno::new(libc::EINVAL)))
+}
+
+pub(crate) fn set_logger_enabled(_enabled: bool) {
+  //Messages of this backend are emitted by the crate itself and always reach the current logger
+}
+
+impl AdapterBackend for TunAdapter {
+  fn get_luid(&self) -> WintunResult<u64> {
+    Ok(luid_from_index(self.index))
+  }
+
+  fn get_guid(&self) -> WintunResult<u128> {
+    Ok(self.guid)
+  }
+
+  fn get_adapter_index(&self) -> WintunResult<u32> {
+    Ok(self.index)
+  }
+
+  fn start_session(&self, _capacity: RingCapacity) -> WintunResult<Box<dyn SessionBackend>> {
+    let fd = attach_queue(&self.name)?;
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0
+      || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+    {
+      return Err(Errno::get_last_error().into());
+    }
+    let link = LinkClaim::acquire(&self.name, self.index)?;
+    Ok(Box::new(TunSession {
+      fd,
+      buffers: Mutex::new(Vec::new()),
+      _link: link,
+    }))
+  }
+
+  fn set_ip_address(&self, internal_ip: IpAndMaskPrefix) -> WintunResult<()> {
+    netlink::add_address(self.index, internal_ip)
+  }
+
+  fn set_mtu(&self, mtu: IpPacketSize) -> WintunResult<()> {
+    netlink::set_mtu(self.index, mtu.size())
+  }
+
+  fn add_route(&self, route: Route) -> WintunResult<()> {
+    netlink::add_route(self.index, route)
+  }
+
+  fn set_dns_servers(&self, servers: &[std::net::IpAddr]) -> WintunResult<()> {
+    //The kernel has no notion of per-link DNS servers, they are kept by systemd-resolved
+    let mut command = std::process::Command::new("resolvectl");
+    if servers.is_empty() {
+      command.args(["revert", &self.name]);
+    } else {
+      command.args(["dns", &self.name]);
+      command.args(servers.iter().map(|server| server.to_string()));
+    }
+    let output = command
+      .stdin(std::process::Stdio::null())
+      .output()
+      .map_err(|err| Errno::new(err.raw_os_error().unwrap_or(libc::ENOENT)))?;
+    if !output.status.success() {
+      crate::log(
+        WINTUN_LOGGER_LEVEL_WINTUN_LOG_ERR,
+        SystemTime::now(),
+        String::from_utf8_lossy(&output.stderr).trim(),
+      );
+      return Err(Errno::new(libc::EIO).into());
+    }
+    Ok(())
+  }
+
+  fn reopen(&self) -> WintunResult<Box<dyn AdapterBackend>> {
+    open(&self.name)
+  }
+}
+
+/// Shared claim on the link of an interface. Sessions of every process hold one on the same file
+/// while they run, so the last of them to end can tell nobody else needs the link up
+struct LinkClaim {
+  file: File,
+  index: u32,
+}
+
+impl LinkClaim {
+  fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join("wintun2-links").join(name)
+  }
+  fn lock(&self, operation: libc::c_int) -> Result<(), Errno> {
+    loop {
+      if unsafe { libc::flock(self.file.as_raw_fd(), operation) } == 0 {
+        return Ok(());
+      }
+      let error = Errno::get_last_error();
+      if error.code() != libc::EINTR {
+        return Err(error);
+      }
+    }
+  }
+  /// Claims the link of `name`, bringing it up if it is down. The claim file then records that a
+  /// session brought it up
+  fn acquire(name: &str, index: u32) -> WintunResult<Self> {
+    let path = Self::path(name);
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let file = File::options()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)
+      .map_err(io_error)?;
+    let mut claim = Self { file, index };
+    //Waits for the last session ending at the same time to take the link down
+    claim.lock(libc::LOCK_SH)?;
+    let flags = std::fs::read_to_string(sysfs_path(name, "flags")).map_err(io_error)?;
+    let flags = u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16)
+      .map_err(|_| Errno::new(libc::EINVAL))?;
+    if flags & libc::IFF_UP as u32 == 0 {
+      netlink::set_link_up(index, true)?;
+      claim.file.set_len(0).map_err(io_error)?;
+      claim.file.write_all(b"up").map_err(io_error)?;
+    }
+    Ok(claim)
+  }
+}
+
+impl Drop for LinkClaim {
+  fn drop(&mut self) {
+    //Mirror Wintun, where the adapter loses its link once the session ends. Other sessions keep
+    //their shared lock and a link that was up before any session is left alone
+    if self.lock(libc::LOCK_EX | libc::LOCK_NB).is_err() {
+      return;
+    }
+    if self
+      .file
+      .metadata()
+      .is_ok_and(|metadata| metadata.len() > 0)
+    {
+      let _ = netlink::set_link_up(self.index, false);
+      let _ = self.file.set_len(0);
+    }
+  }
+}
+
+pub(crate) struct TunSession {
+  fd: OwnedFd,
+  buffers: Mutex<Vec<Box<[u8]>>>,
+  /// Dropped after the queue is closed
+  _link: LinkClaim,
+}
+
+impl TunSession {
+  fn take_buffer(&self) -> Box<[u8]> {
+    self
+      .buffers
+      .lock()
+      .ok()
+      .and_then(|mut buffers| buffers.pop())
+      .unwrap_or_else(|| vec![0; BUFFER_SIZE].into_boxed_slice())
+  }
+  fn return_buffer(&self, buffer: Box<[u8]>) {
+    if let Ok(mut buffers) = self.buffers.lock() {
+      if buffers.len() < MAX_POOLED_BUFFERS {
+        buffers.push(buffer);
+      }
+    }
+  }
+  /// SAFETY: `data` has to be a pointer previously produced by [`Self::leak_buffer`]
+  unsafe fn reclaim_buffer(data: *const u8) -> Box<[u8]> {
+    Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+      data as *mut u8,
+      BUFFER_SIZE,
+    ))
+  }
+  fn leak_buffer(buffer: Box<[u8]>) -> *mut u8 {
+    Box::into_raw(buffer) as *mut u8
+  }
+}
+
+impl SessionBackend for TunSession {
+  fn receive(&self) -> Result<(*mut u8, u32), ReceivePacketError> {
+    let mut buffer = self.take_buffer();
+    let read = unsafe {
+      libc::read(
+        self.fd.as_raw_fd(),
+        buffer.as_mut_ptr() as *mut _,
+        buffer.len(),
+      )
+    };
+    if read <= 0 {
+      let error = Errno::get_last_error();
+      self.return_buffer(buffer);
+      if read == 0 {
+        return Err(ReceivePacketError::WouldBlock);
+      }
+      return Err(match error.code() {
+        libc::EAGAIN => ReceivePacketError::WouldBlock,
+        libc::EBADFD | libc::EIO | libc::ENXIO | libc::ENODEV => {
+          ReceivePacketError::AdapterIsTerminating
+        }
+        libc::EINVAL => ReceivePacketError::InvalidData,
+        _ => ReceivePacketError::Other(error),
+      });
+    }
+    Ok((Self::leak_buffer(buffer), read as u32))
+  }
+
+  fn release(&self, data: *const u8, _size: u32) {
+    self.return_buffer(unsafe { Self::reclaim_buffer(data) })
+  }
+
+  fn allocate(&self, _size: u32) -> Result<*mut u8, AllocatePacketError> {
+    Ok(Self::leak_buffer(self.take_buffer()))
+  }
+
+  fn send(&self, data: *const u8, size: u32) {
+    //Like a full Wintun ring, a packet the kernel refuses is silently lost
+    unsafe { libc::write(self.fd.as_raw_fd(), data as *const _, size as usize) };
+    self.return_buffer(unsafe { Self::reclaim_buffer(data) })
+  }
+
+  fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    let mut fd = libc::pollfd {
+      fd: self.fd.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    };
+    let timeout = timeout
+      .map(|timeout| timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int)
+      .unwrap_or(-1);
+    let result = unsafe { libc::poll(&mut fd as *mut _, 1, timeout) };
+    if result < 0 {
+      let error = Errno::get_last_error();
+      if error.code() == libc::EINTR {
+        return Ok(false);
+      }
+      return Err(error.into());
+    }
+    Ok(result > 0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{alias, parse_alias, sysfs_path};
+  use crate::{list_adapters, Adapter, RingCapacity};
+
+  fn is_up(name: &str) -> bool {
+    let flags = std::fs::read_to_string(sysfs_path(name, "flags")).unwrap();
+    u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).unwrap() & libc::IFF_UP as u32
+      != 0
+  }
+
+  #[test]
+  fn aliases_carry_the_guid() {
+    let guid = 0x0123_4567_89ab_cdef_0011_2233_4455_6677;
+    assert_eq!(
+      parse_alias(&alias("tunnel type", guid)),
+      ("tunnel type", Some(guid))
+    );
+    assert_eq!(parse_alias(&alias("", guid)), ("", Some(guid)));
+    //Aliases of interfaces that only stored the tunnel type
+    assert_eq!(parse_alias("tunnel_type"), ("tunnel_type", None));
+    assert_eq!(parse_alias("tunnel type"), ("tunnel type", None));
+  }
+
+  #[test]
+  fn guids_are_stored_with_the_interface() {
+    let guid = 0x1234_5678_9abc_def0_1234_5678_9abc_def0;
+    let adapter = Adapter::create("wt-stored-guid", "stored guid", Some(guid)).unwrap();
+    //What another process opening or listing the adapter sees
+    assert_eq!(
+      super::open("wt-stored-guid").unwrap().get_guid().unwrap(),
+      guid
+    );
+    let listed = list_adapters("stored guid").unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].guid, guid);
+    adapter.close();
+  }
+
+  #[test]
+  fn the_last_session_takes_the_link_down() {
+    let adapter = Adapter::create("wt-link", "tunnel_type", None).unwrap();
+    assert!(!is_up("wt-link"));
+    let first = adapter.session(RingCapacity::max()).unwrap();
+    let second = adapter.session(RingCapacity::max()).unwrap();
+    assert!(is_up("wt-link"));
+    first.end();
+    assert!(is_up("wt-link"));
+    second.end();
+    assert!(!is_up("wt-link"));
+
+    //A link that was up before any session stays up
+    super::netlink::set_link_up(adapter.get_adapter_index().unwrap(), true).unwrap();
+    adapter.session(RingCapacity::max()).unwrap().end();
+    assert!(is_up("wt-link"));
+    adapter.close();
+  }
+}
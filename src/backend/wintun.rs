@@ -0,0 +1,596 @@
+use std::{
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  time::Duration,
+};
+
+use get_last_error::Win32Error;
+use widestring::{U16CStr, WideCStr};
+use winapi::{
+  shared::{
+    basetsd::DWORD64, devguid, guiddef::GUID, ifdef::NET_LUID, netioapi, nldef, ntdef::HANDLE,
+    ntdef::LPCWSTR, winerror, ws2def, ws2ipdef,
+  },
+  um::{
+    handleapi, ipexport, iphlpapi, iptypes, setupapi, synchapi, winbase, winnt, winreg,
+  },
+};
+
+use crate::{
+  utility::{
+    encode_utf16, guid_from_u128, guid_to_u128, interface_luid_to_guid, UnsafeHandle,
+  },
+  wintun_raw::{
+    WintunAllocateSendPacket, WintunCloseAdapter, WintunCreateAdapter, WintunDeleteDriver,
+    WintunEndSession, WintunGetAdapterLUID, WintunGetReadWaitEvent, WintunGetRunningDriverVersion,
+    WintunOpenAdapter, WintunReceivePacket, WintunReleaseReceivePacket, WintunSendPacket,
+    WintunSetLogger, WintunStartSession, DWORD, WINTUN_ADAPTER_HANDLE, WINTUN_LOGGER_LEVEL,
+    WINTUN_SESSION_HANDLE,
+  },
+  format_guid, parse_guid, AdapterInfo, AllocatePacketError, DriverVersion,
+  GetRunningDriverVersionError, IpAndMaskPrefix, IpPacketSize, ReceivePacketError, RingCapacity,
+  Route, WintunError, WintunResult, MAX_ADAPTER_NAME,
+};
+
+use super::{AdapterBackend, SessionBackend};
+
+pub(crate) struct WintunAdapter {
+  handle: UnsafeHandle<WINTUN_ADAPTER_HANDLE>,
+  name: String,
+}
+
+pub(crate) fn create(
+  name: &str,
+  tunnel_type: &str,
+  requested_guid: Option<u128>,
+) -> WintunResult<Box<dyn AdapterBackend>> {
+  let name_u16 = encode_utf16(name, MAX_ADAPTER_NAME - 1)?;
+  let tunnel_type = encode_utf16(tunnel_type, MAX_ADAPTER_NAME - 1)?;
+  //The fields of the struct are filled in from the digits as they are written, so the adapter
+  //shows up under the same guid as the one requested
+  let guid_struct: Option<GUID> = requested_guid.map(guid_from_u128);
+
+  let guid_ptr = guid_struct
+    .as_ref()
+    .map(|guid| guid as *const _)
+    .unwrap_or(std::ptr::null());
+  let name_ptr = name_u16.as_ptr();
+  let tunnel_type = tunnel_type.as_ptr();
+  let handle = unsafe { WintunCreateAdapter(name_ptr, tunnel_type, guid_ptr) };
+  if handle.is_null() {
+    return Err(Win32Error::get_last_error().into());
+  }
+  let handle = UnsafeHandle(handle);
+  Ok(Box::new(WintunAdapter {
+    handle,
+    name: name.to_owned(),
+  }))
+}
+
+pub(crate) fn open(name: &str) -> WintunResult<Box<dyn AdapterBackend>> {
+  let name_u16 = encode_utf16(name, MAX_ADAPTER_NAME)?;
+
+  let handle = unsafe { WintunOpenAdapter(name_u16.as_ptr()) };
+  if handle.is_null() {
+    return Err(Win32Error::get_last_error().into());
+  }
+  let handle = UnsafeHandle(handle);
+  Ok(Box::new(WintunAdapter {
+    handle,
+    name: name.to_owned(),
+  }))
+}
+
+/// Lists the Wintun adapters whose device description, set from the tunnel type on creation,
+/// matches `tunnel_type`. Windows appends ` #2`, ` #3` and so on to duplicate descriptions
+pub(crate) fn list(tunnel_type: &str) -> WintunResult<Vec<AdapterInfo>> {
+  const GAA_FLAG_INCLUDE_ALL_INTERFACES: u32 = 0x100;
+  let flags = iptypes::GAA_FLAG_SKIP_UNICAST
+    | iptypes::GAA_FLAG_SKIP_ANYCAST
+    | iptypes::GAA_FLAG_SKIP_MULTICAST
+    | iptypes::GAA_FLAG_SKIP_DNS_SERVER
+    | GAA_FLAG_INCLUDE_ALL_INTERFACES;
+  //IP_ADAPTER_ADDRESSES contains 8 byte fields, back the buffer with u64 for the alignment
+  let mut buf: Vec<u64> = vec![0; 2048];
+  loop {
+    let mut buf_len = (buf.len() * std::mem::size_of::<u64>()) as u32;
+    let result = unsafe {
+      iphlpapi::GetAdaptersAddresses(
+        ws2def::AF_UNSPEC as _,
+        flags,
+        std::ptr::null_mut(),
+        buf.as_mut_ptr() as *mut iptypes::IP_ADAPTER_ADDRESSES,
+        &mut buf_len as *mut _,
+      )
+    };
+    match result {
+      winerror::ERROR_SUCCESS => break,
+      winerror::ERROR_NO_DATA => return Ok(Vec::new()),
+      winerror::ERROR_BUFFER_OVERFLOW => {
+        buf.resize(buf_len as usize / std::mem::size_of::<u64>() + 1, 0)
+      }
+      error => return Err(Win32Error::new(error).into()),
+    }
+  }
+  let mut adapters = Vec::new();
+  let mut current = buf.as_ptr() as *const iptypes::IP_ADAPTER_ADDRESSES;
+  //SAFETY: GetAdaptersAddresses succeeded, so the buffer holds a linked list of valid entries
+  while let Some(adapter) = unsafe { current.as_ref() } {
+    current = adapter.Next;
+    let description = unsafe { U16CStr::from_ptr_str(adapter.Description) }.to_string_lossy();
+    let matches = description
+      .strip_prefix(tunnel_type)
+      .is_some_and(|rest| {
+        rest.is_empty()
+          || rest
+            .strip_prefix(" #")
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+      });
+    if !matches {
+      continue;
+    }
+    let luid = adapter.Luid.Value;
+    let Ok(guid) = interface_luid_to_guid(luid) else {
+      continue;
+    };
+    adapters.push(AdapterInfo {
+      name: unsafe { U16CStr::from_ptr_str(adapter.FriendlyName) }.to_string_lossy(),
+      tunnel_type: tunnel_type.to_owned(),
+      guid: guid_to_u128(guid),
+      index: unsafe { adapter.u.s().IfIndex },
+      in_memory: false,
+    });
+  }
+  Ok(adapters)
+}
+
+/// Removes the network device whose `NetCfgInstanceId` is the adapter's guid, the same way
+/// Device Manager uninstalls it
+pub(crate) fn remove(adapter: &AdapterInfo) -> WintunResult<()> {
+  let wanted = format_guid(adapter.guid);
+  let devices = unsafe {
+    setupapi::SetupDiGetClassDevsW(
+      &devguid::GUID_DEVCLASS_NET,
+      std::ptr::null(),
+      std::ptr::null_mut(),
+      setupapi::DIGCF_PRESENT,
+    )
+  };
+  if devices == handleapi::INVALID_HANDLE_VALUE {
+    return Err(Win32Error::get_last_error().into());
+  }
+  let devices = UnsafeHandle(devices);
+  let result = (|| {
+    for member in 0.. {
+      let mut device = setupapi::SP_DEVINFO_DATA {
+        cbSize: std::mem::size_of::<setupapi::SP_DEVINFO_DATA>() as u32,
+        ..Default::default()
+      };
+      if unsafe { setupapi::SetupDiEnumDeviceInfo(devices.0, member, &mut device as *mut _) } == 0 {
+        let error = Win32Error::get_last_error();
+        if error.code() == winerror::ERROR_NO_MORE_ITEMS {
+          break;
+        }
+        return Err(error.into());
+      }
+      let key = unsafe {
+        setupapi::SetupDiOpenDevRegKey(
+          devices.0,
+          &mut device as *mut _,
+          setupapi::DICS_FLAG_GLOBAL,
+          0,
+          setupapi::DIREG_DRV,
+          winnt::KEY_QUERY_VALUE,
+        )
+      };
+      if key as HANDLE == handleapi::INVALID_HANDLE_VALUE {
+        continue;
+      }
+      let value_name = encode_utf16("NetCfgInstanceId", 17)?;
+      let mut value = [0u16; 64];
+      let mut value_len = std::mem::size_of_val(&value) as u32;
+      let error = unsafe {
+        winreg::RegQueryValueExW(
+          key,
+          value_name.as_ptr(),
+          std::ptr::null_mut(),
+          std::ptr::null_mut(),
+          value.as_mut_ptr() as *mut u8,
+          &mut value_len as *mut _,
+        )
+      };
+      unsafe { winreg::RegCloseKey(key) };
+      if error != winerror::ERROR_SUCCESS as i32 {
+        continue;
+      }
+      let len = value.iter().position(|&c| c == 0).unwrap_or(value.len());
+      if !String::from_utf16_lossy(&value[..len]).eq_ignore_ascii_case(&wanted) {
+        continue;
+      }
+      let removed = unsafe {
+        setupapi::SetupDiCallClassInstaller(
+          setupapi::DIF_REMOVE,
+          devices.0,
+          &mut device as *mut _,
+        )
+      };
+      if removed == 0 {
+        return Err(Win32Error::get_last_error().into());
+      }
+      return Ok(());
+    }
+    Err(WintunError::InterfaceNotFound)
+  })();
+  unsafe { setupapi::SetupDiDestroyDeviceInfoList(devices.0) };
+  result
+}
+
+pub(crate) fn delete_driver() -> WintunResult<()> {
+  let result = unsafe { WintunDeleteDriver() };
+  if result {
+    return Ok(());
+  }
+  Err(Win32Error::get_last_error().into())
+}
+
+pub(crate) fn get_running_driver_version() -> Result<DriverVersion, GetRunningDriverVersionError> {
+  let version = unsafe { WintunGetRunningDriverVersion() };
+  if version != 0 {
+    return Ok(DriverVersion::from_raw(version));
+  }
+  let error = Win32Error::get_last_error();
+  if error.code() == winerror::ERROR_FILE_NOT_FOUND {
+    Err(GetRunningDriverVersionError::WintunNotLoaded)
+  } else {
+    Err(GetRunningDriverVersionError::Other(error))
+  }
+}
+
+extern "C" fn logger_callback_wrapper(
+  level: WINTUN_LOGGER_LEVEL,
+  timestamp: DWORD64,
+  message: LPCWSTR,
+) {
+  const SECS_SINCE_1610_01_01_UNTIL_UNIX_TIMESTAMP: u64 = 131487 * 3600 * 24;
+  let diff = std::time::Duration::from_micros(timestamp) / 10
+    - std::time::Duration::from_secs(SECS_SINCE_1610_01_01_UNTIL_UNIX_TIMESTAMP);
+  let timestamp = std::time::SystemTime::UNIX_EPOCH + diff;
+  let message = unsafe { WideCStr::from_ptr_str(message) }.to_string_lossy();
+  crate::log(level, timestamp, &message)
+}
+
+pub(crate) fn set_logger_enabled(enabled: bool) {
+  if enabled {
+    unsafe { WintunSetLogger(Some(logger_callback_wrapper)) }
+  } else {
+    unsafe { WintunSetLogger(None) }
+  }
+}
+
+fn sockaddr_inet(ip: IpAddr) -> ws2ipdef::SOCKADDR_INET {
+  let mut address = ws2ipdef::SOCKADDR_INET::default();
+  match ip {
+    IpAddr::V4(ip) => unsafe {
+      let ipv4 = address.Ipv4_mut();
+      ipv4.sin_family = ws2def::AF_INET as _;
+      *ipv4.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(ip.octets());
+    },
+    IpAddr::V6(ip) => unsafe {
+      let ipv6 = address.Ipv6_mut();
+      ipv6.sin6_family = ws2def::AF_INET6 as _;
+      *ipv6.sin6_addr.u.Byte_mut() = ip.octets();
+    },
+  }
+  address
+}
+
+impl WintunAdapter {
+  fn set_mtu_for_family(&self, family: u16, mtu: IpPacketSize) -> WintunResult<()> {
+    let mut row = netioapi::MIB_IPINTERFACE_ROW::default();
+    unsafe { netioapi::InitializeIpInterfaceEntry(&mut row as *mut _) };
+    row.Family = family;
+    row.InterfaceLuid = NET_LUID {
+      Value: self.get_luid()?,
+    };
+    let error = unsafe { netioapi::GetIpInterfaceEntry(&mut row as *mut _) };
+    if error != winerror::NO_ERROR {
+      return Err(Win32Error::new(error).into());
+    }
+    row.NlMtu = mtu.size();
+    //SitePrefixLength has to be zeroed for IPv4 or SetIpInterfaceEntry rejects the row
+    row.SitePrefixLength = 0;
+    let error = unsafe { netioapi::SetIpInterfaceEntry(&mut row as *mut _) };
+    if error != winerror::NO_ERROR {
+      return Err(Win32Error::new(error).into());
+    }
+    Ok(())
+  }
+}
+
+impl AdapterBackend for WintunAdapter {
+  fn get_luid(&self) -> WintunResult<u64> {
+    let mut luid = NET_LUID::default();
+    unsafe { WintunGetAdapterLUID(self.handle.0, &mut luid as *mut _) }
+    Ok(luid.Value)
+  }
+
+  fn get_guid(&self) -> WintunResult<u128> {
+    let guid = interface_luid_to_guid(self.get_luid()?)?;
+    Ok(guid_to_u128(guid))
+  }
+
+  fn start_session(&self, capacity: RingCapacity) -> WintunResult<Box<dyn SessionBackend>> {
+    let capacity = capacity.cap();
+    let session = unsafe { WintunStartSession(self.handle.0, capacity) };
+    if session.is_null() {
+      return Err(Win32Error::get_last_error().into());
+    }
+    Ok(Box::new(WintunSession {
+      handle: UnsafeHandle(session),
+    }))
+  }
+
+  fn set_ip_address(&self, internal_ip: IpAndMaskPrefix) -> WintunResult<()> {
+    let mut address_row = netioapi::MIB_UNICASTIPADDRESS_ROW::default();
+    unsafe {
+      netioapi::InitializeUnicastIpAddressEntry(&mut address_row as *mut _);
+    }
+    const IP_SUFFIX_ORIGIN_DHCP: winapi::shared::nldef::NL_SUFFIX_ORIGIN = 3;
+    const IP_PREFIX_ORIGIN_DHCP: winapi::shared::nldef::NL_PREFIX_ORIGIN = 3;
+    address_row.SuffixOrigin = IP_SUFFIX_ORIGIN_DHCP;
+    address_row.PrefixOrigin = IP_PREFIX_ORIGIN_DHCP;
+    const LIFETIME_INFINITE: winapi::ctypes::c_ulong = 0xffffffff;
+    address_row.ValidLifetime = LIFETIME_INFINITE;
+    address_row.PreferredLifetime = LIFETIME_INFINITE;
+    address_row.InterfaceLuid = winapi::shared::ifdef::NET_LUID_LH {
+      Value: self.get_luid()?,
+    };
+    match internal_ip {
+      IpAndMaskPrefix::V4 { ip, prefix } => {
+        unsafe {
+          let ipv4 = address_row.Address.Ipv4_mut();
+          ipv4.sin_family = ws2def::AF_INET as _;
+          *ipv4.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(ip.octets());
+        }
+        address_row.OnLinkPrefixLength = prefix.mask();
+      }
+      IpAndMaskPrefix::V6 { ip, prefix } => {
+        unsafe {
+          let ipv6 = address_row.Address.Ipv6_mut();
+          ipv6.sin6_family = ws2def::AF_INET6 as _;
+          *ipv6.sin6_addr.u.Byte_mut() = ip.octets();
+        }
+        address_row.OnLinkPrefixLength = prefix.mask();
+      }
+    }
+
+    address_row.DadState = winapi::shared::nldef::IpDadStatePreferred;
+    let error = unsafe { netioapi::CreateUnicastIpAddressEntry(&mut address_row as *mut _) };
+    if error != winerror::ERROR_SUCCESS {
+      return Err(Win32Error::new(error).into());
+    }
+    Ok(())
+  }
+
+  fn set_mtu(&self, mtu: IpPacketSize) -> WintunResult<()> {
+    self.set_mtu_for_family(ws2def::AF_INET as _, mtu)?;
+    //IPv6 requires links to carry at least 1280 bytes, the stack refuses smaller values
+    if mtu.size() >= 1280 {
+      self.set_mtu_for_family(ws2def::AF_INET6 as _, mtu)?;
+    }
+    Ok(())
+  }
+
+  fn add_route(&self, route: Route) -> WintunResult<()> {
+    let mut row = netioapi::MIB_IPFORWARD_ROW2::default();
+    unsafe { netioapi::InitializeIpForwardEntry(&mut row as *mut _) };
+    row.InterfaceLuid = NET_LUID {
+      Value: self.get_luid()?,
+    };
+    let (destination, prefix, unspecified) = match route.destination {
+      IpAndMaskPrefix::V4 { ip, prefix } => (
+        IpAddr::V4(ip),
+        prefix.mask(),
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+      ),
+      IpAndMaskPrefix::V6 { ip, prefix } => (
+        IpAddr::V6(ip),
+        prefix.mask(),
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+      ),
+    };
+    row.DestinationPrefix.Prefix = sockaddr_inet(destination);
+    row.DestinationPrefix.PrefixLength = prefix;
+    //On-link routes still need the next hop family set, with an unspecified address
+    row.NextHop = sockaddr_inet(route.next_hop.unwrap_or(unspecified));
+    row.Metric = route.metric;
+    row.Protocol = nldef::MIB_IPPROTO_NETMGMT;
+    let error = unsafe { netioapi::CreateIpForwardEntry2(&row as *const _) };
+    if error != winerror::NO_ERROR {
+      return Err(Win32Error::new(error).into());
+    }
+    Ok(())
+  }
+
+  fn set_dns_servers(&self, servers: &[IpAddr]) -> WintunResult<()> {
+    let guid = format_guid(self.get_guid()?);
+    //Each stack keeps the servers of its family as a comma separated list per interface
+    for (stack, ipv6) in [("Tcpip", false), ("Tcpip6", true)] {
+      let list = servers
+        .iter()
+        .filter(|server| server.is_ipv6() == ipv6)
+        .map(|server| server.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+      let key = format!("SYSTEM\\CurrentControlSet\\Services\\{stack}\\Parameters\\Interfaces\\{guid}");
+      let key = encode_utf16(&key, key.len() + 1)?;
+      let value_name = encode_utf16("NameServer", 11)?;
+      let value = encode_utf16(&list, list.len() + 1)?;
+      let error = unsafe {
+        winreg::RegSetKeyValueW(
+          winreg::HKEY_LOCAL_MACHINE,
+          key.as_ptr(),
+          value_name.as_ptr(),
+          winnt::REG_SZ,
+          value.as_ptr() as *const _,
+          (value.len() as u32 + 1) * 2,
+        )
+      };
+      if error != winerror::ERROR_SUCCESS as i32 {
+        return Err(Win32Error::new(error as u32).into());
+      }
+    }
+    Ok(())
+  }
+
+  fn get_adapter_index(&self) -> WintunResult<u32> {
+    let guid = self.get_guid()?;
+    let mut buf_len: u32 = 0;
+    //First figure out the size of the buffer needed to store the adapter info
+    //SAFETY: We are upholding the contract of GetInterfaceInfo. buf_len is a valid pointer to
+    //stack memory
+    let result =
+      unsafe { iphlpapi::GetInterfaceInfo(std::ptr::null_mut(), &mut buf_len as *mut u32) };
+    if result != winerror::NO_ERROR && result != winerror::ERROR_INSUFFICIENT_BUFFER {
+      return Err(Win32Error::new(result).into());
+    }
+
+    //Allocate a buffer of the requested size
+    //IP_INTERFACE_INFO must be aligned by at least 4 byte boundaries so use u32 as the
+    //underlying data storage type
+    let buf_elements = buf_len as usize / std::mem::size_of::<u32>() + 1;
+    //Round up incase integer division truncated a byte that filled a partial element
+    let mut buf: Vec<u32> = vec![0; buf_elements];
+
+    let buf_bytes = buf.len() * std::mem::size_of::<u32>();
+    assert!(buf_bytes >= buf_len as usize);
+
+    //SAFETY:
+    //
+    //  1. We are upholding the contract of GetInterfaceInfo.
+    //  2. `final_buf_len` is an aligned, valid pointer to stack memory
+    //  3. buf is a valid, non-null pointer to at least `buf_len` bytes of heap memory,
+    //     aligned to at least 4 byte boundaries
+    //
+    //Get the info
+    let mut final_buf_len: u32 = buf_len;
+    let result = unsafe {
+      iphlpapi::GetInterfaceInfo(
+        buf.as_mut_ptr() as *mut ipexport::IP_INTERFACE_INFO,
+        &mut final_buf_len as *mut u32,
+      )
+    };
+    if result != winerror::NO_ERROR {
+      return Err(Win32Error::new(result).into());
+    }
+    let info = buf.as_mut_ptr() as *const ipexport::IP_INTERFACE_INFO;
+    //SAFETY:
+    // info is a valid, non-null, at least 4 byte aligned pointer obtained from
+    // Vec::with_capacity that is readable for up to `buf_len` bytes which is guaranteed to be
+    // larger than on IP_INTERFACE_INFO struct as the kernel would never ask for less memory then
+    // what it will write. The largest type inside IP_INTERFACE_INFO is a u32 therefore
+    // a painter to IP_INTERFACE_INFO requires an alignment of at leant 4 bytes, which
+    // Vec<u32>::as_mut_ptr() provides
+    let adapter_base = unsafe { &*info };
+    let adapter_count = adapter_base.NumAdapters;
+    let first_adapter = &adapter_base.Adapter as *const ipexport::IP_ADAPTER_INDEX_MAP;
+
+    // SAFETY:
+    //  1. first_adapter is a valid, non null pointer, aligned to at least 4 byte boundaries
+    //     obtained from moving a multiple of 4 offset into the buf given by Vec::with_capacity.
+    //  2. We gave GetInterfaceInfo a buffer of at least least `buf_len` bytes to work with and it
+    //     succeeded in writing the adapter information within the bounds of that buffer, otherwise
+    //     it would've failed. Because the operation succeeded, we know that reading n=NumAdapters
+    //     IP_ADAPTER_INDEX_MAP structs stays within the bounds of buf's buffer
+    let interfaces = unsafe { std::slice::from_raw_parts(first_adapter, adapter_count as usize) };
+
+    for interface in interfaces {
+      let name = unsafe { U16CStr::from_ptr_str(&interface.Name as *const u16).to_string_lossy() };
+      //Name is something like: \DEVICE\TCPIP_{29C47F55-C7BD-433A-8BF7-408DFD3B3390}
+      let Ok(target_guid) = parse_guid(&name) else {
+        continue;
+      };
+      if target_guid == guid {
+        return Ok(interface.Index);
+      }
+    }
+    Err(WintunError::InterfaceNotFound)
+  }
+
+  fn reopen(&self) -> WintunResult<Box<dyn AdapterBackend>> {
+    open(&self.name)
+  }
+}
+
+impl Drop for WintunAdapter {
+  fn drop(&mut self) {
+    unsafe { WintunCloseAdapter(self.handle.0) };
+  }
+}
+
+pub(crate) struct WintunSession {
+  handle: UnsafeHandle<WINTUN_SESSION_HANDLE>,
+}
+
+impl SessionBackend for WintunSession {
+  fn receive(&self) -> Result<(*mut u8, u32), ReceivePacketError> {
+    let mut packet_size: DWORD = 0;
+    let packet_raw = unsafe { WintunReceivePacket(self.handle.0, &mut packet_size as *mut _) };
+    if packet_raw.is_null() {
+      let error = Win32Error::get_last_error();
+      return Err(match error.code() {
+        winerror::ERROR_HANDLE_EOF => ReceivePacketError::AdapterIsTerminating,
+        winerror::ERROR_NO_MORE_ITEMS => ReceivePacketError::WouldBlock,
+        winerror::ERROR_INVALID_DATA => ReceivePacketError::InvalidData,
+        _ => ReceivePacketError::Other(error),
+      });
+    }
+    Ok((packet_raw, packet_size))
+  }
+
+  fn release(&self, data: *const u8, _size: u32) {
+    unsafe { WintunReleaseReceivePacket(self.handle.0, data) }
+  }
+
+  fn allocate(&self, size: u32) -> Result<*mut u8, AllocatePacketError> {
+    let packet_raw = unsafe { WintunAllocateSendPacket(self.handle.0, size) };
+    if packet_raw.is_null() {
+      let error = Win32Error::get_last_error();
+      return Err(match error.code() {
+        winerror::ERROR_HANDLE_EOF => AllocatePacketError::AdapterIsTerminating,
+        winerror::ERROR_BUFFER_OVERFLOW => AllocatePacketError::WouldBlock,
+        _ => AllocatePacketError::Other(error),
+      });
+    }
+    Ok(packet_raw)
+  }
+
+  fn send(&self, data: *const u8, _size: u32) {
+    unsafe { WintunSendPacket(self.handle.0, data) }
+  }
+
+  fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    let event = self.read_wait_event()?;
+    let timeout = timeout
+      .map(|timeout| timeout.as_millis().min(winbase::INFINITE as u128 - 1) as u32)
+      .unwrap_or(winbase::INFINITE);
+    match unsafe { synchapi::WaitForSingleObject(event, timeout) } {
+      winbase::WAIT_OBJECT_0 => Ok(true),
+      winerror::WAIT_TIMEOUT => Ok(false),
+      _ => Err(Win32Error::get_last_error().into()),
+    }
+  }
+
+  fn read_wait_event(&self) -> WintunResult<HANDLE> {
+    let event = unsafe { WintunGetReadWaitEvent(self.handle.0) };
+    if event.is_null() {
+      return Err(Win32Error::get_last_error().into());
+    }
+    Ok(event)
+  }
+}
+
+impl Drop for WintunSession {
+  fn drop(&mut self) {
+    unsafe { WintunEndSession(self.handle.0) };
+  }
+}
@@ -0,0 +1,549 @@
+//! Hand written argument parsing. Options may appear anywhere after the subcommand and take their
+//! value either as the next argument or after `=`
+
+use std::{
+  fmt,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  path::PathBuf,
+  str::FromStr,
+};
+
+use wintun2::{GuidParseError, IpAndMaskPrefix, RingCapacity};
+
+pub const DEFAULT_TUNNEL_TYPE: &str = "wintun2";
+
+pub const USAGE: &str = "\
+Usage: wintun2 [--json] <command> [arguments]
+
+Commands:
+  create <name> [--tunnel-type <type>] [--guid <guid>]
+      Create an adapter and keep it until the tool is stopped
+  open <name>
+      Show an existing adapter
+  list [--tunnel-type <type>]
+      List the adapters of a tunnel type
+  delete <name> [--tunnel-type <type>]
+      Remove an adapter of a tunnel type
+  set-ip <name> <address/prefix>...
+      Assign addresses to an adapter
+  routes <name> <destination/prefix>... [--via <address>] [--metric <metric>]
+      Route destinations through an adapter
+  dns <name> [<server>...]
+      Replace the DNS servers of an adapter, none removes them
+  driver-version
+      Show the version of the running driver
+  delete-driver
+      Remove the driver once no adapter uses it
+  capture <name> <file> [--count <packets>] [--capacity <bytes>]
+      Receive packets from an adapter into a pcap file
+  apply <config>
+      Create the adapters described by a config file and keep them until the tool is stopped
+
+Options:
+  --json        Print the result as JSON
+  -h, --help    Print this help";
+
+/// Options taking a value
+const VALUE_OPTIONS: &[&str] = &[
+  "--tunnel-type",
+  "--guid",
+  "--via",
+  "--metric",
+  "--count",
+  "--capacity",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cli {
+  pub json: bool,
+  /// Works on in-memory adapters instead of native ones. Hidden from the usage, it exists so the
+  /// tool can be exercised without privileges
+  pub in_memory: bool,
+  pub command: Command,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+  Help,
+  Create {
+    name: String,
+    tunnel_type: String,
+    guid: Option<u128>,
+  },
+  Open {
+    name: String,
+  },
+  List {
+    tunnel_type: String,
+  },
+  Delete {
+    name: String,
+    tunnel_type: String,
+  },
+  SetIp {
+    name: String,
+    addresses: Vec<IpAndMaskPrefix>,
+  },
+  Routes {
+    name: String,
+    destinations: Vec<IpAndMaskPrefix>,
+    via: Option<IpAddr>,
+    metric: u32,
+  },
+  Dns {
+    name: String,
+    servers: Vec<IpAddr>,
+  },
+  DriverVersion,
+  DeleteDriver,
+  Capture {
+    name: String,
+    file: PathBuf,
+    count: Option<u64>,
+    capacity: RingCapacity,
+  },
+  Apply {
+    config: PathBuf,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgError {
+  MissingCommand,
+  UnknownCommand(String),
+  /// A required positional argument is missing
+  Missing(&'static str),
+  Unexpected(String),
+  UnknownOption(String),
+  MissingValue(String),
+  Invalid {
+    what: &'static str,
+    value: String,
+  },
+  InvalidGuid {
+    value: String,
+    error: GuidParseError,
+  },
+}
+
+impl fmt::Display for ArgError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ArgError::MissingCommand => f.write_str("Missing command"),
+      ArgError::UnknownCommand(command) => {
+        f.write_fmt(format_args!("Unknown command \"{command}\""))
+      }
+      ArgError::Missing(what) => f.write_fmt(format_args!("Missing {what}")),
+      ArgError::Unexpected(arg) => f.write_fmt(format_args!("Unexpected argument \"{arg}\"")),
+      ArgError::UnknownOption(option) => f.write_fmt(format_args!("Unknown option \"{option}\"")),
+      ArgError::MissingValue(option) => {
+        f.write_fmt(format_args!("Option \"{option}\" requires a value"))
+      }
+      ArgError::Invalid { what, value } => f.write_fmt(format_args!("Invalid {what} \"{value}\"")),
+      ArgError::InvalidGuid { value, error } => {
+        f.write_fmt(format_args!("Invalid guid \"{value}\": {error}"))
+      }
+    }
+  }
+}
+
+impl std::error::Error for ArgError {}
+
+/// Arguments split into positionals and options, before the command gives them meaning
+struct Arguments {
+  positionals: std::vec::IntoIter<String>,
+  options: Vec<(String, String)>,
+}
+
+impl Arguments {
+  fn positional(&mut self, what: &'static str) -> Result<String, ArgError> {
+    self.positionals.next().ok_or(ArgError::Missing(what))
+  }
+  fn rest(&mut self) -> Vec<String> {
+    self.positionals.by_ref().collect()
+  }
+  /// Takes the last value given for `option`
+  fn option(&mut self, option: &str) -> Option<String> {
+    let mut value = None;
+    self.options.retain(|(name, given)| {
+      if name != option {
+        return true;
+      }
+      value = Some(given.clone());
+      false
+    });
+    value
+  }
+  fn parsed_option<T: FromStr>(
+    &mut self,
+    option: &str,
+    what: &'static str,
+  ) -> Result<Option<T>, ArgError> {
+    self
+      .option(option)
+      .map(|value| parse(&value, what))
+      .transpose()
+  }
+  /// Fails on anything the command did not consume
+  fn finish(mut self) -> Result<(), ArgError> {
+    if let Some(arg) = self.positionals.next() {
+      return Err(ArgError::Unexpected(arg));
+    }
+    if let Some((option, _)) = self.options.pop() {
+      return Err(ArgError::UnknownOption(option));
+    }
+    Ok(())
+  }
+}
+
+fn parse<T: FromStr>(value: &str, what: &'static str) -> Result<T, ArgError> {
+  value.parse().map_err(|_| ArgError::Invalid {
+    what,
+    value: value.to_owned(),
+  })
+}
+
+pub fn parse_args<I>(args: I) -> Result<Cli, ArgError>
+where
+  I: IntoIterator,
+  I::Item: Into<String>,
+{
+  let mut json = false;
+  let mut in_memory = false;
+  let mut help = false;
+  let mut positionals = Vec::new();
+  let mut options = Vec::new();
+  let mut args = args.into_iter().map(Into::into);
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--json" => json = true,
+      "--in-memory" => in_memory = true,
+      "-h" | "--help" => help = true,
+      option if option.starts_with("--") => {
+        let (name, value) = match option.split_once('=') {
+          Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+          None => (option.to_owned(), None),
+        };
+        if !VALUE_OPTIONS.contains(&name.as_str()) {
+          return Err(ArgError::UnknownOption(name));
+        }
+        let value = match value {
+          Some(value) => value,
+          None => args
+            .next()
+            .ok_or_else(|| ArgError::MissingValue(name.clone()))?,
+        };
+        options.push((name, value));
+      }
+      _ => positionals.push(arg),
+    }
+  }
+  let mut arguments = Arguments {
+    positionals: positionals.into_iter(),
+    options,
+  };
+  let command = match arguments.positionals.next() {
+    _ if help => Command::Help,
+    None => return Err(ArgError::MissingCommand),
+    Some(command) => parse_command(&command, &mut arguments)?,
+  };
+  if !help {
+    arguments.finish()?;
+  }
+  Ok(Cli {
+    json,
+    in_memory,
+    command,
+  })
+}
+
+fn parse_command(command: &str, args: &mut Arguments) -> Result<Command, ArgError> {
+  let tunnel_type = |args: &mut Arguments| {
+    args
+      .option("--tunnel-type")
+      .unwrap_or_else(|| DEFAULT_TUNNEL_TYPE.to_owned())
+  };
+  Ok(match command {
+    "help" => Command::Help,
+    "create" => Command::Create {
+      name: args.positional("adapter name")?,
+      tunnel_type: tunnel_type(args),
+      guid: args
+        .option("--guid")
+        .map(|guid| parse_guid(&guid))
+        .transpose()?,
+    },
+    "open" => Command::Open {
+      name: args.positional("adapter name")?,
+    },
+    "list" => Command::List {
+      tunnel_type: tunnel_type(args),
+    },
+    "delete" => Command::Delete {
+      name: args.positional("adapter name")?,
+      tunnel_type: tunnel_type(args),
+    },
+    "set-ip" => {
+      let name = args.positional("adapter name")?;
+      let addresses = args
+        .rest()
+        .iter()
+        .map(|address| parse_prefix(address))
+        .collect::<Result<Vec<_>, _>>()?;
+      if addresses.is_empty() {
+        return Err(ArgError::Missing("address"));
+      }
+      Command::SetIp { name, addresses }
+    }
+    "routes" => {
+      let name = args.positional("adapter name")?;
+      let destinations = args
+        .rest()
+        .iter()
+        .map(|destination| parse_prefix(destination))
+        .collect::<Result<Vec<_>, _>>()?;
+      if destinations.is_empty() {
+        return Err(ArgError::Missing("destination"));
+      }
+      Command::Routes {
+        name,
+        destinations,
+        via: args.parsed_option("--via", "next hop")?,
+        metric: args.parsed_option("--metric", "metric")?.unwrap_or(0),
+      }
+    }
+    "dns" => Command::Dns {
+      name: args.positional("adapter name")?,
+      servers: args
+        .rest()
+        .iter()
+        .map(|server| parse(server, "dns server"))
+        .collect::<Result<_, _>>()?,
+    },
+    "driver-version" => Command::DriverVersion,
+    "delete-driver" => Command::DeleteDriver,
+    "capture" => Command::Capture {
+      name: args.positional("adapter name")?,
+      file: args.positional("capture file")?.into(),
+      count: args.parsed_option("--count", "packet count")?,
+      capacity: match args.parsed_option::<u32>("--capacity", "ring capacity")? {
+        Some(capacity) => capacity.try_into().map_err(|_| ArgError::Invalid {
+          what: "ring capacity",
+          value: capacity.to_string(),
+        })?,
+        None => RingCapacity::max(),
+      },
+    },
+    "apply" => Command::Apply {
+      config: args.positional("config file")?.into(),
+    },
+    command => return Err(ArgError::UnknownCommand(command.to_owned())),
+  })
+}
+
+/// Parses `address/prefix`. Without a prefix the address stands for itself alone
+pub fn parse_prefix(value: &str) -> Result<IpAndMaskPrefix, ArgError> {
+  let invalid = || ArgError::Invalid {
+    what: "address",
+    value: value.to_owned(),
+  };
+  let (address, prefix) = match value.split_once('/') {
+    Some((address, prefix)) => (address, Some(prefix)),
+    None => (value, None),
+  };
+  let prefix = prefix
+    .map(|prefix| prefix.parse::<u8>().map_err(|_| invalid()))
+    .transpose()?;
+  if let Ok(ip) = address.parse::<Ipv4Addr>() {
+    let prefix = prefix.unwrap_or(32).try_into().map_err(|_| invalid())?;
+    return Ok(IpAndMaskPrefix::V4 { ip, prefix });
+  }
+  let ip = address.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+  let prefix = prefix.unwrap_or(128).try_into().map_err(|_| invalid())?;
+  Ok(IpAndMaskPrefix::V6 { ip, prefix })
+}
+
+/// Parses a guid in any of the forms [`wintun2::parse_guid`] accepts
+pub fn parse_guid(value: &str) -> Result<u128, ArgError> {
+  wintun2::parse_guid(value).map_err(|error| ArgError::InvalidGuid {
+    value: value.to_owned(),
+    error,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+  use wintun2::{IpAndMaskPrefix, RingCapacity};
+
+  use super::{parse_args, parse_guid, parse_prefix, ArgError, Cli, Command};
+
+  fn command(args: &[&str]) -> Command {
+    parse_args(args.iter().copied()).unwrap().command
+  }
+
+  #[test]
+  fn global_flags_anywhere() {
+    let cli = parse_args(["list", "--json", "--in-memory"]).unwrap();
+    assert_eq!(
+      cli,
+      Cli {
+        json: true,
+        in_memory: true,
+        command: Command::List {
+          tunnel_type: "wintun2".to_owned()
+        }
+      }
+    );
+    assert!(!parse_args(["--json", "list"]).unwrap().in_memory);
+    assert_eq!(command(&["create", "--help"]), Command::Help);
+    assert_eq!(command(&["-h"]), Command::Help);
+  }
+
+  #[test]
+  fn create_with_options() {
+    assert_eq!(
+      command(&[
+        "create",
+        "wt0",
+        "--tunnel-type=vpn",
+        "--guid",
+        "{0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9}"
+      ]),
+      Command::Create {
+        name: "wt0".to_owned(),
+        tunnel_type: "vpn".to_owned(),
+        guid: Some(0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9),
+      }
+    );
+  }
+
+  #[test]
+  fn routes_and_addresses() {
+    assert_eq!(
+      command(&[
+        "routes",
+        "wt0",
+        "10.1.0.0/16",
+        "fd00::/8",
+        "--via",
+        "10.0.0.2",
+        "--metric",
+        "5"
+      ]),
+      Command::Routes {
+        name: "wt0".to_owned(),
+        destinations: vec![
+          IpAndMaskPrefix::V4 {
+            ip: Ipv4Addr::new(10, 1, 0, 0),
+            prefix: 16.try_into().unwrap()
+          },
+          IpAndMaskPrefix::V6 {
+            ip: Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0),
+            prefix: 8.try_into().unwrap()
+          },
+        ],
+        via: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+        metric: 5,
+      }
+    );
+    assert_eq!(
+      parse_prefix("10.0.0.1").unwrap(),
+      IpAndMaskPrefix::V4 {
+        ip: Ipv4Addr::new(10, 0, 0, 1),
+        prefix: 32.try_into().unwrap()
+      }
+    );
+    assert_eq!(
+      command(&["dns", "wt0"]),
+      Command::Dns {
+        name: "wt0".to_owned(),
+        servers: Vec::new()
+      }
+    );
+    assert_eq!(
+      command(&["capture", "wt0", "out.pcap", "--count", "3"]),
+      Command::Capture {
+        name: "wt0".to_owned(),
+        file: "out.pcap".into(),
+        count: Some(3),
+        capacity: RingCapacity::max(),
+      }
+    );
+  }
+
+  #[test]
+  fn reject_bad_arguments() {
+    let error = |args: &[&str]| parse_args(args.iter().copied()).unwrap_err();
+    assert_eq!(error(&[]), ArgError::MissingCommand);
+    assert_eq!(
+      error(&["frobnicate"]),
+      ArgError::UnknownCommand("frobnicate".to_owned())
+    );
+    assert_eq!(error(&["open"]), ArgError::Missing("adapter name"));
+    assert_eq!(
+      error(&["open", "a", "b"]),
+      ArgError::Unexpected("b".to_owned())
+    );
+    assert_eq!(
+      error(&["open", "a", "--via", "1.1.1.1"]),
+      ArgError::UnknownOption("--via".to_owned())
+    );
+    assert_eq!(
+      error(&["list", "--verbose"]),
+      ArgError::UnknownOption("--verbose".to_owned())
+    );
+    assert_eq!(
+      error(&["list", "--tunnel-type"]),
+      ArgError::MissingValue("--tunnel-type".to_owned())
+    );
+    assert_eq!(error(&["set-ip", "wt0"]), ArgError::Missing("address"));
+    for address in ["10.0.0.1/33", "fd00::/129", "10.0.0/8", "10.0.0.1/"] {
+      assert!(
+        matches!(
+          error(&["set-ip", "wt0", address]),
+          ArgError::Invalid {
+            what: "address",
+            ..
+          }
+        ),
+        "{address}"
+      );
+    }
+    assert!(matches!(
+      error(&["capture", "wt0", "f", "--capacity", "1000"]),
+      ArgError::Invalid {
+        what: "ring capacity",
+        ..
+      }
+    ));
+  }
+
+  #[test]
+  fn guids() {
+    assert_eq!(
+      parse_guid("0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9"),
+      Ok(0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9)
+    );
+    assert_eq!(
+      parse_guid("urn:uuid:0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9"),
+      Ok(0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9)
+    );
+    assert_eq!(
+      parse_guid("0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8fg")
+        .unwrap_err()
+        .to_string(),
+      "Invalid guid \"0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8fg\": Expected a hex digit at position 35 \
+       of the guid, found 'g'"
+    );
+    for guid in [
+      "{0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9",
+      "0a1b2c3d4e5f607182934b5c6d7e8f9",
+      "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8fg",
+      "+a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9",
+    ] {
+      assert!(parse_guid(guid).is_err(), "{guid}");
+    }
+  }
+}
@@ -0,0 +1,481 @@
+use std::{
+  any::Any,
+  fmt,
+  fs::File,
+  io::{self, BufWriter},
+  net::IpAddr,
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+
+use wintun2::{
+  format_guid, Adapter, AdapterInfo, GetRunningDriverVersionError, IpAndMaskPrefix,
+  ReceivePacketError, RingCapacity, Route, TunnelBackend, TunnelSupervisor, WintunError,
+  MAX_IP_PACKET_SIZE,
+};
+
+use crate::{
+  args::{Cli, Command},
+  config::{parse_config, ConfigError},
+  output::Value,
+  pcap::PcapWriter,
+};
+
+#[derive(Debug)]
+pub enum CommandError {
+  Wintun(WintunError),
+  Driver(GetRunningDriverVersionError),
+  Io { path: PathBuf, error: io::Error },
+  Config { path: PathBuf, error: ConfigError },
+  NotFound(String),
+}
+
+impl CommandError {
+  pub fn to_value(&self) -> Value {
+    let (operation, adapter) = match self {
+      CommandError::Wintun(error) => (
+        error.operation().map(|operation| operation.to_string()),
+        error.adapter_name().map(str::to_owned),
+      ),
+      CommandError::NotFound(name) => (None, Some(name.clone())),
+      _ => (None, None),
+    };
+    let kind = match self {
+      CommandError::Wintun(error) => error.kind(),
+      CommandError::Driver(error) => WintunError::from(*error).kind(),
+      CommandError::Io { error, .. } => error.kind(),
+      CommandError::Config { .. } => io::ErrorKind::InvalidData,
+      CommandError::NotFound(_) => io::ErrorKind::NotFound,
+    };
+    Value::object([
+      ("error", format!("{self:#}").into()),
+      ("operation", operation.into()),
+      ("adapter", adapter.into()),
+      ("kind", format!("{kind:?}").into()),
+    ])
+  }
+}
+
+impl fmt::Display for CommandError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CommandError::Wintun(error) => fmt::Display::fmt(error, f),
+      CommandError::Driver(error) => fmt::Display::fmt(error, f),
+      CommandError::Io { path, error } => f.write_fmt(format_args!("{}: {error}", path.display())),
+      CommandError::Config { path, error } => {
+        f.write_fmt(format_args!("{}: {error}", path.display()))
+      }
+      CommandError::NotFound(name) => f.write_fmt(format_args!("No adapter named \"{name}\"")),
+    }
+  }
+}
+
+impl From<WintunError> for CommandError {
+  fn from(value: WintunError) -> Self {
+    Self::Wintun(value)
+  }
+}
+
+pub struct Outcome {
+  pub output: Value,
+  /// Adapters and tunnels that are removed when dropped, so the tool keeps running while any are
+  /// held
+  pub held: Vec<Box<dyn Any>>,
+}
+
+impl From<Value> for Outcome {
+  fn from(output: Value) -> Self {
+    Self {
+      output,
+      held: Vec::new(),
+    }
+  }
+}
+
+pub fn run(cli: &Cli) -> Result<Outcome, CommandError> {
+  Ok(match &cli.command {
+    Command::Help => Value::String(crate::args::USAGE.to_owned()).into(),
+    Command::Create {
+      name,
+      tunnel_type,
+      guid,
+    } => {
+      let adapter = if cli.in_memory {
+        Adapter::create_in_memory(name, tunnel_type, *guid)
+      } else {
+        Adapter::create(name, tunnel_type, *guid)
+      }?;
+      Outcome {
+        output: adapter_value(&adapter)?,
+        held: vec![Box::new(adapter)],
+      }
+    }
+    Command::Open { name } => adapter_value(&Adapter::open(name)?)?.into(),
+    Command::List { tunnel_type } => {
+      let adapters = wintun2::list_adapters(tunnel_type)?
+        .iter()
+        .filter(|adapter| adapter.in_memory == cli.in_memory)
+        .map(info_value)
+        .collect();
+      Value::Array(adapters).into()
+    }
+    Command::Delete { name, tunnel_type } => {
+      let report = wintun2::sweep_adapters(tunnel_type, |adapter| {
+        adapter.name != *name || adapter.in_memory != cli.in_memory
+      })?;
+      if let Some((_, error)) = report.failed.into_iter().next() {
+        return Err(error.into());
+      }
+      if report.removed.is_empty() {
+        return Err(CommandError::NotFound(name.clone()));
+      }
+      Value::object([(
+        "removed",
+        Value::Array(report.removed.iter().map(info_value).collect()),
+      )])
+      .into()
+    }
+    Command::SetIp { name, addresses } => {
+      let mut adapter = Adapter::open(name)?;
+      for address in addresses {
+        adapter.set_ip_address(*address)?;
+      }
+      Value::object([
+        ("name", name.as_str().into()),
+        (
+          "addresses",
+          addresses
+            .iter()
+            .map(|address| prefix_text(*address))
+            .collect::<Vec<_>>()
+            .into(),
+        ),
+      ])
+      .into()
+    }
+    Command::Routes {
+      name,
+      destinations,
+      via,
+      metric,
+    } => {
+      let adapter = Adapter::open(name)?;
+      let mut routes = Vec::new();
+      for destination in destinations {
+        let route = Route {
+          destination: *destination,
+          next_hop: *via,
+          metric: *metric,
+        };
+        adapter.add_route(route)?;
+        routes.push(route_value(&route));
+      }
+      Value::object([
+        ("name", name.as_str().into()),
+        ("routes", Value::Array(routes)),
+      ])
+      .into()
+    }
+    Command::Dns { name, servers } => {
+      Adapter::open(name)?.set_dns_servers(servers)?;
+      Value::object([
+        ("name", name.as_str().into()),
+        (
+          "dns_servers",
+          servers
+            .iter()
+            .map(IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .into(),
+        ),
+      ])
+      .into()
+    }
+    Command::DriverVersion => match wintun2::get_running_driver_version() {
+      Ok(version) => Value::object([
+        ("loaded", true.into()),
+        ("version", version.to_string().into()),
+      ]),
+      Err(GetRunningDriverVersionError::WintunNotLoaded) => {
+        Value::object([("loaded", false.into()), ("version", Value::Null)])
+      }
+      Err(error) => return Err(CommandError::Driver(error)),
+    }
+    .into(),
+    Command::DeleteDriver => {
+      wintun2::delete_driver()?;
+      Value::object([("deleted", true.into())]).into()
+    }
+    Command::Capture {
+      name,
+      file,
+      count,
+      capacity,
+    } => capture(name, file, *count, *capacity)?.into(),
+    Command::Apply { config } => apply(config, cli.in_memory)?,
+  })
+}
+
+/// Receives packets until `count` were captured or the adapter goes away. Receiving takes the
+/// packets away from the session the adapter would otherwise hand them to
+fn capture(
+  name: &str,
+  path: &Path,
+  count: Option<u64>,
+  capacity: RingCapacity,
+) -> Result<Value, CommandError> {
+  let io_error = |error| CommandError::Io {
+    path: path.to_owned(),
+    error,
+  };
+  let session = Adapter::open(name)?.into_session(capacity)?;
+  let (reader, _writer) = session.split();
+  let file = File::create(path).map_err(io_error)?;
+  let mut pcap = PcapWriter::new(BufWriter::new(file), MAX_IP_PACKET_SIZE).map_err(io_error)?;
+  let mut packets = 0u64;
+  let mut bytes = 0u64;
+  while count.is_none_or(|count| packets < count) {
+    let packet = match reader.recv_blocking() {
+      Ok(packet) => packet,
+      Err(ReceivePacketError::AdapterIsTerminating) => break,
+      Err(error) => return Err(WintunError::from(error).into()),
+    };
+    pcap
+      .write_packet(SystemTime::now(), packet.slice())
+      .map_err(io_error)?;
+    //Flushed per packet so the file is usable when the capture is interrupted
+    pcap.flush().map_err(io_error)?;
+    packets += 1;
+    bytes += packet.slice().len() as u64;
+  }
+  Ok(Value::object([
+    ("name", name.into()),
+    ("file", path.display().to_string().into()),
+    ("packets", packets.into()),
+    ("bytes", bytes.into()),
+  ]))
+}
+
+fn apply(path: &Path, in_memory: bool) -> Result<Outcome, CommandError> {
+  let text = std::fs::read_to_string(path).map_err(|error| CommandError::Io {
+    path: path.to_owned(),
+    error,
+  })?;
+  let configs = parse_config(&text).map_err(|error| CommandError::Config {
+    path: path.to_owned(),
+    error,
+  })?;
+  let mut outcome = Outcome::from(Value::Array(Vec::new()));
+  for mut config in configs {
+    if in_memory {
+      config.backend = TunnelBackend::InMemory;
+    }
+    let mut tunnel = TunnelSupervisor::new(config);
+    tunnel.start()?;
+    let adapter = tunnel.adapter().expect("the tunnel was just started");
+    if let Value::Array(adapters) = &mut outcome.output {
+      adapters.push(adapter_value(adapter)?);
+    }
+    outcome.held.push(Box::new(tunnel));
+  }
+  Ok(outcome)
+}
+
+fn adapter_value(adapter: &Adapter) -> Result<Value, WintunError> {
+  Ok(Value::object([
+    ("name", adapter.name().into()),
+    ("guid", format_guid(adapter.get_guid()?).into()),
+    ("luid", adapter.get_luid()?.into()),
+    ("index", adapter.get_adapter_index()?.into()),
+    ("in_memory", adapter.is_in_memory().into()),
+  ]))
+}
+
+fn info_value(info: &AdapterInfo) -> Value {
+  Value::object([
+    ("name", info.name.as_str().into()),
+    ("tunnel_type", info.tunnel_type.as_str().into()),
+    ("guid", format_guid(info.guid).into()),
+    ("index", info.index.into()),
+    ("in_memory", info.in_memory.into()),
+  ])
+}
+
+fn prefix_text(prefix: IpAndMaskPrefix) -> String {
+  match prefix {
+    IpAndMaskPrefix::V4 { ip, prefix } => format!("{ip}/{}", prefix.mask()),
+    IpAndMaskPrefix::V6 { ip, prefix } => format!("{ip}/{}", prefix.mask()),
+  }
+}
+
+fn route_value(route: &Route) -> Value {
+  Value::object([
+    ("destination", prefix_text(route.destination).into()),
+    ("via", route.next_hop.map(|hop| hop.to_string()).into()),
+    ("metric", route.metric.into()),
+  ])
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+  };
+
+  use wintun2::{Adapter, IpAndMaskPrefix, Route};
+
+  use crate::{args::parse_args, output::Value};
+
+  use super::{run, CommandError, Outcome};
+
+  fn run_args(args: &[&str]) -> Result<Outcome, CommandError> {
+    let mut args = args.to_vec();
+    args.push("--in-memory");
+    run(&parse_args(args).unwrap())
+  }
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("wintun2-cli-{}-{name}", std::process::id()))
+  }
+
+  #[test]
+  fn manage_adapter() {
+    let created = run_args(&["create", "cli-manage", "--tunnel-type", "cli-manage-type"]).unwrap();
+    assert_eq!(created.held.len(), 1);
+    assert_eq!(created.output.get("name"), Some(&"cli-manage".into()));
+    assert_eq!(created.output.get("in_memory"), Some(&true.into()));
+    let guid = created.output.get("guid").unwrap().clone();
+
+    let listed = run_args(&["list", "--tunnel-type", "cli-manage-type"])
+      .unwrap()
+      .output;
+    let Value::Array(adapters) = &listed else {
+      panic!("{listed:?}")
+    };
+    assert_eq!(adapters.len(), 1);
+    assert_eq!(adapters[0].get("guid"), Some(&guid));
+
+    let opened = run_args(&["open", "cli-manage"]).unwrap().output;
+    assert_eq!(opened.get("guid"), Some(&guid));
+
+    run_args(&["set-ip", "cli-manage", "10.7.0.1/24"]).unwrap();
+    let routes = run_args(&["routes", "cli-manage", "10.8.0.0/16", "--via", "10.7.0.2"]).unwrap();
+    assert_eq!(
+      routes.output.to_json(),
+      r#"{"name":"cli-manage","routes":[{"destination":"10.8.0.0/16","via":"10.7.0.2","metric":0}]}"#
+    );
+    run_args(&["dns", "cli-manage", "10.7.0.53"]).unwrap();
+
+    let peer = Adapter::open("cli-manage").unwrap().memory_peer().unwrap();
+    let network = |third, fourth, prefix: u8| IpAndMaskPrefix::V4 {
+      ip: Ipv4Addr::new(10, third, 0, fourth),
+      prefix: prefix.try_into().unwrap(),
+    };
+    assert_eq!(peer.addresses(), [network(7, 1, 24)]);
+    assert_eq!(
+      peer.routes(),
+      [Route {
+        destination: network(8, 0, 16),
+        next_hop: Some(IpAddr::V4(Ipv4Addr::new(10, 7, 0, 2))),
+        metric: 0
+      }]
+    );
+    assert_eq!(
+      peer.dns_servers(),
+      [IpAddr::V4(Ipv4Addr::new(10, 7, 0, 53))]
+    );
+
+    let deleted = run_args(&["delete", "cli-manage", "--tunnel-type", "cli-manage-type"]).unwrap();
+    assert_eq!(
+      deleted.output.get("removed").map(Value::to_text),
+      Some(format!(
+        "name: cli-manage\ntunnel_type: cli-manage-type\nguid: {}\nindex: {}\nin_memory: true",
+        guid.to_text(),
+        opened.get("index").unwrap().to_text()
+      ))
+    );
+    assert!(peer.is_terminated());
+    assert!(matches!(
+      run_args(&["delete", "cli-manage", "--tunnel-type", "cli-manage-type"]),
+      Err(CommandError::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn errors_name_operation_and_adapter() {
+    let _adapter = Adapter::create_in_memory("cli-error", "tunnel_type", None).unwrap();
+    let error = run_args(&["create", "cli-error"]).err().unwrap();
+    let value = error.to_value();
+    assert_eq!(value.get("operation"), Some(&"create adapter".into()));
+    assert_eq!(value.get("adapter"), Some(&"cli-error".into()));
+    assert_eq!(value.get("kind"), Some(&"AlreadyExists".into()));
+    assert!(
+      matches!(value.get("error"), Some(Value::String(message)) if message.starts_with("Failed to create adapter \"cli-error\": "))
+    );
+  }
+
+  #[test]
+  fn capture_to_pcap() {
+    let adapter = Adapter::create_in_memory("cli-capture", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let path = temp_path("capture.pcap");
+    let path_arg = path.to_str().unwrap().to_owned();
+    let capture = std::thread::spawn(move || {
+      let args = [
+        "capture",
+        "cli-capture",
+        &path_arg,
+        "--count",
+        "2",
+        "--capacity",
+        "131072",
+      ];
+      run_args(&args).map(|outcome| outcome.output).unwrap()
+    });
+    //The link drops packets until the capture started its session
+    while !peer.has_session() {
+      std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    peer.inject(&[0x45, 1, 2]).unwrap();
+    peer.inject(&[0x45, 3]).unwrap();
+    let output = capture.join().unwrap();
+    assert_eq!(output.get("packets"), Some(&2u64.into()));
+    assert_eq!(output.get("bytes"), Some(&5u64.into()));
+    let bytes = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(bytes.len(), 24 + 16 + 3 + 16 + 2);
+    assert_eq!(&bytes[bytes.len() - 2..], [0x45, 3]);
+  }
+
+  #[test]
+  fn apply_config() {
+    let path = temp_path("apply.conf");
+    std::fs::write(&path, "[adapter]\nname = cli-apply\naddress = 10.9.0.1/24\nroute = 10.10.0.0/16\ndns = 10.9.0.53\nmtu = 1400\n").unwrap();
+    let outcome = run_args(&["apply", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    let outcome = outcome.unwrap();
+    assert_eq!(outcome.held.len(), 1);
+    let peer = Adapter::open("cli-apply").unwrap().memory_peer().unwrap();
+    assert!(peer.has_session());
+    assert_eq!(peer.mtu(), Some(1400.try_into().unwrap()));
+    assert_eq!(peer.routes().len(), 1);
+    assert_eq!(peer.dns_servers().len(), 1);
+    assert!(
+      matches!(&outcome.output, Value::Array(adapters) if adapters[0].get("name") == Some(&"cli-apply".into()))
+    );
+    drop(outcome);
+    assert!(peer.is_terminated());
+
+    assert!(matches!(
+      run_args(&["apply", "/nonexistent/wintun2.conf"]),
+      Err(CommandError::Io { .. })
+    ));
+  }
+
+  #[test]
+  fn driver_version_output() {
+    let output = run_args(&["driver-version"]).unwrap().output;
+    assert!(matches!(output.get("loaded"), Some(Value::Bool(_))));
+  }
+}
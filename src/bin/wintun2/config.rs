@@ -0,0 +1,233 @@
+//! Config files for `apply`. Every `[adapter]` section describes one adapter:
+//!
+//! ```text
+//! # Comments start with a hash
+//! [adapter]
+//! name = wt0
+//! tunnel-type = wintun2
+//! guid = {0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9}
+//! mtu = 1420
+//! capacity = 4194304
+//! address = 10.0.0.1/24
+//! address = fd00::1/64
+//! route = 10.1.0.0/16 via 10.0.0.2 metric 10
+//! dns = 1.1.1.1, 9.9.9.9
+//! ```
+//!
+//! `name` is required, `address`, `route` and `dns` may be repeated
+
+use std::{fmt, net::IpAddr};
+
+use wintun2::{Route, TunnelConfig};
+
+use crate::args::{parse_guid, parse_prefix, DEFAULT_TUNNEL_TYPE};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+  /// One based line number
+  pub line: usize,
+  pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!("Line {}: {}", self.line, self.message))
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn parse_config(text: &str) -> Result<Vec<TunnelConfig>, ConfigError> {
+  let mut tunnels: Vec<TunnelConfig> = Vec::new();
+  //Line of the section header, to report sections without a name
+  let mut section_line = 0;
+  for (i, line) in text.lines().enumerate() {
+    let line_number = i + 1;
+    let error = |message: String| ConfigError {
+      line: line_number,
+      message,
+    };
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    if line.starts_with('[') {
+      if line != "[adapter]" {
+        return Err(error(format!("Unknown section {line}")));
+      }
+      check_name(&tunnels, section_line)?;
+      tunnels.push(TunnelConfig::new("", DEFAULT_TUNNEL_TYPE));
+      section_line = line_number;
+      continue;
+    }
+    let Some(tunnel) = tunnels.last_mut() else {
+      return Err(error("Setting outside of an [adapter] section".to_owned()));
+    };
+    let Some((key, value)) = line.split_once('=') else {
+      return Err(error(format!("Expected key = value, got {line}")));
+    };
+    let value = value.trim();
+    match key.trim() {
+      "name" => tunnel.name = value.to_owned(),
+      "tunnel-type" => tunnel.tunnel_type = value.to_owned(),
+      "guid" => tunnel.guid = Some(parse_guid(value).map_err(|err| error(err.to_string()))?),
+      "mtu" => {
+        tunnel.mtu = Some(
+          value
+            .parse::<u32>()
+            .ok()
+            .and_then(|mtu| mtu.try_into().ok())
+            .ok_or_else(|| error(format!("Invalid mtu \"{value}\"")))?,
+        )
+      }
+      "capacity" => {
+        tunnel.capacity = value
+          .parse::<u32>()
+          .ok()
+          .and_then(|capacity| capacity.try_into().ok())
+          .ok_or_else(|| error(format!("Invalid ring capacity \"{value}\"")))?
+      }
+      "address" => tunnel
+        .addresses
+        .push(parse_prefix(value).map_err(|err| error(err.to_string()))?),
+      "route" => tunnel.routes.push(parse_route(value).map_err(error)?),
+      "dns" => {
+        for server in value.split(',') {
+          let server = server.trim();
+          tunnel.dns_servers.push(
+            server
+              .parse::<IpAddr>()
+              .map_err(|_| error(format!("Invalid dns server \"{server}\"")))?,
+          );
+        }
+      }
+      key => return Err(error(format!("Unknown setting {key}"))),
+    }
+  }
+  check_name(&tunnels, section_line)?;
+  Ok(tunnels)
+}
+
+fn check_name(tunnels: &[TunnelConfig], section_line: usize) -> Result<(), ConfigError> {
+  match tunnels.last() {
+    Some(tunnel) if tunnel.name.is_empty() => Err(ConfigError {
+      line: section_line,
+      message: "Adapter without a name".to_owned(),
+    }),
+    _ => Ok(()),
+  }
+}
+
+/// Parses `destination [via next-hop] [metric n]`
+fn parse_route(value: &str) -> Result<Route, String> {
+  let mut words = value.split_whitespace();
+  let destination = words.next().ok_or("Empty route")?;
+  let mut route = Route::new(parse_prefix(destination).map_err(|err| err.to_string())?);
+  while let Some(word) = words.next() {
+    let argument = words
+      .next()
+      .ok_or_else(|| format!("Missing value after {word}"))?;
+    match word {
+      "via" => {
+        route.next_hop = Some(
+          argument
+            .parse()
+            .map_err(|_| format!("Invalid next hop \"{argument}\""))?,
+        )
+      }
+      "metric" => {
+        route.metric = argument
+          .parse()
+          .map_err(|_| format!("Invalid metric \"{argument}\""))?
+      }
+      word => return Err(format!("Unexpected {word} in route")),
+    }
+  }
+  Ok(route)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{IpAddr, Ipv4Addr};
+
+  use wintun2::{IpAndMaskPrefix, RingCapacity, Route};
+
+  use super::{parse_config, ConfigError};
+
+  #[test]
+  fn parse_adapters() {
+    let tunnels = parse_config(
+      "# two adapters
+[adapter]
+name = wt0
+guid = 0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9
+mtu = 1420
+capacity = 131072
+address = 10.0.0.1/24
+route = 10.1.0.0/16 via 10.0.0.2 metric 10
+route = 10.2.0.0/16
+dns = 1.1.1.1, 9.9.9.9
+
+[adapter]
+name = wt1
+tunnel-type = other
+",
+    )
+    .unwrap();
+    assert_eq!(tunnels.len(), 2);
+    let wt0 = &tunnels[0];
+    assert_eq!(wt0.name, "wt0");
+    assert_eq!(wt0.tunnel_type, "wintun2");
+    assert_eq!(wt0.guid, Some(0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9));
+    assert_eq!(wt0.mtu, Some(1420.try_into().unwrap()));
+    assert_eq!(wt0.capacity, RingCapacity::min());
+    let network = |third, prefix: u8| IpAndMaskPrefix::V4 {
+      ip: Ipv4Addr::new(10, third, 0, if third == 0 { 1 } else { 0 }),
+      prefix: prefix.try_into().unwrap(),
+    };
+    assert_eq!(wt0.addresses, [network(0, 24)]);
+    assert_eq!(
+      wt0.routes,
+      [
+        Route {
+          destination: network(1, 16),
+          next_hop: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+          metric: 10
+        },
+        Route::new(network(2, 16))
+      ]
+    );
+    assert_eq!(
+      wt0.dns_servers,
+      [
+        IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+        IpAddr::V4(Ipv4Addr::new(9, 9, 9, 9))
+      ]
+    );
+    assert_eq!(tunnels[1].name, "wt1");
+    assert_eq!(tunnels[1].tunnel_type, "other");
+  }
+
+  #[test]
+  fn report_line_of_errors() {
+    let error = |text: &str| parse_config(text).unwrap_err();
+    assert_eq!(
+      error("name = wt0"),
+      ConfigError {
+        line: 1,
+        message: "Setting outside of an [adapter] section".to_owned()
+      }
+    );
+    assert_eq!(error("[adapter]\nname = a\n[adapter]\nmtu = 1400").line, 3);
+    assert_eq!(error("[adapter]\nname = a\nmtu = 0").line, 3);
+    assert_eq!(
+      error("[adapter]\nname = a\n\nroute = 10.0.0.0/8 via").line,
+      4
+    );
+    assert_eq!(
+      error("[adapter]\nname = a\ncolour = blue").message,
+      "Unknown setting colour"
+    );
+    assert_eq!(error("[tunnel]").message, "Unknown section [tunnel]");
+  }
+}
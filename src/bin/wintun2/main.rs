@@ -0,0 +1,44 @@
+//! Command-line tool for managing adapters, run `wintun2 --help` for the commands
+
+mod args;
+mod commands;
+mod config;
+mod output;
+mod pcap;
+
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+  let cli = match args::parse_args(std::env::args().skip(1)) {
+    Ok(cli) => cli,
+    Err(error) => {
+      eprintln!("wintun2: {error}\n\n{}", args::USAGE);
+      return ExitCode::from(2);
+    }
+  };
+  let outcome = match commands::run(&cli) {
+    Ok(outcome) => outcome,
+    Err(error) if cli.json => {
+      println!("{}", error.to_value().to_json());
+      return ExitCode::FAILURE;
+    }
+    Err(error) => {
+      eprintln!("wintun2: {error:#}");
+      return ExitCode::FAILURE;
+    }
+  };
+  if cli.json {
+    println!("{}", outcome.output.to_json());
+  } else {
+    println!("{}", outcome.output.to_text());
+  }
+  if !outcome.held.is_empty() {
+    if !cli.json {
+      eprintln!("Keeping the adapters until the tool is stopped");
+    }
+    loop {
+      std::thread::park();
+    }
+  }
+  ExitCode::SUCCESS
+}
@@ -0,0 +1,199 @@
+//! Results of the commands, printed either as JSON for scripts or as `key: value` lines for people
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Null,
+  Bool(bool),
+  Number(u64),
+  String(String),
+  Array(Vec<Value>),
+  /// Keys keep the order they were inserted in
+  Object(Vec<(String, Value)>),
+}
+
+impl Value {
+  pub fn object<const N: usize>(entries: [(&str, Value); N]) -> Self {
+    Value::Object(
+      entries
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect(),
+    )
+  }
+  #[cfg(test)]
+  pub fn get(&self, key: &str) -> Option<&Value> {
+    match self {
+      Value::Object(entries) => entries
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value),
+      _ => None,
+    }
+  }
+  pub fn to_json(&self) -> String {
+    let mut out = String::new();
+    write_json(self, &mut out).expect("writing to a string can't fail");
+    out
+  }
+  /// Renders objects as one `key: value` line per entry and arrays of objects as blocks separated
+  /// by empty lines
+  pub fn to_text(&self) -> String {
+    match self {
+      Value::Array(items) if items.iter().any(|item| matches!(item, Value::Object(_))) => items
+        .iter()
+        .map(Value::to_text)
+        .collect::<Vec<_>>()
+        .join("\n\n"),
+      Value::Object(entries) => entries
+        .iter()
+        .map(|(key, value)| format!("{key}: {}", value.to_inline_text()))
+        .collect::<Vec<_>>()
+        .join("\n"),
+      value => value.to_inline_text(),
+    }
+  }
+  fn to_inline_text(&self) -> String {
+    match self {
+      Value::Null => "-".to_owned(),
+      Value::Bool(value) => value.to_string(),
+      Value::Number(value) => value.to_string(),
+      Value::String(value) => value.clone(),
+      Value::Array(items) => items
+        .iter()
+        .map(Value::to_inline_text)
+        .collect::<Vec<_>>()
+        .join(", "),
+      Value::Object(entries) => entries
+        .iter()
+        .map(|(key, value)| format!("{key}={}", value.to_inline_text()))
+        .collect::<Vec<_>>()
+        .join(" "),
+    }
+  }
+}
+
+fn write_json(value: &Value, out: &mut String) -> fmt::Result {
+  match value {
+    Value::Null => out.write_str("null"),
+    Value::Bool(value) => out.write_fmt(format_args!("{value}")),
+    Value::Number(value) => out.write_fmt(format_args!("{value}")),
+    Value::String(value) => write_string(value, out),
+    Value::Array(items) => {
+      out.write_char('[')?;
+      for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+          out.write_char(',')?;
+        }
+        write_json(item, out)?;
+      }
+      out.write_char(']')
+    }
+    Value::Object(entries) => {
+      out.write_char('{')?;
+      for (i, (key, value)) in entries.iter().enumerate() {
+        if i > 0 {
+          out.write_char(',')?;
+        }
+        write_string(key, out)?;
+        out.write_char(':')?;
+        write_json(value, out)?;
+      }
+      out.write_char('}')
+    }
+  }
+}
+
+fn write_string(value: &str, out: &mut String) -> fmt::Result {
+  out.write_char('"')?;
+  for c in value.chars() {
+    match c {
+      '"' => out.write_str("\\\"")?,
+      '\\' => out.write_str("\\\\")?,
+      '\n' => out.write_str("\\n")?,
+      '\r' => out.write_str("\\r")?,
+      '\t' => out.write_str("\\t")?,
+      c if (c as u32) < 0x20 => out.write_fmt(format_args!("\\u{:04x}", c as u32))?,
+      c => out.write_char(c)?,
+    }
+  }
+  out.write_char('"')
+}
+
+impl From<bool> for Value {
+  fn from(value: bool) -> Self {
+    Value::Bool(value)
+  }
+}
+impl From<u32> for Value {
+  fn from(value: u32) -> Self {
+    Value::Number(value as u64)
+  }
+}
+impl From<u64> for Value {
+  fn from(value: u64) -> Self {
+    Value::Number(value)
+  }
+}
+impl From<&str> for Value {
+  fn from(value: &str) -> Self {
+    Value::String(value.to_owned())
+  }
+}
+impl From<String> for Value {
+  fn from(value: String) -> Self {
+    Value::String(value)
+  }
+}
+impl<T: Into<Value>> From<Option<T>> for Value {
+  fn from(value: Option<T>) -> Self {
+    value.map_or(Value::Null, Into::into)
+  }
+}
+impl<T: Into<Value>> From<Vec<T>> for Value {
+  fn from(value: Vec<T>) -> Self {
+    Value::Array(value.into_iter().map(Into::into).collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Value;
+
+  #[test]
+  fn json_escaping_and_nesting() {
+    let value = Value::object([
+      ("name", "wt \"0\"\n".into()),
+      ("index", 7u32.into()),
+      ("up", true.into()),
+      ("guid", Value::Null),
+      ("dns", vec!["1.1.1.1", "9.9.9.9"].into()),
+      ("nested", Value::object([("tab", "\t\u{1}".into())])),
+    ]);
+    assert_eq!(
+      value.to_json(),
+      r#"{"name":"wt \"0\"\n","index":7,"up":true,"guid":null,"dns":["1.1.1.1","9.9.9.9"],"nested":{"tab":"\t\u0001"}}"#
+    );
+    assert_eq!(value.get("index"), Some(&Value::Number(7)));
+    assert_eq!(Value::Array(Vec::new()).to_json(), "[]");
+  }
+
+  #[test]
+  fn text_rendering() {
+    let adapter = |name: &str| Value::object([("name", name.into()), ("index", 1u32.into())]);
+    assert_eq!(adapter("a").to_text(), "name: a\nindex: 1");
+    assert_eq!(
+      Value::Array(vec![adapter("a"), adapter("b")]).to_text(),
+      "name: a\nindex: 1\n\nname: b\nindex: 1"
+    );
+    assert_eq!(
+      Value::object([
+        ("dns", vec!["1.1.1.1", "9.9.9.9"].into()),
+        ("via", Value::Null)
+      ])
+      .to_text(),
+      "dns: 1.1.1.1, 9.9.9.9\nvia: -"
+    );
+  }
+}
@@ -0,0 +1,80 @@
+//! Writer for the classic libpcap file format. Packets are stored as raw IP (`LINKTYPE_RAW`) since
+//! adapters carry neither Ethernet headers nor packet information
+
+use std::{
+  io::{self, Write},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: u32 = 0xA1B2_C3D4;
+const LINKTYPE_RAW: u32 = 101;
+
+pub struct PcapWriter<W: Write> {
+  out: W,
+  snaplen: u32,
+}
+
+impl<W: Write> PcapWriter<W> {
+  /// Writes the file header. Packets longer than `snaplen` are truncated to it
+  pub fn new(mut out: W, snaplen: u32) -> io::Result<Self> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    //Timezone offset and timestamp accuracy, both always zero
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&snaplen.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    out.write_all(&header)?;
+    Ok(Self { out, snaplen })
+  }
+  pub fn write_packet(&mut self, timestamp: SystemTime, packet: &[u8]) -> io::Result<()> {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let captured = packet.len().min(self.snaplen as usize);
+    let mut record = Vec::with_capacity(16 + captured);
+    record.extend_from_slice(&(since_epoch.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&since_epoch.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(captured as u32).to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(&packet[..captured]);
+    self.out.write_all(&record)
+  }
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.out.flush()
+  }
+  #[cfg(test)]
+  pub fn into_inner(self) -> W {
+    self.out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::{Duration, UNIX_EPOCH};
+
+  use super::PcapWriter;
+
+  #[test]
+  fn header_and_records() {
+    let mut writer = PcapWriter::new(Vec::new(), 4).unwrap();
+    let timestamp = UNIX_EPOCH + Duration::new(0x0102_0304, 5_000_000);
+    writer.write_packet(timestamp, &[0x45, 1, 2]).unwrap();
+    writer
+      .write_packet(timestamp, &[0x45, 1, 2, 3, 4, 5])
+      .unwrap();
+    let bytes = writer.into_inner();
+    assert_eq!(
+      &bytes[..24],
+      [0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 101, 0, 0, 0]
+    );
+    assert_eq!(
+      &bytes[24..43],
+      [4, 3, 2, 1, 0x88, 0x13, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, 0x45, 1, 2]
+    );
+    //Truncated to the snapshot length but keeping the original length
+    assert_eq!(&bytes[51..55], [4, 0, 0, 0]);
+    assert_eq!(&bytes[55..59], [6, 0, 0, 0]);
+    assert_eq!(&bytes[59..], [0x45, 1, 2, 3]);
+  }
+}
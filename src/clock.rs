@@ -0,0 +1,93 @@
+//! Time sources for code that schedules packets, so tests can drive time by hand instead of
+//! sleeping
+
+use std::{
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+/// A monotonic clock. Times are measured from an arbitrary, clock specific start
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Duration;
+}
+
+/// Wall clock time, measured from the creation of the clock
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+  start: Instant,
+}
+
+impl SystemClock {
+  pub fn new() -> Self {
+    Self {
+      start: Instant::now(),
+    }
+  }
+}
+
+impl Default for SystemClock {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Clock for SystemClock {
+  fn now(&self) -> Duration {
+    self.start.elapsed()
+  }
+}
+
+/// A clock that only moves when told to. Clones share the same time
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+  now: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+  /// Starts at zero
+  pub fn new() -> Self {
+    Self::default()
+  }
+  pub fn advance(&self, by: Duration) {
+    *self.lock() += by;
+  }
+  /// Moves the clock to `now`. Moving it backwards is ignored so the clock stays monotonic
+  pub fn set(&self, now: Duration) {
+    let mut current = self.lock();
+    *current = (*current).max(now);
+  }
+  fn lock(&self) -> std::sync::MutexGuard<'_, Duration> {
+    self
+      .now
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> Duration {
+    *self.lock()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::{Clock, ManualClock, SystemClock};
+
+  #[test]
+  fn manual_clock_is_shared_and_monotonic() {
+    let clock = ManualClock::new();
+    let other = clock.clone();
+    assert_eq!(clock.now(), Duration::ZERO);
+    clock.advance(Duration::from_millis(5));
+    assert_eq!(other.now(), Duration::from_millis(5));
+    other.set(Duration::from_millis(2));
+    assert_eq!(clock.now(), Duration::from_millis(5));
+    other.set(Duration::from_secs(1));
+    assert_eq!(clock.now(), Duration::from_secs(1));
+    let system = SystemClock::new();
+    assert!(system.now() <= system.now());
+  }
+}
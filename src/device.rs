@@ -0,0 +1,142 @@
+use smoltcp::{
+  phy::{self, DeviceCapabilities, Medium},
+  time::Instant,
+};
+
+use crate::{session::SessionCore, IpPacketSize, RecvPacket, Session};
+
+/// Receive token handed out to smoltcp. Wraps the received packet so the stack reads straight
+/// from the ring, the packet is released back to the ring once the token is consumed
+pub struct SessionRxToken<'session> {
+  packet: RecvPacket<'session>,
+}
+
+impl<'session> phy::RxToken for SessionRxToken<'session> {
+  fn consume<R, F>(self, f: F) -> R
+  where
+    F: FnOnce(&[u8]) -> R,
+  {
+    f(self.packet.slice())
+  }
+}
+
+/// Transmit token handed out to smoltcp. The packet is allocated in the ring only when the
+/// stack knows its length and is sent as soon as the stack finishes writing it
+pub struct SessionTxToken<'session> {
+  session: &'session SessionCore,
+}
+
+impl<'session> phy::TxToken for SessionTxToken<'session> {
+  fn consume<R, F>(self, len: usize, f: F) -> R
+  where
+    F: FnOnce(&mut [u8]) -> R,
+  {
+    let packet = u32::try_from(len)
+      .ok()
+      .and_then(|len| IpPacketSize::try_from(len).ok())
+      .and_then(|size| self.session.allocate(size).ok());
+    match packet {
+      Some(mut packet) => {
+        let result = f(packet.mut_slice());
+        packet.send();
+        result
+      }
+      //The ring is full or the adapter is going away. smoltcp has no way to be told that a
+      //transmission failed, so let it build the packet into a scratch buffer and drop it the
+      //same way a congested link would
+      None => f(&mut vec![0; len]),
+    }
+  }
+}
+
+impl<'adapter> phy::Device for Session<'adapter> {
+  type RxToken<'a>
+    = SessionRxToken<'a>
+  where
+    Self: 'a;
+  type TxToken<'a>
+    = SessionTxToken<'a>
+  where
+    Self: 'a;
+
+  fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+    let session = self.core();
+    let packet = session.recv().ok()?;
+    Some((SessionRxToken { packet }, SessionTxToken { session }))
+  }
+
+  fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+    Some(SessionTxToken {
+      session: self.core(),
+    })
+  }
+
+  fn capabilities(&self) -> DeviceCapabilities {
+    let mut capabilities = DeviceCapabilities::default();
+    capabilities.medium = Medium::Ip;
+    capabilities.max_transmission_unit = self.max_packet_size().size() as usize;
+    capabilities
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use smoltcp::{
+    iface::{Config, Interface, SocketSet},
+    phy::{Device, Medium},
+    socket::udp,
+    time::Instant,
+    wire::{HardwareAddress, IpAddress, IpCidr, IpEndpoint},
+  };
+
+  use crate::{Adapter, IpPacketSize};
+
+  #[test]
+  fn capabilities_follow_packet_size() {
+    let adapter = Adapter::create("wt-smoltcp", "tunnel_type", None).unwrap();
+    let mut session = adapter.session(crate::RingCapacity::max()).unwrap();
+    session.set_max_packet_size(IpPacketSize::try_from(1280).unwrap());
+    let capabilities = session.capabilities();
+    assert_eq!(capabilities.medium, Medium::Ip);
+    assert_eq!(capabilities.max_transmission_unit, 1280);
+  }
+
+  #[test]
+  fn send_udp_through_interface() {
+    let adapter = Adapter::create_in_memory("smoltcp-udp", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let mut session = adapter.session(crate::RingCapacity::max()).unwrap();
+    session.set_max_packet_size(IpPacketSize::try_from(1500).unwrap());
+
+    let mut iface = Interface::new(
+      Config::new(HardwareAddress::Ip),
+      &mut session,
+      Instant::now(),
+    );
+    iface.update_ip_addrs(|addrs| {
+      addrs
+        .push(IpCidr::new(IpAddress::v4(192, 168, 10, 2), 24))
+        .unwrap();
+    });
+    let mut sockets = SocketSet::new(vec![]);
+    let socket = udp::Socket::new(
+      udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY], vec![0; 1500]),
+      udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY], vec![0; 1500]),
+    );
+    let handle = sockets.add(socket);
+    let socket = sockets.get_mut::<udp::Socket>(handle);
+    socket.bind(4000).unwrap();
+    socket
+      .send_slice(
+        b"hello",
+        IpEndpoint::new(IpAddress::v4(192, 168, 10, 1), 4000),
+      )
+      .unwrap();
+    iface.poll(Instant::now(), &mut session, &mut sockets);
+    assert_eq!(sockets.get_mut::<udp::Socket>(handle).send_queue(), 0);
+    let packet = peer.try_recv().unwrap();
+    assert_eq!(packet[0] >> 4, 4);
+    assert_eq!(&packet[16..20], &[192, 168, 10, 1]);
+    assert_eq!(&packet[packet.len() - 5..], b"hello");
+  }
+}
@@ -0,0 +1,805 @@
+//! Egress for flows taken out of the tunnel. An [`UpstreamDialer`] opens the TCP connection or
+//! UDP flow that relays a flow of the system to its [`Destination`]: [`Direct`] connects from
+//! this host, [`HttpProxy`] and [`Socks5Proxy`] go through a proxy, [`Chained`] reaches a proxy
+//! through another dialer, and [`RoutingDialer`] picks a dialer per flow by [`DialRule`]s
+
+use std::{
+  fmt,
+  io::{self, Read, Write},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+  sync::Arc,
+  time::Duration,
+};
+
+use crate::{
+  dns::is_hostname, normalize_name, socket::connect_from, IpAndMaskPrefix, PortRange, Socks5Error,
+  Socks5Proxy, Socks5Udp,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest HTTP response header accepted from a proxy
+const MAX_RESPONSE_HEADER: usize = 16 * 1024;
+
+/// Where a relayed flow goes. Names are resolved by the dialer, or by the proxy it goes through
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+  Address(SocketAddr),
+  Domain(String, u16),
+}
+
+impl Destination {
+  pub fn port(&self) -> u16 {
+    match self {
+      Destination::Address(address) => address.port(),
+      Destination::Domain(_, port) => *port,
+    }
+  }
+  /// The addresses of the destination, resolving names with the system resolver
+  pub fn resolve(&self) -> Result<Vec<SocketAddr>, DialError> {
+    let addresses: Vec<_> = match self {
+      Destination::Address(address) => return Ok(vec![*address]),
+      Destination::Domain(domain, port) => (domain.as_str(), *port).to_socket_addrs()?.collect(),
+    };
+    match addresses.is_empty() {
+      true => Err(DialError::Unresolved(self.to_string())),
+      false => Ok(addresses),
+    }
+  }
+}
+
+impl From<SocketAddr> for Destination {
+  fn from(address: SocketAddr) -> Self {
+    Destination::Address(address)
+  }
+}
+
+impl fmt::Display for Destination {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Destination::Address(address) => f.write_fmt(format_args!("{address}")),
+      Destination::Domain(domain, port) => f.write_fmt(format_args!("{domain}:{port}")),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum DialError {
+  Io(io::Error),
+  /// The name has no addresses
+  Unresolved(String),
+  Socks5(Socks5Error),
+  /// The HTTP proxy answered CONNECT with this status
+  HttpStatus(u16),
+  /// The HTTP proxy answered something that isn't an HTTP response
+  HttpProtocol,
+  /// The dialer doesn't carry UDP
+  UdpUnsupported,
+  /// A rule routed the flow nowhere
+  Blocked,
+  /// The domain isn't a host name and can't be put in a request
+  InvalidDomain(String),
+}
+
+impl From<io::Error> for DialError {
+  fn from(error: io::Error) -> Self {
+    DialError::Io(error)
+  }
+}
+
+impl From<Socks5Error> for DialError {
+  fn from(error: Socks5Error) -> Self {
+    DialError::Socks5(error)
+  }
+}
+
+impl fmt::Display for DialError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DialError::Io(error) => f.write_fmt(format_args!("Connection failed: {error}")),
+      DialError::Unresolved(destination) => {
+        f.write_fmt(format_args!("{destination} has no addresses"))
+      }
+      DialError::Socks5(error) => fmt::Display::fmt(error, f),
+      DialError::HttpStatus(status) => f.write_fmt(format_args!(
+        "HTTP proxy refused CONNECT with status {status}"
+      )),
+      DialError::HttpProtocol => f.write_str("Invalid HTTP proxy response"),
+      DialError::UdpUnsupported => f.write_str("Dialer doesn't carry UDP"),
+      DialError::Blocked => f.write_str("Destination is blocked"),
+      DialError::InvalidDomain(domain) => {
+        f.write_fmt(format_args!("{domain:?} is not a valid host name"))
+      }
+    }
+  }
+}
+
+impl std::error::Error for DialError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      DialError::Io(error) => Some(error),
+      DialError::Socks5(error) => Some(error),
+      _ => None,
+    }
+  }
+}
+
+/// A UDP flow to one destination opened by an [`UpstreamDialer`]
+pub trait UpstreamUdp: Send {
+  fn send(&self, data: &[u8]) -> io::Result<usize>;
+  /// Receives one datagram of the destination into `buf`, returning its length
+  fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl UpstreamUdp for UdpSocket {
+  fn send(&self, data: &[u8]) -> io::Result<usize> {
+    UdpSocket::send(self, data)
+  }
+  fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    UdpSocket::recv(self, buf)
+  }
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    UdpSocket::set_nonblocking(self, nonblocking)
+  }
+}
+
+/// Opens the connections and UDP flows relaying flows of the system to their destinations.
+/// Calls block until the connection is open, so they are made off the packet path
+pub trait UpstreamDialer: Send + Sync {
+  fn dial_tcp(&self, destination: &Destination) -> Result<TcpStream, DialError>;
+  fn bind_udp(&self, destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError>;
+}
+
+impl<T: UpstreamDialer + ?Sized> UpstreamDialer for Arc<T> {
+  fn dial_tcp(&self, destination: &Destination) -> Result<TcpStream, DialError> {
+    (**self).dial_tcp(destination)
+  }
+  fn bind_udp(&self, destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+    (**self).bind_udp(destination)
+  }
+}
+
+/// A proxy that can be reached through a stream some other dialer opened, for [`Chained`]
+pub trait ProxyHandshake: Send + Sync {
+  /// Where the proxy listens
+  fn server(&self) -> Destination;
+  /// Asks the proxy at the other end of `stream` to connect it to `destination`
+  fn handshake(&self, stream: TcpStream, destination: &Destination)
+    -> Result<TcpStream, DialError>;
+}
+
+/// Connects from this host, resolving names with the system resolver.
+///
+/// Connections go out the way the routes of the host say. When the tunnel carries the default
+/// route they go back into the tunnel and are relayed again, in a loop. Bind them to the address
+/// of another interface with [`with_bind`](Self::with_bind), or route the destinations outside
+/// the tunnel
+#[derive(Debug, Clone)]
+pub struct Direct {
+  timeout: Duration,
+  bind_v4: Option<Ipv4Addr>,
+  bind_v6: Option<Ipv6Addr>,
+}
+
+impl Default for Direct {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Direct {
+  pub fn new() -> Self {
+    Self {
+      timeout: DEFAULT_TIMEOUT,
+      bind_v4: None,
+      bind_v6: None,
+    }
+  }
+  /// Gives up connecting to an address after `timeout`. Defaults to 10 seconds
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+  /// Connects to addresses of the family of `ip` from `ip`, given once per family. Addresses of
+  /// a family without one are connected from where the routes say
+  pub fn with_bind(mut self, ip: IpAddr) -> Self {
+    match ip {
+      IpAddr::V4(ip) => self.bind_v4 = Some(ip),
+      IpAddr::V6(ip) => self.bind_v6 = Some(ip),
+    }
+    self
+  }
+  /// The address to connect to `address` from
+  fn local(&self, address: SocketAddr) -> SocketAddr {
+    match address {
+      SocketAddr::V4(_) => SocketAddr::from((self.bind_v4.unwrap_or(Ipv4Addr::UNSPECIFIED), 0)),
+      SocketAddr::V6(_) => SocketAddr::from((self.bind_v6.unwrap_or(Ipv6Addr::UNSPECIFIED), 0)),
+    }
+  }
+}
+
+impl UpstreamDialer for Direct {
+  /// Tries the addresses of the destination in turn
+  fn dial_tcp(&self, destination: &Destination) -> Result<TcpStream, DialError> {
+    let mut last = None;
+    for address in destination.resolve()? {
+      let local = self.local(address);
+      let stream = match local.ip().is_unspecified() {
+        true => TcpStream::connect_timeout(&address, self.timeout),
+        false => connect_from(local, address, self.timeout),
+      };
+      match stream {
+        Ok(stream) => {
+          stream.set_nodelay(true)?;
+          return Ok(stream);
+        }
+        Err(err) => last = Some(err),
+      }
+    }
+    Err(last.map_or(
+      DialError::Unresolved(destination.to_string()),
+      DialError::Io,
+    ))
+  }
+  /// A socket connected to the first address of the destination
+  fn bind_udp(&self, destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+    let address = destination.resolve()?[0];
+    let socket = UdpSocket::bind(self.local(address))?;
+    socket.connect(address)?;
+    Ok(Box::new(socket))
+  }
+}
+
+/// A datagram flow to one destination through a SOCKS5 association
+struct Socks5Flow {
+  association: Socks5Udp,
+  destination: Destination,
+}
+
+impl UpstreamUdp for Socks5Flow {
+  fn send(&self, data: &[u8]) -> io::Result<usize> {
+    self
+      .association
+      .send_to(data, &self.destination)
+      .map_err(|err| match err {
+        Socks5Error::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidInput, err),
+      })
+  }
+  fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    self.association.recv_from(buf).map(|(len, _)| len)
+  }
+  fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    self.association.set_nonblocking(nonblocking)
+  }
+}
+
+impl UpstreamDialer for Socks5Proxy {
+  fn dial_tcp(&self, destination: &Destination) -> Result<TcpStream, DialError> {
+    Ok(self.connect(destination)?)
+  }
+  /// An association of its own, so every flow is relayed independently
+  fn bind_udp(&self, destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+    Ok(Box::new(Socks5Flow {
+      association: self.associate()?,
+      destination: destination.clone(),
+    }))
+  }
+}
+
+impl ProxyHandshake for Socks5Proxy {
+  fn server(&self) -> Destination {
+    Destination::Address(self.address())
+  }
+  fn handshake(
+    &self,
+    stream: TcpStream,
+    destination: &Destination,
+  ) -> Result<TcpStream, DialError> {
+    Ok(self.connect_over(stream, destination)?)
+  }
+}
+
+/// An HTTP proxy taking CONNECT requests (RFC 9110), optionally with basic authentication. It
+/// only carries TCP
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpProxy {
+  server: Destination,
+  authorization: Option<String>,
+  timeout: Duration,
+}
+
+impl HttpProxy {
+  pub fn new(server: impl Into<Destination>) -> Self {
+    Self {
+      server: server.into(),
+      authorization: None,
+      timeout: DEFAULT_TIMEOUT,
+    }
+  }
+  pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+    let credentials = format!("{username}:{password}");
+    self.authorization = Some(format!("Basic {}", base64(credentials.as_bytes())));
+    self
+  }
+  /// Gives up connecting and waiting for the proxy's answer after `timeout`. Defaults to 10
+  /// seconds
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+}
+
+impl UpstreamDialer for HttpProxy {
+  fn dial_tcp(&self, destination: &Destination) -> Result<TcpStream, DialError> {
+    let stream = Direct::new()
+      .with_timeout(self.timeout)
+      .dial_tcp(&self.server)?;
+    self.handshake(stream, destination)
+  }
+  fn bind_udp(&self, _destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+    Err(DialError::UdpUnsupported)
+  }
+}
+
+impl ProxyHandshake for HttpProxy {
+  fn server(&self) -> Destination {
+    self.server.clone()
+  }
+  fn handshake(
+    &self,
+    mut stream: TcpStream,
+    destination: &Destination,
+  ) -> Result<TcpStream, DialError> {
+    let authority = match destination {
+      //The name goes into the request line, anything but a host name could add headers
+      Destination::Domain(domain, _) if !is_hostname(domain) => {
+        return Err(DialError::InvalidDomain(domain.clone()));
+      }
+      Destination::Address(SocketAddr::V6(address)) => {
+        format!("[{}]:{}", address.ip(), address.port())
+      }
+      destination => destination.to_string(),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(authorization) = &self.authorization {
+      request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.set_read_timeout(Some(self.timeout))?;
+    stream.set_write_timeout(Some(self.timeout))?;
+    stream.write_all(request.as_bytes())?;
+    //Read byte by byte, what follows the header already belongs to the destination
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+      if header.len() >= MAX_RESPONSE_HEADER {
+        return Err(DialError::HttpProtocol);
+      }
+      let mut byte = [0];
+      if stream.read(&mut byte)? == 0 {
+        return Err(DialError::HttpProtocol);
+      }
+      header.push(byte[0]);
+    }
+    let status = std::str::from_utf8(&header)
+      .ok()
+      .and_then(|header| header.strip_prefix("HTTP/1."))
+      .and_then(|line| line.get(2..5))
+      .and_then(|status| status.parse::<u16>().ok())
+      .ok_or(DialError::HttpProtocol)?;
+    if !(200..300).contains(&status) {
+      return Err(DialError::HttpStatus(status));
+    }
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+  }
+}
+
+/// Reaches `proxy` through `via`, like a SOCKS5 proxy behind an HTTP proxy. Chains of any
+/// length nest, with the innermost dialer connecting first. Only TCP is carried
+pub struct Chained<D, P> {
+  via: D,
+  proxy: P,
+}
+
+impl<D: UpstreamDialer, P: ProxyHandshake> Chained<D, P> {
+  pub fn new(via: D, proxy: P) -> Self {
+    Self { via, proxy }
+  }
+}
+
+impl<D: UpstreamDialer, P: ProxyHandshake> UpstreamDialer for Chained<D, P> {
+  fn dial_tcp(&self, destination: &Destination) -> Result<TcpStream, DialError> {
+    let stream = self.via.dial_tcp(&self.proxy.server())?;
+    self.proxy.handshake(stream, destination)
+  }
+  fn bind_udp(&self, _destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+    Err(DialError::UdpUnsupported)
+  }
+}
+
+/// Matches destinations on every criterion that was set. Networks only match addresses and
+/// domains only names, so flows to fake addresses are routed by the name they stand for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DialRule {
+  network: Option<IpAndMaskPrefix>,
+  ports: Option<PortRange>,
+  domain: Option<String>,
+}
+
+impl DialRule {
+  /// Matches every destination
+  pub fn new() -> Self {
+    Self::default()
+  }
+  pub fn network(mut self, network: IpAndMaskPrefix) -> Self {
+    self.network = Some(network);
+    self
+  }
+  pub fn ports(mut self, ports: impl Into<PortRange>) -> Self {
+    self.ports = Some(ports.into());
+    self
+  }
+  /// Matches `domain` and its subdomains
+  pub fn domain(mut self, domain: &str) -> Self {
+    self.domain = Some(normalize_name(domain));
+    self
+  }
+  pub fn matches(&self, destination: &Destination) -> bool {
+    let network = self.network.is_none_or(|network| match destination {
+      Destination::Address(address) => network.contains(address.ip()),
+      Destination::Domain(..) => false,
+    });
+    let domain = self.domain.as_ref().is_none_or(|suffix| match destination {
+      Destination::Domain(domain, _) => {
+        let domain = normalize_name(domain);
+        domain
+          .strip_suffix(suffix.as_str())
+          .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+      }
+      Destination::Address(_) => false,
+    });
+    network
+      && domain
+      && self
+        .ports
+        .is_none_or(|ports| ports.contains(destination.port()))
+  }
+}
+
+/// Picks the dialer of the first rule matching a destination, or the default one
+pub struct RoutingDialer {
+  rules: Vec<(DialRule, Option<Arc<dyn UpstreamDialer>>)>,
+  default: Arc<dyn UpstreamDialer>,
+}
+
+impl RoutingDialer {
+  pub fn new(default: impl UpstreamDialer + 'static) -> Self {
+    Self {
+      rules: Vec::new(),
+      default: Arc::new(default),
+    }
+  }
+  /// Dials destinations matching `rule` with `dialer`. Dialers used by several rules can be
+  /// shared in an [`Arc`]
+  pub fn with_rule(mut self, rule: DialRule, dialer: impl UpstreamDialer + 'static) -> Self {
+    self.rules.push((rule, Some(Arc::new(dialer))));
+    self
+  }
+  /// Refuses destinations matching `rule` with [`DialError::Blocked`]
+  pub fn with_block(mut self, rule: DialRule) -> Self {
+    self.rules.push((rule, None));
+    self
+  }
+  /// The dialer for `destination`, `None` if it is blocked
+  pub fn route(&self, destination: &Destination) -> Option<&dyn UpstreamDialer> {
+    match self
+      .rules
+      .iter()
+      .find(|(rule, _)| rule.matches(destination))
+    {
+      Some((_, dialer)) => dialer.as_deref(),
+      None => Some(&*self.default),
+    }
+  }
+}
+
+impl UpstreamDialer for RoutingDialer {
+  fn dial_tcp(&self, destination: &Destination) -> Result<TcpStream, DialError> {
+    self
+      .route(destination)
+      .ok_or(DialError::Blocked)?
+      .dial_tcp(destination)
+  }
+  fn bind_udp(&self, destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+    self
+      .route(destination)
+      .ok_or(DialError::Blocked)?
+      .bind_udp(destination)
+  }
+}
+
+fn base64(data: &[u8]) -> String {
+  const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+  let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+  for chunk in data.chunks(3) {
+    let bytes = [
+      chunk[0],
+      *chunk.get(1).unwrap_or(&0),
+      *chunk.get(2).unwrap_or(&0),
+    ];
+    let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+    for index in 0..4 {
+      match index <= chunk.len() {
+        true => encoded.push(ALPHABET[(bits >> (18 - 6 * index) & 0x3F) as usize] as char),
+        false => encoded.push('='),
+      }
+    }
+  }
+  encoded
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+  };
+
+  use super::{
+    Chained, DialError, DialRule, Direct, HttpProxy, RoutingDialer, UpstreamDialer, UpstreamUdp,
+  };
+  use crate::{
+    socks5::tests::{echo_server, StandIn},
+    Destination, IpAndMaskPrefix, Socks5Proxy,
+  };
+
+  /// Request lines and the authorization they came with
+  type Requests = Vec<(String, Option<String>)>;
+
+  /// An HTTP proxy for tests. It relays every CONNECT to the same target whatever was asked for,
+  /// recording the request lines and the authorization they came with
+  struct HttpStandIn {
+    address: SocketAddr,
+    requests: Arc<Mutex<Requests>>,
+  }
+
+  impl HttpStandIn {
+    fn start(authorization: Option<&'static str>, target: SocketAddr) -> Self {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let address = listener.local_addr().unwrap();
+      let requests = Arc::new(Mutex::new(Vec::new()));
+      let recorded = requests.clone();
+      thread::spawn(move || {
+        for stream in listener.incoming() {
+          let recorded = recorded.clone();
+          thread::spawn(move || serve(stream?, authorization, target, &recorded));
+        }
+        io::Result::Ok(())
+      });
+      Self { address, requests }
+    }
+    fn requests(&self) -> Requests {
+      self.requests.lock().unwrap().clone()
+    }
+  }
+
+  fn serve(
+    mut stream: TcpStream,
+    authorization: Option<&str>,
+    target: SocketAddr,
+    requests: &Mutex<Requests>,
+  ) -> io::Result<()> {
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+      let mut byte = [0];
+      stream.read_exact(&mut byte)?;
+      header.push(byte[0]);
+    }
+    let header = String::from_utf8_lossy(&header).into_owned();
+    let mut lines = header.split("\r\n");
+    let request = lines.next().unwrap_or_default().to_owned();
+    let presented = lines
+      .filter_map(|line| line.strip_prefix("Proxy-Authorization: "))
+      .map(str::to_owned)
+      .next();
+    requests.lock().unwrap().push((request, presented.clone()));
+    if authorization.is_some() && presented.as_deref() != authorization {
+      return stream.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n");
+    }
+    let mut upstream = TcpStream::connect(target)?;
+    stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+    let (mut from, mut to) = (stream.try_clone()?, upstream.try_clone()?);
+    let forward = thread::spawn(move || {
+      let _ = io::copy(&mut from, &mut to);
+      let _ = to.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut upstream, &mut stream);
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = forward.join();
+    Ok(())
+  }
+
+  fn echo(mut stream: TcpStream, data: &[u8]) -> Vec<u8> {
+    stream.write_all(data).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    echoed
+  }
+
+  /// Fails every dial with a status naming it
+  struct Named(u16);
+
+  impl UpstreamDialer for Named {
+    fn dial_tcp(&self, _destination: &Destination) -> Result<TcpStream, DialError> {
+      Err(DialError::HttpStatus(self.0))
+    }
+    fn bind_udp(&self, _destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+      Err(DialError::HttpStatus(self.0))
+    }
+  }
+
+  #[test]
+  fn dials_through_http_proxy() {
+    let proxy = HttpStandIn::start(Some("Basic dXNlcjpzZWNyZXQ="), echo_server());
+    let dialer = HttpProxy::new(proxy.address).with_credentials("user", "secret");
+    let destination = Destination::Address("[2001:db8::1]:443".parse().unwrap());
+    let stream = dialer.dial_tcp(&destination).unwrap();
+    assert_eq!(echo(stream, b"hello"), b"hello");
+    assert_eq!(
+      proxy.requests(),
+      [(
+        "CONNECT [2001:db8::1]:443 HTTP/1.1".to_owned(),
+        Some("Basic dXNlcjpzZWNyZXQ=".to_owned())
+      )]
+    );
+
+    let result = HttpProxy::new(proxy.address)
+      .with_credentials("user", "wrong")
+      .dial_tcp(&destination);
+    assert!(matches!(result, Err(DialError::HttpStatus(407))));
+    let result = dialer.bind_udp(&destination);
+    assert!(matches!(result, Err(DialError::UdpUnsupported)));
+  }
+
+  #[test]
+  fn refuses_domains_that_are_not_host_names() {
+    let proxy = HttpStandIn::start(None, echo_server());
+    let dialer = HttpProxy::new(proxy.address);
+    let smuggled =
+      "echo.test:80 HTTP/1.1\r\nX-Injected: yes\r\n\r\nGET / HTTP/1.1\r\nHost: echo.test";
+    for domain in [smuggled, "echo test", "echo..test", &"a".repeat(256)] {
+      let result = dialer.dial_tcp(&Destination::Domain(domain.into(), 80));
+      assert!(matches!(result, Err(DialError::InvalidDomain(name)) if name == domain));
+    }
+    assert!(proxy.requests().is_empty());
+  }
+
+  #[test]
+  fn chains_proxies() {
+    let http = HttpStandIn::start(None, echo_server());
+    //The SOCKS5 proxy reaches the HTTP one whatever it is asked for
+    let socks5 = StandIn::start(None, http.address);
+    let dialer = Chained::new(
+      Socks5Proxy::new(socks5.address),
+      HttpProxy::new(Destination::Domain("http-proxy.test".into(), 3128)),
+    );
+    let destination = Destination::Domain("echo.test".into(), 80);
+    let stream = dialer.dial_tcp(&destination).unwrap();
+    assert_eq!(echo(stream, b"through both"), b"through both");
+    assert_eq!(
+      socks5.requests(),
+      [(1, Destination::Domain("http-proxy.test".into(), 3128))]
+    );
+    assert_eq!(
+      http.requests(),
+      [("CONNECT echo.test:80 HTTP/1.1".to_owned(), None)]
+    );
+  }
+
+  #[test]
+  fn dials_directly() {
+    let port = echo_server().port();
+    //Tries every address localhost has, the server only listens on one of them
+    let stream = Direct::new()
+      .dial_tcp(&Destination::Domain("localhost".into(), port))
+      .unwrap();
+    assert_eq!(echo(stream, b"direct"), b"direct");
+
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let destination = Destination::Address(server.local_addr().unwrap());
+    let flow = Direct::new().bind_udp(&destination).unwrap();
+    flow.send(b"ping").unwrap();
+    let mut buf = [0; 1500];
+    let (len, client) = server.recv_from(&mut buf).unwrap();
+    server.send_to(&buf[..len], client).unwrap();
+    let len = flow.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+
+    let proxy = StandIn::start(None, echo_server());
+    let flow = Socks5Proxy::new(proxy.address)
+      .bind_udp(&destination)
+      .unwrap();
+    flow.send(b"pong").unwrap();
+    let len = flow.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"pong");
+  }
+
+  #[test]
+  fn dials_from_the_bound_address() {
+    let bound = IpAddr::from([127, 0, 0, 2]);
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let destination = Destination::Address(listener.local_addr().unwrap());
+    let direct = Direct::new().with_bind(bound);
+    let stream = direct.dial_tcp(&destination).unwrap();
+    let (_, client) = listener.accept().unwrap();
+    assert_eq!((client.ip(), stream.local_addr().unwrap()), (bound, client));
+
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let flow = direct
+      .bind_udp(&Destination::Address(server.local_addr().unwrap()))
+      .unwrap();
+    flow.send(b"ping").unwrap();
+    let mut buf = [0; 1500];
+    let (_, client) = server.recv_from(&mut buf).unwrap();
+    assert_eq!(client.ip(), bound);
+
+    //Closed ports and addresses of no interface fail
+    let closed = Destination::Address((Ipv4Addr::LOCALHOST, 0).into());
+    assert!(direct.dial_tcp(&closed).is_err());
+    let unassigned = Direct::new().with_bind(IpAddr::from([192, 0, 2, 1]));
+    assert!(unassigned
+      .dial_tcp(&Destination::Address(server.local_addr().unwrap()))
+      .is_err());
+  }
+
+  #[test]
+  fn routes_by_rule() {
+    let private = IpAndMaskPrefix::V4 {
+      ip: Ipv4Addr::new(10, 0, 0, 0),
+      prefix: 8.try_into().unwrap(),
+    };
+    let dialer = RoutingDialer::new(Named(1))
+      .with_rule(DialRule::new().domain("Example.com"), Named(2))
+      .with_rule(DialRule::new().network(private).ports(53), Named(3))
+      .with_block(DialRule::new().ports(25..=26));
+    let routed = |destination: Destination| match dialer.dial_tcp(&destination) {
+      Err(DialError::HttpStatus(status)) => Some(status),
+      Err(DialError::Blocked) => None,
+      result => panic!("{result:?}"),
+    };
+    assert_eq!(
+      routed(Destination::Domain("example.com".into(), 443)),
+      Some(2)
+    );
+    assert_eq!(
+      routed(Destination::Domain("www.EXAMPLE.com.".into(), 80)),
+      Some(2)
+    );
+    assert_eq!(
+      routed(Destination::Domain("notexample.com".into(), 80)),
+      Some(1)
+    );
+    assert_eq!(
+      routed("10.1.2.3:53".parse::<SocketAddr>().unwrap().into()),
+      Some(3)
+    );
+    assert_eq!(
+      routed("10.1.2.3:80".parse::<SocketAddr>().unwrap().into()),
+      Some(1)
+    );
+    assert_eq!(
+      routed("192.0.2.1:53".parse::<SocketAddr>().unwrap().into()),
+      Some(1)
+    );
+    assert_eq!(routed(Destination::Domain("mail.test".into(), 25)), None);
+    assert!(matches!(
+      dialer.bind_udp(&Destination::Domain("www.example.com".into(), 53)),
+      Err(DialError::HttpStatus(2))
+    ));
+  }
+}
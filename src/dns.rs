@@ -0,0 +1,1609 @@
+//! A DNS server living on a virtual address inside the tunnel. [`DnsMessage`] parses and encodes
+//! DNS messages (RFC 1035), [`DnsResponder`] answers queries sent to its addresses over UDP and
+//! TCP port 53 from a [`Resolver`] such as a static [`Hosts`] map. Other traffic passes by
+
+use std::{
+  collections::{hash_map::RandomState, HashMap},
+  fmt,
+  hash::BuildHasher,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+  sync::Arc,
+  time::Duration,
+};
+
+use crate::{
+  mss::mss_option,
+  wire::{IPV6_HEADER_LEN, TCP_HEADER_LEN},
+};
+use crate::{
+  tcp_packet, udp_packet, update_checksum, Clock, IpPacket, IpProtocol, PacketProcessor,
+  SystemClock, Verdict, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN,
+};
+
+pub const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+const MAX_NAME_LEN: usize = 255;
+const MAX_LABEL_LEN: usize = 63;
+/// Compression pointers followed at most while reading one name
+const MAX_POINTERS: usize = 32;
+/// Largest UDP response for clients that don't say otherwise with EDNS (RFC 6891)
+const MAX_UDP_LEN: usize = 512;
+const CLASS_IN: u16 = 1;
+const OPCODE_QUERY: u8 = 0;
+const DEFAULT_TTL: u32 = 60;
+/// CNAMEs followed at most for one answer
+const MAX_CNAME_CHAIN: usize = 8;
+/// TCP connections answered at once
+const MAX_TCP_CONNECTIONS: usize = 256;
+/// How long TCP connections are kept without a segment from the client
+const DEFAULT_TCP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of queries buffered per TCP connection, enough for one message and its length
+const MAX_TCP_BUFFER: usize = 2 + u16::MAX as usize;
+/// Bytes of answers waiting for a TCP client, beyond which further answers are dropped
+const MAX_TCP_OUTGOING: usize = 2 * MAX_TCP_BUFFER;
+/// MSS assumed for clients that don't announce one (RFC 9293)
+const DEFAULT_MSS_V4: u16 = 536;
+const DEFAULT_MSS_V6: u16 = 1220;
+/// Largest MSS that still fits an IPv6 packet with its TCP header
+const MAX_MSS: u16 = u16::MAX - (IPV6_HEADER_LEN + TCP_HEADER_LEN) as u16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+  /// The message ends in the middle of a field
+  Truncated,
+  /// A name is too long, has a label over 63 bytes or loops through compression pointers
+  InvalidName,
+  /// A message to encode would be longer than 65535 bytes
+  TooLong,
+}
+
+impl fmt::Display for DnsError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DnsError::Truncated => f.write_str("DNS message is truncated"),
+      DnsError::InvalidName => f.write_str("Invalid DNS name"),
+      DnsError::TooLong => f.write_str("DNS message would be longer than 65535 bytes"),
+    }
+  }
+}
+
+impl std::error::Error for DnsError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordType {
+  A,
+  Cname,
+  Ptr,
+  Aaaa,
+  Other(u16),
+}
+
+impl From<u16> for RecordType {
+  fn from(value: u16) -> Self {
+    match value {
+      1 => RecordType::A,
+      5 => RecordType::Cname,
+      12 => RecordType::Ptr,
+      28 => RecordType::Aaaa,
+      other => RecordType::Other(other),
+    }
+  }
+}
+
+impl From<RecordType> for u16 {
+  fn from(value: RecordType) -> Self {
+    match value {
+      RecordType::A => 1,
+      RecordType::Cname => 5,
+      RecordType::Ptr => 12,
+      RecordType::Aaaa => 28,
+      RecordType::Other(other) => other,
+    }
+  }
+}
+
+/// EDNS pseudo records (RFC 6891), whose class is the largest UDP response the client takes
+const RECORD_OPT: u16 = 41;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+  A(Ipv4Addr),
+  Aaaa(Ipv6Addr),
+  Cname(String),
+  Ptr(String),
+  /// Data of other types as it is on the wire
+  Other(u16, Vec<u8>),
+}
+
+impl RecordData {
+  pub fn record_type(&self) -> RecordType {
+    match self {
+      RecordData::A(_) => RecordType::A,
+      RecordData::Aaaa(_) => RecordType::Aaaa,
+      RecordData::Cname(_) => RecordType::Cname,
+      RecordData::Ptr(_) => RecordType::Ptr,
+      RecordData::Other(record_type, _) => RecordType::Other(*record_type),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCode {
+  NoError,
+  FormatError,
+  ServerFailure,
+  /// NXDOMAIN, the name doesn't exist
+  NameError,
+  NotImplemented,
+  Refused,
+  Other(u8),
+}
+
+impl From<u8> for ResponseCode {
+  fn from(value: u8) -> Self {
+    match value {
+      0 => ResponseCode::NoError,
+      1 => ResponseCode::FormatError,
+      2 => ResponseCode::ServerFailure,
+      3 => ResponseCode::NameError,
+      4 => ResponseCode::NotImplemented,
+      5 => ResponseCode::Refused,
+      other => ResponseCode::Other(other),
+    }
+  }
+}
+
+impl From<ResponseCode> for u8 {
+  fn from(value: ResponseCode) -> Self {
+    match value {
+      ResponseCode::NoError => 0,
+      ResponseCode::FormatError => 1,
+      ResponseCode::ServerFailure => 2,
+      ResponseCode::NameError => 3,
+      ResponseCode::NotImplemented => 4,
+      ResponseCode::Refused => 5,
+      ResponseCode::Other(other) => other & 0xF,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+  /// Without the trailing dot, with dots, backslashes and unprintable bytes in labels escaped
+  /// like `\.` and `\032` (RFC 1035 section 5.1)
+  pub name: String,
+  pub record_type: RecordType,
+  pub class: u16,
+}
+
+impl DnsQuestion {
+  pub fn new(name: impl Into<String>, record_type: RecordType) -> Self {
+    Self {
+      name: name.into(),
+      record_type,
+      class: CLASS_IN,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsRecord {
+  pub name: String,
+  pub class: u16,
+  pub ttl: u32,
+  pub data: RecordData,
+}
+
+impl DnsRecord {
+  /// A record of class IN
+  pub fn new(name: impl Into<String>, ttl: u32, data: RecordData) -> Self {
+    Self {
+      name: name.into(),
+      class: CLASS_IN,
+      ttl,
+      data,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsMessage {
+  pub id: u16,
+  pub response: bool,
+  pub opcode: u8,
+  pub authoritative: bool,
+  pub truncated: bool,
+  pub recursion_desired: bool,
+  pub recursion_available: bool,
+  pub response_code: ResponseCode,
+  pub questions: Vec<DnsQuestion>,
+  pub answers: Vec<DnsRecord>,
+  pub authorities: Vec<DnsRecord>,
+  pub additionals: Vec<DnsRecord>,
+}
+
+impl DnsMessage {
+  /// A recursive query with one question
+  pub fn query(id: u16, name: impl Into<String>, record_type: RecordType) -> Self {
+    Self {
+      id,
+      response: false,
+      opcode: OPCODE_QUERY,
+      authoritative: false,
+      truncated: false,
+      recursion_desired: true,
+      recursion_available: false,
+      response_code: ResponseCode::NoError,
+      questions: vec![DnsQuestion::new(name, record_type)],
+      answers: Vec::new(),
+      authorities: Vec::new(),
+      additionals: Vec::new(),
+    }
+  }
+  /// An empty response to this message, repeating its id, opcode and questions
+  pub fn response(&self, response_code: ResponseCode) -> Self {
+    Self {
+      id: self.id,
+      response: true,
+      opcode: self.opcode,
+      authoritative: false,
+      truncated: false,
+      recursion_desired: self.recursion_desired,
+      recursion_available: true,
+      response_code,
+      questions: self.questions.clone(),
+      answers: Vec::new(),
+      authorities: Vec::new(),
+      additionals: Vec::new(),
+    }
+  }
+  /// Largest UDP response the sender takes, from its EDNS record
+  pub fn max_udp_len(&self) -> usize {
+    self
+      .additionals
+      .iter()
+      .find(|record| record.data.record_type() == RecordType::Other(RECORD_OPT))
+      .map_or(MAX_UDP_LEN, |opt| (opt.class as usize).max(MAX_UDP_LEN))
+  }
+  pub fn parse(message: &[u8]) -> Result<Self, DnsError> {
+    let header = message.get(..HEADER_LEN).ok_or(DnsError::Truncated)?;
+    let word = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+    let flags = word(2);
+    let mut reader = Reader {
+      message,
+      at: HEADER_LEN,
+    };
+    let mut questions = Vec::new();
+    for _ in 0..word(4) {
+      questions.push(DnsQuestion {
+        name: reader.name()?,
+        record_type: reader.u16()?.into(),
+        class: reader.u16()?,
+      });
+    }
+    let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+    for (section, count) in sections.iter_mut().zip([word(6), word(8), word(10)]) {
+      for _ in 0..count {
+        section.push(reader.record()?);
+      }
+    }
+    let [answers, authorities, additionals] = sections;
+    Ok(Self {
+      id: word(0),
+      response: flags & 0x8000 != 0,
+      opcode: (flags >> 11 & 0xF) as u8,
+      authoritative: flags & 0x0400 != 0,
+      truncated: flags & 0x0200 != 0,
+      recursion_desired: flags & 0x0100 != 0,
+      recursion_available: flags & 0x0080 != 0,
+      response_code: ((flags & 0xF) as u8).into(),
+      questions,
+      answers,
+      authorities,
+      additionals,
+    })
+  }
+  /// Encodes the message without name compression
+  pub fn encode(&self) -> Result<Vec<u8>, DnsError> {
+    let mut message = Vec::with_capacity(MAX_UDP_LEN);
+    let flags = (self.response as u16) << 15
+      | ((self.opcode & 0xF) as u16) << 11
+      | (self.authoritative as u16) << 10
+      | (self.truncated as u16) << 9
+      | (self.recursion_desired as u16) << 8
+      | (self.recursion_available as u16) << 7
+      | u8::from(self.response_code) as u16;
+    message.extend_from_slice(&self.id.to_be_bytes());
+    message.extend_from_slice(&flags.to_be_bytes());
+    for count in [
+      self.questions.len(),
+      self.answers.len(),
+      self.authorities.len(),
+      self.additionals.len(),
+    ] {
+      let count = u16::try_from(count).map_err(|_| DnsError::TooLong)?;
+      message.extend_from_slice(&count.to_be_bytes());
+    }
+    for question in &self.questions {
+      encode_name(&mut message, &question.name)?;
+      message.extend_from_slice(&u16::from(question.record_type).to_be_bytes());
+      message.extend_from_slice(&question.class.to_be_bytes());
+    }
+    for record in self
+      .answers
+      .iter()
+      .chain(&self.authorities)
+      .chain(&self.additionals)
+    {
+      encode_record(&mut message, record)?;
+    }
+    if message.len() > u16::MAX as usize {
+      return Err(DnsError::TooLong);
+    }
+    Ok(message)
+  }
+}
+
+struct Reader<'a> {
+  message: &'a [u8],
+  at: usize,
+}
+
+impl Reader<'_> {
+  fn bytes(&mut self, len: usize) -> Result<&[u8], DnsError> {
+    let bytes = self
+      .message
+      .get(self.at..self.at + len)
+      .ok_or(DnsError::Truncated)?;
+    self.at += len;
+    Ok(bytes)
+  }
+  fn u16(&mut self) -> Result<u16, DnsError> {
+    let bytes = self.bytes(2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+  fn u32(&mut self) -> Result<u32, DnsError> {
+    let bytes = self.bytes(4)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+  }
+  /// Reads a possibly compressed name, escaping the bytes of labels that aren't printable
+  fn name(&mut self) -> Result<String, DnsError> {
+    let mut name = String::new();
+    //Counts the root label
+    let mut len_on_wire = 1;
+    let mut at = self.at;
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+      let len = *self.message.get(at).ok_or(DnsError::Truncated)? as usize;
+      match len >> 6 {
+        0 if len == 0 => break,
+        0 => {
+          let label = self
+            .message
+            .get(at + 1..at + 1 + len)
+            .ok_or(DnsError::Truncated)?;
+          len_on_wire += 1 + len;
+          if len_on_wire > MAX_NAME_LEN {
+            return Err(DnsError::InvalidName);
+          }
+          if !name.is_empty() {
+            name.push('.');
+          }
+          for &byte in label {
+            match byte {
+              b'.' | b'\\' => {
+                name.push('\\');
+                name.push(byte as char);
+              }
+              0x21..=0x7E => name.push(byte as char),
+              _ => name.push_str(&format!("\\{byte:03}")),
+            }
+          }
+          at += 1 + len;
+        }
+        0b11 => {
+          let low = *self.message.get(at + 1).ok_or(DnsError::Truncated)? as usize;
+          end.get_or_insert(at + 2);
+          pointers += 1;
+          if pointers > MAX_POINTERS {
+            return Err(DnsError::InvalidName);
+          }
+          at = (len & 0x3F) << 8 | low;
+        }
+        _ => return Err(DnsError::InvalidName),
+      }
+    }
+    self.at = end.unwrap_or(at + 1);
+    Ok(name)
+  }
+  fn record(&mut self) -> Result<DnsRecord, DnsError> {
+    let name = self.name()?;
+    let record_type = RecordType::from(self.u16()?);
+    let class = self.u16()?;
+    let ttl = self.u32()?;
+    let len = self.u16()? as usize;
+    let start = self.at;
+    let data = self.bytes(len)?;
+    let data = match record_type {
+      RecordType::A => RecordData::A(
+        <[u8; 4]>::try_from(data)
+          .map_err(|_| DnsError::Truncated)?
+          .into(),
+      ),
+      RecordType::Aaaa => RecordData::Aaaa(
+        <[u8; 16]>::try_from(data)
+          .map_err(|_| DnsError::Truncated)?
+          .into(),
+      ),
+      //Names in record data may point anywhere in the message
+      RecordType::Cname | RecordType::Ptr => {
+        let mut reader = Reader {
+          message: &self.message[..start + len],
+          at: start,
+        };
+        let target = reader.name()?;
+        match record_type {
+          RecordType::Cname => RecordData::Cname(target),
+          _ => RecordData::Ptr(target),
+        }
+      }
+      RecordType::Other(record_type) => RecordData::Other(record_type, data.to_vec()),
+    };
+    Ok(DnsRecord {
+      name,
+      class,
+      ttl,
+      data,
+    })
+  }
+}
+
+/// Encodes a name, undoing the escapes of [`Reader::name`]
+fn encode_name(message: &mut Vec<u8>, name: &str) -> Result<(), DnsError> {
+  let start = message.len();
+  let mut label = Vec::new();
+  let mut bytes = name.bytes();
+  loop {
+    let next = bytes.next();
+    match next {
+      Some(b'\\') => {
+        let byte = match bytes.next().ok_or(DnsError::InvalidName)? {
+          digit @ b'0'..=b'9' => {
+            let mut value = (digit - b'0') as u32;
+            for _ in 0..2 {
+              match bytes.next() {
+                Some(digit @ b'0'..=b'9') => value = value * 10 + (digit - b'0') as u32,
+                _ => return Err(DnsError::InvalidName),
+              }
+            }
+            u8::try_from(value).map_err(|_| DnsError::InvalidName)?
+          }
+          byte => byte,
+        };
+        label.push(byte);
+      }
+      Some(b'.') | None if label.is_empty() => {}
+      Some(b'.') | None => {
+        if label.len() > MAX_LABEL_LEN {
+          return Err(DnsError::InvalidName);
+        }
+        message.push(label.len() as u8);
+        message.append(&mut label);
+      }
+      Some(byte) => label.push(byte),
+    }
+    if next.is_none() {
+      break;
+    }
+  }
+  message.push(0);
+  if message.len() - start > MAX_NAME_LEN {
+    return Err(DnsError::InvalidName);
+  }
+  Ok(())
+}
+
+fn encode_record(message: &mut Vec<u8>, record: &DnsRecord) -> Result<(), DnsError> {
+  encode_name(message, &record.name)?;
+  message.extend_from_slice(&u16::from(record.data.record_type()).to_be_bytes());
+  message.extend_from_slice(&record.class.to_be_bytes());
+  message.extend_from_slice(&record.ttl.to_be_bytes());
+  let len_at = message.len();
+  message.extend_from_slice(&[0, 0]);
+  match &record.data {
+    RecordData::A(ip) => message.extend_from_slice(&ip.octets()),
+    RecordData::Aaaa(ip) => message.extend_from_slice(&ip.octets()),
+    RecordData::Cname(name) | RecordData::Ptr(name) => encode_name(message, name)?,
+    RecordData::Other(_, data) => message.extend_from_slice(data),
+  }
+  let len = u16::try_from(message.len() - len_at - 2).map_err(|_| DnsError::TooLong)?;
+  message[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+  Ok(())
+}
+
+/// Lowercase and without the trailing dot, for comparing names
+pub fn normalize_name(name: &str) -> String {
+  name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Letters, digits, hyphens and the underscores of service names
+fn is_label_byte(byte: u8) -> bool {
+  byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'
+}
+
+/// Whether `name` is a non-empty host name within the length limits, with or without the
+/// trailing dot
+pub(crate) fn is_hostname(name: &str) -> bool {
+  let name = name.strip_suffix('.').unwrap_or(name);
+  !name.is_empty()
+    //Encoded with a length byte per label and the root label
+    && name.len() + 2 <= MAX_NAME_LEN
+    && name.split('.').all(|label| {
+      (1..=MAX_LABEL_LEN).contains(&label.len()) && label.bytes().all(is_label_byte)
+    })
+}
+
+/// The name PTR queries for `ip` ask about, like `4.3.2.1.in-addr.arpa`
+pub fn reverse_name(ip: IpAddr) -> String {
+  match ip {
+    IpAddr::V4(ip) => {
+      let [a, b, c, d] = ip.octets();
+      format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+    }
+    IpAddr::V6(ip) => {
+      let mut name = String::with_capacity(72);
+      for byte in ip.octets().iter().rev() {
+        name.push_str(&format!("{:x}.{:x}.", byte & 0xF, byte >> 4));
+      }
+      name.push_str("ip6.arpa");
+      name
+    }
+  }
+}
+
+/// The address a PTR query is about, the reverse of [`reverse_name`]
+pub fn parse_reverse_name(name: &str) -> Option<IpAddr> {
+  let name = normalize_name(name);
+  if let Some(labels) = name.strip_suffix(".in-addr.arpa") {
+    let mut octets: Vec<u8> = labels
+      .split('.')
+      .map(|label| label.parse().ok())
+      .collect::<Option<_>>()?;
+    octets.reverse();
+    return Some(IpAddr::V4(<[u8; 4]>::try_from(octets).ok()?.into()));
+  }
+  let labels = name.strip_suffix(".ip6.arpa")?;
+  let nibbles: Vec<u8> = labels
+    .split('.')
+    .map(|label| match label.len() {
+      1 => u8::from_str_radix(label, 16).ok(),
+      _ => None,
+    })
+    .collect::<Option<_>>()?;
+  if nibbles.len() != 32 {
+    return None;
+  }
+  let mut octets = [0; 16];
+  for (index, pair) in nibbles.chunks(2).rev().enumerate() {
+    octets[index] = pair[1] << 4 | pair[0];
+  }
+  Some(IpAddr::V6(octets.into()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResolveError {
+  /// The name doesn't exist, answered with NXDOMAIN
+  NotFound,
+  /// The name couldn't be resolved, answered with SERVFAIL
+  Failed,
+}
+
+/// Answers the questions of a [`DnsResponder`]. An empty answer means the name exists without
+/// records of the asked type
+pub trait Resolver: Send {
+  fn resolve(&mut self, question: &DnsQuestion) -> Result<Vec<DnsRecord>, ResolveError>;
+}
+
+impl<F> Resolver for F
+where
+  F: FnMut(&DnsQuestion) -> Result<Vec<DnsRecord>, ResolveError> + Send,
+{
+  fn resolve(&mut self, question: &DnsQuestion) -> Result<Vec<DnsRecord>, ResolveError> {
+    self(question)
+  }
+}
+
+/// A static name table, like a hosts file. Addresses of names also answer PTR queries for them
+#[derive(Debug, Clone)]
+pub struct Hosts {
+  addresses: HashMap<String, Vec<IpAddr>>,
+  aliases: HashMap<String, String>,
+  /// The first name given for each address
+  names: HashMap<IpAddr, String>,
+  ttl: u32,
+}
+
+impl Default for Hosts {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Hosts {
+  /// An empty table whose answers live 60 seconds
+  pub fn new() -> Self {
+    Self {
+      addresses: HashMap::new(),
+      aliases: HashMap::new(),
+      names: HashMap::new(),
+      ttl: DEFAULT_TTL,
+    }
+  }
+  pub fn with_ttl(mut self, ttl: u32) -> Self {
+    self.ttl = ttl;
+    self
+  }
+  /// Adds an address of `name`
+  pub fn with(mut self, name: &str, ip: IpAddr) -> Self {
+    self.insert(name, ip);
+    self
+  }
+  /// Makes `alias` a CNAME of `target`
+  pub fn with_alias(mut self, alias: &str, target: &str) -> Self {
+    self
+      .aliases
+      .insert(normalize_name(alias), normalize_name(target));
+    self
+  }
+  pub fn insert(&mut self, name: &str, ip: IpAddr) {
+    let name = normalize_name(name);
+    self.names.entry(ip).or_insert_with(|| name.clone());
+    let addresses = self.addresses.entry(name).or_default();
+    if !addresses.contains(&ip) {
+      addresses.push(ip);
+    }
+  }
+  pub fn remove(&mut self, name: &str) {
+    let name = normalize_name(name);
+    self.aliases.remove(&name);
+    for ip in self.addresses.remove(&name).unwrap_or_default() {
+      if self.names.get(&ip) == Some(&name) {
+        self.names.remove(&ip);
+      }
+    }
+  }
+}
+
+impl Resolver for Hosts {
+  fn resolve(&mut self, question: &DnsQuestion) -> Result<Vec<DnsRecord>, ResolveError> {
+    if question.record_type == RecordType::Ptr {
+      let name = parse_reverse_name(&question.name)
+        .and_then(|ip| self.names.get(&ip))
+        .ok_or(ResolveError::NotFound)?;
+      return Ok(vec![DnsRecord::new(
+        question.name.clone(),
+        self.ttl,
+        RecordData::Ptr(name.clone()),
+      )]);
+    }
+    let mut records = Vec::new();
+    let mut owner = question.name.clone();
+    let mut name = normalize_name(&question.name);
+    for _ in 0..MAX_CNAME_CHAIN {
+      let Some(target) = self.aliases.get(&name) else {
+        break;
+      };
+      records.push(DnsRecord::new(
+        owner,
+        self.ttl,
+        RecordData::Cname(target.clone()),
+      ));
+      if question.record_type == RecordType::Cname {
+        return Ok(records);
+      }
+      owner = target.clone();
+      name = target.clone();
+    }
+    let Some(addresses) = self.addresses.get(&name) else {
+      //A CNAME pointing out of the table is still an answer
+      return match records.is_empty() {
+        true => Err(ResolveError::NotFound),
+        false => Ok(records),
+      };
+    };
+    records.extend(addresses.iter().filter_map(|ip| {
+      let data = match (ip, question.record_type) {
+        (IpAddr::V4(ip), RecordType::A) => RecordData::A(*ip),
+        (IpAddr::V6(ip), RecordType::Aaaa) => RecordData::Aaaa(*ip),
+        _ => return None,
+      };
+      Some(DnsRecord::new(owner.clone(), self.ttl, data))
+    }));
+    Ok(records)
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DnsStats {
+  pub queries: u64,
+  /// Answered with NXDOMAIN
+  pub not_found: u64,
+  /// Answered with SERVFAIL
+  pub failed: u64,
+  /// Answered with FORMERR or dropped because they couldn't be parsed
+  pub malformed: u64,
+  /// UDP answers that didn't fit and were sent truncated
+  pub truncated: u64,
+  /// TCP answers dropped because too many were waiting for the client
+  pub dropped: u64,
+  /// TCP connections forgotten after the client went quiet
+  pub expired: u64,
+}
+
+/// A TCP connection to the responder
+struct TcpQuery {
+  /// Our initial sequence number
+  isn: u32,
+  /// Next sequence number we expect and send
+  their_next: u32,
+  our_next: u32,
+  /// Received bytes not yet forming a whole length-prefixed message
+  buffer: Vec<u8>,
+  /// Largest segment the client takes
+  mss: usize,
+  /// Answers not acknowledged yet, starting with the segment in flight
+  outgoing: Vec<u8>,
+  /// Bytes of `outgoing` in flight. Answers go out one segment at a time, each acknowledgment
+  /// releasing the next
+  sent: usize,
+  /// They sent a FIN, which we answer once every answer went out
+  fin_received: bool,
+  /// We sent our FIN and only wait for the last ACK
+  closing: bool,
+  /// Clock time of the last segment from the client
+  last_seen: Duration,
+}
+
+impl TcpQuery {
+  /// Drops the bytes in flight that `ack` covers. False if it covers none of them
+  fn acknowledge(&mut self, ack: u32) -> bool {
+    let unacked = self
+      .our_next
+      .wrapping_sub(self.sent as u32 + self.closing as u32);
+    let acked = ack.wrapping_sub(unacked) as usize;
+    if acked <= self.sent {
+      self.outgoing.drain(..acked);
+      self.sent -= acked;
+    }
+    acked != 0 && acked <= self.sent + self.closing as usize
+  }
+  /// The next segment for the client, carrying answers if none are in flight and our FIN once
+  /// they all went out. Without either it is a bare ACK, or `None` unless `ack` is set
+  fn segment(&mut self, server: SocketAddr, client: SocketAddr, ack: bool) -> Option<Vec<u8>> {
+    let len = match self.sent {
+      0 => self.outgoing.len().min(self.mss),
+      _ => 0,
+    };
+    let fin = self.fin_received && !self.closing && self.sent + len == self.outgoing.len();
+    if len == 0 && !fin && !ack {
+      return None;
+    }
+    let mut flags = TCP_ACK;
+    if len > 0 {
+      flags |= TCP_PSH;
+    }
+    if fin {
+      flags |= TCP_FIN;
+      self.closing = true;
+    }
+    let segment = tcp_segment(
+      server,
+      client,
+      self.our_next,
+      self.their_next,
+      flags,
+      &self.outgoing[..len],
+    )?;
+    self.sent += len;
+    self.our_next = self.our_next.wrapping_add(len as u32 + fin as u32);
+    Some(segment)
+  }
+  /// The segment in flight once more, or a bare ACK if nothing is in flight
+  fn resend(&self, server: SocketAddr, client: SocketAddr) -> Option<Vec<u8>> {
+    let mut flags = TCP_ACK;
+    if self.sent > 0 {
+      flags |= TCP_PSH;
+    }
+    if self.closing {
+      flags |= TCP_FIN;
+    }
+    tcp_segment(
+      server,
+      client,
+      self
+        .our_next
+        .wrapping_sub(self.sent as u32 + self.closing as u32),
+      self.their_next,
+      flags,
+      &self.outgoing[..self.sent],
+    )
+  }
+}
+
+/// Answers DNS queries sent to its addresses over UDP and TCP. Queries are answered in the
+/// direction they arrive from, so the responder sees queries of the system in
+/// [`on_inbound`](PacketProcessor::on_inbound) and queries of the session in
+/// [`on_outbound`](PacketProcessor::on_outbound). TCP connections are forgotten once the client
+/// stays quiet for 30 seconds
+pub struct DnsResponder {
+  addresses: Vec<IpAddr>,
+  resolver: Box<dyn Resolver>,
+  connections: HashMap<(SocketAddr, SocketAddr), TcpQuery>,
+  /// Keys the initial sequence numbers of TCP connections
+  isn_secret: RandomState,
+  tcp_timeout: Duration,
+  clock: Arc<dyn Clock>,
+  stats: DnsStats,
+}
+
+impl DnsResponder {
+  pub fn new(address: IpAddr, resolver: impl Resolver + 'static) -> Self {
+    Self {
+      addresses: vec![address],
+      resolver: Box::new(resolver),
+      connections: HashMap::new(),
+      isn_secret: RandomState::new(),
+      tcp_timeout: DEFAULT_TCP_TIMEOUT,
+      clock: Arc::new(SystemClock::new()),
+      stats: DnsStats::default(),
+    }
+  }
+  /// Answers queries to `address` too, typically the address of the other family
+  pub fn with_address(mut self, address: IpAddr) -> Self {
+    self.addresses.push(address);
+    self
+  }
+  /// How long TCP connections are kept without a segment from the client
+  pub fn with_tcp_timeout(mut self, timeout: Duration) -> Self {
+    self.tcp_timeout = timeout;
+    self
+  }
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+  pub fn addresses(&self) -> &[IpAddr] {
+    &self.addresses
+  }
+  /// Forgets TCP connections whose client went quiet. Also done on every TCP segment
+  pub fn expire(&mut self) -> usize {
+    let now = self.clock.now();
+    let timeout = self.tcp_timeout;
+    let before = self.connections.len();
+    self
+      .connections
+      .retain(|_, connection| now.saturating_sub(connection.last_seen) < timeout);
+    let expired = before - self.connections.len();
+    self.stats.expired += expired as u64;
+    expired
+  }
+  pub fn stats(&self) -> DnsStats {
+    self.stats
+  }
+  /// The response to `query`. Queries with several questions get the answers to all of them,
+  /// the response code is the one of the first question that failed. Names that aren't host
+  /// names don't reach the resolver and aren't found
+  pub fn respond(&mut self, query: &DnsMessage) -> DnsMessage {
+    self.stats.queries += 1;
+    if query.response || query.opcode != OPCODE_QUERY {
+      return query.response(ResponseCode::NotImplemented);
+    }
+    let mut response = query.response(ResponseCode::NoError);
+    for question in &query.questions {
+      let records = match is_hostname(&question.name) {
+        true => self.resolver.resolve(question),
+        false => Err(ResolveError::NotFound),
+      };
+      match records {
+        Ok(records) => response.answers.extend(records),
+        Err(err) if response.response_code == ResponseCode::NoError => {
+          response.response_code = match err {
+            ResolveError::NotFound => {
+              self.stats.not_found += 1;
+              ResponseCode::NameError
+            }
+            ResolveError::Failed => {
+              self.stats.failed += 1;
+              ResponseCode::ServerFailure
+            }
+          };
+        }
+        Err(_) => {}
+      }
+    }
+    response
+  }
+  /// Encoded response to an encoded query, truncated to the client's limit for `udp`. `None` if
+  /// the query is beyond answering
+  fn answer(&mut self, query: &[u8], udp: bool) -> Option<Vec<u8>> {
+    let query = match DnsMessage::parse(query) {
+      Ok(query) => query,
+      //Echoes the id of queries whose header is readable
+      Err(_) if query.len() >= HEADER_LEN && query[2] & 0x80 == 0 => {
+        self.stats.malformed += 1;
+        let mut response =
+          DnsMessage::query(u16::from_be_bytes([query[0], query[1]]), "", RecordType::A)
+            .response(ResponseCode::FormatError);
+        response.questions.clear();
+        return response.encode().ok();
+      }
+      Err(_) => {
+        self.stats.malformed += 1;
+        return None;
+      }
+    };
+    let mut response = self.respond(&query);
+    let encoded = match response.encode() {
+      Ok(encoded) => encoded,
+      Err(_) => {
+        response = query.response(ResponseCode::ServerFailure);
+        response.encode().ok()?
+      }
+    };
+    if udp && encoded.len() > query.max_udp_len() {
+      //The client retries over TCP
+      self.stats.truncated += 1;
+      response.truncated = true;
+      response.answers.clear();
+      response.authorities.clear();
+      response.additionals.clear();
+      return response.encode().ok();
+    }
+    Some(encoded)
+  }
+  fn process(&mut self, packet: &mut [u8]) -> Verdict {
+    let Ok(parsed) = IpPacket::parse(packet) else {
+      return Verdict::Accept;
+    };
+    if !self.addresses.contains(&parsed.destination())
+      || parsed.destination_port() != Some(DNS_PORT)
+      || parsed.is_fragment()
+    {
+      return Verdict::Accept;
+    }
+    let (source_port, _) = parsed.ports().unwrap();
+    let client = SocketAddr::new(parsed.source(), source_port);
+    let server = SocketAddr::new(parsed.destination(), DNS_PORT);
+    match parsed.protocol() {
+      IpProtocol::Udp => {
+        let Some(query) = parsed.payload().get(8..) else {
+          return Verdict::Drop;
+        };
+        match self.answer(query, true) {
+          Some(response) => {
+            udp_packet(server, client, &response).map_or(Verdict::Drop, Verdict::Reply)
+          }
+          None => Verdict::Drop,
+        }
+      }
+      _ => self.tcp(&parsed, client, server),
+    }
+  }
+  /// Plays the server side of a TCP connection carrying length-prefixed queries (RFC 7766)
+  fn tcp(&mut self, packet: &IpPacket, client: SocketAddr, server: SocketAddr) -> Verdict {
+    let segment = packet.payload();
+    let Some(header) = segment.get(..TCP_HEADER_LEN) else {
+      return Verdict::Drop;
+    };
+    let seq = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let ack = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let flags = header[13];
+    let data = segment
+      .get((header[12] >> 4) as usize * 4..)
+      .unwrap_or_default();
+    let key = (client, server);
+    self.expire();
+    let now = self.clock.now();
+    if flags & TCP_RST != 0 {
+      self.connections.remove(&key);
+      return Verdict::Drop;
+    }
+    if flags & TCP_SYN != 0 {
+      if !self.connections.contains_key(&key) && self.connections.len() >= MAX_TCP_CONNECTIONS {
+        return reply(tcp_segment(
+          server,
+          client,
+          0,
+          seq.wrapping_add(1),
+          TCP_RST | TCP_ACK,
+          &[],
+        ));
+      }
+      let mss = match mss_option(segment) {
+        Some(at) => u16::from_be_bytes([segment[at], segment[at + 1]]),
+        None if client.is_ipv4() => DEFAULT_MSS_V4,
+        None => DEFAULT_MSS_V6,
+      };
+      let isn = self.isn_secret.hash_one(key) as u32;
+      let connection = self.connections.entry(key).or_insert(TcpQuery {
+        isn,
+        their_next: seq.wrapping_add(1),
+        our_next: isn.wrapping_add(1),
+        buffer: Vec::new(),
+        mss: mss.clamp(1, MAX_MSS) as usize,
+        outgoing: Vec::new(),
+        sent: 0,
+        fin_received: false,
+        closing: false,
+        last_seen: now,
+      });
+      connection.last_seen = now;
+      return reply(tcp_segment(
+        server,
+        client,
+        connection.isn,
+        connection.their_next,
+        TCP_SYN | TCP_ACK,
+        &[],
+      ));
+    }
+    let Some(connection) = self.connections.get_mut(&key) else {
+      //Nothing to talk about
+      return reply(tcp_segment(server, client, ack, 0, TCP_RST, &[]));
+    };
+    connection.last_seen = now;
+    let acknowledged = flags & TCP_ACK != 0 && connection.acknowledge(ack);
+    if connection.closing && ack == connection.our_next {
+      self.connections.remove(&key);
+      return Verdict::Drop;
+    }
+    let fin = flags & TCP_FIN != 0;
+    if data.is_empty() && !fin {
+      if !acknowledged && (connection.sent > 0 || connection.closing) {
+        //A duplicate acknowledgment, what is in flight got lost
+        return reply(connection.resend(server, client));
+      }
+      //An acknowledgment, which may release the next answers
+      return reply(connection.segment(server, client, false));
+    }
+    if seq != connection.their_next || connection.closing {
+      //A retransmission or a gap. Our answer to what they resent may have got lost, and what
+      //is in flight tells them what we expect
+      return reply(connection.resend(server, client));
+    }
+    connection.their_next = connection.their_next.wrapping_add(data.len() as u32);
+    connection.buffer.extend_from_slice(data);
+    if connection.buffer.len() > MAX_TCP_BUFFER {
+      self.connections.remove(&key);
+      return reply(tcp_segment(server, client, ack, 0, TCP_RST, &[]));
+    }
+    let mut queries = Vec::new();
+    while let [high, low, rest @ ..] = &connection.buffer[..] {
+      let len = u16::from_be_bytes([*high, *low]) as usize;
+      if rest.len() < len {
+        break;
+      }
+      queries.push(rest[..len].to_vec());
+      connection.buffer.drain(..2 + len);
+    }
+    for query in queries {
+      let Some(response) = self.answer(&query, false) else {
+        continue;
+      };
+      let outgoing = &mut self.connections.get_mut(&key).unwrap().outgoing;
+      if outgoing.len() + 2 + response.len() > MAX_TCP_OUTGOING {
+        self.stats.dropped += 1;
+        continue;
+      }
+      outgoing.extend_from_slice(&(response.len() as u16).to_be_bytes());
+      outgoing.extend_from_slice(&response);
+    }
+    let connection = self.connections.get_mut(&key).unwrap();
+    if fin {
+      connection.their_next = connection.their_next.wrapping_add(1);
+      connection.fin_received = true;
+    }
+    reply(connection.segment(server, client, true))
+  }
+}
+
+fn reply(segment: Option<Vec<u8>>) -> Verdict {
+  segment.map_or(Verdict::Drop, Verdict::Reply)
+}
+
+/// A TCP segment with the given sequence and acknowledgment numbers, if the payload fits
+fn tcp_segment(
+  source: SocketAddr,
+  destination: SocketAddr,
+  seq: u32,
+  ack: u32,
+  flags: u8,
+  payload: &[u8],
+) -> Option<Vec<u8>> {
+  let mut packet = tcp_packet(source, destination, flags, &[], payload).ok()?;
+  let at = match source {
+    SocketAddr::V4(_) => 20,
+    SocketAddr::V6(_) => 40,
+  };
+  let segment = &mut packet[at..];
+  let mut numbers = seq.to_be_bytes().to_vec();
+  numbers.extend_from_slice(&ack.to_be_bytes());
+  let checksum = u16::from_be_bytes([segment[16], segment[17]]);
+  let checksum = update_checksum(checksum, &segment[4..12], &numbers);
+  segment[4..12].copy_from_slice(&numbers);
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  Some(packet)
+}
+
+impl PacketProcessor for DnsResponder {
+  fn name(&self) -> &'static str {
+    "dns"
+  }
+  fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+    self.process(packet)
+  }
+  fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+    self.process(packet)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+  };
+
+  use super::{
+    parse_reverse_name, reverse_name, tcp_segment, DnsError, DnsMessage, DnsQuestion, DnsRecord,
+    DnsResponder, Hosts, RecordData, RecordType, ResolveError, ResponseCode,
+  };
+  use crate::{
+    tcp_packet, transport_checksum, udp_packet, Adapter, IpPacket, ManualClock, PacketPipeline,
+    PacketProcessor, PipelineSession, RingCapacity, Verdict, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST,
+    TCP_SYN,
+  };
+
+  fn hosts() -> Hosts {
+    Hosts::new()
+      .with("gateway.tunnel", "10.8.0.1".parse().unwrap())
+      .with("gateway.tunnel", "fd00::1".parse().unwrap())
+      .with_alias("www.tunnel", "gateway.tunnel")
+      .with_alias("outside.tunnel", "example.com")
+  }
+
+  /// The DNS message in a UDP or TCP reply, checking the transport checksum on the way
+  fn message(reply: &[u8]) -> DnsMessage {
+    let parsed = IpPacket::parse(reply).unwrap();
+    assert_eq!(
+      transport_checksum(
+        parsed.source(),
+        parsed.destination(),
+        parsed.protocol(),
+        parsed.payload()
+      ),
+      0
+    );
+    DnsMessage::parse(&parsed.payload()[8..]).unwrap()
+  }
+
+  #[test]
+  fn parses_compressed_responses() {
+    //A response to `www.example.com A` with a CNAME, as a recursive resolver sends it
+    let response = [
+      0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 1, //Header
+      3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0,
+      1, 0, 1, //Question
+      0xC0, 12, 0, 5, 0, 1, 0, 0, 0x0E, 0x10, 0, 6, 3, b'c', b'd', b'n', 0xC0, 16, //CNAME
+      0xC0, 45, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34, //A of cdn.example.com
+      0, 0, 41, 0x04, 0xD0, 0, 0, 0, 0, 0, 0, //EDNS allowing 1232 bytes
+    ];
+    let message = DnsMessage::parse(&response).unwrap();
+    assert_eq!(message.id, 0x1234);
+    assert!(message.response && message.recursion_available);
+    assert_eq!(message.response_code, ResponseCode::NoError);
+    assert_eq!(
+      message.questions,
+      [DnsQuestion::new("www.example.com", RecordType::A)]
+    );
+    assert_eq!(
+      message.answers,
+      [
+        DnsRecord::new(
+          "www.example.com",
+          3600,
+          RecordData::Cname("cdn.example.com".into())
+        ),
+        DnsRecord::new(
+          "cdn.example.com",
+          60,
+          RecordData::A("93.184.216.34".parse().unwrap())
+        ),
+      ]
+    );
+    assert_eq!(message.max_udp_len(), 1232);
+    //Encoding drops compression, parsing it again gives the same message
+    let encoded = message.encode().unwrap();
+    assert!(encoded.len() > response.len());
+    assert_eq!(DnsMessage::parse(&encoded).unwrap(), message);
+
+    //Pointer loops and truncated messages are rejected
+    let mut looping = response[..29].to_vec();
+    looping[12..14].copy_from_slice(&[0xC0, 12]);
+    assert!(DnsMessage::parse(&looping).is_err());
+    assert!(DnsMessage::parse(&response[..40]).is_err());
+  }
+
+  #[test]
+  fn reverse_names() {
+    for ip in ["10.8.0.1", "fd00::1:2"] {
+      let ip: IpAddr = ip.parse().unwrap();
+      assert_eq!(parse_reverse_name(&reverse_name(ip)), Some(ip));
+    }
+    assert_eq!(
+      reverse_name("10.8.0.1".parse().unwrap()),
+      "1.0.8.10.in-addr.arpa"
+    );
+    assert_eq!(parse_reverse_name("1.0.8.in-addr.arpa."), None);
+  }
+
+  #[test]
+  fn answers_from_hosts() {
+    let mut responder = DnsResponder::new("10.8.0.53".parse().unwrap(), hosts());
+    let ask = |responder: &mut DnsResponder, name: &str, record_type| {
+      responder.respond(&DnsMessage::query(7, name, record_type))
+    };
+
+    let response = ask(&mut responder, "Gateway.Tunnel.", RecordType::Aaaa);
+    assert_eq!(response.id, 7);
+    assert_eq!(
+      response.answers,
+      [DnsRecord::new(
+        "Gateway.Tunnel.",
+        60,
+        RecordData::Aaaa("fd00::1".parse().unwrap())
+      )]
+    );
+    let response = ask(&mut responder, "www.tunnel", RecordType::A);
+    assert_eq!(
+      response.answers,
+      [
+        DnsRecord::new("www.tunnel", 60, RecordData::Cname("gateway.tunnel".into())),
+        DnsRecord::new(
+          "gateway.tunnel",
+          60,
+          RecordData::A("10.8.0.1".parse().unwrap())
+        ),
+      ]
+    );
+    assert_eq!(
+      ask(&mut responder, "outside.tunnel", RecordType::A).answers,
+      [DnsRecord::new(
+        "outside.tunnel",
+        60,
+        RecordData::Cname("example.com".into())
+      )]
+    );
+    let response = ask(&mut responder, "1.0.8.10.in-addr.arpa", RecordType::Ptr);
+    assert_eq!(
+      response.answers[0].data,
+      RecordData::Ptr("gateway.tunnel".into())
+    );
+    //Known names without records of a type, and unknown names
+    let response = ask(&mut responder, "gateway.tunnel", RecordType::Other(16));
+    assert_eq!(response.response_code, ResponseCode::NoError);
+    assert!(response.answers.is_empty());
+    let response = ask(&mut responder, "missing.tunnel", RecordType::A);
+    assert_eq!(response.response_code, ResponseCode::NameError);
+    assert_eq!(responder.stats().not_found, 1);
+
+    let mut failing = DnsResponder::new(
+      "10.8.0.53".parse().unwrap(),
+      |_: &DnsQuestion| -> Result<Vec<DnsRecord>, ResolveError> { Err(ResolveError::Failed) },
+    );
+    let response = ask(&mut failing, "example.com", RecordType::A);
+    assert_eq!(response.response_code, ResponseCode::ServerFailure);
+  }
+
+  #[test]
+  fn udp_queries_through_the_session() {
+    let adapter = Adapter::create_in_memory("dns-udp", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = PipelineSession::new(
+      adapter.session(RingCapacity::min()).unwrap(),
+      PacketPipeline::new().with(DnsResponder::new("10.8.0.53".parse().unwrap(), hosts())),
+    );
+    let client: SocketAddr = "10.8.0.2:40000".parse().unwrap();
+    let server: SocketAddr = "10.8.0.53:53".parse().unwrap();
+    let query = DnsMessage::query(9, "gateway.tunnel", RecordType::A);
+    peer
+      .inject(&udp_packet(client, server, &query.encode().unwrap()).unwrap())
+      .unwrap();
+    //Other traffic reaches the session
+    let other = udp_packet(client, "10.8.0.1:53".parse().unwrap(), b"not for us").unwrap();
+    peer.inject(&other).unwrap();
+    assert_eq!(session.recv().unwrap().slice(), &other[..]);
+
+    let reply = peer.try_recv().unwrap();
+    let parsed = IpPacket::parse(&reply).unwrap();
+    assert_eq!(parsed.ports(), Some((53, 40000)));
+    let response = message(&reply);
+    assert_eq!(response.id, 9);
+    assert_eq!(
+      response.answers[0].data,
+      RecordData::A("10.8.0.1".parse().unwrap())
+    );
+
+    //Garbage with a readable header gets FORMERR
+    let mut garbage = query.encode().unwrap();
+    garbage.truncate(16);
+    peer
+      .inject(&udp_packet(client, server, &garbage).unwrap())
+      .unwrap();
+    assert!(session.recv().is_err());
+    assert_eq!(
+      message(&peer.try_recv().unwrap()).response_code,
+      ResponseCode::FormatError
+    );
+  }
+
+  #[test]
+  fn names_with_any_bytes_are_not_found() {
+    let mut query = DnsMessage::query(4, "gateway.tunnel", RecordType::A)
+      .encode()
+      .unwrap();
+    for (byte, name) in [
+      (b'\n', "g\\010teway.tunnel"),
+      (b'.', "g\\.teway.tunnel"),
+      (b'\\', "g\\\\teway.tunnel"),
+      (b' ', "g\\032teway.tunnel"),
+      (0xC3, "g\\195teway.tunnel"),
+      (b'*', "g*teway.tunnel"),
+    ] {
+      query[14] = byte;
+      let parsed = DnsMessage::parse(&query).unwrap();
+      assert_eq!(parsed.questions[0].name, name);
+      assert_eq!(parsed.encode().unwrap(), query);
+      let mut responder = DnsResponder::new("10.8.0.53".parse().unwrap(), hosts());
+      let client = "10.8.0.2:40000".parse().unwrap();
+      let mut packet = udp_packet(client, "10.8.0.53:53".parse().unwrap(), &query).unwrap();
+      let Verdict::Reply(reply) = responder.on_inbound(&mut packet) else {
+        panic!("expected an answer");
+      };
+      let response = message(&reply);
+      assert_eq!(
+        (response.id, response.response_code),
+        (4, ResponseCode::NameError)
+      );
+      assert_eq!(response.questions, parsed.questions);
+    }
+    //Queries cut short still get FORMERR
+    query.truncate(16);
+    let mut responder = DnsResponder::new("10.8.0.53".parse().unwrap(), hosts());
+    let client = "10.8.0.2:40000".parse().unwrap();
+    let mut packet = udp_packet(client, "10.8.0.53:53".parse().unwrap(), &query).unwrap();
+    let Verdict::Reply(reply) = responder.on_inbound(&mut packet) else {
+      panic!("expected an answer");
+    };
+    assert_eq!(message(&reply).response_code, ResponseCode::FormatError);
+    //Escapes have to be complete
+    for name in ["a\\", "a\\12", "a\\256"] {
+      assert_eq!(
+        DnsMessage::query(4, name, RecordType::A).encode(),
+        Err(DnsError::InvalidName)
+      );
+    }
+  }
+
+  #[test]
+  fn large_udp_answers_are_truncated() {
+    let mut hosts = Hosts::new();
+    for host in 1..=40 {
+      hosts.insert(
+        "many.tunnel",
+        IpAddr::from([0xfd00, 0, 0, 0, 0, 0, 0, host]),
+      );
+    }
+    let mut responder = DnsResponder::new("fd00::53".parse().unwrap(), hosts);
+    let client: SocketAddr = "[fd00::2]:40000".parse().unwrap();
+    let server: SocketAddr = "[fd00::53]:53".parse().unwrap();
+    let mut query = DnsMessage::query(1, "many.tunnel", RecordType::Aaaa);
+    let mut packet = udp_packet(client, server, &query.encode().unwrap()).unwrap();
+    let Verdict::Reply(reply) = responder.on_inbound(&mut packet) else {
+      panic!("expected an answer");
+    };
+    let response = message(&reply);
+    assert!(response.truncated && response.answers.is_empty());
+
+    //EDNS raises the limit
+    query.additionals.push(DnsRecord {
+      name: String::new(),
+      class: 4096,
+      ttl: 0,
+      data: RecordData::Other(41, Vec::new()),
+    });
+    let mut packet = udp_packet(client, server, &query.encode().unwrap()).unwrap();
+    let Verdict::Reply(reply) = responder.on_inbound(&mut packet) else {
+      panic!("expected an answer");
+    };
+    assert_eq!(message(&reply).answers.len(), 40);
+    assert_eq!(responder.stats().truncated, 1);
+  }
+
+  #[test]
+  fn tcp_queries() {
+    let mut responder = DnsResponder::new("10.8.0.53".parse().unwrap(), hosts());
+    let client: SocketAddr = "10.8.0.2:40000".parse().unwrap();
+    let server: SocketAddr = "10.8.0.53:53".parse().unwrap();
+    let mut send = |seq: u32, ack: u32, flags: u8, payload: &[u8]| {
+      let mut packet = tcp_segment(client, server, seq, ack, flags, payload).unwrap();
+      match responder.on_inbound(&mut packet) {
+        Verdict::Reply(reply) => {
+          let parsed = IpPacket::parse(&reply).unwrap();
+          assert_eq!(
+            transport_checksum(
+              parsed.source(),
+              parsed.destination(),
+              parsed.protocol(),
+              parsed.payload()
+            ),
+            0
+          );
+          let segment = parsed.payload();
+          let number = |at: usize| u32::from_be_bytes(segment[at..at + 4].try_into().unwrap());
+          Some((number(4), number(8), segment[13], segment[20..].to_vec()))
+        }
+        Verdict::Drop => None,
+        verdict => panic!("unexpected {verdict:?}"),
+      }
+    };
+
+    let (isn, ack, flags, _) = send(1000, 0, TCP_SYN, &[]).unwrap();
+    assert_eq!((ack, flags), (1001, TCP_SYN | TCP_ACK));
+    assert_eq!(send(1001, isn + 1, TCP_ACK, &[]), None);
+    //A query split over two segments
+    let query = DnsMessage::query(5, "www.tunnel", RecordType::A)
+      .encode()
+      .unwrap();
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&query);
+    let (seq, ack, flags, data) = send(1001, isn + 1, TCP_ACK, &framed[..10]).unwrap();
+    assert_eq!((seq, ack, flags), (isn + 1, 1011, TCP_ACK));
+    assert!(data.is_empty());
+    let end = 1001 + framed.len() as u32;
+    let (seq, ack, flags, data) = send(1011, isn + 1, TCP_ACK | TCP_PSH, &framed[10..]).unwrap();
+    assert_eq!((seq, ack, flags), (isn + 1, end, TCP_ACK | TCP_PSH));
+    let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    assert_eq!(len, data.len() - 2);
+    let response = DnsMessage::parse(&data[2..]).unwrap();
+    assert_eq!((response.id, response.answers.len()), (5, 2));
+
+    //Closing
+    let answered = isn + 1 + data.len() as u32;
+    let (seq, ack, flags, _) = send(end, answered, TCP_FIN | TCP_ACK, &[]).unwrap();
+    assert_eq!((seq, ack, flags), (answered, end + 1, TCP_FIN | TCP_ACK));
+    assert_eq!(send(end + 1, answered + 1, TCP_ACK, &[]), None);
+    //Unknown connections are reset
+    let (_, _, flags, _) = send(end + 1, answered + 1, TCP_ACK, b"x").unwrap();
+    assert_eq!(flags, TCP_RST);
+  }
+
+  #[test]
+  fn large_tcp_answers_are_split() {
+    let mut responder = DnsResponder::new("10.8.0.53".parse().unwrap(), hosts());
+    let client: SocketAddr = "10.8.0.2:40000".parse().unwrap();
+    let server: SocketAddr = "10.8.0.53:53".parse().unwrap();
+    //Unknown names, so the answer repeats the questions and is as long as the query
+    let mut query = DnsMessage::query(11, "missing.tunnel", RecordType::A);
+    query.questions = vec![query.questions[0].clone(); 3240];
+    let query = query.encode().unwrap();
+    assert!(query.len() > u16::MAX as usize - 1000);
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&query);
+    let segment = |reply: &[u8]| {
+      let parsed = IpPacket::parse(reply).unwrap();
+      let segment = parsed.payload();
+      let seq = u32::from_be_bytes(segment[4..8].try_into().unwrap());
+      (seq, segment[13], segment[20..].to_vec())
+    };
+
+    //An MSS of 1400
+    let mut syn = tcp_packet(client, server, TCP_SYN, &[2, 4, 0x05, 0x78], &[]).unwrap();
+    let Verdict::Reply(reply) = responder.on_inbound(&mut syn) else {
+      panic!("expected a SYN-ACK");
+    };
+    let (isn, _, _) = segment(&reply);
+    let mut seq = 1;
+    let mut verdict = Verdict::Drop;
+    for chunk in framed.chunks(1400) {
+      let mut packet = tcp_segment(client, server, seq, isn + 1, TCP_ACK, chunk).unwrap();
+      seq += chunk.len() as u32;
+      verdict = responder.on_inbound(&mut packet);
+    }
+    //Each acknowledgment releases the next segment of the answer
+    let mut answer = Vec::new();
+    while let Verdict::Reply(reply) = verdict {
+      let (their_seq, flags, data) = segment(&reply);
+      assert_eq!(flags, TCP_ACK | TCP_PSH);
+      assert_eq!(their_seq, isn + 1 + answer.len() as u32);
+      assert!(!data.is_empty() && data.len() <= 1400);
+      answer.extend_from_slice(&data);
+      let acked = their_seq + data.len() as u32;
+      let mut ack = tcp_segment(client, server, seq, acked, TCP_ACK, &[]).unwrap();
+      verdict = responder.on_inbound(&mut ack);
+    }
+    assert!(answer.len() > u16::MAX as usize - 1000);
+    assert_eq!(
+      u16::from_be_bytes([answer[0], answer[1]]) as usize,
+      answer.len() - 2
+    );
+    let response = DnsMessage::parse(&answer[2..]).unwrap();
+    assert_eq!((response.id, response.questions.len()), (11, 3240));
+    assert_eq!(response.response_code, ResponseCode::NameError);
+    assert_eq!(responder.stats().dropped, 0);
+  }
+
+  #[test]
+  fn tcp_retransmissions_and_idle_connections() {
+    let clock = ManualClock::new();
+    let mut responder = DnsResponder::new("fd00::53".parse().unwrap(), hosts())
+      .with_clock(Arc::new(clock.clone()))
+      .with_tcp_timeout(Duration::from_secs(30));
+    let client: SocketAddr = "[fd00::2]:40000".parse().unwrap();
+    let server: SocketAddr = "[fd00::53]:53".parse().unwrap();
+    let send = |responder: &mut DnsResponder, seq: u32, ack: u32, flags: u8, data: &[u8]| {
+      let mut packet = tcp_segment(client, server, seq, ack, flags, data).unwrap();
+      match responder.on_inbound(&mut packet) {
+        Verdict::Reply(reply) => {
+          let parsed = IpPacket::parse(&reply).unwrap();
+          let segment = parsed.payload();
+          let number = |at: usize| u32::from_be_bytes(segment[at..at + 4].try_into().unwrap());
+          Some((number(4), number(8), segment[13], segment[20..].to_vec()))
+        }
+        _ => None,
+      }
+    };
+    let query = DnsMessage::query(6, "www.tunnel", RecordType::Aaaa)
+      .encode()
+      .unwrap();
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(&query);
+    let end = 1001 + framed.len() as u32;
+
+    let (isn, _, _, _) = send(&mut responder, 1000, 0, TCP_SYN, &[]).unwrap();
+    let answer = send(&mut responder, 1001, isn + 1, TCP_ACK, &framed).unwrap();
+    assert_eq!(answer.1, end);
+    assert_eq!(answer.2, TCP_ACK | TCP_PSH);
+    //A duplicate acknowledgment and the query sent again both get the answer again, which is
+    //only resolved once
+    assert_eq!(
+      send(&mut responder, end, isn + 1, TCP_ACK, &[]),
+      Some(answer.clone())
+    );
+    assert_eq!(
+      send(&mut responder, 1001, isn + 1, TCP_ACK, &framed),
+      Some(answer.clone())
+    );
+    assert_eq!(responder.stats().queries, 1);
+    let answered = isn + 1 + answer.3.len() as u32;
+    assert_eq!(send(&mut responder, end, answered, TCP_ACK, &[]), None);
+    //With nothing in flight a retransmission gets a bare ACK
+    assert_eq!(
+      send(&mut responder, 1001, answered, TCP_ACK, &framed),
+      Some((answered, end, TCP_ACK, Vec::new()))
+    );
+
+    //Each segment keeps the connection alive
+    clock.advance(Duration::from_secs(29));
+    assert_eq!(send(&mut responder, end, answered, TCP_ACK, &[]), None);
+    clock.advance(Duration::from_secs(29));
+    assert_eq!(responder.expire(), 0);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(responder.expire(), 1);
+    assert_eq!(responder.stats().expired, 1);
+    let (_, _, flags, _) = send(&mut responder, end, answered, TCP_ACK, &framed).unwrap();
+    assert_eq!(flags, TCP_RST);
+  }
+}
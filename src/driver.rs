@@ -0,0 +1,304 @@
+//! Driver version handling and the checks applications run at startup before creating adapters
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+  DriverPolicyError, DriverVersionParseError, GetRunningDriverVersionError, WintunError,
+  WintunResult,
+};
+
+/// Version of the running driver. Ordered by `major`, then `minor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DriverVersion {
+  pub major: u16,
+  pub minor: u16,
+}
+
+impl DriverVersion {
+  pub fn new(major: u16, minor: u16) -> Self {
+    Self { major, minor }
+  }
+  /// Decodes the `major << 16 | minor` layout returned by `WintunGetRunningDriverVersion`
+  pub fn from_raw(raw: u32) -> Self {
+    Self {
+      major: (raw >> 16) as u16,
+      minor: raw as u16,
+    }
+  }
+  pub fn to_raw(self) -> u32 {
+    ((self.major as u32) << 16) | self.minor as u32
+  }
+}
+
+impl fmt::Display for DriverVersion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!("{}.{}", self.major, self.minor))
+  }
+}
+
+impl FromStr for DriverVersion {
+  type Err = DriverVersionParseError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (major, minor) = s
+      .split_once('.')
+      .ok_or(DriverVersionParseError::InvalidFormat)?;
+    let component = |part: &str| {
+      if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(DriverVersionParseError::InvalidNumber);
+      }
+      part
+        .parse::<u16>()
+        .map_err(|_| DriverVersionParseError::InvalidNumber)
+    };
+    Ok(Self {
+      major: component(major)?,
+      minor: component(minor)?,
+    })
+  }
+}
+
+/// What a [`DriverPolicy`] found and did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverCheck {
+  /// The running driver satisfies the requirement, nothing was done
+  Satisfied { running: DriverVersion },
+  /// No driver is running. The bundled driver is installed when the first adapter is created
+  NotLoaded,
+  /// An older driver was removed, the bundled driver is installed when the next adapter is
+  /// created
+  Removed { previous: DriverVersion },
+  /// An older driver could not be removed and keeps running. Only reported by
+  /// [`DriverPolicy::reinstall_if_older`], the other policies fail instead
+  KeptOlder {
+    running: DriverVersion,
+    error: WintunError,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PolicyKind {
+  RequireAtLeast,
+  UpgradeOrFail,
+  ReinstallIfOlder,
+}
+
+type Probe = Box<dyn Fn() -> Result<DriverVersion, GetRunningDriverVersionError>>;
+type Deleter = Box<dyn FnMut() -> WintunResult<()>>;
+
+/// Decides what to do about the running driver at startup, see the constructors for the
+/// available policies
+pub struct DriverPolicy {
+  kind: PolicyKind,
+  required: DriverVersion,
+  probe: Probe,
+  deleter: Deleter,
+}
+
+impl DriverPolicy {
+  /// Fails if the running driver is older than `required`. Never changes anything
+  pub fn require_at_least(required: DriverVersion) -> Self {
+    Self::new(PolicyKind::RequireAtLeast, required)
+  }
+  /// Removes a running driver older than `required` so the bundled one gets installed, and fails
+  /// if it can't be removed
+  pub fn upgrade_or_fail(required: DriverVersion) -> Self {
+    Self::new(PolicyKind::UpgradeOrFail, required)
+  }
+  /// Like [`upgrade_or_fail`](Self::upgrade_or_fail) but keeps running with the older driver if it
+  /// can't be removed, reporting [`DriverCheck::KeptOlder`]
+  pub fn reinstall_if_older(required: DriverVersion) -> Self {
+    Self::new(PolicyKind::ReinstallIfOlder, required)
+  }
+  fn new(kind: PolicyKind, required: DriverVersion) -> Self {
+    Self {
+      kind,
+      required,
+      probe: Box::new(crate::get_running_driver_version),
+      deleter: Box::new(crate::delete_driver),
+    }
+  }
+  /// Replaces the query of the running version,
+  /// [`get_running_driver_version`](crate::get_running_driver_version) by default
+  pub fn with_probe(
+    mut self,
+    probe: impl Fn() -> Result<DriverVersion, GetRunningDriverVersionError> + 'static,
+  ) -> Self {
+    self.probe = Box::new(probe);
+    self
+  }
+  /// Replaces the removal of the running driver, [`delete_driver`](crate::delete_driver) by
+  /// default
+  pub fn with_deleter(mut self, deleter: impl FnMut() -> WintunResult<()> + 'static) -> Self {
+    self.deleter = Box::new(deleter);
+    self
+  }
+  pub fn required(&self) -> DriverVersion {
+    self.required
+  }
+  pub fn run(mut self) -> Result<DriverCheck, DriverPolicyError> {
+    let running = match (self.probe)() {
+      Ok(running) => running,
+      Err(GetRunningDriverVersionError::WintunNotLoaded) => return Ok(DriverCheck::NotLoaded),
+      Err(GetRunningDriverVersionError::Other(err)) => return Err(DriverPolicyError::Probe(err)),
+    };
+    if running >= self.required {
+      return Ok(DriverCheck::Satisfied { running });
+    }
+    if self.kind == PolicyKind::RequireAtLeast {
+      return Err(DriverPolicyError::TooOld {
+        running,
+        required: self.required,
+      });
+    }
+    match (self.deleter)() {
+      Ok(()) => Ok(DriverCheck::Removed { previous: running }),
+      Err(error) if self.kind == PolicyKind::ReinstallIfOlder => {
+        Ok(DriverCheck::KeptOlder { running, error })
+      }
+      Err(error) => Err(DriverPolicyError::RemoveFailed { running, error }),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::Cell, rc::Rc};
+
+  use crate::{
+    DriverPolicyError, DriverVersionParseError, GetRunningDriverVersionError, WintunError,
+  };
+
+  use super::{DriverCheck, DriverPolicy, DriverVersion};
+
+  #[test]
+  fn decode_and_format() {
+    let version = DriverVersion::from_raw(0x000E_0003);
+    assert_eq!(version, DriverVersion::new(14, 3));
+    assert_eq!(version.to_raw(), 0x000E_0003);
+    assert_eq!(version.to_string(), "14.3");
+    assert_eq!("14.3".parse(), Ok(version));
+    assert_eq!("1.6".parse(), Ok(DriverVersion::new(1, 6)));
+  }
+
+  #[test]
+  fn reject_malformed_versions() {
+    assert_eq!(
+      "14".parse::<DriverVersion>(),
+      Err(DriverVersionParseError::InvalidFormat)
+    );
+    for input in ["14.", ".3", "1.2.3", "+1.2", "65536.0", "a.b"] {
+      assert_eq!(
+        input.parse::<DriverVersion>(),
+        Err(DriverVersionParseError::InvalidNumber),
+        "{input}"
+      );
+    }
+  }
+
+  #[test]
+  fn order_by_major_then_minor() {
+    assert!(DriverVersion::new(0, 14) < DriverVersion::new(1, 0));
+    assert!(DriverVersion::new(1, 2) < DriverVersion::new(1, 10));
+    assert!(DriverVersion::from_raw(0x0001_0000) > DriverVersion::from_raw(0x0000_FFFF));
+  }
+
+  fn running(
+    version: DriverVersion,
+  ) -> impl Fn() -> Result<DriverVersion, GetRunningDriverVersionError> {
+    move || Ok(version)
+  }
+
+  #[test]
+  fn require_at_least() {
+    let required = DriverVersion::new(0, 14);
+    let check = DriverPolicy::require_at_least(required)
+      .with_probe(running(DriverVersion::new(0, 14)))
+      .with_deleter(|| unreachable!())
+      .run();
+    assert_eq!(
+      check,
+      Ok(DriverCheck::Satisfied {
+        running: DriverVersion::new(0, 14)
+      })
+    );
+    let check = DriverPolicy::require_at_least(required)
+      .with_probe(running(DriverVersion::new(0, 13)))
+      .with_deleter(|| unreachable!())
+      .run();
+    assert_eq!(
+      check,
+      Err(DriverPolicyError::TooOld {
+        running: DriverVersion::new(0, 13),
+        required
+      })
+    );
+    let check = DriverPolicy::require_at_least(required)
+      .with_probe(|| Err(GetRunningDriverVersionError::WintunNotLoaded))
+      .run();
+    assert_eq!(check, Ok(DriverCheck::NotLoaded));
+  }
+
+  #[test]
+  fn upgrade_or_fail() {
+    let deleted = Rc::new(Cell::new(false));
+    let flag = deleted.clone();
+    let check = DriverPolicy::upgrade_or_fail(DriverVersion::new(1, 0))
+      .with_probe(running(DriverVersion::new(0, 14)))
+      .with_deleter(move || {
+        flag.set(true);
+        Ok(())
+      })
+      .run();
+    assert!(deleted.get());
+    assert_eq!(
+      check,
+      Ok(DriverCheck::Removed {
+        previous: DriverVersion::new(0, 14)
+      })
+    );
+    let check = DriverPolicy::upgrade_or_fail(DriverVersion::new(1, 0))
+      .with_probe(running(DriverVersion::new(0, 14)))
+      .with_deleter(|| Err(WintunError::WouldBlock))
+      .run();
+    assert_eq!(
+      check,
+      Err(DriverPolicyError::RemoveFailed {
+        running: DriverVersion::new(0, 14),
+        error: WintunError::WouldBlock
+      })
+    );
+  }
+
+  #[test]
+  fn reinstall_if_older() {
+    let check = DriverPolicy::reinstall_if_older(DriverVersion::new(1, 0))
+      .with_probe(running(DriverVersion::new(0, 14)))
+      .with_deleter(|| Err(WintunError::WouldBlock))
+      .run();
+    assert_eq!(
+      check,
+      Ok(DriverCheck::KeptOlder {
+        running: DriverVersion::new(0, 14),
+        error: WintunError::WouldBlock
+      })
+    );
+    let check = DriverPolicy::reinstall_if_older(DriverVersion::new(1, 0))
+      .with_probe(running(DriverVersion::new(1, 1)))
+      .with_deleter(|| unreachable!())
+      .run();
+    assert_eq!(
+      check,
+      Ok(DriverCheck::Satisfied {
+        running: DriverVersion::new(1, 1)
+      })
+    );
+  }
+
+  #[test]
+  fn running_driver_version() {
+    match crate::get_running_driver_version() {
+      Ok(version) => assert_eq!(DriverVersion::from_raw(version.to_raw()), version),
+      Err(err) => assert!(err.is_not_loaded()),
+    }
+  }
+}
@@ -0,0 +1,75 @@
+/// An `errno` value returned by a failed system call
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Errno(i32);
+
+impl Errno {
+  pub fn new(code: i32) -> Self {
+    Self(code)
+  }
+  pub fn code(self) -> i32 {
+    self.0
+  }
+  /// Symbolic name of the code, `None` for codes the crate does not know
+  pub fn name(self) -> Option<&'static str> {
+    ERRNO_NAMES
+      .iter()
+      .find(|(code, _)| *code == self.0)
+      .map(|(_, name)| *name)
+  }
+  /// Captures `errno` of the calling thread
+  pub fn get_last_error() -> Self {
+    Self(std::io::Error::last_os_error().raw_os_error().unwrap_or(0))
+  }
+}
+
+const ERRNO_NAMES: &[(i32, &str)] = &[
+  (libc::EPERM, "EPERM"),
+  (libc::ENOENT, "ENOENT"),
+  (libc::ESRCH, "ESRCH"),
+  (libc::EINTR, "EINTR"),
+  (libc::EIO, "EIO"),
+  (libc::ENXIO, "ENXIO"),
+  (libc::EBADF, "EBADF"),
+  (libc::EAGAIN, "EAGAIN"),
+  (libc::ENOMEM, "ENOMEM"),
+  (libc::EACCES, "EACCES"),
+  (libc::EFAULT, "EFAULT"),
+  (libc::EBUSY, "EBUSY"),
+  (libc::EEXIST, "EEXIST"),
+  (libc::ENODEV, "ENODEV"),
+  (libc::EINVAL, "EINVAL"),
+  (libc::ENFILE, "ENFILE"),
+  (libc::EMFILE, "EMFILE"),
+  (libc::ENOSPC, "ENOSPC"),
+  (libc::EPIPE, "EPIPE"),
+  (libc::ERANGE, "ERANGE"),
+  (libc::ENOSYS, "ENOSYS"),
+  (libc::ENOBUFS, "ENOBUFS"),
+  (libc::EOPNOTSUPP, "EOPNOTSUPP"),
+  (libc::EADDRINUSE, "EADDRINUSE"),
+  (libc::EADDRNOTAVAIL, "EADDRNOTAVAIL"),
+  (libc::ENETDOWN, "ENETDOWN"),
+  (libc::ENETUNREACH, "ENETUNREACH"),
+  (libc::ETIMEDOUT, "ETIMEDOUT"),
+  (libc::EHOSTUNREACH, "EHOSTUNREACH"),
+];
+
+impl std::fmt::Debug for Errno {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_fmt(format_args!("Errno({}): {self}", self.0))
+  }
+}
+
+impl std::fmt::Display for Errno {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    std::fmt::Display::fmt(&std::io::Error::from_raw_os_error(self.0), f)
+  }
+}
+
+impl std::error::Error for Errno {}
+
+impl From<Errno> for std::io::Error {
+  fn from(value: Errno) -> Self {
+    std::io::Error::from_raw_os_error(value.0)
+  }
+}
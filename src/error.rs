@@ -0,0 +1,640 @@
+use std::{error::Error, fmt, io};
+
+use crate::{DriverVersion, MAX_IP_PACKET_SIZE, MAX_RING_CAPACITY, MIN_RING_CAPACITY};
+
+/// Operating system error carried by the `Other` variants of the error types. This is the
+/// Win32 error code on Windows and the `errno` value on Linux
+#[cfg(windows)]
+pub type OsError = get_last_error::Win32Error;
+/// Operating system error carried by the `Other` variants of the error types. This is the
+/// Win32 error code on Windows and the `errno` value on Linux
+#[cfg(not(windows))]
+pub type OsError = crate::Errno;
+
+#[cfg(windows)]
+fn os_error_code(err: OsError) -> i64 {
+  err.code() as i64
+}
+#[cfg(windows)]
+fn os_error_name(err: OsError) -> Option<&'static str> {
+  win32_error_name(err.code())
+}
+#[cfg(windows)]
+fn os_error_kind(err: OsError) -> io::ErrorKind {
+  win32_error_kind(err.code())
+}
+#[cfg(not(windows))]
+fn os_error_code(err: OsError) -> i64 {
+  err.code() as i64
+}
+#[cfg(not(windows))]
+fn os_error_name(err: OsError) -> Option<&'static str> {
+  err.name()
+}
+#[cfg(not(windows))]
+fn os_error_kind(err: OsError) -> io::ErrorKind {
+  io::Error::from_raw_os_error(err.code()).kind()
+}
+
+/// Win32 error codes the crate's calls commonly fail with, their symbolic names and the
+/// [`io::ErrorKind`] they map to
+const WIN32_ERRORS: &[(u32, &str, io::ErrorKind)] = &[
+  (0, "ERROR_SUCCESS", io::ErrorKind::Other),
+  (1, "ERROR_INVALID_FUNCTION", io::ErrorKind::Unsupported),
+  (2, "ERROR_FILE_NOT_FOUND", io::ErrorKind::NotFound),
+  (3, "ERROR_PATH_NOT_FOUND", io::ErrorKind::NotFound),
+  (5, "ERROR_ACCESS_DENIED", io::ErrorKind::PermissionDenied),
+  (6, "ERROR_INVALID_HANDLE", io::ErrorKind::InvalidInput),
+  (8, "ERROR_NOT_ENOUGH_MEMORY", io::ErrorKind::OutOfMemory),
+  (13, "ERROR_INVALID_DATA", io::ErrorKind::InvalidData),
+  (14, "ERROR_OUTOFMEMORY", io::ErrorKind::OutOfMemory),
+  (31, "ERROR_GEN_FAILURE", io::ErrorKind::Other),
+  (32, "ERROR_SHARING_VIOLATION", io::ErrorKind::ResourceBusy),
+  (38, "ERROR_HANDLE_EOF", io::ErrorKind::UnexpectedEof),
+  (50, "ERROR_NOT_SUPPORTED", io::ErrorKind::Unsupported),
+  (55, "ERROR_DEV_NOT_EXIST", io::ErrorKind::NotFound),
+  (80, "ERROR_FILE_EXISTS", io::ErrorKind::AlreadyExists),
+  (87, "ERROR_INVALID_PARAMETER", io::ErrorKind::InvalidInput),
+  (109, "ERROR_BROKEN_PIPE", io::ErrorKind::BrokenPipe),
+  (111, "ERROR_BUFFER_OVERFLOW", io::ErrorKind::Other),
+  (
+    120,
+    "ERROR_CALL_NOT_IMPLEMENTED",
+    io::ErrorKind::Unsupported,
+  ),
+  (122, "ERROR_INSUFFICIENT_BUFFER", io::ErrorKind::Other),
+  (123, "ERROR_INVALID_NAME", io::ErrorKind::InvalidInput),
+  (126, "ERROR_MOD_NOT_FOUND", io::ErrorKind::NotFound),
+  (127, "ERROR_PROC_NOT_FOUND", io::ErrorKind::NotFound),
+  (183, "ERROR_ALREADY_EXISTS", io::ErrorKind::AlreadyExists),
+  (232, "ERROR_NO_DATA", io::ErrorKind::Other),
+  (234, "ERROR_MORE_DATA", io::ErrorKind::Other),
+  (258, "WAIT_TIMEOUT", io::ErrorKind::TimedOut),
+  //Returned by WintunReceivePacket when the ring is empty
+  (259, "ERROR_NO_MORE_ITEMS", io::ErrorKind::WouldBlock),
+  (995, "ERROR_OPERATION_ABORTED", io::ErrorKind::Interrupted),
+  (
+    1060,
+    "ERROR_SERVICE_DOES_NOT_EXIST",
+    io::ErrorKind::NotFound,
+  ),
+  (1168, "ERROR_NOT_FOUND", io::ErrorKind::NotFound),
+  (1223, "ERROR_CANCELLED", io::ErrorKind::Interrupted),
+  (
+    1231,
+    "ERROR_NETWORK_UNREACHABLE",
+    io::ErrorKind::NetworkUnreachable,
+  ),
+  (
+    1232,
+    "ERROR_HOST_UNREACHABLE",
+    io::ErrorKind::HostUnreachable,
+  ),
+  (1460, "ERROR_TIMEOUT", io::ErrorKind::TimedOut),
+  (
+    5010,
+    "ERROR_OBJECT_ALREADY_EXISTS",
+    io::ErrorKind::AlreadyExists,
+  ),
+];
+
+/// Symbolic name of a Win32 error code, `None` for codes the crate does not know
+pub fn win32_error_name(code: u32) -> Option<&'static str> {
+  WIN32_ERRORS
+    .iter()
+    .find(|(known, _, _)| *known == code)
+    .map(|(_, name, _)| *name)
+}
+
+/// The [`io::ErrorKind`] closest to a Win32 error code, [`io::ErrorKind::Other`] for codes the
+/// crate does not know
+pub fn win32_error_kind(code: u32) -> io::ErrorKind {
+  WIN32_ERRORS
+    .iter()
+    .find(|(known, _, _)| *known == code)
+    .map_or(io::ErrorKind::Other, |(_, _, kind)| *kind)
+}
+
+/// The call that failed, carried by [`WintunError::Context`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+  CreateAdapter,
+  OpenAdapter,
+  ReopenAdapter,
+  StartSession,
+  SetIpAddress,
+  SetMtu,
+  AddRoute,
+  SetDnsServers,
+  GetLuid,
+  GetGuid,
+  GetAdapterIndex,
+  DeleteDriver,
+  ListAdapters,
+  RemoveAdapter,
+  ClaimAdapter,
+}
+
+impl fmt::Display for Operation {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Operation::CreateAdapter => "create adapter",
+      Operation::OpenAdapter => "open adapter",
+      Operation::ReopenAdapter => "reopen adapter",
+      Operation::StartSession => "start session",
+      Operation::SetIpAddress => "set ip address",
+      Operation::SetMtu => "set mtu",
+      Operation::AddRoute => "add route",
+      Operation::SetDnsServers => "set dns servers",
+      Operation::GetLuid => "get luid",
+      Operation::GetGuid => "get guid",
+      Operation::GetAdapterIndex => "get adapter index",
+      Operation::DeleteDriver => "delete driver",
+      Operation::ListAdapters => "list adapters",
+      Operation::RemoveAdapter => "remove adapter",
+      Operation::ClaimAdapter => "claim adapter",
+    })
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GetRunningDriverVersionError {
+  WintunNotLoaded,
+  Other(OsError),
+}
+
+impl GetRunningDriverVersionError {
+  pub fn is_not_loaded(self) -> bool {
+    matches!(self, Self::WintunNotLoaded)
+  }
+}
+
+impl fmt::Display for GetRunningDriverVersionError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(&WintunError::from(*self), f)
+  }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceivePacketError {
+  AdapterIsTerminating,
+  WouldBlock,
+  InvalidData,
+  Other(OsError),
+}
+
+impl ReceivePacketError {
+  pub fn is_adapter_terminating(self) -> bool {
+    matches!(self, Self::AdapterIsTerminating)
+  }
+  pub fn is_would_block(self) -> bool {
+    matches!(self, Self::WouldBlock)
+  }
+  pub fn is_invalid_data(self) -> bool {
+    matches!(self, Self::InvalidData)
+  }
+}
+
+impl fmt::Display for ReceivePacketError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(&WintunError::from(*self), f)
+  }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocatePacketError {
+  AdapterIsTerminating,
+  WouldBlock,
+  Other(OsError),
+}
+
+impl AllocatePacketError {
+  pub fn is_adapter_terminating(self) -> bool {
+    matches!(self, Self::AdapterIsTerminating)
+  }
+  pub fn is_would_block(self) -> bool {
+    matches!(self, Self::WouldBlock)
+  }
+}
+
+impl fmt::Display for AllocatePacketError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(&WintunError::from(*self), f)
+  }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingCapacityError {
+  InvalidInput,
+}
+
+impl fmt::Display for RingCapacityError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Invalid ring capacity")
+  }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpPacketSizeError {
+  InvalidInput,
+}
+
+impl fmt::Display for IpPacketSizeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Invalid ip packet size")
+  }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpMaskPrefixError {
+  InvalidInput,
+}
+
+impl fmt::Display for IpMaskPrefixError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Invalid ip mask prefix. It should be in range 0..=32 or 0..=128 for Ipv4 and Ipv6 respectively")
+  }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverVersionParseError {
+  /// The version is not of the form `major.minor`
+  InvalidFormat,
+  /// A component is not a number or does not fit into 16 bits
+  InvalidNumber,
+}
+
+impl fmt::Display for DriverVersionParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DriverVersionParseError::InvalidFormat => {
+        f.write_str("Driver version should be of the form major.minor")
+      }
+      DriverVersionParseError::InvalidNumber => {
+        f.write_str("Driver version components should be in range 0..=65535")
+      }
+    }
+  }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverPolicyError {
+  /// The running driver is older than required and the policy does not replace it
+  TooOld {
+    running: DriverVersion,
+    required: DriverVersion,
+  },
+  /// The running driver is older than required and removing it failed
+  RemoveFailed {
+    running: DriverVersion,
+    error: WintunError,
+  },
+  /// The running driver version could not be queried
+  Probe(OsError),
+}
+
+impl fmt::Display for DriverPolicyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DriverPolicyError::TooOld { running, required } => f.write_fmt(format_args!(
+        "Running driver {running} is older than the required {required}"
+      )),
+      DriverPolicyError::RemoveFailed { running, .. } => {
+        f.write_fmt(format_args!("Failed to remove outdated driver {running}"))
+      }
+      DriverPolicyError::Probe(_) => f.write_str("Failed to query running driver version"),
+    }?;
+    write_source(self, f)
+  }
+}
+
+/// Error returned by most of the crate's operations.
+///
+/// Errors of adapter operations are wrapped in [`Context`](Self::Context) naming the operation
+/// and the adapter, [`root`](Self::root) gets to the underlying error. `Display` prints the
+/// outermost error only, the alternate form (`{:#}`) appends the whole
+/// [`source`](std::error::Error::source) chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WintunError {
+  TooLongName {
+    max: usize,
+    got: usize,
+  },
+  ContainsNull(usize),
+  InvalidRingCapacity,
+  InvalidPacketSize,
+  InvalidIpMaskPrefix,
+  WintunNotLoaded,
+  AdapterIsTerminating,
+  WouldBlock,
+  InvalidData,
+  InterfaceNotFound,
+  Other(OsError),
+  /// `source` occurred while performing `operation` on the adapter named `adapter`
+  Context {
+    operation: Operation,
+    adapter: Option<String>,
+    source: Box<WintunError>,
+  },
+}
+
+impl WintunError {
+  /// Wraps the error into [`Context`](Self::Context)
+  pub fn context(self, operation: Operation, adapter: Option<&str>) -> Self {
+    Self::Context {
+      operation,
+      adapter: adapter.map(str::to_owned),
+      source: Box::new(self),
+    }
+  }
+  /// The innermost error, below every [`Context`](Self::Context)
+  pub fn root(&self) -> &WintunError {
+    let mut error = self;
+    while let Self::Context { source, .. } = error {
+      error = source;
+    }
+    error
+  }
+  /// The outermost operation the error is annotated with
+  pub fn operation(&self) -> Option<Operation> {
+    match self {
+      Self::Context { operation, .. } => Some(*operation),
+      _ => None,
+    }
+  }
+  /// The first adapter name found in the context chain
+  pub fn adapter_name(&self) -> Option<&str> {
+    let mut error = self;
+    while let Self::Context {
+      adapter, source, ..
+    } = error
+    {
+      if let Some(adapter) = adapter {
+        return Some(adapter);
+      }
+      error = source;
+    }
+    None
+  }
+  /// The operating system error at the root of the chain, if any
+  pub fn os_error(&self) -> Option<OsError> {
+    match self.root() {
+      Self::Other(err) => Some(*err),
+      _ => None,
+    }
+  }
+  pub fn is_would_block(&self) -> bool {
+    matches!(self.root(), Self::WouldBlock)
+  }
+  pub fn is_adapter_terminating(&self) -> bool {
+    matches!(self.root(), Self::AdapterIsTerminating)
+  }
+  pub fn kind(&self) -> io::ErrorKind {
+    match self.root() {
+      Self::TooLongName { .. }
+      | Self::ContainsNull(_)
+      | Self::InvalidRingCapacity
+      | Self::InvalidPacketSize
+      | Self::InvalidIpMaskPrefix => io::ErrorKind::InvalidInput,
+      Self::WintunNotLoaded | Self::InterfaceNotFound => io::ErrorKind::NotFound,
+      Self::AdapterIsTerminating => io::ErrorKind::BrokenPipe,
+      Self::WouldBlock => io::ErrorKind::WouldBlock,
+      Self::InvalidData => io::ErrorKind::InvalidData,
+      Self::Other(err) => os_error_kind(*err),
+      Self::Context { .. } => unreachable!("root is never a context"),
+    }
+  }
+}
+
+/// Appends `: source` for every error of the chain when formatting with `{:#}`
+fn write_source(error: &dyn Error, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+  if !f.alternate() {
+    return Ok(());
+  }
+  let mut source = error.source();
+  while let Some(error) = source {
+    f.write_fmt(format_args!(": {error}"))?;
+    source = error.source();
+  }
+  Ok(())
+}
+
+impl From<GetRunningDriverVersionError> for WintunError {
+  fn from(value: GetRunningDriverVersionError) -> Self {
+    match value {
+      GetRunningDriverVersionError::WintunNotLoaded => Self::WintunNotLoaded,
+      GetRunningDriverVersionError::Other(err) => Self::Other(err),
+    }
+  }
+}
+
+impl From<ReceivePacketError> for WintunError {
+  fn from(value: ReceivePacketError) -> Self {
+    match value {
+      ReceivePacketError::AdapterIsTerminating => Self::AdapterIsTerminating,
+      ReceivePacketError::InvalidData => Self::InvalidData,
+      ReceivePacketError::WouldBlock => Self::WouldBlock,
+      ReceivePacketError::Other(err) => Self::Other(err),
+    }
+  }
+}
+
+impl From<AllocatePacketError> for WintunError {
+  fn from(value: AllocatePacketError) -> Self {
+    match value {
+      AllocatePacketError::AdapterIsTerminating => Self::AdapterIsTerminating,
+      AllocatePacketError::WouldBlock => Self::WouldBlock,
+      AllocatePacketError::Other(err) => Self::Other(err),
+    }
+  }
+}
+
+impl From<RingCapacityError> for WintunError {
+  fn from(_: RingCapacityError) -> Self {
+    Self::InvalidRingCapacity
+  }
+}
+
+impl From<IpPacketSizeError> for WintunError {
+  fn from(_: IpPacketSizeError) -> Self {
+    Self::InvalidPacketSize
+  }
+}
+
+impl From<IpMaskPrefixError> for WintunError {
+  fn from(_: IpMaskPrefixError) -> Self {
+    Self::InvalidIpMaskPrefix
+  }
+}
+
+impl From<OsError> for WintunError {
+  fn from(value: OsError) -> Self {
+    Self::Other(value)
+  }
+}
+
+impl From<WintunError> for io::Error {
+  fn from(value: WintunError) -> Self {
+    io::Error::new(value.kind(), value)
+  }
+}
+
+impl fmt::Display for WintunError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WintunError::TooLongName { max, got } => f.write_fmt(format_args!(
+        "Too long string supplied. Max expected: {max}, received: {got}"
+      )),
+      WintunError::ContainsNull(pos) => f.write_fmt(format_args!(
+        "Received null byte in string at position: {pos}"
+      )),
+      WintunError::Other(err) => match os_error_name(*err) {
+        Some(name) => f.write_fmt(format_args!("{name} ({})", os_error_code(*err))),
+        None => f.write_fmt(format_args!("OS error {}", os_error_code(*err))),
+      },
+      WintunError::InvalidRingCapacity => f.write_fmt(format_args!(
+        "Ring capacity should be in range {MIN_RING_CAPACITY}..={MAX_RING_CAPACITY} and be a power of two"
+      )),
+      WintunError::InvalidPacketSize => f.write_fmt(format_args!(
+        "Ip packet size should be in range 1..={MAX_IP_PACKET_SIZE}"
+      )),
+      WintunError::InvalidIpMaskPrefix => f.write_str(
+        "Ip mask prefix should be in range 0..=32 or 0..=128 for Ipv4 and Ipv6 respectively"
+      ),
+      WintunError::WintunNotLoaded => f.write_str("Wintun driver is not loaded"),
+      WintunError::AdapterIsTerminating => f.write_str("Tried to perform operation on terminated adapter"),
+      WintunError::WouldBlock => f.write_str("Requested operation would block"),
+      WintunError::InvalidData => f.write_str("Buffer contained invalid data"),
+      WintunError::InterfaceNotFound => f.write_str("Failed to find interface for specified guid"),
+      WintunError::Context {
+        operation,
+        adapter: Some(adapter),
+        ..
+      } => f.write_fmt(format_args!("Failed to {operation} \"{adapter}\"")),
+      WintunError::Context { operation, .. } => f.write_fmt(format_args!("Failed to {operation}")),
+    }?;
+    write_source(self, f)
+  }
+}
+
+impl Error for WintunError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      WintunError::Other(err) => Some(err),
+      WintunError::Context { source, .. } => Some(&**source),
+      _ => None,
+    }
+  }
+}
+impl Error for GetRunningDriverVersionError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      GetRunningDriverVersionError::Other(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+impl Error for ReceivePacketError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      ReceivePacketError::Other(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+impl Error for AllocatePacketError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      AllocatePacketError::Other(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+impl Error for RingCapacityError {}
+impl Error for IpPacketSizeError {}
+impl Error for IpMaskPrefixError {}
+impl Error for DriverVersionParseError {}
+impl Error for DriverPolicyError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      DriverPolicyError::RemoveFailed { error, .. } => Some(error),
+      DriverPolicyError::Probe(err) => Some(err),
+      DriverPolicyError::TooOld { .. } => None,
+    }
+  }
+}
+
+pub type WintunResult<T> = Result<T, WintunError>;
+
+#[cfg(test)]
+mod tests {
+  use std::{error::Error, io};
+
+  use super::{win32_error_kind, win32_error_name, Operation, WintunError, WIN32_ERRORS};
+
+  #[test]
+  fn win32_names_and_kinds() {
+    assert_eq!(win32_error_name(183), Some("ERROR_ALREADY_EXISTS"));
+    assert_eq!(win32_error_kind(183), io::ErrorKind::AlreadyExists);
+    assert_eq!(win32_error_name(5), Some("ERROR_ACCESS_DENIED"));
+    assert_eq!(win32_error_kind(5), io::ErrorKind::PermissionDenied);
+    assert_eq!(win32_error_kind(259), io::ErrorKind::WouldBlock);
+    assert_eq!(win32_error_kind(1460), io::ErrorKind::TimedOut);
+    assert_eq!(win32_error_name(0xDEAD), None);
+    assert_eq!(win32_error_kind(0xDEAD), io::ErrorKind::Other);
+  }
+
+  #[test]
+  fn win32_table_is_consistent() {
+    for (i, (code, name, _)) in WIN32_ERRORS.iter().enumerate() {
+      assert!(name.starts_with("ERROR_") || name.starts_with("WAIT_"));
+      //Sorted by code so duplicates would be neighbours
+      if let Some((next, _, _)) = WIN32_ERRORS.get(i + 1) {
+        assert!(code < next, "{name}");
+      }
+    }
+  }
+
+  #[cfg(not(windows))]
+  #[test]
+  fn errno_names_and_kinds() {
+    let error = WintunError::Other(crate::Errno::new(libc::EEXIST));
+    assert_eq!(error.to_string(), format!("EEXIST ({})", libc::EEXIST));
+    assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(crate::Errno::new(libc::ENODEV).name(), Some("ENODEV"));
+    assert_eq!(crate::Errno::new(4095).name(), None);
+    assert_eq!(
+      WintunError::Other(crate::Errno::new(4095)).to_string(),
+      "OS error 4095"
+    );
+  }
+
+  #[test]
+  fn context_chain() {
+    let error = WintunError::WouldBlock
+      .context(Operation::StartSession, None)
+      .context(Operation::CreateAdapter, Some("wt0"));
+    assert_eq!(error.operation(), Some(Operation::CreateAdapter));
+    assert_eq!(error.adapter_name(), Some("wt0"));
+    assert_eq!(error.root(), &WintunError::WouldBlock);
+    assert!(error.is_would_block());
+    assert_eq!(error.to_string(), "Failed to create adapter \"wt0\"");
+    assert_eq!(
+      format!("{error:#}"),
+      "Failed to create adapter \"wt0\": Failed to start session: Requested operation would block"
+    );
+    let source = error.source().unwrap();
+    assert_eq!(source.to_string(), "Failed to start session");
+    assert!(source.source().unwrap().source().is_none());
+
+    let io_error = io::Error::from(error);
+    assert_eq!(io_error.kind(), io::ErrorKind::WouldBlock);
+    let inner = io_error.into_inner().unwrap();
+    assert!(inner.downcast_ref::<WintunError>().is_some());
+  }
+
+  #[test]
+  fn adapter_errors_name_operation_and_adapter() {
+    let _adapter = crate::Adapter::create_in_memory("error-context", "tunnel_type", None).unwrap();
+    let error = crate::Adapter::create_in_memory("error-context", "tunnel_type", None)
+      .err()
+      .unwrap();
+    assert_eq!(error.operation(), Some(Operation::CreateAdapter));
+    assert_eq!(error.adapter_name(), Some("error-context"));
+    assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    assert!(error.os_error().is_some());
+  }
+}
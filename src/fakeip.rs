@@ -0,0 +1,593 @@
+//! Synthetic DNS answers for routing by domain. [`FakeIpPool`] answers every name with an address
+//! of its own from a reserved network such as 198.18.0.0/15 (RFC 2544), and remembers which name
+//! each address stands for. Packets the system then sends to such an address can be traced back
+//! to the name it connected to, to pick a policy or to dial the name upstream
+
+use std::{
+  collections::{HashMap, HashSet},
+  fs, io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  path::Path,
+  sync::{Arc, Mutex, MutexGuard},
+  time::Duration,
+};
+
+use crate::{
+  dns::is_hostname, normalize_name, parse_reverse_name, Clock, DnsQuestion, DnsRecord,
+  IpAndMaskPrefix, IpPacket, RecordData, RecordType, ResolveError, Resolver, SystemClock,
+};
+
+/// Mappings unused for this long are forgotten
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(600);
+/// TTL of answers, short so clients ask again and keep their mappings alive
+const DEFAULT_TTL: u32 = 1;
+const DEFAULT_CAPACITY: usize = 65536;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FakeIpStats {
+  /// Addresses handed out to new names
+  pub allocated: u64,
+  /// Mappings forgotten after their lifetime
+  pub expired: u64,
+  /// Live mappings dropped to make room for new ones
+  pub evicted: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+  domain: String,
+  /// Clock time the mapping is forgotten at, pushed back whenever it is used
+  expires: Duration,
+}
+
+struct Table {
+  network: IpAndMaskPrefix,
+  /// The network address and the range of host offsets handed out
+  base: u128,
+  first: u128,
+  last: u128,
+  /// Offset the next allocation starts looking at
+  next: u128,
+  reserved: HashSet<IpAddr>,
+  by_domain: HashMap<String, IpAddr>,
+  by_address: HashMap<IpAddr, Entry>,
+  lifetime: Duration,
+  ttl: u32,
+  capacity: usize,
+  clock: Arc<dyn Clock>,
+  stats: FakeIpStats,
+}
+
+impl Table {
+  fn address(&self, offset: u128) -> IpAddr {
+    match self.network {
+      IpAndMaskPrefix::V4 { .. } => IpAddr::V4(Ipv4Addr::from((self.base + offset) as u32)),
+      IpAndMaskPrefix::V6 { .. } => IpAddr::V6(Ipv6Addr::from(self.base + offset)),
+    }
+  }
+  /// Whether `ip` is in the range handed out, reserved or not
+  fn in_range(&self, ip: IpAddr) -> bool {
+    if !self.network.contains(ip) {
+      return false;
+    }
+    let offset = match ip {
+      IpAddr::V4(ip) => u32::from(ip) as u128,
+      IpAddr::V6(ip) => u128::from(ip),
+    } - self.base;
+    (self.first..=self.last).contains(&offset)
+  }
+  /// Whether `ip` is one of the addresses handed out
+  fn hands_out(&self, ip: IpAddr) -> bool {
+    self.in_range(ip) && !self.reserved.contains(&ip)
+  }
+  /// Addresses neither reserved nor in use
+  fn free(&self) -> u128 {
+    let reserved = self
+      .reserved
+      .iter()
+      .filter(|ip| self.in_range(**ip))
+      .count() as u128;
+    (self.last - self.first)
+      .saturating_add(1)
+      .saturating_sub(reserved)
+      .saturating_sub(self.by_address.len() as u128)
+  }
+  fn expire(&mut self) -> usize {
+    let now = self.clock.now();
+    let expired: Vec<_> = self
+      .by_address
+      .iter()
+      .filter(|(_, entry)| entry.expires <= now)
+      .map(|(ip, _)| *ip)
+      .collect();
+    for ip in &expired {
+      self.remove(*ip);
+    }
+    self.stats.expired += expired.len() as u64;
+    expired.len()
+  }
+  fn remove(&mut self, ip: IpAddr) -> Option<Entry> {
+    let entry = self.by_address.remove(&ip)?;
+    self.by_domain.remove(&entry.domain);
+    Some(entry)
+  }
+  /// The live entry of `ip`, dropping it if it expired
+  fn live(&mut self, ip: IpAddr) -> Option<&mut Entry> {
+    let now = self.clock.now();
+    if self
+      .by_address
+      .get(&ip)
+      .is_some_and(|entry| entry.expires <= now)
+    {
+      self.remove(ip);
+      self.stats.expired += 1;
+    }
+    self.by_address.get_mut(&ip)
+  }
+  fn allocate(&mut self, domain: String) -> Option<IpAddr> {
+    let expires = self.clock.now() + self.lifetime;
+    if let Some(&ip) = self.by_domain.get(&domain) {
+      if let Some(entry) = self.live(ip) {
+        entry.expires = expires;
+        return Some(ip);
+      }
+    }
+    let full = |table: &Self| table.by_address.len() >= table.capacity || table.free() == 0;
+    if full(self) {
+      self.expire();
+    }
+    let ip = if full(self) {
+      //Reuses the address of the least recently used name
+      let oldest = self
+        .by_address
+        .iter()
+        .min_by_key(|(_, entry)| entry.expires)
+        .map(|(ip, _)| *ip)?;
+      self.remove(oldest);
+      self.stats.evicted += 1;
+      oldest
+    } else {
+      loop {
+        let ip = self.address(self.next);
+        self.next = match self.next {
+          next if next >= self.last => self.first,
+          next => next + 1,
+        };
+        if !self.reserved.contains(&ip) && !self.by_address.contains_key(&ip) {
+          break ip;
+        }
+      }
+    };
+    self.by_domain.insert(domain.clone(), ip);
+    self.by_address.insert(ip, Entry { domain, expires });
+    self.stats.allocated += 1;
+    Some(ip)
+  }
+}
+
+/// A table of synthetic addresses standing for domain names. Every name gets an address of the
+/// pool's network when it is first [`allocate`](Self::allocate)d, typically by answering a DNS
+/// query as the [`Resolver`] of a [`DnsResponder`](crate::DnsResponder). Mappings live until
+/// they go unused for their lifetime, and when the pool is full the least recently used one
+/// makes room.
+///
+/// Clones share the same table, so one can answer DNS inside a pipeline while another maps the
+/// destinations of session packets back to names
+#[derive(Clone)]
+pub struct FakeIpPool {
+  table: Arc<Mutex<Table>>,
+}
+
+impl FakeIpPool {
+  /// A pool handing out the addresses of `network` except its network address and, for IPv4,
+  /// its broadcast address
+  pub fn new(network: IpAndMaskPrefix) -> Self {
+    let (host_bits, base) = match network {
+      IpAndMaskPrefix::V4 { ip, prefix } => {
+        let host_bits = 32 - prefix.mask() as u32;
+        let mask = u32::MAX.checked_shl(host_bits).unwrap_or(0);
+        (host_bits, (u32::from(ip) & mask) as u128)
+      }
+      IpAndMaskPrefix::V6 { ip, prefix } => {
+        let host_bits = 128 - prefix.mask() as u32;
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        (host_bits, u128::from(ip) & mask)
+      }
+    };
+    let last = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+    //Networks too small to spare them hand out every address
+    let (first, last) = match (network, host_bits) {
+      (_, 0 | 1) => (0, last),
+      (IpAndMaskPrefix::V4 { .. }, _) => (1, last - 1),
+      (IpAndMaskPrefix::V6 { .. }, _) => (1, last),
+    };
+    Self {
+      table: Arc::new(Mutex::new(Table {
+        network,
+        base,
+        first,
+        last,
+        next: first,
+        reserved: HashSet::new(),
+        by_domain: HashMap::new(),
+        by_address: HashMap::new(),
+        lifetime: DEFAULT_LIFETIME,
+        ttl: DEFAULT_TTL,
+        capacity: DEFAULT_CAPACITY,
+        clock: Arc::new(SystemClock::new()),
+        stats: FakeIpStats::default(),
+      })),
+    }
+  }
+  /// Forgets mappings unused for `lifetime`. Defaults to 10 minutes
+  pub fn with_lifetime(self, lifetime: Duration) -> Self {
+    self.lock().lifetime = lifetime;
+    self
+  }
+  /// TTL of DNS answers. Defaults to 1 second
+  pub fn with_ttl(self, ttl: u32) -> Self {
+    self.lock().ttl = ttl;
+    self
+  }
+  /// Keeps at most `capacity` mappings. Defaults to 65536
+  pub fn with_capacity(self, capacity: usize) -> Self {
+    self.lock().capacity = capacity;
+    self
+  }
+  /// Never hands out `ip`, for addresses of the network used otherwise like that of the
+  /// [`DnsResponder`](crate::DnsResponder)
+  pub fn with_reserved(self, ip: IpAddr) -> Self {
+    self.lock().reserved.insert(ip);
+    self
+  }
+  pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+    self.lock().clock = clock;
+    self
+  }
+  pub fn network(&self) -> IpAndMaskPrefix {
+    self.lock().network
+  }
+  pub fn ttl(&self) -> u32 {
+    self.lock().ttl
+  }
+  pub fn stats(&self) -> FakeIpStats {
+    self.lock().stats
+  }
+  /// Mappings, including expired ones not dropped yet
+  pub fn len(&self) -> usize {
+    self.lock().by_address.len()
+  }
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+  /// The address standing for `domain`, handing out a new one for names without it. Renews the
+  /// mapping. `None` for anything but a host name, or when every address is reserved or the
+  /// capacity is zero
+  pub fn allocate(&self, domain: &str) -> Option<IpAddr> {
+    if !is_hostname(domain) {
+      return None;
+    }
+    self.lock().allocate(normalize_name(domain))
+  }
+  /// The address standing for `domain` if it has one, without renewing the mapping
+  pub fn address(&self, domain: &str) -> Option<IpAddr> {
+    let mut table = self.lock();
+    let ip = *table.by_domain.get(&normalize_name(domain))?;
+    table.live(ip).map(|_| ip)
+  }
+  /// The name `ip` stands for. Renews the mapping, as traffic to the address uses it
+  pub fn domain(&self, ip: IpAddr) -> Option<String> {
+    let mut table = self.lock();
+    let expires = table.clock.now() + table.lifetime;
+    let entry = table.live(ip)?;
+    entry.expires = expires;
+    Some(entry.domain.clone())
+  }
+  /// Whether `ip` is an address the pool hands out, whether or not it stands for a name now
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    self.lock().hands_out(ip)
+  }
+  /// The name the destination of `packet` stands for, for packets the system sends and the
+  /// session receives. Answers from upstream go the other way, their source is looked up with
+  /// [`domain`](Self::domain)
+  pub fn packet_domain(&self, packet: &[u8]) -> Option<String> {
+    let destination = IpPacket::parse(packet).ok()?.destination();
+    self.domain(destination)
+  }
+  /// Forgets `domain`, returning the address it had
+  pub fn remove(&self, domain: &str) -> Option<IpAddr> {
+    let mut table = self.lock();
+    let ip = *table.by_domain.get(&normalize_name(domain))?;
+    table.remove(ip).map(|_| ip)
+  }
+  /// Drops expired mappings, returning how many
+  pub fn expire(&self) -> usize {
+    self.lock().expire()
+  }
+  /// Writes the live mappings to `path`, one `address remaining-milliseconds name` line each.
+  /// Mappings keep the lifetime they have left, the clocks of processes don't compare
+  pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    let contents = {
+      let table = self.lock();
+      let now = table.clock.now();
+      let mut contents = String::new();
+      for (ip, entry) in &table.by_address {
+        if entry.expires > now {
+          let remaining = (entry.expires - now).as_millis();
+          contents.push_str(&format!("{ip} {remaining} {}\n", entry.domain));
+        }
+      }
+      contents
+    };
+    //Write to a temporary file first so a crash never leaves a partial table behind
+    let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+  }
+  /// Restores mappings written by [`save`](Self::save), returning how many. Lines that are
+  /// malformed, outside the network or in conflict with current mappings are skipped, as are
+  /// mappings beyond the capacity
+  pub fn load(&self, path: impl AsRef<Path>) -> io::Result<usize> {
+    let contents = fs::read_to_string(path)?;
+    let mut table = self.lock();
+    let now = table.clock.now();
+    let mut loaded = 0;
+    for line in contents.lines() {
+      let mut fields = line.splitn(3, ' ');
+      let (Some(Ok(ip)), Some(Ok(remaining)), Some(domain)) = (
+        fields.next().map(str::parse::<IpAddr>),
+        fields.next().map(str::parse::<u64>),
+        fields.next(),
+      ) else {
+        continue;
+      };
+      let domain = normalize_name(domain);
+      if remaining == 0
+        || !is_hostname(&domain)
+        || !table.hands_out(ip)
+        || table.by_address.contains_key(&ip)
+        || table.by_domain.contains_key(&domain)
+        || table.by_address.len() >= table.capacity
+      {
+        continue;
+      }
+      let expires = now + Duration::from_millis(remaining);
+      table.by_domain.insert(domain.clone(), ip);
+      table.by_address.insert(ip, Entry { domain, expires });
+      loaded += 1;
+    }
+    Ok(loaded)
+  }
+  fn lock(&self) -> MutexGuard<'_, Table> {
+    self
+      .table
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+/// Answers address queries of the pool's family with a fake address, queries of the other family
+/// with no records so clients fall back, and PTR queries of handed out addresses with their name
+impl Resolver for FakeIpPool {
+  fn resolve(&mut self, question: &DnsQuestion) -> Result<Vec<DnsRecord>, ResolveError> {
+    let ttl = self.ttl();
+    let family_type = match self.network() {
+      IpAndMaskPrefix::V4 { .. } => RecordType::A,
+      IpAndMaskPrefix::V6 { .. } => RecordType::Aaaa,
+    };
+    match question.record_type {
+      RecordType::Ptr => {
+        let domain = parse_reverse_name(&question.name)
+          .and_then(|ip| self.domain(ip))
+          .ok_or(ResolveError::NotFound)?;
+        Ok(vec![DnsRecord::new(
+          question.name.clone(),
+          ttl,
+          RecordData::Ptr(domain),
+        )])
+      }
+      _ if !is_hostname(&question.name) => Err(ResolveError::NotFound),
+      record_type if record_type == family_type => {
+        let data = match self.allocate(&question.name).ok_or(ResolveError::Failed)? {
+          IpAddr::V4(ip) => RecordData::A(ip),
+          IpAddr::V6(ip) => RecordData::Aaaa(ip),
+        };
+        Ok(vec![DnsRecord::new(question.name.clone(), ttl, data)])
+      }
+      _ => Ok(Vec::new()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, net::IpAddr, sync::Arc, time::Duration};
+
+  use super::FakeIpPool;
+  use crate::{
+    fixtures::network, reverse_name, tcp_packet, udp_packet, Adapter, DnsMessage, DnsResponder,
+    IpPacket, ManualClock, PacketPipeline, PipelineSession, RecordData, RecordType, RingCapacity,
+    TCP_SYN,
+  };
+
+  fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+  }
+
+  #[test]
+  fn maps_names_both_ways() {
+    let pool = FakeIpPool::new(network("198.18.0.0", 15)).with_reserved(ip("198.18.0.1"));
+    let first = pool.allocate("Example.com.").unwrap();
+    assert_eq!(first, ip("198.18.0.2"));
+    assert_eq!(pool.allocate("example.com"), Some(first));
+    assert_eq!(pool.allocate("example.org"), Some(ip("198.18.0.3")));
+    assert_eq!(pool.domain(first).as_deref(), Some("example.com"));
+    assert_eq!(pool.address("EXAMPLE.ORG"), Some(ip("198.18.0.3")));
+    assert!(pool.contains(ip("198.19.255.254")));
+    assert!(!pool.contains(ip("198.19.255.255")));
+    assert!(!pool.contains(ip("198.18.0.1")));
+    assert_eq!(pool.domain(ip("198.18.0.4")), None);
+
+    let packet = tcp_packet(
+      "10.8.0.2:40000".parse().unwrap(),
+      "198.18.0.3:443".parse().unwrap(),
+      TCP_SYN,
+      &[],
+      b"",
+    )
+    .unwrap();
+    assert_eq!(pool.packet_domain(&packet).as_deref(), Some("example.org"));
+
+    assert_eq!(pool.remove("example.org"), Some(ip("198.18.0.3")));
+    assert_eq!(pool.packet_domain(&packet), None);
+    assert_eq!((pool.len(), pool.stats().allocated), (1, 2));
+  }
+
+  #[test]
+  fn expiry_and_eviction() {
+    let clock = ManualClock::new();
+    //A /126 hands out three addresses, one of them is reserved
+    let pool = FakeIpPool::new(network("fd00::", 126))
+      .with_reserved(ip("fd00::1"))
+      .with_lifetime(Duration::from_secs(60))
+      .with_clock(Arc::new(clock.clone()));
+    let a = pool.allocate("a.test").unwrap();
+    let b = pool.allocate("b.test").unwrap();
+    assert_eq!((a, b), (ip("fd00::2"), ip("fd00::3")));
+
+    //Lookups renew mappings, so `a` outlives `b`
+    clock.advance(Duration::from_secs(40));
+    assert!(pool.domain(a).is_some());
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(pool.address("b.test"), None);
+    assert_eq!(pool.stats().expired, 1);
+
+    //Full pools evict the least recently used name
+    assert_eq!(pool.allocate("c.test"), Some(b));
+    clock.advance(Duration::from_secs(1));
+    pool.domain(a);
+    assert_eq!(pool.allocate("d.test"), Some(b));
+    assert_eq!(pool.domain(b).as_deref(), Some("d.test"));
+    assert_eq!(pool.address("c.test"), None);
+    assert_eq!(pool.stats().evicted, 1);
+
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(pool.expire(), 2);
+    assert!(pool.is_empty());
+    assert_eq!(
+      FakeIpPool::new(network("10.0.0.0", 24))
+        .with_capacity(0)
+        .allocate("a.test"),
+      None
+    );
+  }
+
+  #[test]
+  fn persists_mappings() {
+    let path = std::env::temp_dir().join(format!("wintun2-fakeip-{}", std::process::id()));
+    let clock = ManualClock::new();
+    let pool = FakeIpPool::new(network("198.18.0.0", 15))
+      .with_lifetime(Duration::from_secs(60))
+      .with_clock(Arc::new(clock.clone()));
+    let a = pool.allocate("a.test").unwrap();
+    clock.advance(Duration::from_secs(30));
+    let b = pool.allocate("b.test").unwrap();
+    pool.save(&path).unwrap();
+
+    //A new process with its own clock, the file also carries junk and foreign addresses
+    let mut contents = fs::read_to_string(&path).unwrap();
+    contents.push_str("garbage\n10.0.0.1 5000 outside.test\n198.18.0.9 0 dead.test\n");
+    fs::write(&path, contents).unwrap();
+    let clock = ManualClock::new();
+    let restored = FakeIpPool::new(network("198.18.0.0", 15)).with_clock(Arc::new(clock.clone()));
+    let taken = restored.allocate("taken.test").unwrap();
+    assert_eq!(taken, a);
+    assert_eq!(restored.load(&path).unwrap(), 1);
+    assert_eq!(restored.domain(b).as_deref(), Some("b.test"));
+    assert_eq!(restored.domain(a).as_deref(), Some("taken.test"));
+    //Restored mappings keep the lifetime they had left, then renew with the new one
+    let restored = FakeIpPool::new(network("198.18.0.0", 15)).with_clock(Arc::new(clock.clone()));
+    assert_eq!(restored.load(&path).unwrap(), 2);
+    //New names don't collide with restored ones
+    let c = restored.allocate("c.test").unwrap();
+    assert!(c != a && c != b);
+    clock.advance(Duration::from_secs(45));
+    assert_eq!(restored.address("a.test"), None);
+    assert_eq!(restored.address("b.test"), Some(b));
+    fs::remove_file(&path).unwrap();
+    assert!(restored.load(&path).is_err());
+  }
+
+  #[test]
+  fn hostile_names_stay_out_of_the_file() {
+    let path = std::env::temp_dir().join(format!("wintun2-fakeip-hostile-{}", std::process::id()));
+    let pool = FakeIpPool::new(network("198.18.0.0", 15));
+    let a = pool.allocate("a.test").unwrap();
+    for name in [
+      "evil.test\n198.18.0.9 60000 injected.test",
+      "spaced name.test",
+      "dot..test",
+      "",
+      &"x".repeat(64),
+    ] {
+      assert_eq!(pool.allocate(name), None, "{name:?}");
+    }
+    pool.save(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+
+    //Names that don't belong in the file are skipped when it was edited by hand
+    let mut contents = fs::read_to_string(&path).unwrap();
+    contents.push_str("198.18.0.9 60000 bad name.test\n198.18.0.10 60000 \u{7f}.test\n");
+    fs::write(&path, contents).unwrap();
+    let restored = FakeIpPool::new(network("198.18.0.0", 15));
+    assert_eq!(restored.load(&path).unwrap(), 1);
+    assert_eq!(restored.domain(a).as_deref(), Some("a.test"));
+    assert_eq!(restored.address("injected.test"), None);
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn answers_dns_through_the_session() {
+    let pool = FakeIpPool::new(network("198.18.0.0", 15)).with_reserved(ip("198.18.0.1"));
+    let adapter = Adapter::create_in_memory("fakeip", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = PipelineSession::new(
+      adapter.session(RingCapacity::min()).unwrap(),
+      PacketPipeline::new().with(DnsResponder::new(ip("198.18.0.1"), pool.clone())),
+    );
+    let client = "10.8.0.2:40000".parse().unwrap();
+    let server = "198.18.0.1:53".parse().unwrap();
+    let ask = |name: &str, record_type| {
+      let query = DnsMessage::query(3, name, record_type).encode().unwrap();
+      peer
+        .inject(&udp_packet(client, server, &query).unwrap())
+        .unwrap();
+      assert!(session.recv().is_err());
+      let reply = peer.try_recv().unwrap();
+      let parsed = IpPacket::parse(&reply).unwrap();
+      DnsMessage::parse(&parsed.payload()[8..]).unwrap()
+    };
+
+    let response = ask("video.example", RecordType::A);
+    assert_eq!(response.answers[0].ttl, 1);
+    let RecordData::A(fake) = response.answers[0].data else {
+      panic!("expected an address");
+    };
+    assert!(ask("video.example", RecordType::Aaaa).answers.is_empty());
+    let response = ask(&reverse_name(fake.into()), RecordType::Ptr);
+    assert_eq!(
+      response.answers[0].data,
+      RecordData::Ptr("video.example".into())
+    );
+
+    //The connection that follows leads back to the name
+    let syn = tcp_packet(client, (fake, 443).into(), TCP_SYN, &[], b"").unwrap();
+    peer.inject(&syn).unwrap();
+    let packet = session.recv().unwrap();
+    assert_eq!(
+      pool.packet_domain(packet.slice()).as_deref(),
+      Some("video.example")
+    );
+  }
+}
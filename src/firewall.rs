@@ -0,0 +1,500 @@
+//! Stateful packet filter for session traffic. A [`Firewall`] checks packets against ordered
+//! [`Rule`]s where the first match decides, falling back to a default action. Flows a rule
+//! allowed are tracked, so the packets coming back the other way, and ICMP errors about the
+//! flow, are admitted without a rule of their own. It is a [`PacketProcessor`], attach it to a
+//! session with a [`PipelineSession`](crate::PipelineSession)
+
+use std::{ops::RangeInclusive, sync::Arc, time::Duration};
+
+use crate::{
+  Clock, Direction, FlowKey, FlowSide, FlowTable, FlowTimeouts, IpAndMaskPrefix, IpPacket,
+  IpProtocol, PacketProcessor, Verdict,
+};
+
+const DEFAULT_MAX_FLOWS: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+  Allow,
+  Deny,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpFamily {
+  V4,
+  V6,
+}
+
+/// Inclusive range of ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+  first: u16,
+  last: u16,
+}
+
+impl PortRange {
+  pub fn new(first: u16, last: u16) -> Self {
+    Self {
+      first: first.min(last),
+      last: first.max(last),
+    }
+  }
+  pub fn first(&self) -> u16 {
+    self.first
+  }
+  pub fn last(&self) -> u16 {
+    self.last
+  }
+  pub fn contains(&self, port: u16) -> bool {
+    (self.first..=self.last).contains(&port)
+  }
+}
+
+impl From<u16> for PortRange {
+  fn from(port: u16) -> Self {
+    Self::new(port, port)
+  }
+}
+
+impl From<RangeInclusive<u16>> for PortRange {
+  fn from(range: RangeInclusive<u16>) -> Self {
+    Self::new(*range.start(), *range.end())
+  }
+}
+
+/// Matches packets on every criterion that was set. Port criteria only match TCP and UDP, the
+/// ICMP type only ICMP and ICMPv6
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+  action: Action,
+  direction: Option<Direction>,
+  family: Option<IpFamily>,
+  source: Option<IpAndMaskPrefix>,
+  destination: Option<IpAndMaskPrefix>,
+  protocol: Option<IpProtocol>,
+  source_ports: Option<PortRange>,
+  destination_ports: Option<PortRange>,
+  icmp_type: Option<u8>,
+}
+
+impl Rule {
+  fn new(action: Action) -> Self {
+    Self {
+      action,
+      direction: None,
+      family: None,
+      source: None,
+      destination: None,
+      protocol: None,
+      source_ports: None,
+      destination_ports: None,
+      icmp_type: None,
+    }
+  }
+  /// A rule letting every packet through, narrowed down by the builder methods
+  pub fn allow() -> Self {
+    Self::new(Action::Allow)
+  }
+  /// A rule blocking every packet, narrowed down by the builder methods
+  pub fn deny() -> Self {
+    Self::new(Action::Deny)
+  }
+  pub fn direction(mut self, direction: Direction) -> Self {
+    self.direction = Some(direction);
+    self
+  }
+  pub fn family(mut self, family: IpFamily) -> Self {
+    self.family = Some(family);
+    self
+  }
+  pub fn source(mut self, network: IpAndMaskPrefix) -> Self {
+    self.source = Some(network);
+    self
+  }
+  pub fn destination(mut self, network: IpAndMaskPrefix) -> Self {
+    self.destination = Some(network);
+    self
+  }
+  pub fn protocol(mut self, protocol: IpProtocol) -> Self {
+    self.protocol = Some(protocol);
+    self
+  }
+  pub fn source_ports(mut self, ports: impl Into<PortRange>) -> Self {
+    self.source_ports = Some(ports.into());
+    self
+  }
+  pub fn destination_ports(mut self, ports: impl Into<PortRange>) -> Self {
+    self.destination_ports = Some(ports.into());
+    self
+  }
+  pub fn icmp_type(mut self, icmp_type: u8) -> Self {
+    self.icmp_type = Some(icmp_type);
+    self
+  }
+  pub fn action(&self) -> Action {
+    self.action
+  }
+  pub fn matches(&self, direction: Direction, packet: &IpPacket) -> bool {
+    let family = match packet.version() {
+      4 => IpFamily::V4,
+      _ => IpFamily::V6,
+    };
+    let ports = packet.ports();
+    self.direction.is_none_or(|expected| expected == direction)
+      && self.family.is_none_or(|expected| expected == family)
+      && self
+        .source
+        .is_none_or(|network| network.contains(packet.source()))
+      && self
+        .destination
+        .is_none_or(|network| network.contains(packet.destination()))
+      && self
+        .protocol
+        .is_none_or(|expected| expected == packet.protocol())
+      && self
+        .source_ports
+        .is_none_or(|range| ports.is_some_and(|(source, _)| range.contains(source)))
+      && self
+        .destination_ports
+        .is_none_or(|range| ports.is_some_and(|(_, destination)| range.contains(destination)))
+      && self
+        .icmp_type
+        .is_none_or(|expected| packet.icmp_type() == Some(expected))
+  }
+}
+
+/// Ordered rules plus connection tracking. Packets that can't be parsed are always denied
+pub struct Firewall {
+  rules: Vec<Rule>,
+  default_action: Action,
+  flows: FlowTable<()>,
+}
+
+impl Firewall {
+  /// A firewall without rules, deciding every packet with `default_action`
+  pub fn new(default_action: Action) -> Self {
+    Self {
+      rules: Vec::new(),
+      default_action,
+      flows: FlowTable::new(DEFAULT_MAX_FLOWS),
+    }
+  }
+  /// Appends a rule, it is checked after the ones added before
+  pub fn rule(mut self, rule: Rule) -> Self {
+    self.rules.push(rule);
+    self
+  }
+  /// Flows without packets for this long are forgotten, whatever their state
+  pub fn with_idle_timeout(self, timeout: Duration) -> Self {
+    self.with_timeouts(FlowTimeouts::uniform(timeout))
+  }
+  /// How long flows are kept without packets. Defaults to [`FlowTimeouts::default`]
+  pub fn with_timeouts(mut self, timeouts: FlowTimeouts) -> Self {
+    self.flows.set_timeouts(timeouts);
+    self
+  }
+  /// Flows tracked at most, the least recently seen flow makes room for a new one. Defaults to
+  /// 65536
+  pub fn with_max_flows(mut self, max_flows: usize) -> Self {
+    self.flows = FlowTable::new(max_flows)
+      .with_timeouts(self.flows.timeouts())
+      .with_clock(self.flows.clock());
+    self
+  }
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.flows = FlowTable::new(self.flows.capacity())
+      .with_timeouts(self.flows.timeouts())
+      .with_clock(clock);
+    self
+  }
+  pub fn rules(&self) -> &[Rule] {
+    &self.rules
+  }
+  pub fn flows(&self) -> &FlowTable<()> {
+    &self.flows
+  }
+  /// Decides about a packet travelling in `direction` and tracks its flow if it is allowed
+  pub fn check(&mut self, direction: Direction, packet: &[u8]) -> Action {
+    let Ok(parsed) = IpPacket::parse(packet) else {
+      return Action::Deny;
+    };
+    if self.tracked(&FlowKey::of(&parsed), direction) {
+      self.flows.update(packet);
+      return Action::Allow;
+    }
+    //Errors travel against the packet they quote
+    let against = match direction {
+      Direction::Inbound => Direction::Outbound,
+      Direction::Outbound => Direction::Inbound,
+    };
+    if FlowKey::quoted(&parsed).is_some_and(|quoted| self.tracked(&quoted, against)) {
+      return Action::Allow;
+    }
+    let action = self
+      .rules
+      .iter()
+      .find(|rule| rule.matches(direction, &parsed))
+      .map_or(self.default_action, Rule::action);
+    if action == Action::Allow {
+      self.flows.insert(direction, packet, ());
+    }
+    action
+  }
+  /// Whether a packet with `key` travelling in `direction` belongs to a tracked flow. Replies have
+  /// to come from the other side than the flow was opened from, so a packet repeating the opening
+  /// one from the wrong side gets no pass
+  fn tracked(&self, key: &FlowKey, direction: Direction) -> bool {
+    self.flows.get(key).is_some_and(|(flow, side)| match side {
+      FlowSide::Original => direction == flow.info().opened,
+      FlowSide::Reply => direction != flow.info().opened,
+    })
+  }
+  /// Direction in which the flow of `packet` was opened, if it is tracked
+  pub fn flow_direction(&self, packet: &[u8]) -> Option<Direction> {
+    let key = FlowKey::of(&IpPacket::parse(packet).ok()?);
+    self.flows.get(&key).map(|(flow, _)| flow.info().opened)
+  }
+}
+
+impl PacketProcessor for Firewall {
+  fn name(&self) -> &'static str {
+    "firewall"
+  }
+  fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+    match self.check(Direction::Inbound, packet) {
+      Action::Allow => Verdict::Accept,
+      Action::Deny => Verdict::Drop,
+    }
+  }
+  fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+    match self.check(Direction::Outbound, packet) {
+      Action::Allow => Verdict::Accept,
+      Action::Deny => Verdict::Drop,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+  };
+
+  use super::{Action, Firewall, IpFamily, Rule};
+  use crate::{
+    fixtures::{echo, icmp, network, udp},
+    Direction, IpPacket, IpProtocol, ManualClock,
+  };
+
+  #[test]
+  fn prefixes() {
+    let v4 = network("10.1.0.0", 16);
+    assert!(v4.contains(Ipv4Addr::new(10, 1, 255, 3).into()));
+    assert!(!v4.contains(Ipv4Addr::new(10, 2, 0, 1).into()));
+    assert!(!v4.contains(Ipv6Addr::LOCALHOST.into()));
+    assert!(network("0.0.0.0", 0).contains(Ipv4Addr::BROADCAST.into()));
+    assert!(network("fd00::", 8).contains("fdff::1".parse().unwrap()));
+    assert!(!network("fd00::1", 128).contains("fd00::2".parse().unwrap()));
+  }
+
+  #[test]
+  fn rules_match_every_criterion() {
+    let packet = udp("10.0.0.2:5000", "192.168.1.1:53", b"payload");
+    let packet = IpPacket::parse(&packet).unwrap();
+    let dns = Rule::allow()
+      .direction(Direction::Outbound)
+      .family(IpFamily::V4)
+      .source(network("10.0.0.0", 24))
+      .destination(network("192.168.1.1", 32))
+      .protocol(IpProtocol::Udp)
+      .source_ports(1024..=65535)
+      .destination_ports(53);
+    assert!(dns.matches(Direction::Outbound, &packet));
+    assert!(!dns.matches(Direction::Inbound, &packet));
+    for rule in [
+      dns.clone().family(IpFamily::V6),
+      dns.clone().source(network("10.0.1.0", 24)),
+      dns.clone().protocol(IpProtocol::Tcp),
+      dns.clone().destination_ports(5353),
+      dns.clone().source_ports(1..=1023),
+      dns.clone().icmp_type(8),
+    ] {
+      assert!(!rule.matches(Direction::Outbound, &packet), "{rule:?}");
+    }
+
+    let ping = echo("10.0.0.2", "10.0.0.1", 8, 7);
+    let ping = IpPacket::parse(&ping).unwrap();
+    assert!(Rule::deny().icmp_type(8).matches(Direction::Inbound, &ping));
+    assert!(!Rule::deny().icmp_type(0).matches(Direction::Inbound, &ping));
+    assert!(!Rule::deny()
+      .destination_ports(0..=65535)
+      .matches(Direction::Inbound, &ping));
+  }
+
+  #[test]
+  fn first_matching_rule_decides() {
+    let mut firewall = Firewall::new(Action::Allow)
+      .rule(Rule::deny().destination_ports(22))
+      .rule(Rule::allow().destination_ports(1..=1000));
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &udp("10.0.0.1:1", "10.0.0.2:22", b"payload")
+      ),
+      Action::Deny
+    );
+    let mut firewall = Firewall::new(Action::Deny).rule(Rule::allow().protocol(IpProtocol::Tcp));
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &udp("10.0.0.1:1", "10.0.0.2:22", b"payload")
+      ),
+      Action::Deny
+    );
+    assert_eq!(firewall.check(Direction::Inbound, &[0x45, 0]), Action::Deny);
+  }
+
+  #[test]
+  fn replies_to_allowed_flows_are_admitted() {
+    let clock = ManualClock::new();
+    let mut firewall = Firewall::new(Action::Deny)
+      .rule(Rule::allow().direction(Direction::Outbound))
+      .with_idle_timeout(Duration::from_secs(30))
+      .with_clock(Arc::new(clock.clone()));
+    let query = udp("10.0.0.2:5000", "1.1.1.1:53", b"payload");
+    let answer = udp("1.1.1.1:53", "10.0.0.2:5000", b"payload");
+    assert_eq!(firewall.check(Direction::Inbound, &answer), Action::Deny);
+    assert_eq!(firewall.check(Direction::Outbound, &query), Action::Allow);
+    assert_eq!(firewall.flow_direction(&answer), Some(Direction::Outbound));
+    assert_eq!(firewall.check(Direction::Inbound, &answer), Action::Allow);
+    //Another port of the same host is not part of the flow
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &udp("1.1.1.1:53", "10.0.0.2:5001", b"payload")
+      ),
+      Action::Deny
+    );
+
+    let request = echo("10.0.0.2", "8.8.8.8", 8, 99);
+    assert_eq!(firewall.check(Direction::Outbound, &request), Action::Allow);
+    assert_eq!(
+      firewall.check(Direction::Inbound, &echo("8.8.8.8", "10.0.0.2", 0, 99)),
+      Action::Allow
+    );
+    assert_eq!(
+      firewall.check(Direction::Inbound, &echo("8.8.8.8", "10.0.0.2", 0, 100)),
+      Action::Deny
+    );
+
+    //Traffic keeps the flow alive, silence ends it
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(firewall.check(Direction::Inbound, &answer), Action::Allow);
+    clock.advance(Duration::from_secs(31));
+    assert_eq!(firewall.check(Direction::Inbound, &answer), Action::Deny);
+  }
+
+  #[test]
+  fn tracked_flows_only_admit_their_own_sides() {
+    let mut firewall =
+      Firewall::new(Action::Deny).rule(Rule::allow().direction(Direction::Outbound));
+    let query = udp("10.0.0.2:5000", "1.1.1.1:53", b"payload");
+    assert_eq!(firewall.check(Direction::Outbound, &query), Action::Allow);
+    assert_eq!(firewall.check(Direction::Outbound, &query), Action::Allow);
+    //The opening packet's tuple, but coming in from the outside
+    assert_eq!(firewall.check(Direction::Inbound, &query), Action::Deny);
+    //A flow opened from the outside gets replies from the session, not from the outside
+    let mut firewall = Firewall::new(Action::Deny).rule(
+      Rule::allow()
+        .direction(Direction::Inbound)
+        .destination_ports(53),
+    );
+    let answer = udp("1.1.1.1:53", "10.0.0.2:5000", b"payload");
+    assert_eq!(firewall.check(Direction::Inbound, &query), Action::Allow);
+    assert_eq!(firewall.check(Direction::Inbound, &answer), Action::Deny);
+    assert_eq!(firewall.check(Direction::Outbound, &answer), Action::Allow);
+  }
+
+  #[test]
+  fn errors_about_tracked_flows_are_admitted() {
+    let mut firewall =
+      Firewall::new(Action::Deny).rule(Rule::allow().direction(Direction::Outbound));
+    let query = udp("10.0.0.2:5000", "1.1.1.1:53", &[0; 1400]);
+    let error = |source: &str, destination: &str, quoted: &[u8]| {
+      //Fragmentation needed with an MTU of 1400
+      let message = [&[3, 4, 0, 0, 0, 0, 0x05, 0x78][..], &quoted[..48]].concat();
+      icmp(source, destination, &message)
+    };
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &error("192.168.1.1", "10.0.0.2", &query)
+      ),
+      Action::Deny
+    );
+    assert_eq!(firewall.check(Direction::Outbound, &query), Action::Allow);
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &error("192.168.1.1", "10.0.0.2", &query)
+      ),
+      Action::Allow
+    );
+    //Another flow, or an error about the flow's packets that went the other way
+    let other = udp("10.0.0.2:5001", "1.1.1.1:53", &[0; 1400]);
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &error("192.168.1.1", "10.0.0.2", &other)
+      ),
+      Action::Deny
+    );
+    let answer = udp("1.1.1.1:53", "10.0.0.2:5000", &[0; 1400]);
+    assert_eq!(
+      firewall.check(Direction::Inbound, &error("10.0.0.2", "1.1.1.1", &answer)),
+      Action::Deny
+    );
+    assert_eq!(
+      firewall.check(Direction::Outbound, &error("10.0.0.2", "1.1.1.1", &answer)),
+      Action::Allow
+    );
+  }
+
+  #[test]
+  fn flow_capacity_is_bounded() {
+    let clock = ManualClock::new();
+    let mut firewall = Firewall::new(Action::Deny)
+      .rule(Rule::allow().direction(Direction::Outbound))
+      .with_max_flows(2)
+      .with_idle_timeout(Duration::from_secs(10))
+      .with_clock(Arc::new(clock.clone()));
+    for port in 1..=3 {
+      let query = udp(&format!("10.0.0.2:{port}"), "1.1.1.1:53", b"payload");
+      assert_eq!(firewall.check(Direction::Outbound, &query), Action::Allow);
+      clock.advance(Duration::from_secs(1));
+    }
+    //The least recently seen flow made room for the last one
+    assert_eq!((firewall.flows().len(), firewall.flows().evicted()), (2, 1));
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &udp("1.1.1.1:53", "10.0.0.2:1", b"payload")
+      ),
+      Action::Deny
+    );
+    assert_eq!(
+      firewall.check(
+        Direction::Inbound,
+        &udp("1.1.1.1:53", "10.0.0.2:3", b"payload")
+      ),
+      Action::Allow
+    );
+    //Expired flows make room without evicting
+    clock.advance(Duration::from_secs(11));
+    let query = udp("10.0.0.2:4", "1.1.1.1:53", b"payload");
+    assert_eq!(firewall.check(Direction::Outbound, &query), Action::Allow);
+    assert_eq!((firewall.flows().len(), firewall.flows().evicted()), (1, 1));
+  }
+}
@@ -0,0 +1,80 @@
+//! Builders and checks for the packets and addresses used by the tests of the packet processors
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{
+  checksum, ip_packet, tcp_packet, transport_checksum, udp_packet, IpAndMaskPrefix, IpPacket,
+  IpProtocol,
+};
+
+pub fn network(ip: &str, prefix: u8) -> IpAndMaskPrefix {
+  match ip.parse().unwrap() {
+    IpAddr::V4(ip) => IpAndMaskPrefix::V4 {
+      ip,
+      prefix: prefix.try_into().unwrap(),
+    },
+    IpAddr::V6(ip) => IpAndMaskPrefix::V6 {
+      ip,
+      prefix: prefix.try_into().unwrap(),
+    },
+  }
+}
+
+pub fn address(address: &str) -> SocketAddr {
+  address.parse().unwrap()
+}
+
+pub fn udp(source: &str, destination: &str, payload: &[u8]) -> Vec<u8> {
+  udp_packet(address(source), address(destination), payload).unwrap()
+}
+
+/// A TCP segment without payload
+pub fn tcp(source: &str, destination: &str, flags: u8, options: &[u8]) -> Vec<u8> {
+  tcp_packet(address(source), address(destination), flags, options, &[]).unwrap()
+}
+
+/// An ICMP or ICMPv6 packet, depending on the addresses, with the checksum of `message` filled in
+pub fn icmp(source: &str, destination: &str, message: &[u8]) -> Vec<u8> {
+  let source: IpAddr = source.parse().unwrap();
+  let destination: IpAddr = destination.parse().unwrap();
+  let mut message = message.to_vec();
+  let protocol = match source {
+    IpAddr::V4(_) => IpProtocol::Icmp,
+    IpAddr::V6(_) => IpProtocol::Icmpv6,
+  };
+  let icmp_checksum = match protocol {
+    IpProtocol::Icmp => checksum(&message),
+    _ => transport_checksum(source, destination, protocol, &message),
+  };
+  message[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+  ip_packet(source, destination, protocol, &message).unwrap()
+}
+
+/// An echo request or reply with sequence number 1 and a byte of data
+pub fn echo(source: &str, destination: &str, icmp_type: u8, identifier: u16) -> Vec<u8> {
+  let mut message = vec![icmp_type, 0, 0, 0];
+  message.extend_from_slice(&identifier.to_be_bytes());
+  message.extend_from_slice(&[0, 1, 0xAB]);
+  icmp(source, destination, &message)
+}
+
+/// Parses a packet, asserting that the IPv4 header checksum and that of the message are valid
+pub fn checked(packet: &[u8]) -> IpPacket<'_> {
+  let parsed = IpPacket::parse(packet).unwrap();
+  if parsed.version() == 4 {
+    assert_eq!(checksum(parsed.header()), 0, "IPv4 header checksum");
+  }
+  let valid = match parsed.protocol() {
+    IpProtocol::Icmp => checksum(parsed.payload()) == 0,
+    protocol => {
+      transport_checksum(
+        parsed.source(),
+        parsed.destination(),
+        protocol,
+        parsed.payload(),
+      ) == 0
+    }
+  };
+  assert!(valid, "{} checksum", parsed.protocol());
+  parsed
+}
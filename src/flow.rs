@@ -0,0 +1,799 @@
+//! Per-flow state for traffic crossing a session. A [`FlowTable`] recognizes the packets of a
+//! flow in both directions by their 5-tuple, follows TCP through its handshake and teardown,
+//! gives UDP and ICMP a pseudo-state, forgets idle flows and evicts the least recently seen flow
+//! when full. Time comes from a [`Clock`], so with a [`ManualClock`](crate::ManualClock) every
+//! expiry is deterministic
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  fmt,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+  time::Duration,
+};
+
+use crate::wire::{
+  ICMPV6_ECHO_REPLY, ICMPV6_ECHO_REQUEST, ICMPV6_ERRORS, ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST,
+  ICMP_ERRORS, ICMP_HEADER_LEN, IPV4_HEADER_LEN, IPV6_HEADER_LEN,
+};
+use crate::{
+  Clock, Direction, IpPacket, IpProtocol, SystemClock, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN,
+};
+
+/// Protocol and endpoints of a flow, seen in the direction of one of its packets. Protocols
+/// without ports use 0, except ICMP echo which uses the identifier on both ends so a reply
+/// matches its reversed request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+  pub protocol: IpProtocol,
+  pub source: SocketAddr,
+  pub destination: SocketAddr,
+}
+
+impl FlowKey {
+  pub fn of(packet: &IpPacket) -> Self {
+    let (source_port, destination_port) = packet
+      .ports()
+      .or_else(|| echo_identifier(packet).map(|identifier| (identifier, identifier)))
+      .unwrap_or_default();
+    Self {
+      protocol: packet.protocol(),
+      source: SocketAddr::new(packet.source(), source_port),
+      destination: SocketAddr::new(packet.destination(), destination_port),
+    }
+  }
+  /// The key of the packet an ICMP error quotes, as that packet was sent. `None` if `packet` is
+  /// no ICMP error or quotes too little. Extension headers of quoted IPv6 packets aren't walked
+  pub fn quoted(packet: &IpPacket) -> Option<Self> {
+    let errors = match packet.protocol() {
+      IpProtocol::Icmp => &ICMP_ERRORS[..],
+      IpProtocol::Icmpv6 => &ICMPV6_ERRORS[..],
+      _ => return None,
+    };
+    if !errors.contains(&packet.icmp_type()?) {
+      return None;
+    }
+    let quoted = packet.payload().get(ICMP_HEADER_LEN..)?;
+    let (header_len, protocol, source, destination): (_, IpProtocol, IpAddr, IpAddr) =
+      match quoted.first()? >> 4 {
+        4 => {
+          let header_len = (quoted[0] & 0xF) as usize * 4;
+          let header = quoted.get(..header_len.max(IPV4_HEADER_LEN))?;
+          let address = |at: usize| <[u8; 4]>::try_from(&header[at..at + 4]).unwrap();
+          (
+            header.len(),
+            header[9].into(),
+            address(12).into(),
+            address(16).into(),
+          )
+        }
+        6 => {
+          let header = quoted.get(..IPV6_HEADER_LEN)?;
+          let address = |at: usize| <[u8; 16]>::try_from(&header[at..at + 16]).unwrap();
+          (
+            IPV6_HEADER_LEN,
+            header[6].into(),
+            address(8).into(),
+            address(24).into(),
+          )
+        }
+        _ => return None,
+      };
+    let transport = &quoted[header_len..];
+    let word = |at: usize| {
+      Some(u16::from_be_bytes([
+        *transport.get(at)?,
+        *transport.get(at + 1)?,
+      ]))
+    };
+    let ports = match (protocol, transport.first()) {
+      (IpProtocol::Tcp | IpProtocol::Udp, _) => (word(0)?, word(2)?),
+      (
+        IpProtocol::Icmp | IpProtocol::Icmpv6,
+        Some(&(ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY | ICMPV6_ECHO_REQUEST | ICMPV6_ECHO_REPLY)),
+      ) => (word(4)?, word(4)?),
+      _ => (0, 0),
+    };
+    Some(Self {
+      protocol,
+      source: SocketAddr::new(source, ports.0),
+      destination: SocketAddr::new(destination, ports.1),
+    })
+  }
+  /// The key of packets travelling the other way
+  pub fn reversed(self) -> Self {
+    Self {
+      protocol: self.protocol,
+      source: self.destination,
+      destination: self.source,
+    }
+  }
+}
+
+/// Formats as `UDP 10.0.0.2:5000 -> 1.1.1.1:53`
+impl fmt::Display for FlowKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!(
+      "{} {} -> {}",
+      self.protocol, self.source, self.destination
+    ))
+  }
+}
+
+fn echo_identifier(packet: &IpPacket) -> Option<u16> {
+  match packet.icmp_type()? {
+    ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY | ICMPV6_ECHO_REQUEST | ICMPV6_ECHO_REPLY => {
+      let identifier = packet.payload().get(4..6)?;
+      Some(u16::from_be_bytes([identifier[0], identifier[1]]))
+    }
+    _ => None,
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlowState {
+  /// Only the side that opened a UDP, ICMP or other flow has sent packets
+  New,
+  /// The opening TCP SYN was seen
+  SynSent,
+  /// The SYN was answered with a SYN-ACK
+  SynReceived,
+  /// The TCP handshake completed, or the other side of any other flow answered. TCP flows
+  /// picked up without their handshake start here as well
+  Established,
+  /// One side of a TCP flow sent a FIN
+  Closing,
+  /// Both sides of a TCP flow sent a FIN, or one reset it
+  Closed,
+}
+
+/// Which way a packet travels within its flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FlowSide {
+  /// Like the packet that opened the flow
+  Original,
+  /// The other way
+  Reply,
+}
+
+/// How long flows are kept without packets, by state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowTimeouts {
+  /// TCP flows in the handshake. Defaults to 2 minutes
+  pub tcp_handshake: Duration,
+  /// Established TCP flows. Defaults to 2 hours
+  pub tcp_established: Duration,
+  /// TCP flows with one FIN. Defaults to 2 minutes
+  pub tcp_closing: Duration,
+  /// TCP flows that were closed or reset. Defaults to 10 seconds
+  pub tcp_closed: Duration,
+  /// Other flows that weren't answered. Defaults to 30 seconds
+  pub unreplied: Duration,
+  /// Other flows that were answered. Defaults to 3 minutes
+  pub replied: Duration,
+}
+
+impl FlowTimeouts {
+  /// The same timeout in every state
+  pub fn uniform(timeout: Duration) -> Self {
+    Self {
+      tcp_handshake: timeout,
+      tcp_established: timeout,
+      tcp_closing: timeout,
+      tcp_closed: timeout,
+      unreplied: timeout,
+      replied: timeout,
+    }
+  }
+  fn of(&self, protocol: IpProtocol, state: FlowState) -> Duration {
+    match (protocol, state) {
+      (IpProtocol::Tcp, FlowState::New | FlowState::SynSent | FlowState::SynReceived) => {
+        self.tcp_handshake
+      }
+      (IpProtocol::Tcp, FlowState::Established) => self.tcp_established,
+      (IpProtocol::Tcp, FlowState::Closing) => self.tcp_closing,
+      (IpProtocol::Tcp, FlowState::Closed) => self.tcp_closed,
+      (_, FlowState::New) => self.unreplied,
+      _ => self.replied,
+    }
+  }
+}
+
+impl Default for FlowTimeouts {
+  fn default() -> Self {
+    Self {
+      tcp_handshake: Duration::from_secs(120),
+      tcp_established: Duration::from_secs(2 * 60 * 60),
+      tcp_closing: Duration::from_secs(120),
+      tcp_closed: Duration::from_secs(10),
+      unreplied: Duration::from_secs(30),
+      replied: Duration::from_secs(180),
+    }
+  }
+}
+
+/// What a table knows about a flow, apart from the data attached to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowInfo {
+  /// Key of the packet that opened the flow
+  pub key: FlowKey,
+  /// Direction of the packet that opened the flow
+  pub opened: Direction,
+  pub state: FlowState,
+  /// Clock times of the first and the latest packet
+  pub created: Duration,
+  pub last_seen: Duration,
+  /// Packets and bytes on the original side
+  pub original: (u64, u64),
+  /// Packets and bytes on the reply side
+  pub reply: (u64, u64),
+}
+
+/// A tracked flow with data of type `T` attached
+#[derive(Debug, Clone)]
+pub struct Flow<T> {
+  info: FlowInfo,
+  /// Sides that sent a FIN
+  fins: [bool; 2],
+  /// Tells flows seen at the same time apart in the [`FlowIndex`]
+  id: u64,
+  data: T,
+}
+
+impl<T> Flow<T> {
+  pub fn info(&self) -> &FlowInfo {
+    &self.info
+  }
+  pub fn key(&self) -> FlowKey {
+    self.info.key
+  }
+  pub fn state(&self) -> FlowState {
+    self.info.state
+  }
+  pub fn data(&self) -> &T {
+    &self.data
+  }
+  pub fn data_mut(&mut self) -> &mut T {
+    &mut self.data
+  }
+  fn expires(&self, timeouts: &FlowTimeouts) -> Duration {
+    self.info.last_seen + timeouts.of(self.info.key.protocol, self.info.state)
+  }
+  fn apply(&mut self, side: FlowSide, packet: &IpPacket, now: Duration) {
+    let counters = match side {
+      FlowSide::Original => &mut self.info.original,
+      FlowSide::Reply => &mut self.info.reply,
+    };
+    counters.0 += 1;
+    counters.1 += packet.as_bytes().len() as u64;
+    self.info.last_seen = now;
+    let Some(flags) = packet.tcp_flags() else {
+      if side == FlowSide::Reply && self.info.state == FlowState::New {
+        self.info.state = FlowState::Established;
+      }
+      return;
+    };
+    let state = &mut self.info.state;
+    if flags & TCP_RST != 0 {
+      *state = FlowState::Closed;
+      return;
+    }
+    let syn = flags & TCP_SYN != 0;
+    let ack = flags & TCP_ACK != 0;
+    *state = match (*state, side) {
+      (FlowState::New, _) if syn && !ack => FlowState::SynSent,
+      (FlowState::New, _) => FlowState::Established,
+      //A new connection reusing the ports of a closed one
+      (FlowState::Closed, FlowSide::Original) if syn && !ack => {
+        self.fins = [false; 2];
+        FlowState::SynSent
+      }
+      (FlowState::SynSent, FlowSide::Reply) if syn && ack => FlowState::SynReceived,
+      (FlowState::SynReceived, FlowSide::Original) if ack && !syn => FlowState::Established,
+      (state, _) => state,
+    };
+    if flags & TCP_FIN != 0 && *state != FlowState::Closed {
+      self.fins[side as usize] = true;
+      *state = if self.fins == [true; 2] {
+        FlowState::Closed
+      } else {
+        FlowState::Closing
+      };
+    }
+  }
+}
+
+/// Keys of the flows of a table ordered by when they were last seen and by when they expire, so
+/// expiry and eviction don't scan the table
+#[derive(Default)]
+struct FlowIndex {
+  by_last_seen: BTreeMap<(Duration, u64), FlowKey>,
+  by_expiry: BTreeMap<(Duration, u64), FlowKey>,
+}
+
+impl FlowIndex {
+  fn insert<T>(&mut self, flow: &Flow<T>, timeouts: &FlowTimeouts) {
+    let key = flow.info.key;
+    self
+      .by_last_seen
+      .insert((flow.info.last_seen, flow.id), key);
+    self
+      .by_expiry
+      .insert((flow.expires(timeouts), flow.id), key);
+  }
+  fn remove<T>(&mut self, flow: &Flow<T>, timeouts: &FlowTimeouts) {
+    self.by_last_seen.remove(&(flow.info.last_seen, flow.id));
+    self.by_expiry.remove(&(flow.expires(timeouts), flow.id));
+  }
+}
+
+/// Flows keyed by the 5-tuple of the packet that opened them, with data of type `T` attached
+pub struct FlowTable<T> {
+  flows: HashMap<FlowKey, Flow<T>>,
+  index: FlowIndex,
+  next_id: u64,
+  capacity: usize,
+  timeouts: FlowTimeouts,
+  clock: Arc<dyn Clock>,
+  evicted: u64,
+}
+
+impl<T> FlowTable<T> {
+  /// A table holding up to `capacity` flows
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      flows: HashMap::new(),
+      index: FlowIndex::default(),
+      next_id: 0,
+      capacity,
+      timeouts: FlowTimeouts::default(),
+      clock: Arc::new(SystemClock::new()),
+      evicted: 0,
+    }
+  }
+  pub fn with_timeouts(mut self, timeouts: FlowTimeouts) -> Self {
+    self.set_timeouts(timeouts);
+    self
+  }
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+  pub fn timeouts(&self) -> FlowTimeouts {
+    self.timeouts
+  }
+  pub fn set_timeouts(&mut self, timeouts: FlowTimeouts) {
+    self.timeouts = timeouts;
+    self.index.by_expiry = self
+      .flows
+      .values()
+      .map(|flow| ((flow.expires(&timeouts), flow.id), flow.info.key))
+      .collect();
+  }
+  pub fn clock(&self) -> Arc<dyn Clock> {
+    self.clock.clone()
+  }
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+  /// Flows in the table, including expired ones that weren't purged yet
+  pub fn len(&self) -> usize {
+    self.flows.len()
+  }
+  pub fn is_empty(&self) -> bool {
+    self.flows.is_empty()
+  }
+  /// Live flows that were evicted to make room for new ones
+  pub fn evicted(&self) -> u64 {
+    self.evicted
+  }
+  /// The key a live flow that a packet with `key` belongs to is stored under
+  fn locate(&self, key: &FlowKey) -> Option<(FlowKey, FlowSide)> {
+    let now = self.clock.now();
+    let (key, side) = if self.flows.contains_key(key) {
+      (*key, FlowSide::Original)
+    } else {
+      (key.reversed(), FlowSide::Reply)
+    };
+    let flow = self.flows.get(&key)?;
+    (flow.expires(&self.timeouts) > now).then_some((key, side))
+  }
+  /// Finds the live flow a packet with `key` belongs to
+  pub fn get(&self, key: &FlowKey) -> Option<(&Flow<T>, FlowSide)> {
+    let (key, side) = self.locate(key)?;
+    Some((&self.flows[&key], side))
+  }
+  pub fn get_mut(&mut self, key: &FlowKey) -> Option<(&mut Flow<T>, FlowSide)> {
+    let (key, side) = self.locate(key)?;
+    Some((self.flows.get_mut(&key)?, side))
+  }
+  /// Accounts a packet to its live flow, if there is one
+  pub fn update(&mut self, packet: &[u8]) -> Option<(&mut Flow<T>, FlowSide)> {
+    let packet = IpPacket::parse(packet).ok()?;
+    let now = self.clock.now();
+    let (key, side) = self.locate(&FlowKey::of(&packet))?;
+    let flow = self.flows.get_mut(&key)?;
+    self.index.remove(flow, &self.timeouts);
+    flow.apply(side, &packet, now);
+    self.index.insert(flow, &self.timeouts);
+    Some((flow, side))
+  }
+  /// Opens a flow with a packet travelling in `direction`, replacing any flow between the same
+  /// endpoints. Returns `None` if the packet can't be parsed
+  pub fn insert(&mut self, direction: Direction, packet: &[u8], data: T) -> Option<&mut Flow<T>> {
+    self.insert_with(direction, packet, data, drop)
+  }
+  /// Like [`insert`](Self::insert), passing the flows it replaces, expires or evicts to `removed`
+  pub fn insert_with(
+    &mut self,
+    direction: Direction,
+    packet: &[u8],
+    data: T,
+    mut removed: impl FnMut(Flow<T>),
+  ) -> Option<&mut Flow<T>> {
+    let packet = IpPacket::parse(packet).ok()?;
+    let now = self.clock.now();
+    let key = FlowKey::of(&packet);
+    if let Some(flow) = self.remove_stored(&key.reversed()) {
+      removed(flow);
+    }
+    match self.remove_stored(&key) {
+      Some(flow) => removed(flow),
+      None => self.make_room(&mut removed),
+    }
+    let mut flow = Flow {
+      info: FlowInfo {
+        key,
+        opened: direction,
+        state: FlowState::New,
+        created: now,
+        last_seen: now,
+        original: (0, 0),
+        reply: (0, 0),
+      },
+      fins: [false; 2],
+      id: self.next_id,
+      data,
+    };
+    self.next_id += 1;
+    flow.apply(FlowSide::Original, &packet, now);
+    self.index.insert(&flow, &self.timeouts);
+    Some(self.flows.entry(key).or_insert(flow))
+  }
+  /// Accounts a packet to its live flow, opening one with `data` if there is none
+  pub fn observe(
+    &mut self,
+    direction: Direction,
+    packet: &[u8],
+    data: impl FnOnce() -> T,
+  ) -> Option<(&mut Flow<T>, FlowSide)> {
+    let parsed = IpPacket::parse(packet).ok()?;
+    let key = FlowKey::of(&parsed);
+    if self.get(&key).is_none() {
+      return self
+        .insert(direction, packet, data())
+        .map(|flow| (flow, FlowSide::Original));
+    }
+    self.update(packet)
+  }
+  pub fn remove(&mut self, key: &FlowKey) -> Option<Flow<T>> {
+    self
+      .remove_stored(key)
+      .or_else(|| self.remove_stored(&key.reversed()))
+  }
+  /// Removes the flow stored under `key`, without looking at its reverse
+  fn remove_stored(&mut self, key: &FlowKey) -> Option<Flow<T>> {
+    let flow = self.flows.remove(key)?;
+    self.index.remove(&flow, &self.timeouts);
+    Some(flow)
+  }
+  /// Drops expired flows, returning how many
+  pub fn expire(&mut self) -> usize {
+    self.expire_with(drop)
+  }
+  /// Like [`expire`](Self::expire), passing the dropped flows to `removed`
+  pub fn expire_with(&mut self, mut removed: impl FnMut(Flow<T>)) -> usize {
+    let now = self.clock.now();
+    let mut expired = 0;
+    while let Some(entry) = self.index.by_expiry.first_entry() {
+      if entry.key().0 > now {
+        break;
+      }
+      let key = entry.remove();
+      if let Some(flow) = self.remove_stored(&key) {
+        removed(flow);
+      }
+      expired += 1;
+    }
+    expired
+  }
+  fn make_room(&mut self, removed: &mut impl FnMut(Flow<T>)) {
+    if self.flows.len() < self.capacity {
+      return;
+    }
+    if self.expire_with(&mut *removed) > 0 {
+      return;
+    }
+    if let Some((_, oldest)) = self.index.by_last_seen.pop_first() {
+      if let Some(flow) = self.remove_stored(&oldest) {
+        removed(flow);
+      }
+      self.evicted += 1;
+    }
+  }
+  /// Live flows in no particular order
+  pub fn iter(&self) -> impl Iterator<Item = &Flow<T>> {
+    let now = self.clock.now();
+    let timeouts = self.timeouts;
+    self
+      .flows
+      .values()
+      .filter(move |flow| flow.expires(&timeouts) > now)
+  }
+  /// Live flows, oldest first
+  pub fn snapshot(&self) -> Vec<FlowInfo> {
+    let mut flows: Vec<_> = self.iter().map(|flow| flow.info).collect();
+    flows.sort_by_key(|flow| (flow.created, flow.last_seen));
+    flows
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::Arc, time::Duration};
+
+  use super::{FlowKey, FlowSide, FlowState, FlowTable, FlowTimeouts};
+  use crate::{
+    fixtures::{echo, icmp, tcp, udp},
+    ip_packet, Direction, IpPacket, IpProtocol, ManualClock, TCP_ACK, TCP_FIN, TCP_RST, TCP_SYN,
+  };
+
+  fn table(clock: &ManualClock, capacity: usize) -> FlowTable<u32> {
+    FlowTable::new(capacity).with_clock(Arc::new(clock.clone()))
+  }
+
+  #[test]
+  fn keys() {
+    let packet = udp("10.0.0.2:5000", "1.1.1.1:53", b"data");
+    let key = FlowKey::of(&IpPacket::parse(&packet).unwrap());
+    assert_eq!(key.to_string(), "UDP 10.0.0.2:5000 -> 1.1.1.1:53");
+    assert_eq!(key.reversed().reversed(), key);
+    let answer = udp("1.1.1.1:53", "10.0.0.2:5000", b"data");
+    assert_eq!(
+      FlowKey::of(&IpPacket::parse(&answer).unwrap()),
+      key.reversed()
+    );
+  }
+
+  #[test]
+  fn keys_of_quoted_packets() {
+    //Errors quote the start of a packet the session sent
+    let quote = |packet: &[u8], message: [u8; 8], router: &str| {
+      let quoted = &packet[..packet.len().min(48)];
+      let session = IpPacket::parse(packet).unwrap().source().to_string();
+      let error = icmp(router, &session, &[&message[..], quoted].concat());
+      FlowKey::quoted(&IpPacket::parse(&error).unwrap())
+    };
+    let key = |packet: &[u8]| Some(FlowKey::of(&IpPacket::parse(packet).unwrap()));
+    let query = udp("10.0.0.2:5000", "1.1.1.1:53", &[0; 100]);
+    let frag_needed = [3, 4, 0, 0, 0, 0, 0x05, 0x78];
+    assert_eq!(quote(&query, frag_needed, "192.168.1.1"), key(&query));
+    let ping = echo("10.0.0.2", "1.1.1.1", 8, 7);
+    let time_exceeded = [11, 0, 0, 0, 0, 0, 0, 0];
+    assert_eq!(quote(&ping, time_exceeded, "192.168.1.1"), key(&ping));
+    let query = udp("[fd00::2]:5000", "[2001:db8::1]:53", &[0; 100]);
+    let too_big = [2, 0, 0, 0, 0, 0, 0x05, 0x00];
+    assert_eq!(quote(&query, too_big, "fd00::1"), key(&query));
+
+    //Quoting too little, or not an error
+    let error = icmp("fd00::1", "fd00::2", &[&too_big[..], &query[..40]].concat());
+    assert_eq!(FlowKey::quoted(&IpPacket::parse(&error).unwrap()), None);
+    let reply = echo("1.1.1.1", "10.0.0.2", 0, 7);
+    assert_eq!(FlowKey::quoted(&IpPacket::parse(&reply).unwrap()), None);
+  }
+
+  #[test]
+  fn tcp_lifecycle() {
+    let clock = ManualClock::new();
+    let mut flows = table(&clock, 16);
+    let client = "10.0.0.2:40000";
+    let server = "93.184.216.34:443";
+    let syn = tcp(client, server, TCP_SYN, &[]);
+    let flow = flows.insert(Direction::Outbound, &syn, 7).unwrap();
+    assert_eq!(flow.state(), FlowState::SynSent);
+    assert_eq!(*flow.data(), 7);
+
+    let steps = [
+      (
+        server,
+        client,
+        TCP_SYN | TCP_ACK,
+        FlowSide::Reply,
+        FlowState::SynReceived,
+      ),
+      (
+        client,
+        server,
+        TCP_ACK,
+        FlowSide::Original,
+        FlowState::Established,
+      ),
+      (
+        server,
+        client,
+        TCP_ACK,
+        FlowSide::Reply,
+        FlowState::Established,
+      ),
+      (
+        server,
+        client,
+        TCP_FIN | TCP_ACK,
+        FlowSide::Reply,
+        FlowState::Closing,
+      ),
+      (
+        server,
+        client,
+        TCP_FIN | TCP_ACK,
+        FlowSide::Reply,
+        FlowState::Closing,
+      ),
+      (
+        client,
+        server,
+        TCP_FIN | TCP_ACK,
+        FlowSide::Original,
+        FlowState::Closed,
+      ),
+    ];
+    for (source, destination, flags, side, state) in steps {
+      let (flow, seen) = flows.update(&tcp(source, destination, flags, &[])).unwrap();
+      assert_eq!(
+        (seen, flow.state()),
+        (side, state),
+        "{source} -> {destination}"
+      );
+    }
+    let info = flows.snapshot()[0];
+    assert_eq!(info.opened, Direction::Outbound);
+    assert_eq!((info.original.0, info.reply.0), (3, 4));
+    assert_eq!(info.original.1, 3 * 40);
+
+    //Closed flows go away quickly and the ports can be reused
+    clock.advance(Duration::from_secs(11));
+    assert!(flows.update(&tcp(client, server, TCP_ACK, &[])).is_none());
+    let (flow, _) = flows.observe(Direction::Outbound, &syn, || 8).unwrap();
+    assert_eq!((flow.state(), *flow.data()), (FlowState::SynSent, 8));
+    let (flow, _) = flows.update(&tcp(server, client, TCP_RST, &[])).unwrap();
+    assert_eq!(flow.state(), FlowState::Closed);
+    assert_eq!(flows.len(), 1);
+  }
+
+  #[test]
+  fn midstream_tcp_and_datagrams() {
+    let clock = ManualClock::new();
+    let mut flows = table(&clock, 16);
+    let ack = tcp("10.0.0.2:40000", "10.0.0.1:22", TCP_ACK, &[]);
+    let (flow, _) = flows.observe(Direction::Inbound, &ack, || 0).unwrap();
+    assert_eq!(flow.state(), FlowState::Established);
+
+    let query = udp("10.0.0.2:5000", "1.1.1.1:53", b"data");
+    let (flow, side) = flows.observe(Direction::Outbound, &query, || 1).unwrap();
+    assert_eq!((flow.state(), side), (FlowState::New, FlowSide::Original));
+    *flow.data_mut() += 1;
+    let answer = udp("1.1.1.1:53", "10.0.0.2:5000", b"data");
+    let (flow, side) = flows.observe(Direction::Inbound, &answer, || 100).unwrap();
+    assert_eq!(
+      (flow.state(), side),
+      (FlowState::Established, FlowSide::Reply)
+    );
+    assert_eq!(*flow.data(), 2);
+
+    let ping = ip_packet(
+      "10.0.0.2".parse().unwrap(),
+      "8.8.8.8".parse().unwrap(),
+      IpProtocol::Icmp,
+      &[8, 0, 0, 0, 0, 42, 0, 1],
+    )
+    .unwrap();
+    let pong = ip_packet(
+      "8.8.8.8".parse().unwrap(),
+      "10.0.0.2".parse().unwrap(),
+      IpProtocol::Icmp,
+      &[0, 0, 0, 0, 0, 42, 0, 1],
+    )
+    .unwrap();
+    flows.insert(Direction::Outbound, &ping, 3).unwrap();
+    let (flow, side) = flows.update(&pong).unwrap();
+    assert_eq!(
+      (flow.state(), side, *flow.data()),
+      (FlowState::Established, FlowSide::Reply, 3)
+    );
+    assert!(flows.update(&[0x45]).is_none());
+  }
+
+  #[test]
+  fn expiry_follows_state_and_timeouts() {
+    let clock = ManualClock::new();
+    let mut flows = table(&clock, 3);
+    let udp_flow = |port: u16| udp(&format!("10.0.0.2:{port}"), "1.1.1.1:53", b"data");
+    flows.insert(Direction::Outbound, &udp_flow(1), 1);
+    clock.advance(Duration::from_secs(1));
+    let client = "10.0.0.2:40000";
+    let server = "10.0.0.1:22";
+    flows.insert(Direction::Outbound, &tcp(client, server, TCP_SYN, &[]), 2);
+    flows.update(&tcp(server, client, TCP_RST, &[])).unwrap();
+    clock.advance(Duration::from_secs(1));
+    flows.insert(Direction::Outbound, &udp_flow(3), 3);
+
+    //The reset flow expires first although it isn't the least recently seen
+    clock.advance(Duration::from_secs(10));
+    flows.insert(Direction::Outbound, &udp_flow(4), 4);
+    assert_eq!((flows.len(), flows.evicted()), (3, 0));
+    let mut data: Vec<_> = flows.iter().map(|flow| *flow.data()).collect();
+    data.sort();
+    assert_eq!(data, [1, 3, 4]);
+
+    //Changed timeouts apply to the flows already in the table
+    flows.set_timeouts(FlowTimeouts::uniform(Duration::from_secs(5)));
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(flows.expire(), 3);
+    assert!(flows.is_empty());
+  }
+
+  #[test]
+  fn timeouts_and_eviction() {
+    let clock = ManualClock::new();
+    let mut flows = table(&clock, 2).with_timeouts(FlowTimeouts {
+      unreplied: Duration::from_secs(5),
+      ..FlowTimeouts::default()
+    });
+    flows.insert(
+      Direction::Outbound,
+      &udp("10.0.0.2:1", "1.1.1.1:53", b"data"),
+      1,
+    );
+    clock.advance(Duration::from_secs(1));
+    flows.insert(
+      Direction::Outbound,
+      &udp("10.0.0.2:2", "1.1.1.1:53", b"data"),
+      2,
+    );
+    clock.advance(Duration::from_secs(1));
+    //Touching the first flow makes the second one the least recently seen
+    flows
+      .update(&udp("10.0.0.2:1", "1.1.1.1:53", b"data"))
+      .unwrap();
+    flows.insert(
+      Direction::Outbound,
+      &udp("10.0.0.2:3", "1.1.1.1:53", b"data"),
+      3,
+    );
+    assert_eq!(flows.evicted(), 1);
+    let mut data: Vec<_> = flows.iter().map(|flow| *flow.data()).collect();
+    data.sort();
+    assert_eq!(data, [1, 3]);
+    assert_eq!(
+      flows
+        .snapshot()
+        .iter()
+        .map(|info| info.key.source.port())
+        .collect::<Vec<_>>(),
+      [1, 3]
+    );
+
+    //Expired flows are dropped before anything is evicted
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(flows.iter().count(), 0);
+    flows.insert(
+      Direction::Outbound,
+      &udp("10.0.0.2:4", "1.1.1.1:53", b"data"),
+      4,
+    );
+    assert_eq!((flows.len(), flows.evicted()), (1, 1));
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(flows.expire(), 1);
+    assert!(flows.is_empty());
+  }
+}
@@ -0,0 +1,970 @@
+//! IP fragmentation. [`Fragmenter`] splits packets that don't fit the MTU of the transport
+//! carrying the tunnel, [`Reassembler`] puts fragments received from the system back together
+//! (RFC 791, RFC 8200). Overlapping fragments drop the whole datagram for both families, as RFC
+//! 5722 asks for IPv6; exact duplicates are ignored
+
+use std::{
+  collections::{hash_map::RandomState, BTreeMap, HashMap},
+  fmt,
+  hash::{BuildHasher, Hasher},
+  net::IpAddr,
+  ops::Deref,
+  sync::Arc,
+  time::Duration,
+};
+
+use crate::wire::{IPV4_HEADER_LEN, IPV6_HEADER_LEN};
+use crate::{
+  checksum, AllocatePacketError, Clock, IpPacket, IpPacketSize, RecvPacket, SendPacket,
+  SystemClock, WireError, MAX_IP_PACKET_SIZE,
+};
+
+const FRAGMENT_HEADER_LEN: usize = 8;
+const IPV6_FRAGMENT_HEADER: u8 = 44;
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+const IPV4_OFFSET_MASK: u16 = 0x1FFF;
+/// IPv4 options with this bit are repeated in every fragment
+const IPV4_OPTION_COPIED: u8 = 0x80;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MEMORY_LIMIT: usize = 4 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+  Wire(WireError),
+  /// The IPv4 packet doesn't fit and has the don't fragment flag set
+  DontFragment,
+  /// The MTU leaves no room for 8 bytes of data after the headers every fragment repeats
+  MtuTooSmall,
+  /// IPv6 packets are only fragmented once, by their source
+  AlreadyFragmented,
+  /// A fragment couldn't be allocated. Fragments sent before are lost with the datagram
+  Allocate(AllocatePacketError),
+}
+
+impl fmt::Display for FragmentError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      FragmentError::Wire(err) => err.fmt(f),
+      FragmentError::DontFragment => f.write_str("Packet doesn't fit and may not be fragmented"),
+      FragmentError::MtuTooSmall => f.write_str("MTU is too small to fragment the packet"),
+      FragmentError::AlreadyFragmented => f.write_str("IPv6 packet is already a fragment"),
+      FragmentError::Allocate(err) => f.write_fmt(format_args!("Allocating a fragment: {err}")),
+    }
+  }
+}
+
+impl std::error::Error for FragmentError {}
+
+impl From<WireError> for FragmentError {
+  fn from(value: WireError) -> Self {
+    FragmentError::Wire(value)
+  }
+}
+
+/// Splits packets into fragments of at most `mtu` bytes
+#[derive(Debug, Clone)]
+pub struct Fragmenter {
+  mtu: IpPacketSize,
+  /// Identification of the next fragmented IPv6 packet
+  next_identification: u32,
+}
+
+impl Fragmenter {
+  pub fn new(mtu: IpPacketSize) -> Self {
+    //Identifications shouldn't be predictable (RFC 7739)
+    let next_identification = RandomState::new().build_hasher().finish() as u32;
+    Self {
+      mtu,
+      next_identification,
+    }
+  }
+  pub fn mtu(&self) -> IpPacketSize {
+    self.mtu
+  }
+  pub fn set_mtu(&mut self, mtu: IpPacketSize) {
+    self.mtu = mtu;
+  }
+  /// The fragments of `packet` in order. A packet that fits is returned as it is
+  pub fn fragment(&mut self, packet: &[u8]) -> Result<Vec<Vec<u8>>, FragmentError> {
+    let parsed = IpPacket::parse(packet)?;
+    let packet = parsed.as_bytes();
+    if packet.len() <= self.mtu.size() as usize {
+      return Ok(vec![packet.to_vec()]);
+    }
+    match parsed.version() {
+      4 => self.fragment_v4(&parsed),
+      _ => self.fragment_v6(packet),
+    }
+  }
+  /// Sends the fragments of `packet` in packets from `allocate`, usually
+  /// [`Session::allocate`](crate::Session::allocate). Returns how many were sent
+  pub fn send<'session>(
+    &mut self,
+    packet: &[u8],
+    mut allocate: impl FnMut(IpPacketSize) -> Result<SendPacket<'session>, AllocatePacketError>,
+  ) -> Result<usize, FragmentError> {
+    let fragments = self.fragment(packet)?;
+    for fragment in &fragments {
+      let size = IpPacketSize::try_from(fragment.len() as u32)
+        .expect("fragments are no longer than the packet");
+      let mut packet = allocate(size).map_err(FragmentError::Allocate)?;
+      packet.mut_slice().copy_from_slice(fragment);
+      packet.send();
+    }
+    Ok(fragments.len())
+  }
+  fn fragment_v4(&self, packet: &IpPacket) -> Result<Vec<Vec<u8>>, FragmentError> {
+    let data = packet.as_bytes();
+    let flags_and_offset = u16::from_be_bytes([data[6], data[7]]);
+    if flags_and_offset & IPV4_DONT_FRAGMENT != 0 {
+      return Err(FragmentError::DontFragment);
+    }
+    //Fragments of a fragment keep its offset, and its last one whether more follow
+    let base_offset = (flags_and_offset & IPV4_OFFSET_MASK) as usize * 8;
+    let more_fragments = flags_and_offset & IPV4_MORE_FRAGMENTS != 0;
+    let later_header = copied_header(packet.header());
+    let payload = packet.payload();
+    let mut fragments = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() {
+      let header = match offset {
+        0 => packet.header(),
+        _ => &later_header,
+      };
+      let room = (self.mtu.size() as usize).saturating_sub(header.len()) / 8 * 8;
+      if room == 0 {
+        return Err(FragmentError::MtuTooSmall);
+      }
+      let len = room.min(payload.len() - offset);
+      let last = offset + len == payload.len();
+      let mut fragment = Vec::with_capacity(header.len() + len);
+      fragment.extend_from_slice(header);
+      fragment.extend_from_slice(&payload[offset..offset + len]);
+      let total_len = fragment.len() as u16;
+      fragment[2..4].copy_from_slice(&total_len.to_be_bytes());
+      let mut flags_and_offset = ((base_offset + offset) / 8) as u16;
+      if !last || more_fragments {
+        flags_and_offset |= IPV4_MORE_FRAGMENTS;
+      }
+      fragment[6..8].copy_from_slice(&flags_and_offset.to_be_bytes());
+      set_header_checksum(&mut fragment, header.len());
+      fragments.push(fragment);
+      offset += len;
+    }
+    Ok(fragments)
+  }
+  fn fragment_v6(&mut self, packet: &[u8]) -> Result<Vec<Vec<u8>>, FragmentError> {
+    let chain = Ipv6Chain::of(packet)?;
+    if chain.fragment.is_some() {
+      return Err(FragmentError::AlreadyFragmented);
+    }
+    let unfragmentable = &packet[..chain.unfragmentable];
+    let payload = &packet[chain.unfragmentable..];
+    let next_header = packet[chain.next_header_field];
+    let room =
+      (self.mtu.size() as usize).saturating_sub(unfragmentable.len() + FRAGMENT_HEADER_LEN) / 8 * 8;
+    if room == 0 {
+      return Err(FragmentError::MtuTooSmall);
+    }
+    let identification = self.next_identification;
+    self.next_identification = self.next_identification.wrapping_add(1);
+    let mut fragments = Vec::new();
+    for (index, chunk) in payload.chunks(room).enumerate() {
+      let offset = index * room;
+      let more_fragments = offset + chunk.len() < payload.len();
+      let mut fragment =
+        Vec::with_capacity(unfragmentable.len() + FRAGMENT_HEADER_LEN + chunk.len());
+      fragment.extend_from_slice(unfragmentable);
+      fragment[chain.next_header_field] = IPV6_FRAGMENT_HEADER;
+      let offset_and_flags = offset as u16 | more_fragments as u16;
+      fragment.extend_from_slice(&[next_header, 0]);
+      fragment.extend_from_slice(&offset_and_flags.to_be_bytes());
+      fragment.extend_from_slice(&identification.to_be_bytes());
+      fragment.extend_from_slice(chunk);
+      let payload_len = (fragment.len() - IPV6_HEADER_LEN) as u16;
+      fragment[4..6].copy_from_slice(&payload_len.to_be_bytes());
+      fragments.push(fragment);
+    }
+    Ok(fragments)
+  }
+}
+
+/// The IPv4 header of fragments after the first, which only repeat options with the copied bit
+fn copied_header(header: &[u8]) -> Vec<u8> {
+  let mut copied = header[..IPV4_HEADER_LEN].to_vec();
+  let mut options = &header[IPV4_HEADER_LEN..];
+  while let Some(&option) = options.first() {
+    let len = match option {
+      //End of options list
+      0 => break,
+      //No operation
+      1 => 1,
+      _ => match options.get(1) {
+        Some(&len) if len >= 2 && len as usize <= options.len() => len as usize,
+        _ => break,
+      },
+    };
+    if option & IPV4_OPTION_COPIED != 0 {
+      copied.extend_from_slice(&options[..len]);
+    }
+    options = &options[len..];
+  }
+  copied.resize(copied.len().div_ceil(4) * 4, 0);
+  copied[0] = 0x40 | (copied.len() / 4) as u8;
+  copied
+}
+
+fn set_header_checksum(packet: &mut [u8], header_len: usize) {
+  packet[10..12].copy_from_slice(&[0, 0]);
+  let header_checksum = checksum(&packet[..header_len]);
+  packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+}
+
+/// Where the parts of an IPv6 packet start
+struct Ipv6Chain {
+  /// Offset of the next header field pointing at the fragmentable part
+  next_header_field: usize,
+  /// Length of the headers every fragment repeats
+  unfragmentable: usize,
+  /// Offset of the fragment header
+  fragment: Option<usize>,
+}
+
+impl Ipv6Chain {
+  fn of(packet: &[u8]) -> Result<Self, WireError> {
+    let mut chain = Self {
+      next_header_field: 6,
+      unfragmentable: IPV6_HEADER_LEN,
+      fragment: None,
+    };
+    let mut next_header = packet[6];
+    //The field holding `next_header`
+    let mut field = 6;
+    let mut at = IPV6_HEADER_LEN;
+    loop {
+      match next_header {
+        //Hop-by-hop options, routing and destination options. Destination options only stay
+        //with the unfragmentable part when a routing header follows them
+        0 | 43 | 60 => {
+          let extension = packet.get(at..at + 2).ok_or(WireError::Truncated)?;
+          let len = (extension[1] as usize + 1) * 8;
+          if next_header != 60 {
+            chain.next_header_field = at;
+            chain.unfragmentable = at + len;
+          }
+          next_header = extension[0];
+          field = at;
+          at += len;
+        }
+        IPV6_FRAGMENT_HEADER => {
+          //The headers before the fragment header are the unfragmentable part, whichever they are
+          chain.next_header_field = field;
+          chain.unfragmentable = at;
+          chain.fragment = Some(at);
+          return Ok(chain);
+        }
+        _ => return Ok(chain),
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReassemblyError {
+  Wire(WireError),
+  /// A fragment other than the last isn't a multiple of 8 bytes long. Only it is dropped
+  Misaligned,
+  /// The fragments add up to more than [`MAX_IP_PACKET_SIZE`] bytes, the datagram was dropped
+  TooLong,
+  /// Fragments overlap, the datagram was dropped
+  Overlap,
+  /// Fragments disagree on where the datagram ends, the datagram was dropped
+  InconsistentLength,
+  /// The fragment doesn't fit in the memory limit even after dropping every older datagram
+  MemoryLimit,
+}
+
+impl fmt::Display for ReassemblyError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ReassemblyError::Wire(err) => err.fmt(f),
+      ReassemblyError::Misaligned => f.write_str("Fragment length is not a multiple of 8"),
+      ReassemblyError::TooLong => f.write_fmt(format_args!(
+        "Fragments add up to more than {MAX_IP_PACKET_SIZE} bytes"
+      )),
+      ReassemblyError::Overlap => f.write_str("Fragments overlap"),
+      ReassemblyError::InconsistentLength => f.write_str("Fragments disagree on the length"),
+      ReassemblyError::MemoryLimit => f.write_str("Fragment exceeds the memory limit"),
+    }
+  }
+}
+
+impl std::error::Error for ReassemblyError {}
+
+impl From<WireError> for ReassemblyError {
+  fn from(value: WireError) -> Self {
+    ReassemblyError::Wire(value)
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReassemblyStats {
+  pub reassembled: u64,
+  /// Datagrams dropped because their fragments didn't all arrive in time
+  pub timed_out: u64,
+  /// Datagrams dropped for invalid fragments
+  pub invalid: u64,
+  /// Datagrams dropped to stay within the memory limit
+  pub evicted: u64,
+  /// Fragments refused because they didn't fit within the memory limit
+  pub rejected: u64,
+}
+
+/// A received packet, or a datagram put together from fragments
+pub enum Datagram<'session> {
+  Packet(RecvPacket<'session>),
+  Reassembled(Vec<u8>),
+}
+
+impl Deref for Datagram<'_> {
+  type Target = [u8];
+  fn deref(&self) -> &Self::Target {
+    match self {
+      Datagram::Packet(packet) => packet.slice(),
+      Datagram::Reassembled(datagram) => datagram,
+    }
+  }
+}
+
+impl AsRef<[u8]> for Datagram<'_> {
+  fn as_ref(&self) -> &[u8] {
+    self
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct DatagramKey {
+  source: IpAddr,
+  destination: IpAddr,
+  /// Only part of the key for IPv4
+  protocol: u8,
+  identification: u32,
+}
+
+struct Fragment<'a> {
+  key: DatagramKey,
+  /// Headers of the datagram, taken from the first fragment
+  header: &'a [u8],
+  /// Offset of the next header field in `header` to point at the fragmentable part (IPv6)
+  next_header_field: Option<(usize, u8)>,
+  offset: usize,
+  more_fragments: bool,
+  data: &'a [u8],
+}
+
+impl<'a> Fragment<'a> {
+  /// `None` for packets that aren't fragments
+  fn parse(packet: &'a [u8]) -> Result<Option<Self>, WireError> {
+    let parsed = IpPacket::parse(packet)?;
+    let packet = parsed.as_bytes();
+    if parsed.version() == 4 {
+      if !parsed.is_fragment() {
+        return Ok(None);
+      }
+      let flags_and_offset = u16::from_be_bytes([packet[6], packet[7]]);
+      return Ok(Some(Self {
+        key: DatagramKey {
+          source: parsed.source(),
+          destination: parsed.destination(),
+          protocol: packet[9],
+          identification: u16::from_be_bytes([packet[4], packet[5]]) as u32,
+        },
+        header: parsed.header(),
+        next_header_field: None,
+        offset: (flags_and_offset & IPV4_OFFSET_MASK) as usize * 8,
+        more_fragments: flags_and_offset & IPV4_MORE_FRAGMENTS != 0,
+        data: parsed.payload(),
+      }));
+    }
+    let chain = Ipv6Chain::of(packet)?;
+    let Some(at) = chain.fragment else {
+      return Ok(None);
+    };
+    let header = packet
+      .get(at..at + FRAGMENT_HEADER_LEN)
+      .ok_or(WireError::Truncated)?;
+    let offset_and_flags = u16::from_be_bytes([header[2], header[3]]);
+    Ok(Some(Self {
+      key: DatagramKey {
+        source: parsed.source(),
+        destination: parsed.destination(),
+        protocol: 0,
+        identification: u32::from_be_bytes(header[4..8].try_into().unwrap()),
+      },
+      header: &packet[..at],
+      next_header_field: Some((chain.next_header_field, header[0])),
+      offset: (offset_and_flags & !7) as usize,
+      more_fragments: offset_and_flags & 1 != 0,
+      data: &packet[at + FRAGMENT_HEADER_LEN..],
+    }))
+  }
+}
+
+/// Fragments of one datagram received so far
+struct Pending {
+  created: Duration,
+  header: Option<Vec<u8>>,
+  /// Fragment data by offset
+  pieces: BTreeMap<usize, Vec<u8>>,
+  /// Length of the data, known once the last fragment arrived
+  len: Option<usize>,
+  /// Bytes counted against the memory limit
+  size: usize,
+}
+
+impl Pending {
+  /// Adds a fragment, ignoring exact duplicates
+  fn add(&mut self, fragment: &Fragment) -> Result<(), ReassemblyError> {
+    let end = fragment.offset + fragment.data.len();
+    if fragment.more_fragments && fragment.data.len() % 8 != 0 {
+      return Err(ReassemblyError::Misaligned);
+    }
+    if fragment.header.len() + end > MAX_IP_PACKET_SIZE as usize {
+      return Err(ReassemblyError::TooLong);
+    }
+    let last_end = self
+      .pieces
+      .last_key_value()
+      .map_or(0, |(offset, data)| offset + data.len());
+    match (fragment.more_fragments, self.len) {
+      (false, Some(len)) if len != end => return Err(ReassemblyError::InconsistentLength),
+      (false, None) if last_end > end => return Err(ReassemblyError::InconsistentLength),
+      (true, Some(len)) if end > len => return Err(ReassemblyError::InconsistentLength),
+      _ => {}
+    }
+    if let Some((&offset, data)) = self.pieces.range(..=fragment.offset).next_back() {
+      if offset == fragment.offset && data == fragment.data {
+        return Ok(());
+      }
+      if offset + data.len() > fragment.offset {
+        return Err(ReassemblyError::Overlap);
+      }
+    }
+    if let Some((&offset, _)) = self.pieces.range(fragment.offset + 1..).next() {
+      if offset < end {
+        return Err(ReassemblyError::Overlap);
+      }
+    }
+    if !fragment.more_fragments {
+      self.len = Some(end);
+    }
+    if fragment.offset == 0 && self.header.is_none() {
+      let mut header = fragment.header.to_vec();
+      if let Some((at, next_header)) = fragment.next_header_field {
+        header[at] = next_header;
+      }
+      self.size += header.len();
+      self.header = Some(header);
+    }
+    if !fragment.data.is_empty() {
+      self.size += fragment.data.len();
+      self.pieces.insert(fragment.offset, fragment.data.to_vec());
+    }
+    Ok(())
+  }
+  /// The datagram, once every fragment arrived
+  fn complete(&self) -> Option<Vec<u8>> {
+    let (header, len) = (self.header.as_ref()?, self.len?);
+    let mut datagram = Vec::with_capacity(header.len() + len);
+    datagram.extend_from_slice(header);
+    for (&offset, data) in &self.pieces {
+      if offset != datagram.len() - header.len() {
+        return None;
+      }
+      datagram.extend_from_slice(data);
+    }
+    if datagram.len() - header.len() != len {
+      return None;
+    }
+    let total_len = datagram.len() as u16;
+    match datagram[0] >> 4 {
+      4 => {
+        datagram[2..4].copy_from_slice(&total_len.to_be_bytes());
+        //Keeps the reserved and don't fragment bits
+        datagram[6] &= 0xC0;
+        datagram[7] = 0;
+        set_header_checksum(&mut datagram, header.len());
+      }
+      _ => {
+        let payload_len = total_len - IPV6_HEADER_LEN as u16;
+        datagram[4..6].copy_from_slice(&payload_len.to_be_bytes());
+      }
+    }
+    Some(datagram)
+  }
+}
+
+/// Collects fragments until their datagram is complete. Datagrams whose fragments don't all
+/// arrive within the timeout (30 seconds by default) are dropped, and the oldest ones are dropped
+/// when the fragments held exceed the memory limit (4 MiB by default)
+pub struct Reassembler {
+  pending: HashMap<DatagramKey, Pending>,
+  timeout: Duration,
+  memory_limit: usize,
+  memory_used: usize,
+  clock: Arc<dyn Clock>,
+  stats: ReassemblyStats,
+}
+
+impl Default for Reassembler {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Reassembler {
+  pub fn new() -> Self {
+    Self {
+      pending: HashMap::new(),
+      timeout: DEFAULT_TIMEOUT,
+      memory_limit: DEFAULT_MEMORY_LIMIT,
+      memory_used: 0,
+      clock: Arc::new(SystemClock::new()),
+      stats: ReassemblyStats::default(),
+    }
+  }
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+  /// Bytes of fragments held at most
+  pub fn with_memory_limit(mut self, memory_limit: usize) -> Self {
+    self.memory_limit = memory_limit;
+    self
+  }
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+  pub fn stats(&self) -> ReassemblyStats {
+    self.stats
+  }
+  /// Datagrams waiting for more fragments
+  pub fn pending(&self) -> usize {
+    self.pending.len()
+  }
+  pub fn memory_used(&self) -> usize {
+    self.memory_used
+  }
+  /// Hands on packets that aren't fragments and the datagrams fragments complete. Packets that
+  /// aren't valid IP are handed on too, for the caller to deal with
+  pub fn reassemble<'session>(
+    &mut self,
+    packet: RecvPacket<'session>,
+  ) -> Result<Option<Datagram<'session>>, ReassemblyError> {
+    match Fragment::parse(packet.slice()) {
+      Ok(Some(fragment)) => Ok(self.add(&fragment)?.map(Datagram::Reassembled)),
+      Ok(None) | Err(_) => Ok(Some(Datagram::Packet(packet))),
+    }
+  }
+  /// Like [`reassemble`](Self::reassemble) for packets in a buffer. Packets that aren't
+  /// fragments are copied
+  pub fn push(&mut self, packet: &[u8]) -> Result<Option<Vec<u8>>, ReassemblyError> {
+    match Fragment::parse(packet)? {
+      Some(fragment) => self.add(&fragment),
+      None => Ok(Some(IpPacket::parse(packet)?.as_bytes().to_vec())),
+    }
+  }
+  /// Drops datagrams that timed out. Also done on every fragment
+  pub fn expire(&mut self) -> usize {
+    let now = self.clock.now();
+    let timeout = self.timeout;
+    let before = self.pending.len();
+    let mut freed = 0;
+    self.pending.retain(|_, pending| {
+      let alive = now.saturating_sub(pending.created) < timeout;
+      if !alive {
+        freed += pending.size;
+      }
+      alive
+    });
+    self.memory_used -= freed;
+    let expired = before - self.pending.len();
+    self.stats.timed_out += expired as u64;
+    expired
+  }
+  fn add(&mut self, fragment: &Fragment) -> Result<Option<Vec<u8>>, ReassemblyError> {
+    self.expire();
+    //Atomic fragments are complete on their own (RFC 6946)
+    if fragment.offset == 0 && !fragment.more_fragments {
+      let mut pending = Pending {
+        created: self.clock.now(),
+        header: None,
+        pieces: BTreeMap::new(),
+        len: None,
+        size: 0,
+      };
+      if let Err(error) = pending.add(fragment) {
+        self.stats.invalid += 1;
+        return Err(error);
+      }
+      self.stats.reassembled += 1;
+      return Ok(pending.complete());
+    }
+    let held = self
+      .pending
+      .get(&fragment.key)
+      .map_or(0, |pending| pending.size);
+    let needed = fragment.data.len() + fragment.header.len();
+    while self.memory_used + needed > self.memory_limit {
+      let oldest = self
+        .pending
+        .iter()
+        .filter(|(key, _)| **key != fragment.key)
+        .min_by_key(|(_, pending)| pending.created)
+        .map(|(key, _)| *key);
+      let Some(oldest) = oldest else {
+        break;
+      };
+      self.drop_datagram(&oldest);
+      self.stats.evicted += 1;
+    }
+    if self.memory_used + needed > self.memory_limit {
+      if held > 0 {
+        self.drop_datagram(&fragment.key);
+        self.stats.evicted += 1;
+      }
+      self.stats.rejected += 1;
+      return Err(ReassemblyError::MemoryLimit);
+    }
+    let now = self.clock.now();
+    let pending = self.pending.entry(fragment.key).or_insert_with(|| Pending {
+      created: now,
+      header: None,
+      pieces: BTreeMap::new(),
+      len: None,
+      size: 0,
+    });
+    let size = pending.size;
+    match pending.add(fragment) {
+      Ok(()) => self.memory_used += pending.size - size,
+      Err(ReassemblyError::Misaligned) => return Err(ReassemblyError::Misaligned),
+      Err(err) => {
+        self.drop_datagram(&fragment.key);
+        self.stats.invalid += 1;
+        return Err(err);
+      }
+    }
+    let Some(datagram) = pending.complete() else {
+      return Ok(None);
+    };
+    self.drop_datagram(&fragment.key);
+    self.stats.reassembled += 1;
+    Ok(Some(datagram))
+  }
+  fn drop_datagram(&mut self, key: &DatagramKey) {
+    if let Some(pending) = self.pending.remove(key) {
+      self.memory_used -= pending.size;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+    time::Duration,
+  };
+
+  use super::{Datagram, FragmentError, Fragmenter, Reassembler, ReassemblyError};
+  use crate::{
+    checksum, fixtures, ip_packet, Adapter, IpPacket, IpProtocol, ManualClock, RingCapacity,
+  };
+
+  fn udp(source: &str, destination: &str, len: usize) -> Vec<u8> {
+    let payload: Vec<u8> = (0..len).map(|byte| byte as u8).collect();
+    let mut packet = fixtures::udp(source, destination, &payload);
+    if packet[0] >> 4 == 4 {
+      //Some identification, as a real stack would set
+      packet[4..6].copy_from_slice(&0x1234u16.to_be_bytes());
+      super::set_header_checksum(&mut packet, 20);
+    }
+    packet
+  }
+
+  fn fragmenter(mtu: u32) -> Fragmenter {
+    Fragmenter::new(mtu.try_into().unwrap())
+  }
+
+  fn offset_and_more(fragment: &[u8]) -> (usize, bool) {
+    let field = u16::from_be_bytes([fragment[6], fragment[7]]);
+    ((field & 0x1FFF) as usize * 8, field & 0x2000 != 0)
+  }
+
+  #[test]
+  fn ipv4_roundtrip() {
+    let packet = udp("10.0.0.1:1000", "10.0.0.2:2000", 3000);
+    let fragments = fragmenter(1280).fragment(&packet).unwrap();
+    assert_eq!(fragments.len(), 3);
+    let mut expected_offset = 0;
+    for (index, fragment) in fragments.iter().enumerate() {
+      assert!(fragment.len() <= 1280);
+      assert_eq!(checksum(&fragment[..20]), 0);
+      let parsed = IpPacket::parse(fragment).unwrap();
+      assert!(parsed.is_fragment());
+      assert_eq!(offset_and_more(fragment), (expected_offset, index < 2));
+      assert_eq!(&fragment[4..6], &[0x12, 0x34]);
+      expected_offset += parsed.payload().len();
+    }
+    assert_eq!(expected_offset, packet.len() - 20);
+
+    let mut reassembler = Reassembler::new();
+    assert_eq!(reassembler.push(&fragments[2]), Ok(None));
+    assert_eq!(reassembler.push(&fragments[0]), Ok(None));
+    //Exact duplicates are ignored
+    assert_eq!(reassembler.push(&fragments[0]), Ok(None));
+    assert_eq!(reassembler.pending(), 1);
+    assert_eq!(reassembler.push(&fragments[1]), Ok(Some(packet)));
+    assert_eq!(reassembler.pending(), 0);
+    assert_eq!(reassembler.memory_used(), 0);
+    assert_eq!(reassembler.stats().reassembled, 1);
+  }
+
+  #[test]
+  fn ipv4_options_and_flags() {
+    let mut packet = udp("10.0.0.1:1000", "10.0.0.2:2000", 100);
+    //A copied option (type 0x88, stream identifier) and one that isn't (record route)
+    let options = [0x88, 4, 0xAB, 0xCD, 0x07, 3, 4, 0];
+    packet.splice(20..20, options);
+    packet[0] = 0x47;
+    let total_len = packet.len() as u16;
+    packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+    super::set_header_checksum(&mut packet, 28);
+
+    let fragments = fragmenter(80).fragment(&packet).unwrap();
+    assert_eq!(&fragments[0][..4], &[0x47, 0, 0, 76]);
+    for fragment in &fragments[1..] {
+      assert_eq!(fragment[0], 0x46);
+      assert_eq!(&fragment[20..24], &[0x88, 4, 0xAB, 0xCD]);
+      assert_eq!(checksum(&fragment[..24]), 0);
+    }
+    let mut reassembler = Reassembler::new();
+    let mut datagram = None;
+    for fragment in &fragments {
+      datagram = reassembler.push(fragment).unwrap();
+    }
+    assert_eq!(datagram, Some(packet.clone()));
+
+    //Packets that fit are left alone, even with don't fragment set
+    packet[6] = 0x40;
+    super::set_header_checksum(&mut packet, 28);
+    assert_eq!(fragmenter(1500).fragment(&packet), Ok(vec![packet.clone()]));
+    assert_eq!(
+      fragmenter(80).fragment(&packet),
+      Err(FragmentError::DontFragment)
+    );
+    packet[6] = 0;
+    assert_eq!(
+      fragmenter(30).fragment(&packet),
+      Err(FragmentError::MtuTooSmall)
+    );
+  }
+
+  #[test]
+  fn ipv6_roundtrip() {
+    let mut packet = udp("[fd00::1]:1000", "[fd00::2]:2000", 3000);
+    //A hop-by-hop options header, which stays in front of the fragment header
+    packet.splice(40..40, [17, 0, 1, 4, 0, 0, 0, 0]);
+    packet[6] = 0;
+    let payload_len = (packet.len() - 40) as u16;
+    packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+
+    let mut fragmenter = fragmenter(1280);
+    let fragments = fragmenter.fragment(&packet).unwrap();
+    assert_eq!(fragments.len(), 3);
+    let mut expected_offset = 0;
+    for (index, fragment) in fragments.iter().enumerate() {
+      assert!(fragment.len() <= 1280);
+      assert_eq!(fragment[40], 44);
+      assert_eq!(fragment[48], 17);
+      let field = u16::from_be_bytes([fragment[50], fragment[51]]);
+      assert_eq!(
+        ((field & !7) as usize, field & 1 != 0),
+        (expected_offset, index < 2)
+      );
+      assert_eq!(fragment[52..56], fragments[0][52..56]);
+      let parsed = IpPacket::parse(fragment).unwrap();
+      assert!(parsed.is_fragment());
+      assert_eq!(parsed.protocol(), IpProtocol::Udp);
+      expected_offset += fragment.len() - 56;
+    }
+    fragmenter.set_mtu(1000.try_into().unwrap());
+    assert_eq!(
+      fragmenter.fragment(&fragments[0]),
+      Err(FragmentError::AlreadyFragmented)
+    );
+
+    let mut reassembler = Reassembler::new();
+    assert_eq!(reassembler.push(&fragments[1]), Ok(None));
+    assert_eq!(reassembler.push(&fragments[2]), Ok(None));
+    assert_eq!(reassembler.push(&fragments[0]), Ok(Some(packet)));
+  }
+
+  #[test]
+  fn ipv6_destination_options_before_the_fragment_header() {
+    let with_options = |packet: &[u8], next_header: u8| {
+      let mut packet = packet.to_vec();
+      packet.splice(40..40, [next_header, 0, 1, 4, 0, 0, 0, 0]);
+      packet[6] = 60;
+      let payload_len = (packet.len() - 40) as u16;
+      packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+      packet
+    };
+    let packet = udp("[fd00::1]:1000", "[fd00::2]:2000", 2000);
+    let fragments = fragmenter(1280).fragment(&packet).unwrap();
+    assert_eq!(fragments.len(), 2);
+    let mut reassembler = Reassembler::new();
+    assert_eq!(reassembler.push(&with_options(&fragments[1], 44)), Ok(None));
+    let reassembled = reassembler
+      .push(&with_options(&fragments[0], 44))
+      .unwrap()
+      .unwrap();
+    //The options header now points at UDP, the fixed header still at the options
+    assert_eq!(reassembled, with_options(&packet, 17));
+  }
+
+  #[test]
+  fn overlaps_drop_the_datagram() {
+    let packet = udp("[fd00::1]:1000", "[fd00::2]:2000", 200);
+    let fragments = fragmenter(120).fragment(&packet).unwrap();
+    let mut overlapping = fragments[1].clone();
+    //Moves the second fragment 8 bytes back, into the first
+    let field = u16::from_be_bytes([overlapping[42], overlapping[43]]) - 8;
+    overlapping[42..44].copy_from_slice(&field.to_be_bytes());
+
+    let mut reassembler = Reassembler::new();
+    assert_eq!(reassembler.push(&fragments[0]), Ok(None));
+    assert_eq!(
+      reassembler.push(&overlapping),
+      Err(ReassemblyError::Overlap)
+    );
+    assert_eq!(reassembler.pending(), 0);
+    assert_eq!(reassembler.memory_used(), 0);
+    //What arrives later starts a new datagram that never completes
+    for fragment in &fragments[1..] {
+      assert_eq!(reassembler.push(fragment), Ok(None));
+    }
+    assert_eq!(reassembler.stats().invalid, 1);
+
+    //Fragments other than the last have to be a multiple of 8 bytes
+    let mut short = fragments[0].clone();
+    short.pop();
+    let payload_len = (short.len() - 40) as u16;
+    short[4..6].copy_from_slice(&payload_len.to_be_bytes());
+    assert_eq!(reassembler.push(&short), Err(ReassemblyError::Misaligned));
+  }
+
+  #[test]
+  fn timeouts_and_memory_limit() {
+    let clock = ManualClock::new();
+    let mut reassembler = Reassembler::new()
+      .with_clock(Arc::new(clock.clone()))
+      .with_timeout(Duration::from_secs(30))
+      .with_memory_limit(2000);
+    let mut fragmenter = fragmenter(1000);
+    let first = fragmenter
+      .fragment(&udp("10.0.0.1:1000", "10.0.0.2:2000", 1500))
+      .unwrap();
+    assert_eq!(reassembler.push(&first[0]), Ok(None));
+    clock.advance(Duration::from_secs(30));
+    assert_eq!(reassembler.push(&first[1]), Ok(None));
+    assert_eq!(reassembler.stats().timed_out, 1);
+
+    //The oldest datagram makes room for newer ones
+    clock.advance(Duration::from_secs(1));
+    let second = fragmenter
+      .fragment(&udp("10.0.0.3:1000", "10.0.0.2:2000", 1500))
+      .unwrap();
+    let third = fragmenter
+      .fragment(&udp("10.0.0.4:1000", "10.0.0.2:2000", 1500))
+      .unwrap();
+    assert_eq!(reassembler.push(&second[0]), Ok(None));
+    assert_eq!(reassembler.push(&third[0]), Ok(None));
+    assert_eq!(reassembler.pending(), 2);
+    assert_eq!(reassembler.stats().evicted, 1);
+    assert!(reassembler.memory_used() <= 2000);
+    assert!(reassembler.push(&third[1]).unwrap().is_some());
+  }
+
+  #[test]
+  fn rejections_are_counted() {
+    //Fragments that don't fit on their own evict nothing
+    let mut reassembler = Reassembler::new().with_memory_limit(500);
+    let fragments = fragmenter(1000)
+      .fragment(&udp("10.0.0.1:1000", "10.0.0.2:2000", 1500))
+      .unwrap();
+    assert_eq!(
+      reassembler.push(&fragments[0]),
+      Err(ReassemblyError::MemoryLimit)
+    );
+    assert_eq!(reassembler.stats().evicted, 0);
+    assert_eq!(reassembler.stats().rejected, 1);
+
+    //An atomic fragment that reassembles past the largest packet
+    let data_len = u16::MAX as usize - 8;
+    let mut atomic = vec![0; 48 + data_len];
+    atomic[0] = 0x60;
+    atomic[4..6].copy_from_slice(&u16::MAX.to_be_bytes());
+    atomic[6] = 44;
+    atomic[7] = 64;
+    atomic[8..24].copy_from_slice(&"fd00::1".parse::<Ipv6Addr>().unwrap().octets());
+    atomic[24..40].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+    atomic[40] = 17;
+    assert_eq!(reassembler.push(&atomic), Err(ReassemblyError::TooLong));
+    assert_eq!(reassembler.stats().invalid, 1);
+    assert_eq!(reassembler.stats().reassembled, 0);
+  }
+
+  #[test]
+  fn session_roundtrip() {
+    let adapter = Adapter::create_in_memory("fragments", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.session(RingCapacity::min()).unwrap();
+    let packet = udp("10.0.0.1:1000", "10.0.0.2:2000", 4000);
+    let sent = fragmenter(1400)
+      .send(&packet, |size| session.allocate(size))
+      .unwrap();
+    assert_eq!(sent, 3);
+
+    let mut reassembler = Reassembler::new();
+    let mut reassembled = None;
+    while let Some(fragment) = peer.try_recv() {
+      assert!(fragment.len() <= 1400);
+      //Sends the fragments back in through the session
+      peer.inject(&fragment).unwrap();
+      if let Some(datagram) = reassembler.reassemble(session.recv().unwrap()).unwrap() {
+        assert!(matches!(datagram, Datagram::Reassembled(_)));
+        reassembled = Some(datagram.to_vec());
+      }
+    }
+    assert_eq!(reassembled, Some(packet));
+
+    //Whole packets are handed on as they were received
+    let small = ip_packet(
+      "10.0.0.1".parse::<IpAddr>().unwrap(),
+      "10.0.0.2".parse().unwrap(),
+      IpProtocol::Other(253),
+      &[1, 2, 3],
+    )
+    .unwrap();
+    peer.inject(&small).unwrap();
+    let datagram = reassembler
+      .reassemble(session.recv().unwrap())
+      .unwrap()
+      .unwrap();
+    assert!(matches!(datagram, Datagram::Packet(_)));
+    assert_eq!(&*datagram, &small[..]);
+  }
+}
@@ -0,0 +1,394 @@
+//! Parsing and formatting of guids.
+//!
+//! Guids are passed around as `u128`s holding the guid the way it is written, so
+//! `{0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9}` is `0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9`
+//!
+//! # Breaking change
+//!
+//! Earlier versions reinterpreted the Windows `GUID` struct as a `u128` in memory, so the guids
+//! passed to [`Adapter::create`](crate::Adapter::create) and returned by
+//! [`Adapter::get_guid`](crate::Adapter::get_guid) had their first three groups byte swapped and
+//! the last two reversed. The same `u128` now names a different adapter on Windows. Values stored
+//! by earlier versions are converted with [`guid_from_native_layout`]
+
+use std::fmt;
+
+/// Lengths in hex digits of the hyphen separated groups
+const GROUPS: [usize; 5] = [8, 4, 4, 4, 12];
+const URN_PREFIX: &str = "urn:uuid:";
+
+/// What the parser was looking for when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuidToken {
+  HexDigit,
+  Hyphen,
+  OpeningBrace,
+  ClosingBrace,
+}
+
+impl fmt::Display for GuidToken {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      GuidToken::HexDigit => "a hex digit",
+      GuidToken::Hyphen => "'-'",
+      GuidToken::OpeningBrace => "'{'",
+      GuidToken::ClosingBrace => "'}'",
+    })
+  }
+}
+
+/// Positions are byte offsets into the parsed string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuidParseError {
+  /// The string ended while `expected` was still missing
+  UnexpectedEnd {
+    position: usize,
+    expected: GuidToken,
+  },
+  UnexpectedCharacter {
+    position: usize,
+    found: char,
+    expected: GuidToken,
+  },
+  /// The guid was complete but more characters followed it
+  TrailingCharacters { position: usize },
+}
+
+impl GuidParseError {
+  pub fn position(&self) -> usize {
+    match *self {
+      GuidParseError::UnexpectedEnd { position, .. }
+      | GuidParseError::UnexpectedCharacter { position, .. }
+      | GuidParseError::TrailingCharacters { position } => position,
+    }
+  }
+}
+
+impl fmt::Display for GuidParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      GuidParseError::UnexpectedEnd { position, expected } => f.write_fmt(format_args!(
+        "Guid ended at position {position}, expected {expected}"
+      )),
+      GuidParseError::UnexpectedCharacter {
+        position,
+        found,
+        expected,
+      } => f.write_fmt(format_args!(
+        "Expected {expected} at position {position} of the guid, found {found:?}"
+      )),
+      GuidParseError::TrailingCharacters { position } => f.write_fmt(format_args!(
+        "Unexpected characters after the guid at position {position}"
+      )),
+    }
+  }
+}
+
+impl std::error::Error for GuidParseError {}
+
+struct Parser<'a> {
+  text: &'a str,
+  position: usize,
+}
+
+impl Parser<'_> {
+  fn fail(&self, expected: GuidToken) -> GuidParseError {
+    match self.text[self.position..].chars().next() {
+      Some(found) => GuidParseError::UnexpectedCharacter {
+        position: self.position,
+        found,
+        expected,
+      },
+      None => GuidParseError::UnexpectedEnd {
+        position: self.position,
+        expected,
+      },
+    }
+  }
+  fn expect(&mut self, byte: u8, token: GuidToken) -> Result<(), GuidParseError> {
+    if self.text.as_bytes().get(self.position) != Some(&byte) {
+      return Err(self.fail(token));
+    }
+    self.position += 1;
+    Ok(())
+  }
+  fn hex_digit(&mut self) -> Result<u128, GuidParseError> {
+    let digit = self
+      .text
+      .as_bytes()
+      .get(self.position)
+      .and_then(|&byte| (byte as char).to_digit(16))
+      .ok_or_else(|| self.fail(GuidToken::HexDigit))?;
+    self.position += 1;
+    Ok(digit as u128)
+  }
+}
+
+/// Parses a guid in any of the forms Windows and other tools print them in:
+///
+/// - `0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9`
+/// - `{0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9}`
+/// - `urn:uuid:0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9`
+/// - registry keys and device names ending in a braced guid, like
+///   `\DEVICE\TCPIP_{0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9}`
+///
+/// Hex digits may be in either case
+///
+/// ```
+/// assert_eq!(
+///   wintun2::parse_guid("urn:uuid:0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9"),
+///   Ok(0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9)
+/// );
+/// ```
+pub fn parse_guid(text: &str) -> Result<u128, GuidParseError> {
+  let (start, braced) = if let Some(separator) = text.rfind('\\') {
+    //The guid is in braces at the end of the last path component
+    let open = text[separator..]
+      .find('{')
+      .ok_or(GuidParseError::UnexpectedEnd {
+        position: text.len(),
+        expected: GuidToken::OpeningBrace,
+      })?;
+    (separator + open + 1, true)
+  } else if text
+    .get(..URN_PREFIX.len())
+    .is_some_and(|prefix| prefix.eq_ignore_ascii_case(URN_PREFIX))
+  {
+    (URN_PREFIX.len(), false)
+  } else if text.starts_with('{') {
+    (1, true)
+  } else {
+    (0, false)
+  };
+  let mut parser = Parser {
+    text,
+    position: start,
+  };
+  let mut guid = 0u128;
+  for (i, length) in GROUPS.into_iter().enumerate() {
+    if i > 0 {
+      parser.expect(b'-', GuidToken::Hyphen)?;
+    }
+    for _ in 0..length {
+      guid = guid << 4 | parser.hex_digit()?;
+    }
+  }
+  if braced {
+    parser.expect(b'}', GuidToken::ClosingBrace)?;
+  }
+  if parser.position < text.len() {
+    return Err(GuidParseError::TrailingCharacters {
+      position: parser.position,
+    });
+  }
+  Ok(guid)
+}
+
+/// Converts a guid from the layout earlier versions used, the Windows `GUID` struct read as a
+/// little endian `u128`, to the written form
+///
+/// ```
+/// assert_eq!(
+///   wintun2::guid_from_native_layout(0xF9E8D7C6_B5A49382_60714E5F_0A1B2C3D),
+///   0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9
+/// );
+/// ```
+pub fn guid_from_native_layout(raw: u128) -> u128 {
+  let bytes = raw.to_le_bytes();
+  let data1 = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+  let data2 = u16::from_le_bytes([bytes[4], bytes[5]]);
+  let data3 = u16::from_le_bytes([bytes[6], bytes[7]]);
+  let mut data4 = [0; 8];
+  data4.copy_from_slice(&bytes[8..]);
+  (data1 as u128) << 96
+    | (data2 as u128) << 80
+    | (data3 as u128) << 64
+    | u64::from_be_bytes(data4) as u128
+}
+
+/// Inverse of [`guid_from_native_layout`]
+pub fn guid_to_native_layout(guid: u128) -> u128 {
+  let mut bytes = [0; 16];
+  bytes[..4].copy_from_slice(&((guid >> 96) as u32).to_le_bytes());
+  bytes[4..6].copy_from_slice(&((guid >> 80) as u16).to_le_bytes());
+  bytes[6..8].copy_from_slice(&((guid >> 64) as u16).to_le_bytes());
+  bytes[8..].copy_from_slice(&(guid as u64).to_be_bytes());
+  u128::from_le_bytes(bytes)
+}
+
+/// Formats a guid the way Windows shows it, `{XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX}`
+pub fn format_guid(guid: u128) -> String {
+  format!(
+    "{{{:08X}-{:04X}-{:04X}-{:04X}-{:012X}}}",
+    guid >> 96,
+    (guid >> 80) & 0xFFFF,
+    (guid >> 64) & 0xFFFF,
+    (guid >> 48) & 0xFFFF,
+    guid & 0xFFFF_FFFF_FFFF
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use proptest::prelude::*;
+
+  use super::{
+    format_guid, guid_from_native_layout, guid_to_native_layout, parse_guid, GuidParseError,
+    GuidToken,
+  };
+
+  const GUID: u128 = 0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9;
+  const HYPHENATED: &str = "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9";
+
+  #[test]
+  fn accepted_forms() {
+    for text in [
+      HYPHENATED.to_owned(),
+      HYPHENATED.to_uppercase(),
+      format!("{{{HYPHENATED}}}"),
+      format!("urn:uuid:{HYPHENATED}"),
+      format!("URN:UUID:{HYPHENATED}"),
+      format!("\\DEVICE\\TCPIP_{{{HYPHENATED}}}"),
+      format!(
+        "SYSTEM\\CurrentControlSet\\Services\\Tcpip\\Parameters\\Interfaces\\{{{HYPHENATED}}}"
+      ),
+    ] {
+      assert_eq!(parse_guid(&text), Ok(GUID), "{text}");
+    }
+    assert_eq!(format_guid(GUID), "{0A1B2C3D-4E5F-6071-8293-A4B5C6D7E8F9}");
+    assert_eq!(parse_guid(&format_guid(u128::MAX)), Ok(u128::MAX));
+    assert_eq!(parse_guid(&format_guid(0)), Ok(0));
+  }
+
+  #[test]
+  fn native_layout_is_pinned() {
+    //The bytes of the Windows GUID struct holding GUID, in memory order
+    let native = [
+      0x3D, 0x2C, 0x1B, 0x0A, 0x5F, 0x4E, 0x71, 0x60, 0x82, 0x93, 0xA4, 0xB5, 0xC6, 0xD7, 0xE8,
+      0xF9,
+    ];
+    assert_eq!(guid_to_native_layout(GUID), u128::from_le_bytes(native));
+    assert_eq!(guid_from_native_layout(u128::from_le_bytes(native)), GUID);
+  }
+
+  #[test]
+  fn errors_point_at_the_offending_character() {
+    assert_eq!(
+      parse_guid(""),
+      Err(GuidParseError::UnexpectedEnd {
+        position: 0,
+        expected: GuidToken::HexDigit
+      })
+    );
+    assert_eq!(
+      parse_guid("0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8fg"),
+      Err(GuidParseError::UnexpectedCharacter {
+        position: 35,
+        found: 'g',
+        expected: GuidToken::HexDigit
+      })
+    );
+    assert_eq!(
+      parse_guid("{0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9"),
+      Err(GuidParseError::UnexpectedEnd {
+        position: 37,
+        expected: GuidToken::ClosingBrace
+      })
+    );
+    assert_eq!(
+      parse_guid("0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9}"),
+      Err(GuidParseError::TrailingCharacters { position: 36 })
+    );
+    assert_eq!(
+      parse_guid("0a1b2c3d4e5f-6071-8293-a4b5c6d7e8f9"),
+      Err(GuidParseError::UnexpectedCharacter {
+        position: 8,
+        found: '4',
+        expected: GuidToken::Hyphen
+      })
+    );
+    assert_eq!(
+      parse_guid("\\DEVICE\\TCPIP_"),
+      Err(GuidParseError::UnexpectedEnd {
+        position: 14,
+        expected: GuidToken::OpeningBrace
+      })
+    );
+    //Multi-byte characters are reported whole at their byte offset
+    assert_eq!(
+      parse_guid("{0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8fé}"),
+      Err(GuidParseError::UnexpectedCharacter {
+        position: 36,
+        found: 'é',
+        expected: GuidToken::HexDigit
+      })
+    );
+    assert_eq!(
+      parse_guid("urn:uuid:").unwrap_err().to_string(),
+      "Guid ended at position 9, expected a hex digit"
+    );
+  }
+
+  #[test]
+  fn every_position_rejects_wrong_characters() {
+    let braced = format!("{{{HYPHENATED}}}");
+    for position in 0..braced.len() {
+      let expected = match braced.as_bytes()[position] {
+        b'{' => continue,
+        b'}' => GuidToken::ClosingBrace,
+        b'-' => GuidToken::Hyphen,
+        _ => GuidToken::HexDigit,
+      };
+      for replacement in ['x', '-', '}', ' ', 'ü'] {
+        if braced.as_bytes()[position] == replacement as u8 {
+          continue;
+        }
+        let mut text = braced.clone();
+        text.replace_range(position..position + 1, replacement.encode_utf8(&mut [0; 4]));
+        assert_eq!(
+          parse_guid(&text),
+          Err(GuidParseError::UnexpectedCharacter {
+            position,
+            found: replacement,
+            expected
+          }),
+          "{text}"
+        );
+      }
+      //Cutting the guid short reports the end at the same position
+      assert_eq!(
+        parse_guid(&braced[..position]).map_err(|err| err.position()),
+        Err(position),
+        "{}",
+        &braced[..position]
+      );
+    }
+  }
+
+  proptest! {
+    #[test]
+    fn format_roundtrips(guid: u128) {
+      let formatted = format_guid(guid);
+      prop_assert_eq!(parse_guid(&formatted), Ok(guid));
+      prop_assert_eq!(parse_guid(&formatted[1..37]), Ok(guid));
+      prop_assert_eq!(parse_guid(&format!("urn:uuid:{}", formatted[1..37].to_lowercase())), Ok(guid));
+    }
+
+    #[test]
+    fn never_panics(text in "\\PC{0,48}") {
+      if let Err(err) = parse_guid(&text) {
+        prop_assert!(err.position() <= text.len());
+        prop_assert!(text.is_char_boundary(err.position()));
+      }
+    }
+
+    #[test]
+    fn only_well_formed_strings_parse(text in "[{}0-9a-f:nrdiu-]{36,45}") {
+      if let Ok(guid) = parse_guid(&text) {
+        let hyphenated = format_guid(guid)[1..37].to_lowercase();
+        let forms = [format!("{{{hyphenated}}}"), format!("urn:uuid:{hyphenated}"), hyphenated];
+        prop_assert!(forms.contains(&text), "{} parsed", text);
+      }
+    }
+  }
+}
@@ -1,21 +1,80 @@
 mod adapter;
+mod backend;
+mod clock;
+#[cfg(feature = "smoltcp")]
+mod device;
+mod dialer;
+mod dns;
+mod driver;
+mod error;
+mod firewall;
+mod flow;
+#[cfg(not(windows))]
+mod errno;
+mod fakeip;
+mod fragment;
+#[cfg(test)]
+mod fixtures;
+mod guid;
+mod mss;
+mod mtu;
+mod nat;
 mod packet;
+mod packet_io;
+mod pipeline;
+mod ring;
 mod session;
+mod simulation;
+mod socket;
+mod socks5;
+mod split;
+mod supervisor;
+mod sweep;
+mod test_peer;
+#[cfg(feature = "tun2socks")]
+mod tun2socks;
+#[cfg(windows)]
 mod utility;
 pub mod wintun_raw;
+mod wire;
 
 pub use adapter::*;
+pub use backend::memory::MemoryPeer;
+pub use clock::*;
+#[cfg(feature = "smoltcp")]
+pub use device::*;
+pub use dialer::*;
+pub use dns::*;
+pub use driver::*;
+pub use error::*;
+pub use firewall::*;
+pub use flow::*;
+#[cfg(not(windows))]
+pub use errno::Errno;
+pub use fakeip::*;
+pub use fragment::*;
+pub use guid::*;
+pub use mss::*;
+pub use mtu::*;
+pub use nat::*;
 pub use packet::*;
+pub use packet_io::*;
+pub use pipeline::*;
+pub use ring::*;
 pub use session::*;
+pub use simulation::*;
+pub use socks5::*;
+pub use split::*;
+pub use supervisor::*;
+pub use sweep::*;
+pub use test_peer::*;
+#[cfg(feature = "tun2socks")]
+pub use tun2socks::*;
+pub use wire::*;
 
-use get_last_error::Win32Error;
-use std::net::{Ipv4Addr, Ipv6Addr};
-use widestring::WideCStr;
-use winapi::shared::{basetsd::DWORD64, ntdef::LPCWSTR, winerror};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use self::wintun_raw::{
-  WintunDeleteDriver, WintunGetRunningDriverVersion, WintunSetLogger, DWORD, WINTUN_LOGGER_LEVEL,
-};
+use self::wintun_raw::WINTUN_LOGGER_LEVEL;
 
 /// Maximum adapter name length including zero terminator
 pub const MAX_ADAPTER_NAME: usize = 128;
@@ -23,284 +82,39 @@ pub const MIN_RING_CAPACITY: u32 = 0x20000;
 pub const MAX_RING_CAPACITY: u32 = 0x4000000;
 pub const MAX_IP_PACKET_SIZE: u32 = 0xFFFF;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum GetRunningDriverVersionError {
-  WintunNotLoaded,
-  Other(Win32Error),
-}
-
-impl GetRunningDriverVersionError {
-  pub fn is_not_loaded(self) -> bool {
-    matches!(self, Self::WintunNotLoaded)
-  }
-}
-
-impl std::fmt::Debug for GetRunningDriverVersionError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    let wte: WintunError = (*self).into();
-    f.write_fmt(format_args!("{wte:?}"))
-  }
-}
-
-impl std::fmt::Display for GetRunningDriverVersionError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_fmt(format_args!("{self:?}"))
-  }
-}
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum ReceivePacketError {
-  AdapterIsTerminating,
-  WouldBlock,
-  InvalidData,
-  Other(Win32Error),
-}
-
-impl ReceivePacketError {
-  pub fn is_adapter_terminating(self) -> bool {
-    matches!(self, Self::AdapterIsTerminating)
-  }
-  pub fn is_would_block(self) -> bool {
-    matches!(self, Self::WouldBlock)
-  }
-  pub fn is_invalid_data(self) -> bool {
-    matches!(self, Self::InvalidData)
-  }
-}
-
-impl std::fmt::Debug for ReceivePacketError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    let wte: WintunError = (*self).into();
-    f.write_fmt(format_args!("{wte:?}"))
-  }
-}
-
-impl std::fmt::Display for ReceivePacketError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_fmt(format_args!("{self:?}"))
-  }
-}
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum AllocatePacketError {
-  AdapterIsTerminating,
-  WouldBlock,
-  Other(Win32Error),
-}
-
-impl AllocatePacketError {
-  pub fn is_adapter_terminating(self) -> bool {
-    matches!(self, Self::AdapterIsTerminating)
-  }
-  pub fn is_would_block(self) -> bool {
-    matches!(self, Self::WouldBlock)
-  }
-}
-
-impl std::fmt::Debug for AllocatePacketError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    let wte: WintunError = (*self).into();
-    f.write_fmt(format_args!("{wte:?}"))
-  }
-}
-
-impl std::fmt::Display for AllocatePacketError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_fmt(format_args!("{self:?}"))
-  }
-}
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum RingCapacityError {
-  InvalidInput,
-}
-
-impl std::fmt::Display for RingCapacityError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_str("Invalid ring capacity")
-  }
-}
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum IpPacketSizeError {
-  InvalidInput,
-}
-
-impl std::fmt::Display for IpPacketSizeError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_str("Invalid ip packet size")
-  }
-}
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum IpMaskPrefixError {
-  InvalidInput,
-}
-
-impl std::fmt::Display for IpMaskPrefixError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_str("Invalid ip mask prefix. It should be in range 0..=32 or 0..=128 for Ipv4 and Ipv6 respectively")
-  }
-}
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum WintunError {
-  TooLongName { max: usize, got: usize },
-  ContainsNull(usize),
-  InvalidRingCapacity,
-  InvalidPacketSize,
-  InvalidIpMaskPrefix,
-  WintunNotLoaded,
-  AdapterIsTerminating,
-  WouldBlock,
-  InvalidData,
-  InterfaceNotFound,
-  Other(Win32Error),
-}
-
-impl From<GetRunningDriverVersionError> for WintunError {
-  fn from(value: GetRunningDriverVersionError) -> Self {
-    match value {
-      GetRunningDriverVersionError::WintunNotLoaded => Self::WintunNotLoaded,
-      GetRunningDriverVersionError::Other(err) => Self::Other(err),
-    }
-  }
-}
-
-impl From<ReceivePacketError> for WintunError {
-  fn from(value: ReceivePacketError) -> Self {
-    match value {
-      ReceivePacketError::AdapterIsTerminating => Self::AdapterIsTerminating,
-      ReceivePacketError::InvalidData => Self::InvalidData,
-      ReceivePacketError::WouldBlock => Self::WouldBlock,
-      ReceivePacketError::Other(err) => Self::Other(err),
-    }
-  }
-}
-
-impl From<AllocatePacketError> for WintunError {
-  fn from(value: AllocatePacketError) -> Self {
-    match value {
-      AllocatePacketError::AdapterIsTerminating => Self::AdapterIsTerminating,
-      AllocatePacketError::WouldBlock => Self::WouldBlock,
-      AllocatePacketError::Other(err) => Self::Other(err),
-    }
-  }
-}
-
-impl From<RingCapacityError> for WintunError {
-  fn from(_: RingCapacityError) -> Self {
-    Self::InvalidRingCapacity
-  }
-}
-
-impl From<IpPacketSizeError> for WintunError {
-  fn from(_: IpPacketSizeError) -> Self {
-    Self::InvalidPacketSize
-  }
-}
-
-impl From<IpMaskPrefixError> for WintunError {
-  fn from(_: IpMaskPrefixError) -> Self {
-    Self::InvalidIpMaskPrefix
-  }
-}
-
-impl std::fmt::Debug for WintunError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    match self {
-      WintunError::TooLongName { max, got } => f.write_fmt(format_args!(
-        "WintunError: Too long string supplied. Max expected: {max}, received: {got}"
-      )),
-      WintunError::ContainsNull(pos) => f.write_fmt(format_args!(
-        "WintunError: Received null byte in string at position: {pos}"
-      )),
-      WintunError::Other(err) => {
-        f.write_fmt(format_args!("WintunError: Win32Error: {}", err.to_string()))
-      }
-      WintunError::InvalidRingCapacity => f.write_fmt(format_args!(
-        "WintunError: Ring capacity should be in range {MIN_RING_CAPACITY}..={MAX_RING_CAPACITY} and be a power of two"
-      )),
-      WintunError::InvalidPacketSize => f.write_fmt(format_args!(
-        "WintunError: Ip packet size should be in range 1..={MAX_IP_PACKET_SIZE}"
-      )),
-      WintunError::InvalidIpMaskPrefix => f.write_str(
-        "WintunError: Ip mask prefix should be in range 0..=32 or 0..=128 for Ipv4 and Ipv6 respectively"
-      ),
-      WintunError::WintunNotLoaded => f.write_str("Wintun driver is not loaded"),
-      WintunError::AdapterIsTerminating => f.write_str("Tried to perform operation on terminated adapter"),
-      WintunError::WouldBlock => f.write_str("Requested operation would block"),
-      WintunError::InvalidData => f.write_str("Buffer contained invalid data"),
-      WintunError::InterfaceNotFound => f.write_str("Failed to find interface for specified guid"),
-    }
-  }
-}
-
-impl std::fmt::Display for WintunError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    f.write_fmt(format_args!("{self:?}"))
-  }
-}
-
-impl std::error::Error for WintunError {}
-impl std::error::Error for GetRunningDriverVersionError {}
-impl std::error::Error for ReceivePacketError {}
-impl std::error::Error for AllocatePacketError {}
-impl std::error::Error for RingCapacityError {}
-impl std::error::Error for IpPacketSizeError {}
-impl std::error::Error for IpMaskPrefixError {}
-
-impl From<Win32Error> for WintunError {
-  fn from(value: Win32Error) -> Self {
-    Self::Other(value)
-  }
-}
-
-pub type WintunResult<T> = Result<T, WintunError>;
-
 pub fn delete_driver() -> WintunResult<()> {
-  let result = unsafe { WintunDeleteDriver() };
-  if result {
-    return Ok(());
-  }
-  Err(Win32Error::get_last_error().into())
+  backend::native::delete_driver().map_err(|err| err.context(Operation::DeleteDriver, None))
 }
-pub fn get_running_driver_version() -> Result<DWORD, GetRunningDriverVersionError> {
-  let version = unsafe { WintunGetRunningDriverVersion() };
-  if version != 0 {
-    return Ok(version);
-  }
-  let error = Win32Error::get_last_error();
-  if error.code() == winerror::ERROR_FILE_NOT_FOUND {
-    Err(GetRunningDriverVersionError::WintunNotLoaded)
-  } else {
-    Err(GetRunningDriverVersionError::Other(error))
-  }
+/// Returns the version of the running driver. On Linux this is the version of the `tun` kernel
+/// module
+pub fn get_running_driver_version() -> Result<DriverVersion, GetRunningDriverVersionError> {
+  backend::native::get_running_driver_version()
 }
 pub trait LoggerCallback:
   Fn(WINTUN_LOGGER_LEVEL, std::time::SystemTime, &str) + Send + Sync
 {
 }
+impl<F> LoggerCallback for F where
+  F: Fn(WINTUN_LOGGER_LEVEL, std::time::SystemTime, &str) + Send + Sync
+{
+}
 static CURRENT_LOGGER: std::sync::RwLock<Option<Box<dyn LoggerCallback>>> =
   std::sync::RwLock::new(None);
-extern "C" fn logger_callback_wrapper(
-  level: WINTUN_LOGGER_LEVEL,
-  timestamp: DWORD64,
-  message: LPCWSTR,
-) {
+/// Forwards a message to the logger installed with [`set_logger`], if any
+pub(crate) fn log(level: WINTUN_LOGGER_LEVEL, timestamp: std::time::SystemTime, message: &str) {
   let Ok(logger) = CURRENT_LOGGER.read() else {return;};
   let Some(logger) = logger.as_ref() else {return};
-
-  const SECS_SINCE_1610_01_01_UNTIL_UNIX_TIMESTAMP: u64 = 131487 * 3600 * 24;
-  let diff = std::time::Duration::from_micros(timestamp) / 10
-    - std::time::Duration::from_secs(SECS_SINCE_1610_01_01_UNTIL_UNIX_TIMESTAMP);
-  let timestamp = std::time::SystemTime::UNIX_EPOCH + diff;
-  let message = unsafe { WideCStr::from_ptr_str(message) }.to_string_lossy();
-  logger(level, timestamp, &message)
+  logger(level, timestamp, message)
 }
 pub fn set_logger(new_logger: Option<impl LoggerCallback + 'static>) {
   let Ok(mut logger) = CURRENT_LOGGER.write() else {return;};
   if let Some(new_logger) = new_logger {
     let new_logger = Box::new(new_logger);
     logger.replace(new_logger);
-    unsafe { WintunSetLogger(Some(logger_callback_wrapper)) }
+    backend::native::set_logger_enabled(true)
   } else {
     logger.take();
-    unsafe { WintunSetLogger(None) }
+    backend::native::set_logger_enabled(false)
   }
 }
 
@@ -400,3 +214,39 @@ pub enum IpAndMaskPrefix {
     prefix: Ipv6MaskPrefix,
   },
 }
+
+impl IpAndMaskPrefix {
+  /// Whether `ip` lies in the network, addresses of the other family never do
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    match (self, ip) {
+      (IpAndMaskPrefix::V4 { ip: network, prefix }, IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - prefix.mask() as u32).unwrap_or(0);
+        u32::from(*network) & mask == u32::from(ip) & mask
+      }
+      (IpAndMaskPrefix::V6 { ip: network, prefix }, IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - prefix.mask() as u32).unwrap_or(0);
+        u128::from(*network) & mask == u128::from(ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+/// A route sending traffic for `destination` through the adapter. Without a `next_hop` the
+/// destination is treated as directly reachable on the link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+  pub destination: IpAndMaskPrefix,
+  pub next_hop: Option<IpAddr>,
+  pub metric: u32,
+}
+
+impl Route {
+  pub fn new(destination: IpAndMaskPrefix) -> Self {
+    Self {
+      destination,
+      next_hop: None,
+      metric: 0,
+    }
+  }
+}
@@ -0,0 +1,198 @@
+//! TCP MSS clamping. Hosts pick the maximum segment size they advertise from their own link,
+//! which is too large for a tunnel whose transport has a smaller MTU. [`MssClamp`] lowers the MSS
+//! option of SYN and SYN-ACK segments passing through a [`PacketPipeline`](crate::PacketPipeline)
+//! in either direction, so both ends send segments that fit
+
+use crate::wire::TCP_HEADER_LEN;
+use crate::{
+  update_checksum, IpPacket, IpPacketSize, IpProtocol, PacketProcessor, Verdict, TCP_SYN,
+};
+
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+/// IP and TCP headers without options
+const IPV4_OVERHEAD: u32 = 40;
+const IPV6_OVERHEAD: u32 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+  Mss(u16),
+  Mtu(IpPacketSize),
+}
+
+/// Lowers advertised MSS values above a limit. Segments without the option are left alone, the
+/// default they imply (536 for IPv4, 1220 for IPv6) is below any useful limit
+#[derive(Debug, Clone)]
+pub struct MssClamp {
+  limit: Limit,
+  clamped: u64,
+}
+
+impl MssClamp {
+  /// Clamps to `mss` for both address families
+  pub fn new(mss: u16) -> Self {
+    Self {
+      limit: Limit::Mss(mss),
+      clamped: 0,
+    }
+  }
+  /// Clamps to the largest segment that fits in `mtu` with IPv4 or IPv6 and TCP headers
+  /// without options
+  pub fn from_mtu(mtu: IpPacketSize) -> Self {
+    Self {
+      limit: Limit::Mtu(mtu),
+      clamped: 0,
+    }
+  }
+  /// Limit for segments in IP packets of `version`
+  pub fn mss(&self, version: u8) -> u16 {
+    match self.limit {
+      Limit::Mss(mss) => mss,
+      Limit::Mtu(mtu) => {
+        let overhead = match version {
+          4 => IPV4_OVERHEAD,
+          _ => IPV6_OVERHEAD,
+        };
+        mtu.size().saturating_sub(overhead).min(u16::MAX as u32) as u16
+      }
+    }
+  }
+  /// Segments whose MSS was lowered so far
+  pub fn clamped(&self) -> u64 {
+    self.clamped
+  }
+  /// Lowers the MSS of `packet` if it is a SYN advertising more than the limit. Returns whether
+  /// it was changed
+  pub fn clamp(&mut self, packet: &mut [u8]) -> bool {
+    let Ok(parsed) = IpPacket::parse(packet) else {
+      return false;
+    };
+    if parsed.protocol() != IpProtocol::Tcp
+      || parsed.tcp_flags().is_none_or(|flags| flags & TCP_SYN == 0)
+    {
+      return false;
+    }
+    let limit = self.mss(parsed.version());
+    let start = parsed.header().len();
+    let end = parsed.as_bytes().len();
+    let segment = &mut packet[start..end];
+    let Some(at) = mss_option(segment) else {
+      return false;
+    };
+    let mss = u16::from_be_bytes([segment[at], segment[at + 1]]);
+    if mss <= limit {
+      return false;
+    }
+    //The option can start at an odd offset, the checksum is updated over whole words around it
+    let first = at & !1;
+    let last = (at + 2).next_multiple_of(2).min(segment.len());
+    let old = segment[first..last].to_vec();
+    segment[at..at + 2].copy_from_slice(&limit.to_be_bytes());
+    let checksum = u16::from_be_bytes([segment[16], segment[17]]);
+    let checksum = update_checksum(checksum, &old, &segment[first..last]);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    self.clamped += 1;
+    true
+  }
+}
+
+/// Offset of the MSS value in the options of a TCP `segment`
+pub(crate) fn mss_option(segment: &[u8]) -> Option<usize> {
+  let header_len = (*segment.get(12)? >> 4) as usize * 4;
+  let options = segment.get(TCP_HEADER_LEN..header_len)?;
+  let mut at = 0;
+  while let Some(&kind) = options.get(at) {
+    match kind {
+      TCP_OPTION_END => return None,
+      TCP_OPTION_NOP => at += 1,
+      _ => {
+        let len = *options.get(at + 1)? as usize;
+        if len < 2 || at + len > options.len() {
+          return None;
+        }
+        if kind == TCP_OPTION_MSS && len == 4 {
+          return Some(TCP_HEADER_LEN + at + 2);
+        }
+        at += len;
+      }
+    }
+  }
+  None
+}
+
+impl PacketProcessor for MssClamp {
+  fn name(&self) -> &'static str {
+    "mss-clamp"
+  }
+  fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+    self.clamp(packet);
+    Verdict::Accept
+  }
+  fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+    self.clamp(packet);
+    Verdict::Accept
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::MssClamp;
+  use crate::{
+    fixtures::{checked, tcp},
+    TCP_ACK, TCP_SYN,
+  };
+
+  /// MSS 1460, NOP, window scale 7, SACK permitted and timestamps, as Linux sends them
+  const LINUX_OPTIONS: [u8; 20] = [
+    2, 4, 0x05, 0xB4, 4, 2, 8, 10, 0, 0, 0x12, 0x34, 0, 0, 0, 0, 1, 3, 3, 7,
+  ];
+  /// NOP, MSS 8960 at an odd offset, window scale, SACK permitted
+  const ODD_OPTIONS: [u8; 12] = [1, 2, 4, 0x23, 0x00, 1, 3, 3, 8, 4, 2, 0];
+
+  /// The MSS of a SYN, checking its checksums on the way
+  fn mss(packet: &[u8], at: usize) -> u16 {
+    let parsed = checked(packet);
+    let option = &parsed.payload()[20 + at..];
+    u16::from_be_bytes([option[0], option[1]])
+  }
+
+  #[test]
+  fn clamps_syns_of_both_families() {
+    let mut clamp = MssClamp::new(1360);
+    let mut packet = tcp("10.0.0.1:40000", "10.0.0.2:443", TCP_SYN, &LINUX_OPTIONS);
+    let options = packet[40..60].to_vec();
+    assert!(clamp.clamp(&mut packet));
+    assert_eq!(mss(&packet, 2), 1360);
+    //Only the MSS changes
+    assert_eq!(&packet[44..60], &options[4..]);
+
+    let mut packet = tcp(
+      "[fd00::2]:443",
+      "[fd00::1]:40000",
+      TCP_SYN | TCP_ACK,
+      &ODD_OPTIONS,
+    );
+    assert!(clamp.clamp(&mut packet));
+    assert_eq!(mss(&packet, 3), 1360);
+    assert_eq!(clamp.clamped(), 2);
+
+    //Already small enough, not a SYN or without the option
+    let mut packet = tcp("10.0.0.1:40000", "10.0.0.2:443", TCP_SYN, &[2, 4, 5, 0]);
+    assert!(!clamp.clamp(&mut packet));
+    assert_eq!(mss(&packet, 2), 1280);
+    let mut packet = tcp("10.0.0.1:40000", "10.0.0.2:443", TCP_ACK, &LINUX_OPTIONS);
+    assert!(!clamp.clamp(&mut packet));
+    let mut packet = tcp("10.0.0.1:40000", "10.0.0.2:443", TCP_SYN, &[1, 1, 3, 3, 7]);
+    assert!(!clamp.clamp(&mut packet));
+    assert_eq!(clamp.clamped(), 2);
+  }
+
+  #[test]
+  fn limits_from_the_mtu() {
+    let clamp = MssClamp::from_mtu(1400.try_into().unwrap());
+    assert_eq!(clamp.mss(4), 1360);
+    assert_eq!(clamp.mss(6), 1340);
+    assert_eq!(MssClamp::from_mtu(20.try_into().unwrap()).mss(4), 0);
+  }
+}
@@ -0,0 +1,290 @@
+//! Path MTU enforcement. Packets the system hands to the session that are larger than the
+//! transport carrying the tunnel can take are answered with ICMPv4 "fragmentation needed" or
+//! ICMPv6 "packet too big" (RFC 1191, RFC 8201), so the sending host lowers its path MTU instead of
+//! losing packets silently
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use crate::wire::{ICMP_ERRORS, ICMP_HEADER_LEN, IPV4_HEADER_LEN, IPV6_HEADER_LEN};
+use crate::{
+  checksum, ip_packet, transport_checksum, Clock, IpPacket, IpPacketSize, IpProtocol,
+  PacketProcessor, SystemClock, Verdict,
+};
+
+const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+/// Below 128 are errors, informational messages are 128 and up
+const ICMPV6_INFORMATIONAL: u8 = 128;
+const IPV4_DONT_FRAGMENT: u8 = 0x40;
+/// Largest ICMP error for each family, so the answer gets through any path (RFC 1812, RFC 4443)
+const IPV4_MAX_ERROR_LEN: usize = 576;
+const IPV6_MAX_ERROR_LEN: usize = 1280;
+const DEFAULT_RATE_LIMIT: u32 = 10;
+const DEFAULT_RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MtuGuardStats {
+  /// Received packets larger than the MTU
+  pub oversized: u64,
+  /// ICMP messages sent back
+  pub replies: u64,
+  /// Oversized packets dropped without an answer because too many were sent recently
+  pub rate_limited: u64,
+}
+
+/// Answers received packets larger than the MTU with an ICMP error quoting them and drops them.
+/// IPv4 packets without the don't fragment flag pass, fragmenting them is left to the transport
+/// (see [`Fragmenter`](crate::Fragmenter)). Answers are limited to 10 per second by default, and
+/// ICMP errors or packets from unspecified and multicast sources are never answered
+pub struct MtuGuard {
+  mtu: IpPacketSize,
+  /// Answers allowed per `interval`
+  burst: u32,
+  interval: Duration,
+  /// Answers that can be sent right now, refilled over time
+  tokens: f64,
+  refilled: Duration,
+  clock: Arc<dyn Clock>,
+  stats: MtuGuardStats,
+}
+
+impl MtuGuard {
+  pub fn new(mtu: IpPacketSize) -> Self {
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
+    Self {
+      mtu,
+      burst: DEFAULT_RATE_LIMIT,
+      interval: DEFAULT_RATE_INTERVAL,
+      tokens: DEFAULT_RATE_LIMIT as f64,
+      refilled: clock.now(),
+      clock,
+      stats: MtuGuardStats::default(),
+    }
+  }
+  /// Allows `burst` answers at once and as many per `interval` on average
+  pub fn with_rate_limit(mut self, burst: u32, interval: Duration) -> Self {
+    self.burst = burst;
+    self.interval = interval;
+    self.tokens = burst as f64;
+    self
+  }
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.refilled = clock.now();
+    self.clock = clock;
+    self
+  }
+  pub fn mtu(&self) -> IpPacketSize {
+    self.mtu
+  }
+  /// Answers are about the new MTU from now on
+  pub fn set_mtu(&mut self, mtu: IpPacketSize) {
+    self.mtu = mtu;
+  }
+  pub fn stats(&self) -> MtuGuardStats {
+    self.stats
+  }
+  /// The ICMP error for a received `packet` that doesn't fit the MTU. `None` for packets that
+  /// fit, may be fragmented or shouldn't be answered
+  pub fn too_big(&self, packet: &[u8]) -> Option<Vec<u8>> {
+    let parsed = IpPacket::parse(packet).ok()?;
+    let packet = parsed.as_bytes();
+    if packet.len() <= self.mtu.size() as usize || !answerable(&parsed) {
+      return None;
+    }
+    let (icmp_type, code, max_len) = match parsed.version() {
+      4 if packet[6] & IPV4_DONT_FRAGMENT != 0 => (
+        ICMP_DESTINATION_UNREACHABLE,
+        ICMP_FRAGMENTATION_NEEDED,
+        IPV4_MAX_ERROR_LEN,
+      ),
+      4 => return None,
+      _ => (ICMPV6_PACKET_TOO_BIG, 0, IPV6_MAX_ERROR_LEN),
+    };
+    let (protocol, mtu) = match parsed.version() {
+      //The next-hop MTU field of IPv4 is only 16 bits
+      4 => (IpProtocol::Icmp, self.mtu.size().min(u16::MAX as u32)),
+      _ => (IpProtocol::Icmpv6, self.mtu.size()),
+    };
+    //The answer carries no options or extension headers
+    let header_len = match parsed.version() {
+      4 => IPV4_HEADER_LEN,
+      _ => IPV6_HEADER_LEN,
+    };
+    let quoted = &packet[..packet.len().min(max_len - header_len - ICMP_HEADER_LEN)];
+    let mut message = Vec::with_capacity(ICMP_HEADER_LEN + quoted.len());
+    message.extend_from_slice(&[icmp_type, code, 0, 0]);
+    match protocol {
+      IpProtocol::Icmp => message.extend_from_slice(&[0, 0, (mtu >> 8) as u8, mtu as u8]),
+      _ => message.extend_from_slice(&mtu.to_be_bytes()),
+    }
+    message.extend_from_slice(quoted);
+    //The answer comes from the host the packet was for
+    let (source, destination) = (parsed.destination(), parsed.source());
+    let icmp_checksum = match protocol {
+      IpProtocol::Icmp => checksum(&message),
+      _ => transport_checksum(source, destination, protocol, &message),
+    };
+    message[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+    ip_packet(source, destination, protocol, &message).ok()
+  }
+  /// Takes an answer from the rate limit, if one is left
+  fn take_token(&mut self) -> bool {
+    let now = self.clock.now();
+    let elapsed = now.saturating_sub(self.refilled);
+    self.refilled = now;
+    if !self.interval.is_zero() {
+      let refill = elapsed.as_secs_f64() / self.interval.as_secs_f64() * self.burst as f64;
+      self.tokens = (self.tokens + refill).min(self.burst as f64);
+    } else {
+      self.tokens = self.burst as f64;
+    }
+    if self.tokens < 1.0 {
+      return false;
+    }
+    self.tokens -= 1.0;
+    true
+  }
+}
+
+/// Whether an ICMP error may be sent about `packet` (RFC 1812 section 4.3.2.7, RFC 4443 section
+/// 2.4). Packet too big may be sent about multicast destinations, so only sources are checked
+fn answerable(packet: &IpPacket) -> bool {
+  let source = packet.source();
+  let bad_source = source.is_unspecified()
+    || source.is_multicast()
+    || matches!(source, IpAddr::V4(source) if source.is_broadcast());
+  let error = match (packet.protocol(), packet.icmp_type()) {
+    (IpProtocol::Icmp, Some(icmp_type)) => ICMP_ERRORS.contains(&icmp_type),
+    (IpProtocol::Icmpv6, Some(icmp_type)) => icmp_type < ICMPV6_INFORMATIONAL,
+    _ => false,
+  };
+  !bad_source && !error
+}
+
+impl PacketProcessor for MtuGuard {
+  fn name(&self) -> &'static str {
+    "mtu-guard"
+  }
+  fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+    if packet.len() <= self.mtu.size() as usize {
+      return Verdict::Accept;
+    }
+    let Ok(parsed) = IpPacket::parse(packet) else {
+      return Verdict::Accept;
+    };
+    if parsed.as_bytes().len() <= self.mtu.size() as usize
+      || (parsed.version() == 4 && packet[6] & IPV4_DONT_FRAGMENT == 0)
+    {
+      return Verdict::Accept;
+    }
+    self.stats.oversized += 1;
+    let Some(reply) = self.too_big(packet) else {
+      return Verdict::Drop;
+    };
+    if !self.take_token() {
+      self.stats.rate_limited += 1;
+      return Verdict::Drop;
+    }
+    self.stats.replies += 1;
+    Verdict::Reply(reply)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{net::IpAddr, sync::Arc, time::Duration};
+
+  use super::{MtuGuard, MtuGuardStats};
+  use crate::{checksum, fixtures, IpPacket, IpProtocol, ManualClock, PacketProcessor, Verdict};
+
+  fn udp(source: &str, destination: &str, len: usize, dont_fragment: bool) -> Vec<u8> {
+    let mut packet = fixtures::udp(source, destination, &vec![0xAB; len]);
+    if dont_fragment && packet[0] >> 4 == 4 {
+      packet[6] = 0x40;
+      packet[10..12].copy_from_slice(&[0, 0]);
+      let header_checksum = checksum(&packet[..20]);
+      packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+    }
+    packet
+  }
+
+  fn guard(mtu: u32) -> MtuGuard {
+    MtuGuard::new(mtu.try_into().unwrap())
+  }
+
+  /// Checks the checksums of an ICMP error and returns it with the message after the IP header
+  fn icmp(reply: &[u8]) -> (IpPacket<'_>, &[u8]) {
+    let parsed = fixtures::checked(reply);
+    (parsed, parsed.payload())
+  }
+
+  #[test]
+  fn fragmentation_needed() {
+    let mut guard = guard(1400);
+    let mut packet = udp("10.0.0.1:1000", "10.0.0.2:2000", 1500, true);
+    let Verdict::Reply(reply) = guard.on_inbound(&mut packet) else {
+      panic!("expected an answer");
+    };
+    let (parsed, message) = icmp(&reply);
+    assert_eq!(parsed.source(), "10.0.0.2".parse::<IpAddr>().unwrap());
+    assert_eq!(parsed.destination(), "10.0.0.1".parse::<IpAddr>().unwrap());
+    assert_eq!(&message[..2], &[3, 4]);
+    assert_eq!(u16::from_be_bytes([message[6], message[7]]), 1400);
+    assert_eq!(reply.len(), 576);
+    assert_eq!(&message[8..], &packet[..548]);
+
+    //Packets that fit or may be fragmented pass
+    let mut small = udp("10.0.0.1:1000", "10.0.0.2:2000", 1000, true);
+    assert_eq!(guard.on_inbound(&mut small), Verdict::Accept);
+    let mut fragmentable = udp("10.0.0.1:1000", "10.0.0.2:2000", 1500, false);
+    assert_eq!(guard.on_inbound(&mut fragmentable), Verdict::Accept);
+    assert_eq!(guard.on_outbound(&mut packet), Verdict::Accept);
+  }
+
+  #[test]
+  fn packet_too_big() {
+    let mut guard = guard(1280);
+    let mut packet = udp("[fd00::1]:1000", "[fd00::2]:2000", 1400, false);
+    let Verdict::Reply(reply) = guard.on_inbound(&mut packet) else {
+      panic!("expected an answer");
+    };
+    let (parsed, message) = icmp(&reply);
+    assert_eq!(parsed.protocol(), IpProtocol::Icmpv6);
+    assert_eq!(&message[..2], &[2, 0]);
+    assert_eq!(u32::from_be_bytes(message[4..8].try_into().unwrap()), 1280);
+    assert_eq!(reply.len(), 1280);
+
+    //No answers about unspecified sources
+    let mut unspecified = udp("[::]:1000", "[fd00::2]:2000", 1400, false);
+    assert_eq!(guard.on_inbound(&mut unspecified), Verdict::Drop);
+    assert_eq!(
+      guard.stats(),
+      MtuGuardStats {
+        oversized: 2,
+        replies: 1,
+        rate_limited: 0,
+      }
+    );
+  }
+
+  #[test]
+  fn rate_limit() {
+    let clock = ManualClock::new();
+    let mut guard = guard(1280)
+      .with_clock(Arc::new(clock.clone()))
+      .with_rate_limit(2, Duration::from_secs(1));
+    let mut packet = udp("[fd00::1]:1000", "[fd00::2]:2000", 1400, false);
+    let mut replies = || {
+      (0..5)
+        .filter(|_| matches!(guard.on_inbound(&mut packet), Verdict::Reply(_)))
+        .count()
+    };
+    assert_eq!(replies(), 2);
+    clock.advance(Duration::from_millis(500));
+    assert_eq!(replies(), 1);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(replies(), 2);
+    assert_eq!(guard.stats().rate_limited, 10);
+  }
+}
@@ -0,0 +1,1001 @@
+//! Address translation for tunnel traffic. [`Nat`] rewrites the source of packets from a network
+//! to another address (SNAT, or masquerade with an address that can change), and the destination
+//! of packets for an address and port to a fixed endpoint (DNAT). Translations are remembered
+//! per flow, so replies are translated back, and ICMP errors quoting a translated packet are
+//! rewritten along with the packet they quote. Packets are changed in place and checksums are
+//! updated incrementally (RFC 1624). Fragments after the first carry no ports, they get the
+//! addresses the first fragment of their datagram was translated to. Those arriving before it,
+//! or after it was forgotten, are dropped when a rule would translate them
+
+use std::{
+  collections::HashMap,
+  fmt,
+  net::{IpAddr, SocketAddr},
+  sync::Arc,
+  time::Duration,
+};
+
+use crate::wire::{
+  ICMPV6_ECHO_REPLY, ICMPV6_ECHO_REQUEST, ICMPV6_ERRORS, ICMP_ECHO_REPLY, ICMP_ECHO_REQUEST,
+  ICMP_ERRORS, ICMP_HEADER_LEN,
+};
+use crate::{
+  checksum, transport_checksum, update_checksum, Clock, Direction, Flow, FlowKey, FlowSide,
+  FlowTable, FlowTimeouts, IpAndMaskPrefix, IpPacket, IpProtocol, PacketProcessor, PortRange,
+  Verdict,
+};
+
+const DEFAULT_MAX_FLOWS: usize = 65536;
+/// Datagrams whose later fragments are translated at most
+const MAX_FRAGMENTED: usize = 1024;
+/// How long the translation of a fragmented datagram is kept, like the reassembly timeout of Linux
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+const FRAGMENT_HEADER: u8 = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatError {
+  /// Every port of the range is taken by a live flow
+  PortsExhausted,
+  /// A masquerade rule matched before an address was set
+  NoMasqueradeAddress,
+  /// A fragment after the first that a rule would translate, but whose first fragment wasn't
+  /// translated
+  UntrackedFragment,
+}
+
+impl fmt::Display for NatError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      NatError::PortsExhausted => f.write_str("No free port to translate the flow to"),
+      NatError::NoMasqueradeAddress => f.write_str("No masquerade address is set"),
+      NatError::UntrackedFragment => {
+        f.write_str("Fragment of a datagram whose first fragment wasn't translated")
+      }
+    }
+  }
+}
+
+impl std::error::Error for NatError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+  Source(IpAddr),
+  Masquerade,
+  Destination(SocketAddr),
+}
+
+/// Opens flows with a translation. Rules are checked in the order they were added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rule {
+  direction: Direction,
+  protocol: Option<IpProtocol>,
+  source: Option<IpAndMaskPrefix>,
+  destination: Option<SocketAddr>,
+  target: Target,
+}
+
+impl Rule {
+  fn matches(&self, direction: Direction, key: &FlowKey) -> bool {
+    self.direction == direction
+      && self
+        .protocol
+        .is_none_or(|protocol| protocol == key.protocol)
+      && self
+        .source
+        .is_none_or(|network| network.contains(key.source.ip()))
+      && self.destination.is_none_or(|destination| {
+        destination.ip() == key.destination.ip()
+          && (destination.port() == 0 || destination.port() == key.destination.port())
+      })
+  }
+}
+
+/// What a flow opened by a rule is translated to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+  /// Key of the flow's original packets after translation
+  pub translated: FlowKey,
+}
+
+/// Offsets in a packet, tolerating truncation so it also works on packets quoted by ICMP errors
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+  version: u8,
+  header_len: usize,
+  protocol: IpProtocol,
+}
+
+impl Layout {
+  fn of(packet: &[u8]) -> Option<Self> {
+    match packet.first()? >> 4 {
+      4 => {
+        let header_len = (packet[0] & 0xF) as usize * 4;
+        (header_len >= 20 && packet.len() >= header_len).then(|| Self {
+          version: 4,
+          header_len,
+          protocol: packet[9].into(),
+        })
+      }
+      //Quoted IPv6 packets with extension headers are left alone
+      6 => (packet.len() >= 40).then(|| Self {
+        version: 6,
+        header_len: 40,
+        protocol: packet[6].into(),
+      }),
+      _ => None,
+    }
+  }
+  fn addresses(&self) -> (std::ops::Range<usize>, std::ops::Range<usize>) {
+    match self.version {
+      4 => (12..16, 16..20),
+      _ => (8..24, 24..40),
+    }
+  }
+  fn icmp_type(&self, packet: &[u8]) -> Option<u8> {
+    matches!(self.protocol, IpProtocol::Icmp | IpProtocol::Icmpv6)
+      .then(|| packet.get(self.header_len).copied())
+      .flatten()
+  }
+  fn is_echo(&self, packet: &[u8]) -> bool {
+    matches!(
+      (self.protocol, self.icmp_type(packet)),
+      (IpProtocol::Icmp, Some(ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY))
+        | (
+          IpProtocol::Icmpv6,
+          Some(ICMPV6_ECHO_REQUEST | ICMPV6_ECHO_REPLY)
+        )
+    )
+  }
+  fn is_error(&self, packet: &[u8]) -> bool {
+    match (self.protocol, self.icmp_type(packet)) {
+      (IpProtocol::Icmp, Some(icmp_type)) => ICMP_ERRORS.contains(&icmp_type),
+      (IpProtocol::Icmpv6, Some(icmp_type)) => ICMPV6_ERRORS.contains(&icmp_type),
+      _ => false,
+    }
+  }
+  /// The key of a possibly truncated packet
+  fn key(&self, packet: &[u8]) -> Option<FlowKey> {
+    let (source, destination) = self.addresses();
+    let address = |range: std::ops::Range<usize>| -> IpAddr {
+      match self.version {
+        4 => <[u8; 4]>::try_from(&packet[range]).unwrap().into(),
+        _ => <[u8; 16]>::try_from(&packet[range]).unwrap().into(),
+      }
+    };
+    let transport = &packet[self.header_len..];
+    let word = |offset: usize| {
+      Some(u16::from_be_bytes([
+        *transport.get(offset)?,
+        *transport.get(offset + 1)?,
+      ]))
+    };
+    let ports = match self.protocol {
+      IpProtocol::Tcp | IpProtocol::Udp => (word(0)?, word(2)?),
+      _ if self.is_echo(packet) => (word(4)?, word(4)?),
+      _ => (0, 0),
+    };
+    Some(FlowKey {
+      protocol: self.protocol,
+      source: SocketAddr::new(address(source), ports.0),
+      destination: SocketAddr::new(address(destination), ports.1),
+    })
+  }
+  /// Offset of the transport checksum and whether it covers the addresses
+  fn transport_checksum(&self) -> Option<(usize, bool)> {
+    match self.protocol {
+      IpProtocol::Tcp => Some((self.header_len + 16, true)),
+      IpProtocol::Udp => Some((self.header_len + 6, true)),
+      IpProtocol::Icmpv6 => Some((self.header_len + 2, true)),
+      IpProtocol::Icmp => Some((self.header_len + 2, false)),
+      IpProtocol::Other(_) => None,
+    }
+  }
+}
+
+/// A checksum field covering a rewritten field
+#[derive(Debug, Clone, Copy)]
+struct ChecksumField {
+  at: usize,
+  /// Zero means there is no checksum, as with UDP over IPv4
+  optional: bool,
+}
+
+/// Replaces the bytes at `at` with `new`, updating the checksums that cover them. Checksums
+/// beyond the end of a truncated packet are skipped
+fn patch(packet: &mut [u8], at: usize, new: &[u8], checksums: &[ChecksumField]) {
+  let old = packet[at..at + new.len()].to_vec();
+  if old == new {
+    return;
+  }
+  for field in checksums {
+    let Some(bytes) = packet.get(field.at..field.at + 2) else {
+      continue;
+    };
+    let current = u16::from_be_bytes([bytes[0], bytes[1]]);
+    if field.optional && current == 0 {
+      continue;
+    }
+    let updated = match update_checksum(current, &old, new) {
+      0 if field.optional => 0xFFFF,
+      updated => updated,
+    };
+    packet[field.at..field.at + 2].copy_from_slice(&updated.to_be_bytes());
+  }
+  packet[at..at + new.len()].copy_from_slice(new);
+}
+
+fn octets(ip: IpAddr) -> Vec<u8> {
+  match ip {
+    IpAddr::V4(ip) => ip.octets().to_vec(),
+    IpAddr::V6(ip) => ip.octets().to_vec(),
+  }
+}
+
+/// Gives a (possibly truncated) packet new endpoints. Fields and checksums beyond the end of the
+/// packet are skipped
+fn rewrite(packet: &mut [u8], layout: &Layout, source: SocketAddr, destination: SocketAddr) {
+  let header = (layout.version == 4).then_some(ChecksumField {
+    at: 10,
+    optional: false,
+  });
+  let transport = layout.transport_checksum();
+  let transport_field = transport.map(|(at, _)| ChecksumField {
+    at,
+    optional: layout.protocol == IpProtocol::Udp && layout.version == 4,
+  });
+  let pseudo_header = transport_field.filter(|_| transport.is_some_and(|(_, pseudo)| pseudo));
+  let (source_range, destination_range) = layout.addresses();
+  let checksums: Vec<_> = header.into_iter().chain(pseudo_header).collect();
+  for (range, ip) in [
+    (source_range, source.ip()),
+    (destination_range, destination.ip()),
+  ] {
+    patch(packet, range.start, &octets(ip), &checksums);
+  }
+  let checksums: Vec<_> = transport_field.into_iter().collect();
+  let header_len = layout.header_len;
+  let ports = match layout.protocol {
+    IpProtocol::Tcp | IpProtocol::Udp => {
+      vec![
+        (header_len, source.port()),
+        (header_len + 2, destination.port()),
+      ]
+    }
+    //Echo keys carry the identifier as both ports
+    _ if layout.is_echo(packet) => vec![(header_len + 4, source.port())],
+    _ => Vec::new(),
+  };
+  for (at, port) in ports {
+    if packet.len() >= at + 2 {
+      patch(packet, at, &port.to_be_bytes(), &checksums);
+    }
+  }
+}
+
+/// Fragments of one datagram, as they arrive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct FragmentKey {
+  protocol: IpProtocol,
+  source: IpAddr,
+  destination: IpAddr,
+  identification: u32,
+}
+
+impl FragmentKey {
+  fn of(packet: &IpPacket) -> Option<Self> {
+    if !packet.is_fragment() {
+      return None;
+    }
+    let bytes = packet.as_bytes();
+    let identification = match packet.version() {
+      4 => u16::from_be_bytes([bytes[4], bytes[5]]) as u32,
+      //The fragment header is among the extension headers the packet was parsed with
+      _ => {
+        let (mut next_header, mut at) = (bytes[6], 40);
+        while next_header != FRAGMENT_HEADER {
+          next_header = bytes[at];
+          at += (bytes[at + 1] as usize + 1) * 8;
+        }
+        u32::from_be_bytes(bytes[at + 4..at + 8].try_into().unwrap())
+      }
+    };
+    Some(Self {
+      protocol: packet.protocol(),
+      source: packet.source(),
+      destination: packet.destination(),
+      identification,
+    })
+  }
+}
+
+/// Addresses the first fragment of a datagram was translated to
+#[derive(Debug, Clone, Copy)]
+struct FragmentTranslation {
+  source: IpAddr,
+  destination: IpAddr,
+  created: Duration,
+}
+
+/// Drops the reverse entry of a flow that left the table, unless a newer flow took it over
+fn forget(translated: &mut HashMap<FlowKey, FlowKey>, flow: &Flow<Mapping>) {
+  let key = flow.data().translated;
+  if translated.get(&key) == Some(&flow.key()) {
+    translated.remove(&key);
+  }
+}
+
+/// Rules plus the flows they translated
+pub struct Nat {
+  rules: Vec<Rule>,
+  flows: FlowTable<Mapping>,
+  /// Original key of every flow by its translated key
+  translated: HashMap<FlowKey, FlowKey>,
+  /// Translations of fragmented datagrams by the key of their fragments before translation
+  fragments: HashMap<FragmentKey, FragmentTranslation>,
+  ports: PortRange,
+  next_port: u16,
+  masquerade_address: Option<IpAddr>,
+}
+
+impl Default for Nat {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Nat {
+  /// Translates nothing until rules are added. Ports are picked from 1024 to 65535
+  pub fn new() -> Self {
+    Self {
+      rules: Vec::new(),
+      flows: FlowTable::new(DEFAULT_MAX_FLOWS),
+      translated: HashMap::new(),
+      fragments: HashMap::new(),
+      ports: PortRange::new(1024, u16::MAX),
+      next_port: 1024,
+      masquerade_address: None,
+    }
+  }
+  fn rule(mut self, rule: Rule) -> Self {
+    self.rules.push(rule);
+    self
+  }
+  /// Rewrites the source of flows from `network` opened in `direction` to `address`
+  pub fn snat(self, direction: Direction, network: IpAndMaskPrefix, address: IpAddr) -> Self {
+    self.rule(Rule {
+      direction,
+      protocol: None,
+      source: Some(network),
+      destination: None,
+      target: Target::Source(address),
+    })
+  }
+  /// Like [`snat`](Self::snat) to the address given to
+  /// [`set_masquerade_address`](Self::set_masquerade_address), such as the adapter's address
+  pub fn masquerade(self, direction: Direction, network: IpAndMaskPrefix) -> Self {
+    self.rule(Rule {
+      direction,
+      protocol: None,
+      source: Some(network),
+      destination: None,
+      target: Target::Masquerade,
+    })
+  }
+  /// Rewrites the destination of flows opened in `direction` towards `destination` to `to`. A
+  /// port of 0 in `destination` matches every port, in `to` it keeps the port
+  pub fn dnat(
+    self,
+    direction: Direction,
+    protocol: Option<IpProtocol>,
+    destination: SocketAddr,
+    to: SocketAddr,
+  ) -> Self {
+    self.rule(Rule {
+      direction,
+      protocol,
+      source: None,
+      destination: Some(destination),
+      target: Target::Destination(to),
+    })
+  }
+  /// Ports and ICMP identifiers source translation picks from. The original port is kept when it
+  /// is in the range and free
+  pub fn with_ports(mut self, ports: impl Into<PortRange>) -> Self {
+    self.ports = ports.into();
+    self.next_port = self.ports.first();
+    self
+  }
+  /// Flows tracked at most, the least recently seen flow makes room for a new one. Defaults to
+  /// 65536
+  pub fn with_max_flows(mut self, max_flows: usize) -> Self {
+    self.flows = FlowTable::new(max_flows)
+      .with_timeouts(self.flows.timeouts())
+      .with_clock(self.flows.clock());
+    self
+  }
+  pub fn with_timeouts(mut self, timeouts: FlowTimeouts) -> Self {
+    self.flows.set_timeouts(timeouts);
+    self
+  }
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.flows = FlowTable::new(self.flows.capacity())
+      .with_timeouts(self.flows.timeouts())
+      .with_clock(clock);
+    self
+  }
+  /// Address masquerade rules translate new flows to. Flows already translated keep theirs
+  pub fn set_masquerade_address(&mut self, address: Option<IpAddr>) {
+    self.masquerade_address = address;
+  }
+  pub fn flows(&self) -> &FlowTable<Mapping> {
+    &self.flows
+  }
+  /// Forgets expired flows, freeing their ports, and the translations of fragmented datagrams
+  pub fn expire(&mut self) {
+    let translated = &mut self.translated;
+    self.flows.expire_with(|flow| forget(translated, &flow));
+    let now = self.flows.clock().now();
+    self
+      .fragments
+      .retain(|_, fragments| now.saturating_sub(fragments.created) < FRAGMENT_TIMEOUT);
+  }
+  /// Remembers what the first fragment of a datagram was translated to, for the fragments after
+  /// it
+  fn track_fragments(&mut self, fragment: Option<FragmentKey>, packet: &[u8]) {
+    let (Some(fragment), Ok(translated)) = (fragment, IpPacket::parse(packet)) else {
+      return;
+    };
+    if self.fragments.len() >= MAX_FRAGMENTED {
+      self.expire();
+      if self.fragments.len() >= MAX_FRAGMENTED {
+        return;
+      }
+    }
+    let translation = FragmentTranslation {
+      source: translated.source(),
+      destination: translated.destination(),
+      created: self.flows.clock().now(),
+    };
+    self.fragments.insert(fragment, translation);
+  }
+  /// Gives a fragment after the first the addresses of its datagram's first fragment
+  fn translate_later_fragment(
+    &mut self,
+    direction: Direction,
+    packet: &mut [u8],
+    layout: &Layout,
+    fragment: Option<FragmentKey>,
+    key: &FlowKey,
+  ) -> Result<bool, NatError> {
+    let now = self.flows.clock().now();
+    let translation = fragment
+      .and_then(|fragment| self.fragments.get(&fragment))
+      .filter(|translation| now.saturating_sub(translation.created) < FRAGMENT_TIMEOUT);
+    let Some(translation) = translation else {
+      return match self.rules.iter().any(|rule| rule.matches(direction, key)) {
+        true => Err(NatError::UntrackedFragment),
+        false => Ok(false),
+      };
+    };
+    //Only the addresses, what follows the header is data
+    let addresses = Layout {
+      protocol: IpProtocol::Other(0),
+      ..*layout
+    };
+    let source = SocketAddr::new(translation.source, 0);
+    let destination = SocketAddr::new(translation.destination, 0);
+    rewrite(packet, &addresses, source, destination);
+    Ok(true)
+  }
+  /// Original key of the live flow translated to `translated`
+  fn original(&self, translated: &FlowKey) -> Option<FlowKey> {
+    let original = self.translated.get(translated)?;
+    let (flow, side) = self.flows.get(original)?;
+    (side == FlowSide::Original && flow.data().translated == *translated).then_some(*original)
+  }
+  /// Translates `packet` travelling in `direction` in place. Returns whether it was changed
+  pub fn translate(&mut self, direction: Direction, packet: &mut [u8]) -> Result<bool, NatError> {
+    let Ok(parsed) = IpPacket::parse(packet) else {
+      return Ok(false);
+    };
+    let layout = Layout {
+      version: parsed.version(),
+      header_len: parsed.header().len(),
+      protocol: parsed.protocol(),
+    };
+    let len = parsed.as_bytes().len();
+    let unported = parsed.is_fragment() && parsed.ports().is_none();
+    let later_fragment = parsed.fragment_offset() != 0;
+    let fragment = FragmentKey::of(&parsed);
+    let key = FlowKey::of(&parsed);
+    let packet = &mut packet[..len];
+    //Later fragments start with data, which may look like any header
+    if later_fragment {
+      return self.translate_later_fragment(direction, packet, &layout, fragment, &key);
+    }
+    if unported && !layout.is_echo(packet) {
+      return Ok(false);
+    }
+    if layout.is_error(packet) {
+      return Ok(self.translate_error(packet, &layout));
+    }
+    //A reply to a translated flow
+    if let Some(original) = self.original(&key.reversed()) {
+      if self.flows.get(&original).unwrap().0.info().opened == direction {
+        return Ok(false);
+      }
+      rewrite(packet, &layout, original.destination, original.source);
+      self.flows.update(packet);
+      self.track_fragments(fragment, packet);
+      return Ok(true);
+    }
+    //More of a translated flow
+    if let Some((flow, FlowSide::Original)) = self.flows.get(&key) {
+      if flow.info().opened != direction {
+        return Ok(false);
+      }
+      let translated = flow.data().translated;
+      self.flows.update(packet);
+      rewrite(packet, &layout, translated.source, translated.destination);
+      self.track_fragments(fragment, packet);
+      return Ok(translated != key);
+    }
+    let Some(rule) = self
+      .rules
+      .iter()
+      .find(|rule| rule.matches(direction, &key))
+      .copied()
+    else {
+      return Ok(false);
+    };
+    let echo = layout.is_echo(packet);
+    let translated = match rule.target {
+      Target::Destination(to) => {
+        let port = match to.port() {
+          0 => key.destination.port(),
+          port => port,
+        };
+        let mut translated = FlowKey {
+          destination: SocketAddr::new(to.ip(), port),
+          ..key
+        };
+        if echo {
+          translated.destination.set_port(key.source.port());
+        }
+        translated
+      }
+      Target::Source(address) => self.allocate(key, address, echo)?,
+      Target::Masquerade => {
+        let address = self
+          .masquerade_address
+          .ok_or(NatError::NoMasqueradeAddress)?;
+        self.allocate(key, address, echo)?
+      }
+    };
+    if translated.source.is_ipv4() != translated.destination.is_ipv4() {
+      return Ok(false);
+    }
+    let translations = &mut self.translated;
+    self
+      .flows
+      .insert_with(direction, packet, Mapping { translated }, |flow| {
+        forget(translations, &flow)
+      });
+    self.translated.insert(translated, key);
+    rewrite(packet, &layout, translated.source, translated.destination);
+    self.track_fragments(fragment, packet);
+    Ok(translated != key)
+  }
+  /// Picks the translated key of a new flow from `key` to `address`
+  fn allocate(&mut self, key: FlowKey, address: IpAddr, echo: bool) -> Result<FlowKey, NatError> {
+    let candidate = |port: u16| {
+      let mut translated = FlowKey {
+        source: SocketAddr::new(address, port),
+        ..key
+      };
+      if echo {
+        translated.destination.set_port(port);
+      }
+      translated
+    };
+    let has_ports = matches!(key.protocol, IpProtocol::Tcp | IpProtocol::Udp) || echo;
+    let original = candidate(key.source.port());
+    if self.original(&original).is_none() && (!has_ports || self.ports.contains(key.source.port()))
+    {
+      return Ok(original);
+    }
+    if !has_ports {
+      return Err(NatError::PortsExhausted);
+    }
+    let count = self.ports.last() as u32 - self.ports.first() as u32 + 1;
+    for _ in 0..count {
+      let port = self.next_port;
+      self.next_port = if port >= self.ports.last() {
+        self.ports.first()
+      } else {
+        port + 1
+      };
+      let translated = candidate(port);
+      if self.original(&translated).is_none() {
+        return Ok(translated);
+      }
+    }
+    Err(NatError::PortsExhausted)
+  }
+  /// Rewrites an ICMP error quoting a packet of a translated flow, so it matches the packet as
+  /// it was before translation
+  fn translate_error(&mut self, packet: &mut [u8], layout: &Layout) -> bool {
+    let quoted_at = layout.header_len + ICMP_HEADER_LEN;
+    let Some(quoted_layout) = packet.get(quoted_at..).and_then(Layout::of) else {
+      return false;
+    };
+    let Some(quoted) = quoted_layout.key(&packet[quoted_at..]) else {
+      return false;
+    };
+    //The quoted packet either went out translated, or is a reply that was translated back
+    let (from, to) = if let Some(original) = self.original(&quoted) {
+      (quoted, original)
+    } else if let Some((flow, FlowSide::Reply)) = self.flows.get(&quoted) {
+      let translated = flow.data().translated;
+      (quoted, translated.reversed())
+    } else {
+      return false;
+    };
+    rewrite(
+      &mut packet[quoted_at..],
+      &quoted_layout,
+      to.source,
+      to.destination,
+    );
+    //The error travels back to the quoted packet's source, from somewhere along its way
+    let outer = layout.key(packet).expect("the packet was parsed");
+    let source = match outer.source.ip() == from.destination.ip() {
+      true => SocketAddr::new(to.destination.ip(), 0),
+      false => outer.source,
+    };
+    let destination = match outer.destination.ip() == from.source.ip() {
+      true => SocketAddr::new(to.source.ip(), 0),
+      false => outer.destination,
+    };
+    let addresses = Layout {
+      protocol: IpProtocol::Other(0),
+      ..*layout
+    };
+    rewrite(packet, &addresses, source, destination);
+    //The quoted packet changed too much for an incremental update
+    let icmp = &mut packet[layout.header_len..];
+    icmp[2..4].copy_from_slice(&[0, 0]);
+    let icmp_checksum = match layout.protocol {
+      IpProtocol::Icmpv6 => {
+        transport_checksum(source.ip(), destination.ip(), IpProtocol::Icmpv6, icmp)
+      }
+      _ => checksum(icmp),
+    };
+    icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+    true
+  }
+}
+
+impl PacketProcessor for Nat {
+  fn name(&self) -> &'static str {
+    "nat"
+  }
+  fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+    match self.translate(Direction::Inbound, packet) {
+      Ok(_) => Verdict::Accept,
+      Err(_) => Verdict::Drop,
+    }
+  }
+  fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+    match self.translate(Direction::Outbound, packet) {
+      Ok(_) => Verdict::Accept,
+      Err(_) => Verdict::Drop,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+  };
+
+  use super::{Nat, NatError};
+  use crate::{
+    checksum,
+    fixtures::{address, checked, echo, icmp, network, udp},
+    tcp_packet, Direction, FlowTimeouts, Fragmenter, IpPacket, IpProtocol, ManualClock,
+    Reassembler, TCP_ACK, TCP_SYN,
+  };
+
+  /// Checks every checksum of the packet and returns its endpoints
+  fn endpoints(packet: &[u8]) -> (SocketAddr, SocketAddr) {
+    let parsed = checked(packet);
+    let (source_port, destination_port) = parsed.ports().unwrap_or_else(|| {
+      let identifier = u16::from_be_bytes([parsed.payload()[4], parsed.payload()[5]]);
+      (identifier, identifier)
+    });
+    (
+      SocketAddr::new(parsed.source(), source_port),
+      SocketAddr::new(parsed.destination(), destination_port),
+    )
+  }
+
+  #[test]
+  fn source_translation_and_replies() {
+    let mut nat = Nat::new().snat(
+      Direction::Outbound,
+      network("10.8.0.0", 24),
+      "192.168.1.10".parse().unwrap(),
+    );
+    let mut query = udp("10.8.0.2:5000", "1.1.1.1:53", b"query");
+    assert_eq!(nat.translate(Direction::Outbound, &mut query), Ok(true));
+    assert_eq!(
+      endpoints(&query),
+      (address("192.168.1.10:5000"), address("1.1.1.1:53"))
+    );
+    //Another host using the same port gets a different one
+    let mut other = udp("10.8.0.3:5000", "1.1.1.1:53", b"query");
+    assert_eq!(nat.translate(Direction::Outbound, &mut other), Ok(true));
+    let (other_source, _) = endpoints(&other);
+    assert_eq!(other_source.ip(), "192.168.1.10".parse::<IpAddr>().unwrap());
+    assert_ne!(other_source.port(), 5000);
+
+    let mut answer = udp("1.1.1.1:53", &other_source.to_string(), b"query");
+    assert_eq!(nat.translate(Direction::Inbound, &mut answer), Ok(true));
+    assert_eq!(
+      endpoints(&answer),
+      (address("1.1.1.1:53"), address("10.8.0.3:5000"))
+    );
+    //Unrelated traffic and replies arriving the wrong way are left alone
+    let mut unrelated = udp("1.1.1.1:53", "192.168.1.10:6000", b"query");
+    assert_eq!(nat.translate(Direction::Inbound, &mut unrelated), Ok(false));
+    let mut answer = udp("1.1.1.1:53", "192.168.1.10:5000", b"query");
+    assert_eq!(nat.translate(Direction::Outbound, &mut answer), Ok(false));
+    assert_eq!(nat.flows().len(), 2);
+  }
+
+  #[test]
+  fn ports_run_out() {
+    let mut nat = Nat::new()
+      .masquerade(Direction::Outbound, network("fd00::", 64))
+      .with_ports(40000..=40001);
+    let syn = |port: u16| {
+      let source = SocketAddr::new("fd00::2".parse().unwrap(), port);
+      tcp_packet(source, address("[2001:db8::1]:443"), TCP_SYN, &[], &[]).unwrap()
+    };
+    assert_eq!(
+      nat.translate(Direction::Outbound, &mut syn(1000)),
+      Err(NatError::NoMasqueradeAddress)
+    );
+    nat.set_masquerade_address(Some("2001:db8::2".parse().unwrap()));
+    for (port, translated) in [(1000, 40000), (1001, 40001)] {
+      let mut syn = syn(port);
+      assert_eq!(nat.translate(Direction::Outbound, &mut syn), Ok(true));
+      assert_eq!(
+        endpoints(&syn).0,
+        SocketAddr::new("2001:db8::2".parse().unwrap(), translated)
+      );
+    }
+    assert_eq!(
+      nat.translate(Direction::Outbound, &mut syn(1002)),
+      Err(NatError::PortsExhausted)
+    );
+    //Retransmissions still find their flow
+    assert_eq!(nat.translate(Direction::Outbound, &mut syn(1000)), Ok(true));
+  }
+
+  #[test]
+  fn destination_translation() {
+    let mut nat = Nat::new().dnat(
+      Direction::Inbound,
+      Some(IpProtocol::Tcp),
+      address("192.168.1.10:8080"),
+      address("10.8.0.5:80"),
+    );
+    let mut syn = tcp_packet(
+      address("203.0.113.7:50000"),
+      address("192.168.1.10:8080"),
+      TCP_SYN,
+      &[2, 4, 5, 0xB4],
+      &[],
+    )
+    .unwrap();
+    assert_eq!(nat.translate(Direction::Inbound, &mut syn), Ok(true));
+    assert_eq!(
+      endpoints(&syn),
+      (address("203.0.113.7:50000"), address("10.8.0.5:80"))
+    );
+    let mut syn_ack = tcp_packet(
+      address("10.8.0.5:80"),
+      address("203.0.113.7:50000"),
+      TCP_SYN | TCP_ACK,
+      &[],
+      b"hello",
+    )
+    .unwrap();
+    assert_eq!(nat.translate(Direction::Outbound, &mut syn_ack), Ok(true));
+    assert_eq!(
+      endpoints(&syn_ack),
+      (address("192.168.1.10:8080"), address("203.0.113.7:50000"))
+    );
+    //Other ports aren't forwarded
+    let mut other = tcp_packet(
+      address("203.0.113.7:50000"),
+      address("192.168.1.10:22"),
+      TCP_SYN,
+      &[],
+      &[],
+    )
+    .unwrap();
+    assert_eq!(nat.translate(Direction::Inbound, &mut other), Ok(false));
+  }
+
+  #[test]
+  fn echo_identifiers() {
+    let mut nat = Nat::new().snat(
+      Direction::Outbound,
+      network("10.8.0.0", 24),
+      "192.168.1.10".parse().unwrap(),
+    );
+    let mut first = echo("10.8.0.2", "8.8.8.8", 8, 4000);
+    let mut second = echo("10.8.0.3", "8.8.8.8", 8, 4000);
+    nat.translate(Direction::Outbound, &mut first).unwrap();
+    nat.translate(Direction::Outbound, &mut second).unwrap();
+    let (first_source, _) = endpoints(&first);
+    let (second_source, _) = endpoints(&second);
+    assert_eq!(first_source.port(), 4000);
+    assert_ne!(second_source.port(), 4000);
+
+    let mut reply = echo("8.8.8.8", "192.168.1.10", 0, second_source.port());
+    assert_eq!(nat.translate(Direction::Inbound, &mut reply), Ok(true));
+    assert_eq!(
+      endpoints(&reply),
+      (address("8.8.8.8:4000"), address("10.8.0.3:4000"))
+    );
+  }
+
+  #[test]
+  fn later_fragments_follow_the_first() {
+    let mut nat = Nat::new().snat(
+      Direction::Outbound,
+      network("10.8.0.0", 24),
+      "192.168.1.10".parse().unwrap(),
+    );
+    nat
+      .translate(
+        Direction::Outbound,
+        &mut echo("10.8.0.3", "8.8.8.8", 8, 4000),
+      )
+      .unwrap();
+    //Data that reads like an echo request where a later fragment's header would be
+    let mut message = vec![8, 0, 0, 0, 0x0F, 0xA0, 0, 1];
+    message.resize(200, 8);
+    let request = icmp("10.8.0.2", "8.8.8.8", &message);
+    let mut fragmenter = Fragmenter::new(100.try_into().unwrap());
+    let fragments = fragmenter.fragment(&request).unwrap();
+    assert!(fragments.len() > 2);
+    let mut translated = Vec::new();
+    for fragment in fragments.iter().rev() {
+      let mut fragment = fragment.clone();
+      let result = nat.translate(Direction::Outbound, &mut fragment);
+      translated.push((result, fragment));
+    }
+    //Before the first fragment there is nothing to translate them to
+    for (result, _) in &translated[..translated.len() - 1] {
+      assert_eq!(*result, Err(NatError::UntrackedFragment));
+    }
+    let mut reassembler = Reassembler::new();
+    for fragment in &fragments {
+      let mut fragment = fragment.clone();
+      assert_eq!(nat.translate(Direction::Outbound, &mut fragment), Ok(true));
+      let parsed = IpPacket::parse(&fragment).unwrap();
+      assert_eq!(parsed.source(), "192.168.1.10".parse::<IpAddr>().unwrap());
+      assert_eq!(checksum(parsed.header()), 0);
+      if let Some(datagram) = reassembler.push(&fragment).unwrap() {
+        let (source, _) = endpoints(&datagram);
+        assert_eq!(source.ip(), "192.168.1.10".parse::<IpAddr>().unwrap());
+        assert_ne!(source.port(), 4000);
+        //The data of later fragments is left alone
+        assert_eq!(
+          &IpPacket::parse(&datagram).unwrap().payload()[8..],
+          &message[8..]
+        );
+      }
+    }
+    assert_eq!(reassembler.stats().reassembled, 1);
+    assert_eq!((nat.flows().len(), nat.translated.len()), (2, 2));
+
+    //Fragments of replies and of flows no rule translates pass untouched
+    let reply = icmp("8.8.8.8", "192.168.1.10", &message);
+    let reply = fragmenter.fragment(&reply).unwrap();
+    let mut fragment = reply[1].clone();
+    assert_eq!(nat.translate(Direction::Inbound, &mut fragment), Ok(false));
+    assert_eq!(fragment, reply[1]);
+
+    //IPv6 keeps the identification in the fragment header
+    let mut nat = Nat::new().snat(
+      Direction::Outbound,
+      network("fd00::", 64),
+      "2001:db8::10".parse().unwrap(),
+    );
+    let datagram = udp("[fd00::2]:5000", "[2001:db8::1]:53", &[7; 300]);
+    let fragments = Fragmenter::new(150.try_into().unwrap())
+      .fragment(&datagram)
+      .unwrap();
+    assert!(fragments.len() > 2);
+    for fragment in &fragments {
+      let mut fragment = fragment.clone();
+      assert_eq!(nat.translate(Direction::Outbound, &mut fragment), Ok(true));
+      let source = IpPacket::parse(&fragment).unwrap().source();
+      assert_eq!(source, "2001:db8::10".parse::<IpAddr>().unwrap());
+    }
+  }
+
+  #[test]
+  fn reverse_entries_leave_with_their_flows() {
+    let clock = ManualClock::new();
+    let mut nat = Nat::new()
+      .snat(
+        Direction::Outbound,
+        network("10.8.0.0", 24),
+        "192.168.1.10".parse().unwrap(),
+      )
+      .with_max_flows(2)
+      .with_timeouts(FlowTimeouts::uniform(Duration::from_secs(10)))
+      .with_clock(Arc::new(clock.clone()));
+    for port in 1..=3 {
+      let mut query = udp(&format!("10.8.0.2:{port}"), "1.1.1.1:53", b"query");
+      nat.translate(Direction::Outbound, &mut query).unwrap();
+      clock.advance(Duration::from_secs(1));
+    }
+    //The evicted flow took its entry along
+    assert_eq!(nat.flows().evicted(), 1);
+    assert_eq!(nat.translated.len(), 2);
+    assert!(nat
+      .translated
+      .values()
+      .all(|original| original.source.port() != 1));
+
+    clock.advance(Duration::from_secs(10));
+    nat.expire();
+    assert!(nat.translated.is_empty());
+  }
+
+  #[test]
+  fn errors_quoting_translated_packets() {
+    let mut nat = Nat::new().snat(
+      Direction::Outbound,
+      network("10.8.0.0", 24),
+      "192.168.1.10".parse().unwrap(),
+    );
+    let mut busy = udp("10.8.0.9:5000", "1.1.1.1:53", b"query");
+    nat.translate(Direction::Outbound, &mut busy).unwrap();
+    let mut query = udp("10.8.0.2:5000", "1.1.1.1:53", b"query");
+    nat.translate(Direction::Outbound, &mut query).unwrap();
+    let (translated, _) = endpoints(&query);
+
+    //Port unreachable from the server, quoting the IP header and 8 bytes like routers do
+    let mut message = vec![3, 3, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&query[..28]);
+    let mut error = icmp("1.1.1.1", "192.168.1.10", &message);
+    assert_eq!(nat.translate(Direction::Inbound, &mut error), Ok(true));
+    assert_eq!(
+      endpoints(&error).1.ip(),
+      "10.8.0.2".parse::<IpAddr>().unwrap()
+    );
+    let quoted = &error[28..];
+    assert_eq!(checksum(&quoted[..20]), 0, "quoted header checksum");
+    assert_eq!(&quoted[12..16], &[10, 8, 0, 2]);
+    assert_eq!(u16::from_be_bytes([quoted[20], quoted[21]]), 5000);
+    assert_ne!(translated.port(), 5000);
+
+    //Errors about unknown flows pass untouched
+    let mut message = vec![11, 0, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(&udp("192.168.1.10:7777", "1.1.1.1:53", b"query")[..28]);
+    let mut error = icmp("10.0.0.1", "192.168.1.10", &message);
+    assert_eq!(nat.translate(Direction::Inbound, &mut error), Ok(false));
+  }
+}
@@ -1,24 +1,20 @@
-use winapi::shared::minwindef::BYTE;
-
-use crate::wintun_raw::DWORD;
-
-use super::session::Session;
+use super::session::SessionCore;
 
 pub struct RecvPacket<'session> {
-  session: &'session Session,
-  data: *mut BYTE,
-  size: DWORD,
+  session: &'session SessionCore,
+  data: *mut u8,
+  size: u32,
 }
 
 impl<'session> RecvPacket<'session> {
-  pub(crate) unsafe fn from_raw(session: &'session Session, data: *mut BYTE, size: DWORD) -> Self {
+  pub(crate) unsafe fn from_raw(session: &'session SessionCore, data: *mut u8, size: u32) -> Self {
     Self {
       session,
       data,
       size,
     }
   }
-  pub(crate) fn as_raw_ptr(&self) -> *const BYTE {
+  pub(crate) fn as_raw_ptr(&self) -> *const u8 {
     self.data
   }
   pub fn slice(&self) -> &[u8] {
@@ -52,20 +48,20 @@ impl<'session> AsMut<[u8]> for RecvPacket<'session> {
 
 
 pub struct SendPacket<'session> {
-  session: &'session Session,
-  data: *mut BYTE,
-  size: DWORD,
+  session: &'session SessionCore,
+  data: *mut u8,
+  size: u32,
 }
 
 impl<'session> SendPacket<'session> {
-  pub(crate) unsafe fn from_raw(session: &'session Session, data: *mut BYTE, size: DWORD) -> Self {
+  pub(crate) unsafe fn from_raw(session: &'session SessionCore, data: *mut u8, size: u32) -> Self {
     Self {
       session,
       data,
       size,
     }
   }
-  pub(crate) fn as_raw_ptr(&self) -> *const BYTE {
+  pub(crate) fn as_raw_ptr(&self) -> *const u8 {
     self.data
   }
   pub fn slice(&self) -> &[u8] {
@@ -0,0 +1,250 @@
+use std::io;
+
+use crate::{
+  Adapter, IpPacketSize, ReceivePacketError, Session, SessionReader, SessionStats, SessionWriter,
+  WintunError, MAX_IP_PACKET_SIZE,
+};
+
+/// Adapts a [`Session`] to [`io::Read`] and [`io::Write`], one packet per call.
+///
+/// A read receives one packet and copies it into the buffer. Packets longer than the buffer are
+/// truncated, which is reported by [`truncated`](Self::truncated). Once the adapter terminates
+/// reads return `Ok(0)`, so copy loops end cleanly. A write sends the whole buffer as one packet
+/// and fails with [`io::ErrorKind::InvalidInput`] for buffers longer than
+/// [`MAX_IP_PACKET_SIZE`]. Empty buffers are neither received into nor sent.
+///
+/// Calls block until a packet arrives or the send ring has room, after
+/// [`set_nonblocking`](Self::set_nonblocking) they fail with [`io::ErrorKind::WouldBlock`]
+/// instead
+///
+/// ```
+/// use std::io::{Read, Write};
+///
+/// let adapter = wintun2::Adapter::create_in_memory("doc-io", "tunnel_type", None).unwrap();
+/// let peer = adapter.memory_peer().unwrap();
+/// let mut io = wintun2::SessionIo::new(adapter.session(wintun2::RingCapacity::min()).unwrap());
+/// io.write_all(&[0x45, 0, 0, 4]).unwrap();
+/// assert_eq!(peer.try_recv().unwrap(), [0x45, 0, 0, 4]);
+///
+/// peer.inject(&[0x45, 1, 2, 3]).unwrap();
+/// let mut buf = [0; 1500];
+/// assert_eq!(io.read(&mut buf).unwrap(), 4);
+/// ```
+pub struct SessionIo<'adapter> {
+  reader: SessionReader<'adapter>,
+  writer: SessionWriter<'adapter>,
+  nonblocking: bool,
+  truncated: bool,
+}
+
+impl<'adapter> SessionIo<'adapter> {
+  pub fn new(session: Session<'adapter>) -> Self {
+    let (reader, writer) = session.split();
+    Self {
+      reader,
+      writer,
+      nonblocking: false,
+      truncated: false,
+    }
+  }
+  pub fn adapter(&self) -> &Adapter {
+    self.reader.adapter()
+  }
+  pub fn stats(&self) -> SessionStats {
+    SessionStats {
+      rx: self.reader.stats(),
+      tx: self.writer.stats(),
+    }
+  }
+  pub fn set_nonblocking(&mut self, nonblocking: bool) {
+    self.nonblocking = nonblocking;
+  }
+  pub fn is_nonblocking(&self) -> bool {
+    self.nonblocking
+  }
+  /// Whether the packet returned by the last successful read was longer than the buffer
+  pub fn truncated(&self) -> bool {
+    self.truncated
+  }
+  pub fn into_split(self) -> (SessionReader<'adapter>, SessionWriter<'adapter>) {
+    (self.reader, self.writer)
+  }
+}
+
+impl<'adapter> io::Read for SessionIo<'adapter> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    let result = if self.nonblocking {
+      self.reader.recv()
+    } else {
+      self.reader.recv_blocking()
+    };
+    let packet = match result {
+      Ok(packet) => packet,
+      Err(ReceivePacketError::AdapterIsTerminating) => return Ok(0),
+      Err(err) => return Err(WintunError::from(err).into()),
+    };
+    let data = packet.slice();
+    let size = data.len().min(buf.len());
+    buf[..size].copy_from_slice(&data[..size]);
+    self.truncated = data.len() > buf.len();
+    Ok(size)
+  }
+}
+
+impl<'adapter> io::Write for SessionIo<'adapter> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    if buf.len() > MAX_IP_PACKET_SIZE as usize {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        WintunError::InvalidPacketSize,
+      ));
+    }
+    let size = IpPacketSize::try_from(buf.len() as u32).map_err(WintunError::from)?;
+    let result = if self.nonblocking {
+      self.writer.allocate(size)
+    } else {
+      self.writer.allocate_blocking(size)
+    };
+    let mut packet = result.map_err(WintunError::from)?;
+    packet.mut_slice().copy_from_slice(buf);
+    packet.send();
+    Ok(buf.len())
+  }
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::{self, Read, Write},
+    thread,
+    time::Duration,
+  };
+
+  use crate::{Adapter, RingCapacity, MAX_IP_PACKET_SIZE};
+
+  use super::SessionIo;
+
+  #[test]
+  fn read_and_write_packets() {
+    let adapter = Adapter::create_in_memory("io-exchange", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let mut io = SessionIo::new(adapter.session(RingCapacity::min()).unwrap());
+    assert_eq!(io.write(&[0x45, 1, 2]).unwrap(), 3);
+    assert_eq!(io.write(&[0x45, 4]).unwrap(), 2);
+    assert_eq!(peer.try_recv().unwrap(), [0x45, 1, 2]);
+    assert_eq!(peer.try_recv().unwrap(), [0x45, 4]);
+
+    peer.inject(&[0x45, 5, 6, 7]).unwrap();
+    peer.inject(&[0x45, 8]).unwrap();
+    let mut buf = [0; 16];
+    assert_eq!(io.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], [0x45, 5, 6, 7]);
+    assert_eq!(io.read(&mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], [0x45, 8]);
+    assert!(!io.truncated());
+    assert_eq!(io.stats().rx.packets, 2);
+    assert_eq!(io.stats().tx.packets, 2);
+  }
+
+  #[test]
+  fn nonblocking_mode_reports_would_block() {
+    let adapter = Adapter::create_in_memory("io-nonblocking", "tunnel_type", None).unwrap();
+    let mut io = SessionIo::new(adapter.session(RingCapacity::min()).unwrap());
+    io.set_nonblocking(true);
+    let err = io.read(&mut [0; 16]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    let packet = vec![0x45; MAX_IP_PACKET_SIZE as usize];
+    let err = loop {
+      match io.write(&packet) {
+        Ok(written) => assert_eq!(written, packet.len()),
+        Err(err) => break err,
+      }
+    };
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+  }
+
+  #[test]
+  fn blocking_read_waits_for_packet() {
+    let adapter = Adapter::create_in_memory("io-blocking", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let mut io = SessionIo::new(adapter.into_session(RingCapacity::min()).unwrap());
+    let reader = thread::spawn(move || {
+      let mut buf = [0; 16];
+      let size = io.read(&mut buf).unwrap();
+      buf[..size].to_vec()
+    });
+    thread::sleep(Duration::from_millis(20));
+    peer.inject(&[0x45, 9]).unwrap();
+    assert_eq!(reader.join().unwrap(), [0x45, 9]);
+  }
+
+  #[test]
+  fn long_packets_are_truncated() {
+    let adapter = Adapter::create_in_memory("io-truncate", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let mut io = SessionIo::new(adapter.session(RingCapacity::min()).unwrap());
+    peer.inject(&[0x45, 1, 2, 3, 4]).unwrap();
+    peer.inject(&[0x45, 5]).unwrap();
+    let mut buf = [0; 3];
+    assert_eq!(io.read(&mut buf).unwrap(), 3);
+    assert_eq!(buf, [0x45, 1, 2]);
+    assert!(io.truncated());
+    //The rest of a truncated packet is dropped
+    assert_eq!(io.read(&mut buf).unwrap(), 2);
+    assert!(!io.truncated());
+  }
+
+  #[test]
+  fn oversized_writes_are_rejected() {
+    let adapter = Adapter::create_in_memory("io-oversized", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let mut io = SessionIo::new(adapter.session(RingCapacity::min()).unwrap());
+    let err = io
+      .write(&vec![0x45; MAX_IP_PACKET_SIZE as usize + 1])
+      .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(io.write(&[]).unwrap(), 0);
+    assert!(peer.try_recv().is_none());
+  }
+
+  //Forwards every write to a channel so the test knows when packets were copied
+  struct ChannelSink(std::sync::mpsc::Sender<Vec<u8>>);
+
+  impl Write for ChannelSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.send(buf.to_vec()).unwrap();
+      Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn copy_ends_when_adapter_terminates() {
+    let adapter = Adapter::create_in_memory("io-copy", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let mut io = SessionIo::new(adapter.into_session(RingCapacity::min()).unwrap());
+    let (sender, copied) = std::sync::mpsc::channel();
+    let copy = thread::spawn(move || {
+      let total = io::copy(&mut io, &mut ChannelSink(sender)).unwrap();
+      (total, io.write(&[0x45]).unwrap_err().kind())
+    });
+    peer.inject(&[0x45, 1]).unwrap();
+    peer.inject(&[0x45, 2, 3]).unwrap();
+    assert_eq!(copied.recv().unwrap(), [0x45, 1]);
+    assert_eq!(copied.recv().unwrap(), [0x45, 2, 3]);
+    peer.terminate();
+    assert_eq!(copy.join().unwrap(), (5, io::ErrorKind::BrokenPipe));
+  }
+}
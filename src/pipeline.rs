@@ -0,0 +1,604 @@
+//! Middleware for tunnel traffic. A [`PacketPipeline`] runs every packet through an ordered list
+//! of [`PacketProcessor`]s, each of which may let it pass, change it, drop it or answer it.
+//! [`PipelineSession`] puts a pipeline between a [`Session`] and its backend, so every received
+//! packet and every sent packet goes through it
+
+use std::{
+  collections::{HashMap, VecDeque},
+  ops::{Deref, DerefMut},
+  sync::{Arc, Mutex, MutexGuard},
+  time::Duration,
+};
+
+use crate::{
+  backend::SessionBackend, AllocatePacketError, Direction, ReceivePacketError, Session,
+  WintunResult,
+};
+
+/// What a processor decides about a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+  /// Pass the packet on, including changes made to it in place
+  Accept,
+  /// Discard the packet, later stages don't see it
+  Drop,
+  /// Pass this packet on instead, for changes that don't fit in place. An empty packet is
+  /// dropped
+  Rewrite(Vec<u8>),
+  /// Discard the packet and send this one back where it came from. The reply skips the pipeline,
+  /// an empty one is dropped
+  Reply(Vec<u8>),
+}
+
+/// One stage of a [`PacketPipeline`]. Both hooks accept every packet unless overridden
+pub trait PacketProcessor: Send {
+  /// Shown in the stage's [`StageStats`]
+  fn name(&self) -> &'static str {
+    std::any::type_name::<Self>()
+  }
+  /// Called for packets received by the session
+  fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+    let _ = packet;
+    Verdict::Accept
+  }
+  /// Called for packets sent by the session
+  fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+    let _ = packet;
+    Verdict::Accept
+  }
+}
+
+/// How often a stage reached each verdict in one direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerdictStats {
+  pub accepted: u64,
+  pub dropped: u64,
+  pub rewritten: u64,
+  pub replied: u64,
+}
+
+impl VerdictStats {
+  fn count(&mut self, verdict: &Verdict) {
+    match verdict {
+      Verdict::Accept => self.accepted += 1,
+      Verdict::Drop => self.dropped += 1,
+      Verdict::Rewrite(_) => self.rewritten += 1,
+      Verdict::Reply(_) => self.replied += 1,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageStats {
+  pub name: &'static str,
+  pub inbound: VerdictStats,
+  pub outbound: VerdictStats,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+  /// In the order the stages run
+  pub stages: Vec<StageStats>,
+  /// Sent packets and replies that were lost because the send ring stayed full while too many
+  /// of them were waiting for it
+  pub overflowed: u64,
+}
+
+struct Stage {
+  processor: Box<dyn PacketProcessor>,
+  stats: StageStats,
+}
+
+/// Processors that run in the order they were added. A packet stops at the first stage that
+/// drops or answers it
+#[derive(Default)]
+pub struct PacketPipeline {
+  stages: Vec<Stage>,
+  overflowed: u64,
+}
+
+impl PacketPipeline {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Appends a stage
+  pub fn with(mut self, processor: impl PacketProcessor + 'static) -> Self {
+    self.push(processor);
+    self
+  }
+  /// Appends a stage
+  pub fn push(&mut self, processor: impl PacketProcessor + 'static) {
+    let stats = StageStats {
+      name: processor.name(),
+      inbound: VerdictStats::default(),
+      outbound: VerdictStats::default(),
+    };
+    self.stages.push(Stage {
+      processor: Box::new(processor),
+      stats,
+    });
+  }
+  pub fn len(&self) -> usize {
+    self.stages.len()
+  }
+  pub fn is_empty(&self) -> bool {
+    self.stages.is_empty()
+  }
+  pub fn stats(&self) -> PipelineStats {
+    PipelineStats {
+      stages: self
+        .stages
+        .iter()
+        .map(|stage| stage.stats.clone())
+        .collect(),
+      overflowed: self.overflowed,
+    }
+  }
+  /// Runs `packet` through every stage. The result is [`Verdict::Rewrite`] if any stage
+  /// rewrote the packet, changes made in place are left in `packet`
+  pub fn process(&mut self, direction: Direction, packet: &mut [u8]) -> Verdict {
+    let mut rewritten: Option<Vec<u8>> = None;
+    for stage in &mut self.stages {
+      let current = match &mut rewritten {
+        Some(rewritten) => &mut rewritten[..],
+        None => &mut *packet,
+      };
+      let (mut verdict, stats) = match direction {
+        Direction::Inbound => (
+          stage.processor.on_inbound(current),
+          &mut stage.stats.inbound,
+        ),
+        Direction::Outbound => (
+          stage.processor.on_outbound(current),
+          &mut stage.stats.outbound,
+        ),
+      };
+      if matches!(&verdict, Verdict::Rewrite(packet) | Verdict::Reply(packet) if packet.is_empty())
+      {
+        verdict = Verdict::Drop;
+      }
+      stats.count(&verdict);
+      match verdict {
+        Verdict::Accept => {}
+        Verdict::Rewrite(packet) => rewritten = Some(packet),
+        verdict => return verdict,
+      }
+    }
+    rewritten.map_or(Verdict::Accept, Verdict::Rewrite)
+  }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Packets that may wait for room in the send ring before new ones are lost
+const MAX_PENDING: usize = 64;
+/// Longest a wait goes without checking for replies queued by the sending half
+#[cfg(not(windows))]
+const REPLY_POLL: Duration = Duration::from_millis(10);
+
+struct PipelineBackend {
+  inner: Box<dyn SessionBackend>,
+  pipeline: Arc<Mutex<PacketPipeline>>,
+  /// Replies to sent packets, waiting to be received
+  replies: Mutex<VecDeque<Vec<u8>>>,
+  /// Packets for the send ring, waiting for room in it
+  pending: Mutex<VecDeque<Box<[u8]>>>,
+  /// Received packets handed out from our own buffers instead of the ring, by address. They are
+  /// never empty, so addresses are unique
+  owned: Mutex<HashMap<usize, Box<[u8]>>>,
+}
+
+impl PipelineBackend {
+  fn hand_out(&self, packet: Vec<u8>) -> (*mut u8, u32) {
+    let mut packet = packet.into_boxed_slice();
+    let (data, size) = (packet.as_mut_ptr(), packet.len() as u32);
+    lock(&self.owned).insert(data as usize, packet);
+    (data, size)
+  }
+  /// Moves waiting packets to the send ring, failing with `WouldBlock` while some don't fit
+  fn flush(&self, pending: &mut VecDeque<Box<[u8]>>) -> Result<(), AllocatePacketError> {
+    while let Some(packet) = pending.front() {
+      let data = self.inner.allocate(packet.len() as u32)?;
+      unsafe { std::ptr::copy_nonoverlapping(packet.as_ptr(), data, packet.len()) };
+      self.inner.send(data, packet.len() as u32);
+      pending.pop_front();
+    }
+    Ok(())
+  }
+  /// Sends a packet on the underlying session after those still waiting. It waits as well if the
+  /// ring is full, and the oldest waiting packet is lost once too many are
+  fn forward(&self, packet: Box<[u8]>) {
+    let mut pending = lock(&self.pending);
+    pending.push_back(packet);
+    if self.flush(&mut pending).is_ok() || pending.len() <= MAX_PENDING {
+      return;
+    }
+    pending.pop_front();
+    drop(pending);
+    lock(&self.pipeline).overflowed += 1;
+  }
+  fn queue_reply(&self, reply: Vec<u8>) {
+    lock(&self.replies).push_back(reply);
+    //Wakes readers waiting on the event, which is only signalled for the ring otherwise
+    #[cfg(windows)]
+    if let Ok(event) = self.inner.read_wait_event() {
+      unsafe { winapi::um::synchapi::SetEvent(event) };
+    }
+  }
+}
+
+impl SessionBackend for PipelineBackend {
+  fn receive(&self) -> Result<(*mut u8, u32), ReceivePacketError> {
+    if let Some(reply) = lock(&self.replies).pop_front() {
+      return Ok(self.hand_out(reply));
+    }
+    loop {
+      let (data, size) = self.inner.receive()?;
+      let packet = unsafe { std::slice::from_raw_parts_mut(data, size as usize) };
+      let verdict = lock(&self.pipeline).process(Direction::Inbound, packet);
+      if verdict == Verdict::Accept {
+        return Ok((data, size));
+      }
+      self.inner.release(data, size);
+      match verdict {
+        Verdict::Rewrite(packet) => return Ok(self.hand_out(packet)),
+        Verdict::Reply(reply) => self.forward(reply.into_boxed_slice()),
+        _ => {}
+      }
+    }
+  }
+
+  fn release(&self, data: *const u8, size: u32) {
+    let owned = lock(&self.owned).remove(&(data as usize));
+    if owned.is_none() {
+      self.inner.release(data, size);
+    }
+  }
+
+  fn allocate(&self, size: u32) -> Result<*mut u8, AllocatePacketError> {
+    //Packets only go to the ring once sent, as they may still change size or be dropped. Until
+    //the ones waiting fit, the caller is told to wait as well
+    self.flush(&mut lock(&self.pending))?;
+    let packet = vec![0u8; size as usize].into_boxed_slice();
+    Ok(Box::into_raw(packet) as *mut u8)
+  }
+
+  fn send(&self, data: *const u8, size: u32) {
+    let mut packet = unsafe {
+      Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+        data as *mut u8,
+        size as usize,
+      ))
+    };
+    let verdict = lock(&self.pipeline).process(Direction::Outbound, &mut packet);
+    match verdict {
+      Verdict::Accept => {}
+      Verdict::Drop => return,
+      Verdict::Rewrite(rewritten) => packet = rewritten.into_boxed_slice(),
+      Verdict::Reply(reply) => return self.queue_reply(reply),
+    }
+    self.forward(packet);
+  }
+
+  #[cfg(windows)]
+  fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    if !lock(&self.replies).is_empty() {
+      return Ok(true);
+    }
+    self.inner.wait_readable(timeout)
+  }
+
+  //Nothing wakes the ring's wait for replies, so it waits in slices
+  #[cfg(not(windows))]
+  fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+    loop {
+      if !lock(&self.replies).is_empty() {
+        return Ok(true);
+      }
+      let remaining =
+        deadline.map(|deadline| deadline.saturating_duration_since(std::time::Instant::now()));
+      let slice = remaining.map_or(REPLY_POLL, |remaining| remaining.min(REPLY_POLL));
+      if self.inner.wait_readable(Some(slice))? {
+        return Ok(true);
+      }
+      if remaining.is_some_and(|remaining| remaining <= slice) {
+        return Ok(false);
+      }
+    }
+  }
+
+  #[cfg(windows)]
+  fn read_wait_event(&self) -> WintunResult<winapi::shared::ntdef::HANDLE> {
+    self.inner.read_wait_event()
+  }
+}
+
+/// Changes and observes the pipeline of a [`PipelineSession`], also after the session was split
+#[derive(Clone)]
+pub struct PipelineControl {
+  pipeline: Arc<Mutex<PacketPipeline>>,
+}
+
+impl PipelineControl {
+  pub fn stats(&self) -> PipelineStats {
+    lock(&self.pipeline).stats()
+  }
+  /// Appends a stage, it sees the next packet in either direction
+  pub fn push(&self, processor: impl PacketProcessor + 'static) {
+    lock(&self.pipeline).push(processor)
+  }
+}
+
+/// A [`Session`] whose packets pass through a [`PacketPipeline`]. It dereferences to the session,
+/// so it can be used, split and converted like any other. Sent packets are copied to the ring
+/// once the pipeline accepted them; when the ring is full at that point they wait, and
+/// allocating fails with `WouldBlock` until they fit. Waiting packets beyond a small limit are
+/// lost and counted in [`PipelineStats::overflowed`]
+pub struct PipelineSession<'adapter> {
+  session: Session<'adapter>,
+  control: PipelineControl,
+}
+
+impl<'adapter> PipelineSession<'adapter> {
+  pub fn new(session: Session<'adapter>, pipeline: PacketPipeline) -> Self {
+    let pipeline = Arc::new(Mutex::new(pipeline));
+    let session = session.wrap_backend(|inner| {
+      Box::new(PipelineBackend {
+        inner,
+        pipeline: pipeline.clone(),
+        replies: Mutex::default(),
+        pending: Mutex::default(),
+        owned: Mutex::default(),
+      })
+    });
+    Self {
+      session,
+      control: PipelineControl { pipeline },
+    }
+  }
+  pub fn control(&self) -> &PipelineControl {
+    &self.control
+  }
+  pub fn into_parts(self) -> (Session<'adapter>, PipelineControl) {
+    (self.session, self.control)
+  }
+}
+
+impl<'adapter> Deref for PipelineSession<'adapter> {
+  type Target = Session<'adapter>;
+  fn deref(&self) -> &Self::Target {
+    &self.session
+  }
+}
+
+impl<'adapter> DerefMut for PipelineSession<'adapter> {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.session
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::{PacketPipeline, PacketProcessor, PipelineSession, Verdict, VerdictStats};
+  use crate::{Adapter, Direction, RingCapacity};
+
+  /// Drops packets whose second byte is odd, answers those where it is 0xFF
+  struct Filter;
+
+  impl PacketProcessor for Filter {
+    fn name(&self) -> &'static str {
+      "filter"
+    }
+    fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+      match packet[1] {
+        0xFF => Verdict::Reply(vec![packet[0], 0xEE]),
+        byte if byte % 2 == 1 => Verdict::Drop,
+        _ => Verdict::Accept,
+      }
+    }
+    fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+      self.on_inbound(packet)
+    }
+  }
+
+  /// Increments the second byte in place
+  struct Increment;
+
+  impl PacketProcessor for Increment {
+    fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+      packet[1] += 1;
+      Verdict::Accept
+    }
+  }
+
+  /// Appends a byte
+  struct Extend(u8);
+
+  impl PacketProcessor for Extend {
+    fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+      Verdict::Rewrite([&*packet, &[self.0]].concat())
+    }
+    fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+      self.on_inbound(packet)
+    }
+  }
+
+  #[test]
+  fn stages_run_in_order_and_short_circuit() {
+    let mut pipeline = PacketPipeline::new()
+      .with(Extend(1))
+      .with(Increment)
+      .with(Filter)
+      .with(Extend(2));
+    assert_eq!(
+      pipeline.process(Direction::Inbound, &mut [0x45, 0]),
+      Verdict::Drop
+    );
+    assert_eq!(
+      pipeline.process(Direction::Inbound, &mut [0x45, 1]),
+      Verdict::Rewrite(vec![0x45, 2, 1, 2])
+    );
+    assert_eq!(
+      pipeline.process(Direction::Inbound, &mut [0x45, 0xFE]),
+      Verdict::Reply(vec![0x45, 0xEE])
+    );
+    let mut packet = [0x45, 0];
+    assert_eq!(
+      PacketPipeline::new()
+        .with(Increment)
+        .process(Direction::Inbound, &mut packet),
+      Verdict::Accept
+    );
+    assert_eq!(packet, [0x45, 1]);
+
+    let stats = pipeline.stats();
+    assert_eq!(stats.stages[0].inbound.rewritten, 3);
+    assert!(stats.stages[1].name.ends_with("Increment"));
+    assert_eq!(
+      stats.stages[2].inbound,
+      VerdictStats {
+        accepted: 1,
+        dropped: 1,
+        rewritten: 0,
+        replied: 1,
+      }
+    );
+    assert_eq!(stats.stages[3].inbound.rewritten, 1);
+    assert_eq!(stats.stages[3].outbound, VerdictStats::default());
+  }
+
+  #[test]
+  fn session_traffic_passes_through_the_pipeline() {
+    let adapter = Adapter::create_in_memory("pipeline-session", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = PipelineSession::new(
+      adapter.session(RingCapacity::min()).unwrap(),
+      PacketPipeline::new().with(Filter),
+    );
+
+    for second in [1, 0xFF, 2] {
+      peer.inject(&[0x45, second]).unwrap();
+    }
+    //The dropped packet is skipped, the answered one never reaches the session
+    assert_eq!(session.recv().unwrap().slice(), &[0x45, 2]);
+    assert!(matches!(session.recv(), Err(err) if err.is_would_block()));
+    assert_eq!(peer.try_recv().unwrap(), vec![0x45, 0xEE]);
+
+    for second in [3, 0xFF, 4] {
+      let mut packet = session.allocate(2.try_into().unwrap()).unwrap();
+      packet.mut_slice().copy_from_slice(&[0x60, second]);
+      packet.send();
+    }
+    assert_eq!(peer.try_recv().unwrap(), vec![0x60, 4]);
+    assert!(peer.try_recv().is_none());
+    assert!(session.wait_readable(Some(Duration::ZERO)).unwrap());
+    assert_eq!(session.recv().unwrap().slice(), &[0x60, 0xEE]);
+
+    let stats = session.control().stats();
+    assert_eq!(stats.stages[0].name, "filter");
+    assert_eq!(stats.stages[0].inbound.replied, 1);
+    assert_eq!(stats.stages[0].outbound.dropped, 1);
+    assert_eq!(stats.overflowed, 0);
+  }
+
+  #[test]
+  fn rewritten_packets_change_size() {
+    let adapter = Adapter::create_in_memory("pipeline-rewrite", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = PipelineSession::new(
+      adapter.session(RingCapacity::min()).unwrap(),
+      PacketPipeline::new(),
+    );
+    session.control().push(Extend(9));
+    peer.inject(&[0x45, 0]).unwrap();
+    assert_eq!(session.recv().unwrap().slice(), &[0x45, 0, 9]);
+    let mut packet = session.allocate(1.try_into().unwrap()).unwrap();
+    packet.mut_slice()[0] = 0x60;
+    packet.send();
+    assert_eq!(peer.try_recv().unwrap(), vec![0x60, 9]);
+  }
+
+  /// Empties packets whose second byte is set, as a rewrite inbound and a reply outbound
+  struct Empty;
+
+  impl PacketProcessor for Empty {
+    fn on_inbound(&mut self, packet: &mut [u8]) -> Verdict {
+      match packet[1] {
+        0 => Verdict::Accept,
+        _ => Verdict::Rewrite(Vec::new()),
+      }
+    }
+    fn on_outbound(&mut self, packet: &mut [u8]) -> Verdict {
+      match packet[1] {
+        0 => Verdict::Accept,
+        _ => Verdict::Reply(Vec::new()),
+      }
+    }
+  }
+
+  #[test]
+  fn empty_rewrites_and_replies_are_dropped() {
+    let adapter = Adapter::create_in_memory("pipeline-empty", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = PipelineSession::new(
+      adapter.session(RingCapacity::min()).unwrap(),
+      PacketPipeline::new().with(Empty),
+    );
+    for second in [1, 2, 0] {
+      peer.inject(&[0x45, second]).unwrap();
+    }
+    assert_eq!(session.recv().unwrap().slice(), &[0x45, 0]);
+    let mut packet = session.allocate(2.try_into().unwrap()).unwrap();
+    packet.mut_slice().copy_from_slice(&[0x60, 1]);
+    packet.send();
+    assert!(!session.wait_readable(Some(Duration::ZERO)).unwrap());
+    assert!(peer.try_recv().is_none());
+
+    let stats = session.control().stats();
+    assert_eq!(stats.stages[0].inbound.dropped, 2);
+    assert_eq!(stats.stages[0].outbound.dropped, 1);
+  }
+
+  #[test]
+  fn allocate_waits_for_a_full_send_ring() {
+    let adapter = Adapter::create_in_memory("pipeline-full", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = PipelineSession::new(
+      adapter.session(RingCapacity::min()).unwrap(),
+      PacketPipeline::new(),
+    );
+    let size = crate::MAX_IP_PACKET_SIZE;
+    let mut sent = 0;
+    let err = loop {
+      match session.allocate(size.try_into().unwrap()) {
+        Ok(mut packet) => {
+          packet.mut_slice()[0] = sent;
+          packet.send();
+          sent += 1;
+        }
+        Err(err) => break err,
+      }
+      assert!(sent < 16, "the ring never filled up");
+    };
+    assert!(err.is_would_block());
+
+    //The packet that didn't fit was kept and follows the others once there is room
+    for expected in 0..sent {
+      let packet = peer.try_recv().unwrap();
+      assert_eq!((packet.len(), packet[0]), (size as usize, expected));
+      if expected == 0 {
+        session.allocate(1.try_into().unwrap()).unwrap().send();
+      }
+    }
+    assert_eq!(peer.try_recv().unwrap().len(), 1);
+    assert!(peer.try_recv().is_none());
+    assert_eq!(session.control().stats().overflowed, 0);
+  }
+}
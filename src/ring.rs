@@ -0,0 +1,697 @@
+//! Wintun's shared-memory ring protocol. A ring is a 12 byte header (`Head`, `Tail` and
+//! `Alertable`, each a 32 bit word) followed by the data area. Packets are stored as a 32 bit size
+//! followed by the packet, padded to 4 bytes. The data area is `capacity` bytes plus room for one
+//! more maximally sized packet, so packets never wrap around the end. The producer owns
+//! everything from `Tail` up to `Head`, the consumer the rest; each side publishes its position
+//! only once all packets before it are sent or released, which allows handing out packets in
+//! place and completing them out of order.
+//!
+//! [`RingProducer`] and [`RingConsumer`] implement the two sides the same way `wintun.dll` does,
+//! including the checks that turn a corrupted ring into [`ReceivePacketError::InvalidData`].
+//!
+//! Built with `--cfg loom` the header words are loom atomics, so the ordering between the two
+//! sides can be model checked with `RUSTFLAGS="--cfg loom" cargo test --lib ring::model`
+
+#[cfg(loom)]
+use loom::sync::atomic::AtomicU32;
+#[cfg(not(loom))]
+use std::sync::atomic::AtomicU32;
+use std::{
+  fmt,
+  sync::{atomic::Ordering, Arc},
+};
+
+use crate::{AllocatePacketError, ReceivePacketError, RingCapacity, MAX_IP_PACKET_SIZE};
+
+pub const PACKET_ALIGNMENT: u32 = 4;
+const PACKET_HEADER_LEN: u32 = 4;
+/// Set in the size of packets that were allocated but not sent, and of packets that were
+/// received and released
+const PACKET_RELEASE: u32 = 0x8000_0000;
+/// Space a maximally sized packet takes in the ring, including its size
+pub const MAX_RING_PACKET_SIZE: u32 = align(PACKET_HEADER_LEN + MAX_IP_PACKET_SIZE);
+const RING_HEADER_LEN: usize = 12;
+const HEAD: usize = 0;
+const TAIL: usize = 4;
+const ALERTABLE: usize = 8;
+
+const fn align(size: u32) -> u32 {
+  (size + PACKET_ALIGNMENT - 1) & !(PACKET_ALIGNMENT - 1)
+}
+
+/// Bytes of memory a ring with `capacity` occupies
+pub fn ring_size(capacity: RingCapacity) -> usize {
+  RING_HEADER_LEN + capacity.cap() as usize + (MAX_RING_PACKET_SIZE - PACKET_ALIGNMENT) as usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingLayoutError {
+  /// The memory is not aligned to 4 bytes
+  Misaligned,
+  /// No valid capacity results in a ring of this many bytes
+  InvalidSize(usize),
+}
+
+impl fmt::Display for RingLayoutError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RingLayoutError::Misaligned => f.write_str("Ring memory is not aligned to 4 bytes"),
+      RingLayoutError::InvalidSize(size) => {
+        f.write_fmt(format_args!("{size} bytes is not the size of a ring"))
+      }
+    }
+  }
+}
+
+impl std::error::Error for RingLayoutError {}
+
+/// The memory of one ring, shared by its producer and consumer
+pub struct RingBuffer {
+  base: *mut u8,
+  capacity: u32,
+  //Keeps the memory alive for rings allocated by `new`
+  _owned: Option<Box<[std::sync::atomic::AtomicU32]>>,
+  //Loom atomics can't live in the ring memory, they shadow its words and are created on first use
+  #[cfg(loom)]
+  words: Box<[std::sync::OnceLock<AtomicU32>]>,
+}
+
+//SAFETY: the memory is only accessed through atomics and through the ring protocol, which hands
+//each byte of the data area to one side at a time
+unsafe impl Send for RingBuffer {}
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+  /// Allocates an empty ring
+  pub fn new(capacity: RingCapacity) -> Self {
+    let words = ring_size(capacity).div_ceil(4);
+    //A zeroed `vec!` gets its pages lazily, which keeps even the largest rings cheap
+    let memory = Box::into_raw(vec![0u32; words].into_boxed_slice());
+    //SAFETY: AtomicU32 has the same layout as u32
+    let memory = unsafe { Box::from_raw(memory as *mut [std::sync::atomic::AtomicU32]) };
+    Self {
+      base: memory.as_ptr() as *mut u8,
+      capacity: capacity.cap(),
+      #[cfg(loom)]
+      words: (0..words).map(|_| Default::default()).collect(),
+      _owned: Some(memory),
+    }
+  }
+  /// Uses memory laid out as a ring, like the rings Wintun shares with its driver. The capacity
+  /// is derived from `len`
+  ///
+  /// # Safety
+  ///
+  /// `base` has to point to `len` bytes that stay valid while the ring exists and that are only
+  /// accessed following the ring protocol
+  pub unsafe fn from_raw_parts(base: *mut u8, len: usize) -> Result<Self, RingLayoutError> {
+    if base as usize % PACKET_ALIGNMENT as usize != 0 {
+      return Err(RingLayoutError::Misaligned);
+    }
+    let capacity = len
+      .checked_sub(ring_size(RingCapacity::min()) - RingCapacity::min().cap() as usize)
+      .and_then(|capacity| u32::try_from(capacity).ok())
+      .and_then(|capacity| RingCapacity::try_from(capacity).ok())
+      .ok_or(RingLayoutError::InvalidSize(len))?;
+    Ok(Self {
+      base,
+      capacity: capacity.cap(),
+      #[cfg(loom)]
+      words: (0..len / 4).map(|_| Default::default()).collect(),
+      _owned: None,
+    })
+  }
+  pub fn capacity(&self) -> RingCapacity {
+    RingCapacity::try_from(self.capacity).expect("the capacity was validated")
+  }
+  #[cfg(not(loom))]
+  fn word(&self, offset: usize) -> &AtomicU32 {
+    //SAFETY: offsets are 4 byte aligned and within the ring, whose base is 4 byte aligned
+    unsafe { &*(self.base.add(offset) as *const AtomicU32) }
+  }
+  #[cfg(loom)]
+  fn word(&self, offset: usize) -> &AtomicU32 {
+    self.words[offset / 4].get_or_init(|| {
+      //SAFETY: as above, the value is only read once to seed the shadow
+      let value = unsafe {
+        (*(self.base.add(offset) as *const std::sync::atomic::AtomicU32)).load(Ordering::Relaxed)
+      };
+      AtomicU32::new(value)
+    })
+  }
+  /// Offset of the next packet the consumer has not released
+  pub fn head(&self) -> u32 {
+    self.word(HEAD).load(Ordering::Acquire)
+  }
+  /// Offset after the last packet the producer has sent
+  pub fn tail(&self) -> u32 {
+    self.word(TAIL).load(Ordering::Acquire)
+  }
+  /// Set by a consumer that is about to sleep, asking the producer to wake it after sending
+  pub fn is_alertable(&self) -> bool {
+    self.word(ALERTABLE).load(Ordering::SeqCst) != 0
+  }
+  pub fn set_alertable(&self, alertable: bool) {
+    self
+      .word(ALERTABLE)
+      .store(alertable as u32, Ordering::SeqCst);
+  }
+  /// Moves head and tail out of range, which both sides report as
+  /// [`AdapterIsTerminating`](ReceivePacketError::AdapterIsTerminating)
+  pub fn terminate(&self) {
+    self.word(HEAD).store(u32::MAX, Ordering::SeqCst);
+    self.word(TAIL).store(u32::MAX, Ordering::SeqCst);
+  }
+  pub fn is_terminated(&self) -> bool {
+    self.head() >= self.capacity || self.tail() >= self.capacity
+  }
+  /// Stores a position unless the ring was terminated in the meantime
+  fn publish(&self, offset: usize, value: u32) {
+    let _ = self
+      .word(offset)
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+        (current < self.capacity).then_some(value)
+      });
+  }
+  fn packet_size(&self, offset: u32) -> &AtomicU32 {
+    self.word(RING_HEADER_LEN + offset as usize)
+  }
+  fn packet_data(&self, offset: u32) -> *mut u8 {
+    //SAFETY: packets start within the data area and the slack after it holds the largest packet
+    unsafe {
+      self
+        .base
+        .add(RING_HEADER_LEN + (offset + PACKET_HEADER_LEN) as usize)
+    }
+  }
+  /// Offset of the packet whose data starts at `data`
+  fn packet_offset(&self, data: *const u8) -> u32 {
+    (data as usize - self.base as usize - RING_HEADER_LEN) as u32 - PACKET_HEADER_LEN
+  }
+  fn wrap(&self, value: u32) -> u32 {
+    value & (self.capacity - 1)
+  }
+}
+
+/// The side of a ring that reads packets, like `WintunReceivePacket` on the ring the driver fills
+pub struct RingConsumer {
+  ring: Arc<RingBuffer>,
+  head: u32,
+  head_release: u32,
+  packets_to_release: u32,
+}
+
+impl RingConsumer {
+  pub fn new(ring: Arc<RingBuffer>) -> Self {
+    let head = ring.head();
+    Self {
+      ring,
+      head,
+      head_release: head,
+      packets_to_release: 0,
+    }
+  }
+  pub fn ring(&self) -> &Arc<RingBuffer> {
+    &self.ring
+  }
+  /// Ends the ring from this side: the producer fails with `AdapterIsTerminating` while packets
+  /// it already sent can still be received
+  pub fn terminate(&self) {
+    self.ring.word(HEAD).store(u32::MAX, Ordering::SeqCst);
+  }
+  /// Whether [`receive`](Self::receive) would return something other than `WouldBlock`
+  pub fn is_ready(&self) -> bool {
+    self.ring.tail() != self.head
+  }
+  /// Takes the next packet in place. It stays valid until it is passed to
+  /// [`release`](Self::release)
+  pub fn receive(&mut self) -> Result<(*mut u8, u32), ReceivePacketError> {
+    let ring = &*self.ring;
+    if self.head >= ring.capacity {
+      return Err(ReceivePacketError::AdapterIsTerminating);
+    }
+    let tail = ring.tail();
+    if tail >= ring.capacity {
+      return Err(ReceivePacketError::AdapterIsTerminating);
+    }
+    if tail == self.head {
+      return Err(ReceivePacketError::WouldBlock);
+    }
+    let content = ring.wrap(tail.wrapping_sub(self.head));
+    if content < PACKET_HEADER_LEN {
+      return Err(ReceivePacketError::InvalidData);
+    }
+    let size = ring.packet_size(self.head).load(Ordering::Relaxed);
+    if size > MAX_IP_PACKET_SIZE {
+      return Err(ReceivePacketError::InvalidData);
+    }
+    let aligned = align(PACKET_HEADER_LEN + size);
+    if aligned > content {
+      return Err(ReceivePacketError::InvalidData);
+    }
+    let data = ring.packet_data(self.head);
+    self.head = ring.wrap(self.head + aligned);
+    self.packets_to_release += 1;
+    Ok((data, size))
+  }
+  /// Hands a received packet back. The ring space is freed once all packets received before it
+  /// are released as well
+  ///
+  /// # Safety
+  ///
+  /// `data` has to be a packet returned by [`receive`](Self::receive) of this consumer that was
+  /// not released yet
+  pub unsafe fn release(&mut self, data: *const u8) {
+    let ring = &*self.ring;
+    ring
+      .packet_size(ring.packet_offset(data))
+      .fetch_or(PACKET_RELEASE, Ordering::Relaxed);
+    while self.packets_to_release > 0 {
+      let size = ring.packet_size(self.head_release).load(Ordering::Relaxed);
+      if size & PACKET_RELEASE == 0 {
+        break;
+      }
+      let aligned = align(PACKET_HEADER_LEN + (size & !PACKET_RELEASE));
+      self.head_release = ring.wrap(self.head_release + aligned);
+      self.packets_to_release -= 1;
+    }
+    ring.publish(HEAD, self.head_release);
+  }
+}
+
+/// The side of a ring that writes packets, like `WintunAllocateSendPacket` and
+/// `WintunSendPacket` on the ring the driver drains
+pub struct RingProducer {
+  ring: Arc<RingBuffer>,
+  tail: u32,
+  tail_release: u32,
+  packets_to_release: u32,
+}
+
+impl RingProducer {
+  pub fn new(ring: Arc<RingBuffer>) -> Self {
+    let tail = ring.tail();
+    Self {
+      ring,
+      tail,
+      tail_release: tail,
+      packets_to_release: 0,
+    }
+  }
+  pub fn ring(&self) -> &Arc<RingBuffer> {
+    &self.ring
+  }
+  /// Reserves room for a packet of `size` bytes. It stays reserved until it is passed to
+  /// [`send`](Self::send)
+  pub fn allocate(&mut self, size: u32) -> Result<*mut u8, AllocatePacketError> {
+    let ring = &*self.ring;
+    if self.tail >= ring.capacity {
+      return Err(AllocatePacketError::AdapterIsTerminating);
+    }
+    let aligned = align(PACKET_HEADER_LEN + size.min(MAX_IP_PACKET_SIZE));
+    let head = ring.head();
+    if head >= ring.capacity {
+      return Err(AllocatePacketError::AdapterIsTerminating);
+    }
+    //One alignment unit stays free so a full ring can be told apart from an empty one
+    let space = ring.wrap(head.wrapping_sub(self.tail).wrapping_sub(PACKET_ALIGNMENT));
+    if size > MAX_IP_PACKET_SIZE || aligned > space {
+      return Err(AllocatePacketError::WouldBlock);
+    }
+    ring
+      .packet_size(self.tail)
+      .store(size | PACKET_RELEASE, Ordering::Relaxed);
+    let data = ring.packet_data(self.tail);
+    self.tail = ring.wrap(self.tail + aligned);
+    self.packets_to_release += 1;
+    Ok(data)
+  }
+  /// Publishes an allocated packet once all packets allocated before it are sent as well.
+  /// Returns `true` if the consumer asked to be woken up
+  ///
+  /// # Safety
+  ///
+  /// `data` has to be a packet returned by [`allocate`](Self::allocate) of this producer that was
+  /// not sent yet
+  pub unsafe fn send(&mut self, data: *const u8) -> bool {
+    let ring = &*self.ring;
+    ring
+      .packet_size(ring.packet_offset(data))
+      .fetch_and(!PACKET_RELEASE, Ordering::Relaxed);
+    let previous = self.tail_release;
+    while self.packets_to_release > 0 {
+      let size = ring.packet_size(self.tail_release).load(Ordering::Relaxed);
+      if size & PACKET_RELEASE != 0 {
+        break;
+      }
+      self.tail_release = ring.wrap(self.tail_release + align(PACKET_HEADER_LEN + size));
+      self.packets_to_release -= 1;
+    }
+    if self.tail_release == previous {
+      return false;
+    }
+    ring.publish(TAIL, self.tail_release);
+    ring.is_alertable()
+  }
+  /// Publishes a packet header claiming more data than a packet can hold, so consumers see a
+  /// corrupted ring. Only for testing how corruption is handled
+  pub fn publish_corrupt_header(&mut self) {
+    let ring = &*self.ring;
+    ring
+      .packet_size(self.tail)
+      .store(MAX_IP_PACKET_SIZE + 1, Ordering::Relaxed);
+    self.tail = ring.wrap(self.tail + PACKET_HEADER_LEN);
+    if self.packets_to_release == 0 {
+      self.tail_release = self.tail;
+      ring.publish(TAIL, self.tail_release);
+    }
+  }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+  use std::{
+    collections::VecDeque,
+    sync::{atomic::Ordering, Arc},
+  };
+
+  use proptest::prelude::*;
+
+  use super::{
+    ring_size, RingBuffer, RingConsumer, RingLayoutError, RingProducer, MAX_RING_PACKET_SIZE,
+  };
+  use crate::{
+    AllocatePacketError, ReceivePacketError, RingCapacity, MAX_IP_PACKET_SIZE, MIN_RING_CAPACITY,
+  };
+
+  fn ring() -> (RingProducer, RingConsumer) {
+    let ring = Arc::new(RingBuffer::new(RingCapacity::min()));
+    (RingProducer::new(ring.clone()), RingConsumer::new(ring))
+  }
+
+  fn send(producer: &mut RingProducer, packet: &[u8]) -> Result<(), AllocatePacketError> {
+    let data = producer.allocate(packet.len() as u32)?;
+    unsafe {
+      std::ptr::copy_nonoverlapping(packet.as_ptr(), data, packet.len());
+      producer.send(data);
+    }
+    Ok(())
+  }
+
+  fn receive(consumer: &mut RingConsumer) -> Result<Vec<u8>, ReceivePacketError> {
+    let (data, size) = consumer.receive()?;
+    let packet = unsafe { std::slice::from_raw_parts(data, size as usize) }.to_vec();
+    unsafe { consumer.release(data) };
+    Ok(packet)
+  }
+
+  #[test]
+  fn layout() {
+    assert_eq!(MAX_RING_PACKET_SIZE, 0x10004);
+    assert_eq!(
+      ring_size(RingCapacity::min()),
+      12 + MIN_RING_CAPACITY as usize + 0x10000
+    );
+    let mut memory = vec![0u32; ring_size(RingCapacity::min()) / 4];
+    let base = memory.as_mut_ptr() as *mut u8;
+    let ring = unsafe { RingBuffer::from_raw_parts(base, memory.len() * 4) }.unwrap();
+    assert_eq!(ring.capacity(), RingCapacity::min());
+    assert_eq!(
+      unsafe { RingBuffer::from_raw_parts(base, memory.len() * 4 - 4) }.err(),
+      Some(RingLayoutError::InvalidSize(memory.len() * 4 - 4))
+    );
+    assert_eq!(
+      unsafe { RingBuffer::from_raw_parts(base.wrapping_add(1), 16) }.err(),
+      Some(RingLayoutError::Misaligned)
+    );
+
+    //Head, tail and the size of the first packet sit where the driver expects them
+    let ring = Arc::new(ring);
+    let mut producer = RingProducer::new(ring.clone());
+    send(&mut producer, &[0xAA; 5]).unwrap();
+    assert_eq!(memory[1], 12);
+    assert_eq!(memory[3], 5);
+    assert_eq!(memory[4], 0xAAAA_AAAA);
+    let mut consumer = RingConsumer::new(ring);
+    receive(&mut consumer).unwrap();
+    assert_eq!(memory[0], 12);
+  }
+
+  #[test]
+  fn out_of_order_completion() {
+    let (mut producer, mut consumer) = ring();
+    let first = producer.allocate(4).unwrap();
+    let second = producer.allocate(4).unwrap();
+    //The second packet is only published together with the first
+    assert!(!unsafe { producer.send(second) });
+    assert!(!consumer.is_ready());
+    unsafe { producer.send(first) };
+    assert!(consumer.is_ready());
+
+    let (first, _) = consumer.receive().unwrap();
+    let (second, _) = consumer.receive().unwrap();
+    assert_eq!(consumer.receive(), Err(ReceivePacketError::WouldBlock));
+    unsafe { consumer.release(second) };
+    assert_eq!(consumer.ring().head(), 0);
+    unsafe { consumer.release(first) };
+    assert_eq!(consumer.ring().head(), 16);
+  }
+
+  #[test]
+  fn capacity_and_wrapping() {
+    let (mut producer, mut consumer) = ring();
+    let packet = vec![7; MAX_IP_PACKET_SIZE as usize];
+    //One alignment unit always stays free
+    let fits = (MIN_RING_CAPACITY - 4) / MAX_RING_PACKET_SIZE;
+    for _ in 0..fits {
+      send(&mut producer, &packet).unwrap();
+    }
+    assert_eq!(
+      send(&mut producer, &packet),
+      Err(AllocatePacketError::WouldBlock)
+    );
+    //Going around the ring many times, packets running past the end of the data area
+    for round in 0..100u32 {
+      assert_eq!(receive(&mut consumer).unwrap(), packet);
+      let small = vec![round as u8; 1 + round as usize * 7];
+      send(&mut producer, &small).unwrap();
+      assert_eq!(receive(&mut consumer).unwrap(), small);
+      send(&mut producer, &packet).unwrap();
+    }
+    assert_eq!(
+      producer.allocate(MAX_IP_PACKET_SIZE + 1),
+      Err(AllocatePacketError::WouldBlock)
+    );
+  }
+
+  #[test]
+  fn corruption_and_termination() {
+    let (mut producer, mut consumer) = ring();
+    send(&mut producer, &[1]).unwrap();
+    producer.publish_corrupt_header();
+    assert_eq!(receive(&mut consumer), Ok(vec![1]));
+    assert_eq!(consumer.receive(), Err(ReceivePacketError::InvalidData));
+    assert_eq!(consumer.receive(), Err(ReceivePacketError::InvalidData));
+
+    //A tail pointing into the middle of a packet
+    let (_, mut consumer) = ring();
+    consumer.ring().packet_size(0).store(100, Ordering::SeqCst);
+    consumer.ring().word(super::TAIL).store(8, Ordering::SeqCst);
+    assert_eq!(consumer.receive(), Err(ReceivePacketError::InvalidData));
+
+    let (mut producer, mut consumer) = ring();
+    let data = producer.allocate(1).unwrap();
+    producer.ring().terminate();
+    assert!(producer.ring().is_terminated());
+    assert_eq!(
+      consumer.receive(),
+      Err(ReceivePacketError::AdapterIsTerminating)
+    );
+    assert_eq!(
+      producer.allocate(1),
+      Err(AllocatePacketError::AdapterIsTerminating)
+    );
+    //Completing packets afterwards doesn't revive the ring
+    unsafe { producer.send(data) };
+    assert!(producer.ring().is_terminated());
+  }
+
+  #[test]
+  fn consumer_termination_keeps_sent_packets() {
+    let (mut producer, mut consumer) = ring();
+    send(&mut producer, &[1, 2]).unwrap();
+    consumer.terminate();
+    assert_eq!(
+      producer.allocate(1),
+      Err(AllocatePacketError::AdapterIsTerminating)
+    );
+    assert_eq!(receive(&mut consumer), Ok(vec![1, 2]));
+    assert_eq!(consumer.receive(), Err(ReceivePacketError::WouldBlock));
+    assert!(consumer.ring().is_terminated());
+  }
+
+  #[test]
+  fn threads() {
+    let (mut producer, mut consumer) = ring();
+    let count = 20_000u32;
+    std::thread::scope(|scope| {
+      scope.spawn(move || {
+        for i in 0..count {
+          let packet = i.to_le_bytes().repeat(1 + i as usize % 50);
+          while send(&mut producer, &packet).is_err() {
+            std::thread::yield_now();
+          }
+        }
+      });
+      for i in 0..count {
+        let packet = loop {
+          match receive(&mut consumer) {
+            Ok(packet) => break packet,
+            Err(ReceivePacketError::WouldBlock) => std::thread::yield_now(),
+            Err(err) => panic!("{err:?}"),
+          }
+        };
+        assert_eq!(packet, i.to_le_bytes().repeat(1 + i as usize % 50));
+      }
+    });
+  }
+
+  #[derive(Debug, Clone)]
+  enum Operation {
+    Allocate(u16),
+    Send(usize),
+    Receive,
+    Release(usize),
+  }
+
+  fn operation() -> impl Strategy<Value = Operation> {
+    prop_oneof![
+      (1..=MAX_IP_PACKET_SIZE as u16).prop_map(Operation::Allocate),
+      (1..2000u16).prop_map(Operation::Allocate),
+      any::<usize>().prop_map(Operation::Send),
+      Just(Operation::Receive),
+      any::<usize>().prop_map(Operation::Release),
+    ]
+  }
+
+  proptest! {
+    //Packets come out in the order they were allocated, unchanged, and only once every packet
+    //before them was sent
+    #[test]
+    fn matches_a_queue(operations in proptest::collection::vec(operation(), 1..200)) {
+      let (mut producer, mut consumer) = ring();
+      let mut allocated: Vec<(u32, *mut u8, bool)> = Vec::new();
+      let mut sent = VecDeque::new();
+      let mut received: Vec<*mut u8> = Vec::new();
+      let mut next_id = 0u32;
+      for operation in operations {
+        match operation {
+          Operation::Allocate(size) => {
+            if let Ok(data) = producer.allocate(size as u32) {
+              unsafe { std::ptr::write_bytes(data, next_id as u8, size as usize) };
+              allocated.push((next_id, data, false));
+              next_id += 1;
+            }
+          }
+          Operation::Send(index) if !allocated.is_empty() => {
+            let index = index % allocated.len();
+            if !allocated[index].2 {
+              allocated[index].2 = true;
+              unsafe { producer.send(allocated[index].1) };
+            }
+            while allocated.first().is_some_and(|packet| packet.2) {
+              sent.push_back(allocated.remove(0).0);
+            }
+          }
+          Operation::Receive => match consumer.receive() {
+            Ok((data, size)) => {
+              let id = sent.pop_front().expect("only sent packets are received");
+              let packet = unsafe { std::slice::from_raw_parts(data, size as usize) };
+              prop_assert!(packet.iter().all(|&byte| byte == id as u8));
+              received.push(data);
+            }
+            Err(err) => {
+              prop_assert_eq!(err, ReceivePacketError::WouldBlock);
+              prop_assert!(sent.is_empty());
+            }
+          },
+          Operation::Release(index) if !received.is_empty() => {
+            let data = received.swap_remove(index % received.len());
+            unsafe { consumer.release(data) };
+          }
+          _ => {}
+        }
+      }
+    }
+  }
+}
+
+#[cfg(all(test, loom))]
+mod model {
+  use std::sync::Arc;
+
+  use loom::thread;
+
+  use super::{RingBuffer, RingConsumer, RingProducer};
+  use crate::{AllocatePacketError, ReceivePacketError, RingCapacity, MAX_IP_PACKET_SIZE};
+
+  /// Receives `count` packets, spinning while the ring is empty, and returns their sizes
+  fn receive_all(consumer: &mut RingConsumer, count: usize) -> Vec<u32> {
+    let mut sizes = Vec::new();
+    while sizes.len() < count {
+      match consumer.receive() {
+        Ok((data, size)) => {
+          sizes.push(size);
+          unsafe { consumer.release(data) };
+        }
+        Err(ReceivePacketError::WouldBlock) => thread::yield_now(),
+        Err(err) => panic!("{err:?}"),
+      }
+    }
+    sizes
+  }
+
+  #[test]
+  fn consumer_sees_sent_headers() {
+    loom::model(|| {
+      let ring = Arc::new(RingBuffer::new(RingCapacity::min()));
+      let mut producer = RingProducer::new(ring.clone());
+      let mut consumer = RingConsumer::new(ring);
+      let sender = thread::spawn(move || {
+        for size in [3, 5] {
+          let data = producer.allocate(size).unwrap();
+          unsafe { producer.send(data) };
+        }
+      });
+      assert_eq!(receive_all(&mut consumer, 2), [3, 5]);
+      sender.join().unwrap();
+    });
+  }
+
+  #[test]
+  fn producer_reuses_released_space() {
+    loom::model(|| {
+      //Only one packet of the largest size fits, so every allocation waits for a release
+      let ring = Arc::new(RingBuffer::new(RingCapacity::min()));
+      let mut producer = RingProducer::new(ring.clone());
+      let mut consumer = RingConsumer::new(ring);
+      let sender = thread::spawn(move || {
+        for size in [MAX_IP_PACKET_SIZE, MAX_IP_PACKET_SIZE - 1] {
+          let data = loop {
+            match producer.allocate(size) {
+              Ok(data) => break data,
+              Err(AllocatePacketError::WouldBlock) => thread::yield_now(),
+              Err(err) => panic!("{err:?}"),
+            }
+          };
+          unsafe { producer.send(data) };
+        }
+      });
+      assert_eq!(
+        receive_all(&mut consumer, 2),
+        [MAX_IP_PACKET_SIZE, MAX_IP_PACKET_SIZE - 1]
+      );
+      sender.join().unwrap();
+    });
+  }
+}
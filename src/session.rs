@@ -1,92 +1,246 @@
-use winapi::shared::{ntdef::HANDLE, winerror};
-
-use crate::{
-  wintun_raw::{
-    WintunAllocateSendPacket, WintunEndSession, WintunGetReadWaitEvent, WintunReceivePacket,
-    WintunReleaseReceivePacket, WintunSendPacket, DWORD, WINTUN_SESSION_HANDLE,
+use std::{
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
   },
-  AllocatePacketError, IpPacketSize, ReceivePacketError, WintunResult,
+  time::Duration,
 };
 
-use super::{
-  packet::{RecvPacket, SendPacket},
-  utility::UnsafeHandle,
+use crate::{
+  adapter::AdapterRef, backend::SessionBackend, split::Wakers, Adapter, AllocatePacketError, IpPacketSize, OsError,
+  ReceivePacketError, SessionReader, SessionWriter, WintunError, WintunResult,
 };
 
-pub struct Session {
-  handle: UnsafeHandle<WINTUN_SESSION_HANDLE>,
+use super::packet::{RecvPacket, SendPacket};
+
+/// Traffic counters of one direction of a session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DirectionStats {
+  pub packets: u64,
+  pub bytes: u64,
+  /// Calls that failed because the ring was empty (receive) or full (send)
+  pub would_block: u64,
+  /// Calls that failed for any other reason
+  pub errors: u64,
 }
 
-impl Session {
-  pub fn end(self) {
-    drop(self)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionStats {
+  pub rx: DirectionStats,
+  pub tx: DirectionStats,
+}
+
+#[derive(Default)]
+pub(crate) struct Counters {
+  packets: AtomicU64,
+  bytes: AtomicU64,
+  would_block: AtomicU64,
+  errors: AtomicU64,
+}
+
+impl Counters {
+  fn packet(&self, size: u32) {
+    self.packets.fetch_add(1, Ordering::Relaxed);
+    self.bytes.fetch_add(size as u64, Ordering::Relaxed);
   }
-  pub fn get_read_wait_event(&self) -> WintunResult<HANDLE> {
-    let event = unsafe { WintunGetReadWaitEvent(self.handle.0) };
-    if event == std::ptr::null_mut() {
-      return Err(get_last_error::Win32Error::get_last_error().into());
+  fn failure(&self, would_block: bool) {
+    if would_block {
+      self.would_block.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.errors.fetch_add(1, Ordering::Relaxed);
     }
-    Ok(event)
-  }
-  pub fn recv(&self) -> Result<RecvPacket, ReceivePacketError> {
-    let mut packet_size: DWORD = 0;
-    let packet_raw = unsafe { WintunReceivePacket(self.handle.0, &mut packet_size as *mut _) };
-    if packet_raw == std::ptr::null_mut() {
-      let error = get_last_error::Win32Error::get_last_error();
-      return Err(match error.code() {
-        winerror::ERROR_HANDLE_EOF => ReceivePacketError::AdapterIsTerminating,
-        winerror::ERROR_NO_MORE_ITEMS => ReceivePacketError::WouldBlock,
-        winerror::ERROR_INVALID_DATA => ReceivePacketError::InvalidData,
-        _ => ReceivePacketError::Other(error),
-      });
+  }
+  pub(crate) fn snapshot(&self) -> DirectionStats {
+    DirectionStats {
+      packets: self.packets.load(Ordering::Relaxed),
+      bytes: self.bytes.load(Ordering::Relaxed),
+      would_block: self.would_block.load(Ordering::Relaxed),
+      errors: self.errors.load(Ordering::Relaxed),
+    }
+  }
+}
+
+/// State shared by a session and its halves. The ring is ended when the backend is dropped,
+/// which happens once the last handle to the core goes away
+pub(crate) struct SessionCore {
+  backend: Box<dyn SessionBackend>,
+  pub(crate) rx: Counters,
+  pub(crate) tx: Counters,
+  /// Futures of the halves waiting on the rings
+  pub(crate) wakers: Wakers,
+}
+
+impl SessionCore {
+  #[cfg(windows)]
+  pub(crate) fn backend(&self) -> &dyn SessionBackend {
+    &*self.backend
+  }
+  pub(crate) fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    self.backend.wait_readable(timeout)
+  }
+  pub(crate) fn recv(&self) -> Result<RecvPacket<'_>, ReceivePacketError> {
+    match self.backend.receive() {
+      Ok((packet_raw, packet_size)) => {
+        self.rx.packet(packet_size);
+        Ok(unsafe { RecvPacket::from_raw(self, packet_raw, packet_size) })
+      }
+      Err(err) => {
+        self.rx.failure(err.is_would_block());
+        Err(err)
+      }
     }
-    Ok(unsafe { RecvPacket::from_raw(self, packet_raw, packet_size) })
-  }
-  pub fn allocate(&self, size: IpPacketSize) -> Result<SendPacket, AllocatePacketError> {
-    let packet_raw = unsafe { WintunAllocateSendPacket(self.handle.0, size.size()) };
-    if packet_raw == std::ptr::null_mut() {
-      let error = get_last_error::Win32Error::get_last_error();
-      return Err(match error.code() {
-        winerror::ERROR_HANDLE_EOF => AllocatePacketError::AdapterIsTerminating,
-        winerror::ERROR_BUFFER_OVERFLOW => AllocatePacketError::WouldBlock,
-        _ => AllocatePacketError::Other(error),
-      });
+  }
+  pub(crate) fn allocate(&self, size: IpPacketSize) -> Result<SendPacket<'_>, AllocatePacketError> {
+    match self.backend.allocate(size.size()) {
+      Ok(packet_raw) => Ok(unsafe { SendPacket::from_raw(self, packet_raw, size.size()) }),
+      Err(err) => {
+        self.tx.failure(err.is_would_block());
+        Err(err)
+      }
     }
-    Ok(unsafe { SendPacket::from_raw(self, packet_raw, size.size()) })
   }
   pub(crate) fn send_packet(&self, packet: &mut SendPacket) {
-    unsafe { WintunSendPacket(self.handle.0, packet.as_raw_ptr()) }
+    let size = packet.slice().len() as u32;
+    self.backend.send(packet.as_raw_ptr(), size);
+    self.tx.packet(size);
   }
   pub(crate) fn release_packet(&self, packet: &mut RecvPacket) {
-    unsafe { WintunReleaseReceivePacket(self.handle.0, packet.as_raw_ptr()) }
+    self
+      .backend
+      .release(packet.as_raw_ptr(), packet.slice().len() as u32)
   }
-  pub(crate) fn new(handle: WINTUN_SESSION_HANDLE) -> Self {
-    let handle = UnsafeHandle(handle);
-    Self { handle }
+}
+
+/// Maps a failed wait into the error type of a receive. Only a wait that would block is worth
+/// retrying, a missing adapter means it is gone and anything else is reported as an I/O error
+pub(crate) fn wait_error_to_receive(error: WintunError) -> ReceivePacketError {
+  match error.root() {
+    WintunError::WouldBlock => ReceivePacketError::WouldBlock,
+    WintunError::AdapterIsTerminating | WintunError::InterfaceNotFound => {
+      ReceivePacketError::AdapterIsTerminating
+    }
+    WintunError::InvalidData => ReceivePacketError::InvalidData,
+    WintunError::Other(err) => ReceivePacketError::Other(*err),
+    _ => ReceivePacketError::Other(io_failure()),
   }
 }
 
-impl Drop for Session {
-  fn drop(&mut self) {
-    unsafe { WintunEndSession(self.handle.0) };
+#[cfg(windows)]
+fn io_failure() -> OsError {
+  OsError::new(winapi::shared::winerror::ERROR_GEN_FAILURE)
+}
+#[cfg(not(windows))]
+fn io_failure() -> OsError {
+  OsError::new(libc::EIO)
+}
+
+/// A started session. It keeps its [`Adapter`] alive, either by borrowing it
+/// ([`Adapter::session`]) or by owning it ([`Adapter::into_session`])
+pub struct Session<'adapter> {
+  //Declared before the adapter so the ring is ended before the adapter is released
+  core: Arc<SessionCore>,
+  adapter: AdapterRef<'adapter>,
+  max_packet_size: IpPacketSize,
+}
+
+impl<'adapter> Session<'adapter> {
+  pub fn end(self) {
+    drop(self)
+  }
+  /// Splits the session into a receiving and a sending half that can be moved to different
+  /// threads. The ring is ended once both halves are dropped
+  pub fn split(self) -> (SessionReader<'adapter>, SessionWriter<'adapter>) {
+    (
+      SessionReader::new(self.core.clone(), self.adapter.clone()),
+      SessionWriter::new(self.core, self.adapter),
+    )
+  }
+  pub fn adapter(&self) -> &Adapter {
+    &self.adapter
+  }
+  pub fn stats(&self) -> SessionStats {
+    SessionStats {
+      rx: self.core.rx.snapshot(),
+      tx: self.core.tx.snapshot(),
+    }
+  }
+  #[cfg(windows)]
+  pub fn get_read_wait_event(&self) -> WintunResult<winapi::shared::ntdef::HANDLE> {
+    self.core.backend().read_wait_event()
+  }
+  /// Blocks until a packet can be received or `timeout` elapses, `None` waits indefinitely.
+  /// Returns `false` on timeout. Also returns `true` once the adapter is terminating so the
+  /// following [`recv`](Self::recv) can report it
+  pub fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    self.core.wait_readable(timeout)
+  }
+  pub fn recv(&self) -> Result<RecvPacket<'_>, ReceivePacketError> {
+    self.core.recv()
+  }
+  pub fn allocate(&self, size: IpPacketSize) -> Result<SendPacket<'_>, AllocatePacketError> {
+    self.core.allocate(size)
+  }
+  /// Largest packet the session is expected to carry. Defaults to [`IpPacketSize::max`]
+  pub fn max_packet_size(&self) -> IpPacketSize {
+    self.max_packet_size
+  }
+  pub fn set_max_packet_size(&mut self, size: IpPacketSize) {
+    self.max_packet_size = size;
+  }
+  #[cfg(feature = "smoltcp")]
+  pub(crate) fn core(&self) -> &SessionCore {
+    &self.core
+  }
+  /// Replaces the backend with one layered on top of it, keeping the counters
+  pub(crate) fn wrap_backend(
+    self,
+    wrap: impl FnOnce(Box<dyn SessionBackend>) -> Box<dyn SessionBackend>,
+  ) -> Self {
+    let core = Arc::into_inner(self.core).expect("an unsplit session is the only owner of its core");
+    Self {
+      core: Arc::new(SessionCore {
+        backend: wrap(core.backend),
+        rx: core.rx,
+        tx: core.tx,
+        wakers: core.wakers,
+      }),
+      adapter: self.adapter,
+      max_packet_size: self.max_packet_size,
+    }
+  }
+  pub(crate) fn new(backend: Box<dyn SessionBackend>, adapter: AdapterRef<'adapter>) -> Self {
+    Self {
+      core: Arc::new(SessionCore {
+        backend,
+        rx: Counters::default(),
+        tx: Counters::default(),
+        wakers: Wakers::default(),
+      }),
+      adapter,
+      max_packet_size: IpPacketSize::max(),
+    }
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use std::net::Ipv4Addr;
+  use std::{
+    net::{Ipv4Addr, UdpSocket},
+    time::Duration,
+  };
 
-  use crate::Adapter;
+  use super::wait_error_to_receive;
+  use crate::{Adapter, Operation, ReceivePacketError, WintunError};
 
   #[test]
   fn create_session() {
-    let mut adapter = Adapter::create("name", "tunnel_type", None).unwrap();
+    let adapter = Adapter::create("name", "tunnel_type", None).unwrap();
     let session = adapter.session(crate::RingCapacity::max()).unwrap();
     session.end();
   }
   #[test]
   fn send_packet() {
-    let mut adapter = Adapter::create("name", "tunnel_type", None).unwrap();
+    let adapter = Adapter::create("name", "tunnel_type", None).unwrap();
     let session = adapter.session(crate::RingCapacity::max()).unwrap();
     let packet = session.allocate(crate::IpPacketSize::max()).unwrap();
     packet.send();
@@ -115,4 +269,96 @@ mod tests {
     packet.release();
     session.end();
   }
+
+  #[test]
+  fn recv_routed_packet() {
+    let mut adapter = Adapter::create("wt-routed", "tunnel_type", None).unwrap();
+    adapter
+      .set_ip_address(crate::IpAndMaskPrefix::V4 {
+        ip: Ipv4Addr::new(192, 168, 11, 1),
+        prefix: 24.try_into().unwrap(),
+      })
+      .unwrap();
+    let session = adapter.session(crate::RingCapacity::max()).unwrap();
+    //Give the host something to route through the adapter
+    let socket = UdpSocket::bind((Ipv4Addr::new(192, 168, 11, 1), 0)).unwrap();
+    socket
+      .send_to(b"ping", (Ipv4Addr::new(192, 168, 11, 2), 9))
+      .unwrap();
+    assert!(session.wait_readable(Some(Duration::from_secs(5))).unwrap());
+    let packet = session.recv().unwrap();
+    packet.release();
+    session.end();
+  }
+
+  #[test]
+  fn memory_session_exchanges_packets() {
+    let adapter = Adapter::create_in_memory("memory-exchange", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.session(crate::RingCapacity::min()).unwrap();
+    assert!(matches!(session.recv(), Err(err) if err.is_would_block()));
+
+    peer.inject(&[0x45, 1, 2, 3]).unwrap();
+    assert!(session.wait_readable(Some(Duration::ZERO)).unwrap());
+    let packet = session.recv().unwrap();
+    assert_eq!(packet.slice(), &[0x45, 1, 2, 3]);
+    packet.release();
+
+    let mut packet = session.allocate(3.try_into().unwrap()).unwrap();
+    packet.mut_slice().copy_from_slice(&[7, 8, 9]);
+    packet.send();
+    assert_eq!(peer.try_recv().unwrap(), vec![7, 8, 9]);
+    assert!(peer.try_recv().is_none());
+  }
+
+  #[test]
+  fn memory_session_rings_are_bounded() {
+    let adapter = Adapter::create_in_memory("memory-bounded", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.session(crate::RingCapacity::min()).unwrap();
+    //A packet takes its size plus padding, and one alignment unit of the ring always stays free
+    let fits = (crate::RingCapacity::min().cap() - crate::PACKET_ALIGNMENT)
+      / crate::MAX_RING_PACKET_SIZE;
+    let packet = vec![0; crate::MAX_IP_PACKET_SIZE as usize];
+    for _ in 0..fits {
+      peer.inject(&packet).unwrap();
+    }
+    assert!(matches!(
+      peer.inject(&packet),
+      Err(crate::WintunError::WouldBlock)
+    ));
+    let packets: Vec<_> = (0..fits)
+      .map(|_| session.allocate(crate::IpPacketSize::max()).unwrap())
+      .collect();
+    assert!(matches!(
+      session.allocate(crate::IpPacketSize::max()),
+      Err(err) if err.is_would_block()
+    ));
+    drop(packets);
+  }
+
+  #[test]
+  fn wait_errors_are_not_retried() {
+    let gone = WintunError::InterfaceNotFound.context(Operation::StartSession, Some("name"));
+    assert!(wait_error_to_receive(gone).is_adapter_terminating());
+    assert!(matches!(
+      wait_error_to_receive(WintunError::WintunNotLoaded),
+      ReceivePacketError::Other(_)
+    ));
+    assert!(wait_error_to_receive(WintunError::WouldBlock).is_would_block());
+  }
+
+  #[test]
+  fn memory_session_reports_termination() {
+    let adapter = Adapter::create_in_memory("memory-terminate", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.session(crate::RingCapacity::min()).unwrap();
+    peer.terminate();
+    assert!(session.wait_readable(None).unwrap());
+    assert!(matches!(session.recv(), Err(err) if err.is_adapter_terminating()));
+    assert!(matches!(
+      session.allocate(crate::IpPacketSize::max()),
+      Err(err) if err.is_adapter_terminating()
+    ));
+  }
 }
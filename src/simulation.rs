@@ -0,0 +1,740 @@
+//! Sessions over a misbehaving link. [`SimulatedSession`] sits between a [`Session`] and its
+//! backend and delays, drops, duplicates, corrupts and reorders packets in both directions.
+//! Every random decision comes from a seeded generator and time comes from a [`Clock`], so with
+//! a [`ManualClock`](crate::ManualClock) a test sees exactly the same traffic on every run
+
+use std::{
+  cmp::Reverse,
+  collections::BinaryHeap,
+  ops::{Deref, DerefMut},
+  sync::{Arc, Mutex, MutexGuard, Weak},
+  time::{Duration, Instant},
+};
+
+use crate::{
+  backend::SessionBackend, AllocatePacketError, Clock, ReceivePacketError, Session, SystemClock,
+  WintunResult, MIN_RING_CAPACITY,
+};
+
+//Longest a wait blocks on the underlying session while packets are queued, since the clock
+//might not be the wall clock
+const WAIT_SLICE: Duration = Duration::from_millis(10);
+//Shortest such wait, so packets stuck behind a full ring don't make waiting spin
+const MIN_WAIT_SLICE: Duration = Duration::from_millis(1);
+
+/// Which way packets travel, seen from the session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+  /// Towards the session, packets it receives
+  Inbound,
+  /// Away from the session, packets it sends
+  Outbound,
+}
+
+/// Distribution of the delay added to every packet
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Delay {
+  #[default]
+  None,
+  Constant(Duration),
+  Uniform {
+    min: Duration,
+    max: Duration,
+  },
+  /// Normally distributed, negative samples are clamped to zero
+  Normal {
+    mean: Duration,
+    std_dev: Duration,
+  },
+}
+
+impl Delay {
+  fn sample(&self, rng: &mut Rng) -> Duration {
+    match *self {
+      Delay::None => Duration::ZERO,
+      Delay::Constant(delay) => delay,
+      Delay::Uniform { min, max } if max > min => min + (max - min).mul_f64(rng.next_f64()),
+      Delay::Uniform { min, .. } => min,
+      Delay::Normal { mean, std_dev } => {
+        //Box-Muller transform, 1 - u keeps the logarithm finite
+        let radius = (-2.0 * (1.0 - rng.next_f64()).ln()).sqrt();
+        let angle = 2.0 * std::f64::consts::PI * rng.next_f64();
+        let delay = mean.as_secs_f64() + radius * angle.cos() * std_dev.as_secs_f64();
+        Duration::from_secs_f64(delay.max(0.0))
+      }
+    }
+  }
+}
+
+/// Gilbert model of bursty loss: the link switches into a state in which every packet is lost
+/// with probability `enter` and back out of it with probability `exit`, checked once per packet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BurstLoss {
+  pub enter: f64,
+  pub exit: f64,
+}
+
+/// How one direction of the link misbehaves. Probabilities are per packet, the default is a
+/// perfect link
+#[derive(Debug, Clone, PartialEq)]
+pub struct Impairments {
+  pub delay: Delay,
+  pub loss: f64,
+  pub burst_loss: Option<BurstLoss>,
+  /// Probability that a packet is held back by an extra `reorder_delay`, letting the packets
+  /// after it overtake it
+  pub reorder: f64,
+  pub reorder_delay: Duration,
+  pub duplicate: f64,
+  /// Probability that a single random bit of the packet is flipped
+  pub corrupt: f64,
+  /// Bytes per second the link carries, packets queue up behind each other when exceeded
+  pub bandwidth: Option<u64>,
+  /// Bytes that may wait on the link, packets arriving at a full link are dropped
+  pub queue_limit: usize,
+}
+
+impl Default for Impairments {
+  fn default() -> Self {
+    Self {
+      delay: Delay::None,
+      loss: 0.0,
+      burst_loss: None,
+      reorder: 0.0,
+      reorder_delay: Duration::from_millis(10),
+      duplicate: 0.0,
+      corrupt: 0.0,
+      bandwidth: None,
+      queue_limit: MIN_RING_CAPACITY as usize,
+    }
+  }
+}
+
+/// What happened to the packets of one direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+  /// Packets that entered the link
+  pub offered: u64,
+  /// Packets, including duplicates, that left the link
+  pub delivered: u64,
+  pub lost: u64,
+  pub duplicated: u64,
+  pub corrupted: u64,
+  pub reordered: u64,
+  /// Packets dropped because the link's queue was full
+  pub overflowed: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimulationStats {
+  pub inbound: LinkStats,
+  pub outbound: LinkStats,
+}
+
+#[derive(Clone)]
+pub struct SimulationConfig {
+  pub seed: u64,
+  pub inbound: Impairments,
+  pub outbound: Impairments,
+  pub clock: Arc<dyn Clock>,
+}
+
+impl SimulationConfig {
+  /// A perfect link in both directions, timed by a [`SystemClock`]
+  pub fn new(seed: u64) -> Self {
+    Self {
+      seed,
+      inbound: Impairments::default(),
+      outbound: Impairments::default(),
+      clock: Arc::new(SystemClock::new()),
+    }
+  }
+}
+
+/// SplitMix64, small and good enough for picking which packets to mistreat
+struct Rng(u64);
+
+impl Rng {
+  fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+  }
+  /// Uniform in `[0, 1)`
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+  fn chance(&mut self, probability: f64) -> bool {
+    probability > 0.0 && self.next_f64() < probability
+  }
+}
+
+struct Queued {
+  due: Duration,
+  //Keeps packets due at the same time in the order they entered the link
+  sequence: u64,
+  packet: Vec<u8>,
+}
+
+impl PartialEq for Queued {
+  fn eq(&self, other: &Self) -> bool {
+    (self.due, self.sequence) == (other.due, other.sequence)
+  }
+}
+impl Eq for Queued {}
+impl PartialOrd for Queued {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+impl Ord for Queued {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    (self.due, self.sequence).cmp(&(other.due, other.sequence))
+  }
+}
+
+/// One direction of the simulated link
+struct Link {
+  impairments: Impairments,
+  rng: Rng,
+  in_burst: bool,
+  /// When the last queued packet has been fully put on the wire, for the bandwidth limit
+  busy_until: Duration,
+  queue: BinaryHeap<Reverse<Queued>>,
+  queued_bytes: usize,
+  next_sequence: u64,
+  stats: LinkStats,
+}
+
+impl Link {
+  fn new(impairments: Impairments, seed: u64) -> Self {
+    Self {
+      impairments,
+      rng: Rng(seed),
+      in_burst: false,
+      busy_until: Duration::ZERO,
+      queue: BinaryHeap::new(),
+      queued_bytes: 0,
+      next_sequence: 0,
+      stats: LinkStats::default(),
+    }
+  }
+  fn push(&mut self, now: Duration, mut packet: Vec<u8>) {
+    self.stats.offered += 1;
+    self.in_burst = match self.impairments.burst_loss {
+      Some(burst) if self.in_burst => !self.rng.chance(burst.exit),
+      Some(burst) => self.rng.chance(burst.enter),
+      None => false,
+    };
+    if self.in_burst || self.rng.chance(self.impairments.loss) {
+      self.stats.lost += 1;
+      return;
+    }
+    if !packet.is_empty() && self.rng.chance(self.impairments.corrupt) {
+      let bit = self.rng.next_u64() % (packet.len() as u64 * 8);
+      packet[(bit / 8) as usize] ^= 1 << (bit % 8);
+      self.stats.corrupted += 1;
+    }
+    let copies = if self.rng.chance(self.impairments.duplicate) {
+      self.stats.duplicated += 1;
+      vec![packet.clone(), packet]
+    } else {
+      vec![packet]
+    };
+    for packet in copies {
+      if self.queued_bytes + packet.len() > self.impairments.queue_limit {
+        self.stats.overflowed += 1;
+        continue;
+      }
+      let mut due = now;
+      if let Some(bandwidth) = self
+        .impairments
+        .bandwidth
+        .filter(|&bandwidth| bandwidth > 0)
+      {
+        let transmission = packet.len() as u128 * 1_000_000_000 / bandwidth as u128;
+        self.busy_until = self.busy_until.max(now) + Duration::from_nanos(transmission as u64);
+        due = self.busy_until;
+      }
+      due += self.impairments.delay.sample(&mut self.rng);
+      if self.rng.chance(self.impairments.reorder) {
+        due += self.impairments.reorder_delay;
+        self.stats.reordered += 1;
+      }
+      self.queued_bytes += packet.len();
+      self.queue.push(Reverse(Queued {
+        due,
+        sequence: self.next_sequence,
+        packet,
+      }));
+      self.next_sequence += 1;
+    }
+  }
+  fn next_due(&self) -> Option<Duration> {
+    self.queue.peek().map(|Reverse(queued)| queued.due)
+  }
+  /// Length of the next packet if it is due at `now`
+  fn due_len(&self, now: Duration) -> Option<usize> {
+    self
+      .queue
+      .peek()
+      .filter(|Reverse(queued)| queued.due <= now)
+      .map(|Reverse(queued)| queued.packet.len())
+  }
+  fn pop_due(&mut self, now: Duration) -> Option<Vec<u8>> {
+    self.due_len(now)?;
+    let Reverse(queued) = self.queue.pop()?;
+    self.queued_bytes -= queued.packet.len();
+    self.stats.delivered += 1;
+    Some(queued.packet)
+  }
+}
+
+struct Shared {
+  clock: Arc<dyn Clock>,
+  inbound: Mutex<Link>,
+  outbound: Mutex<Link>,
+}
+
+impl Shared {
+  fn link(&self, direction: Direction) -> MutexGuard<'_, Link> {
+    match direction {
+      Direction::Inbound => &self.inbound,
+      Direction::Outbound => &self.outbound,
+    }
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+  /// Moves everything the underlying session received onto the inbound link
+  fn pull_inbound(&self, inner: &dyn SessionBackend) -> Result<(), ReceivePacketError> {
+    loop {
+      let (data, size) = match inner.receive() {
+        Ok(packet) => packet,
+        Err(ReceivePacketError::WouldBlock) => return Ok(()),
+        Err(err) => return Err(err),
+      };
+      let packet = unsafe { std::slice::from_raw_parts(data, size as usize) }.to_vec();
+      inner.release(data, size);
+      self.link(Direction::Inbound).push(self.clock.now(), packet);
+    }
+  }
+  /// Sends the outbound packets that are due through the underlying session, as far as its ring
+  /// has room for them
+  fn deliver_outbound(&self, inner: &dyn SessionBackend) -> Result<(), AllocatePacketError> {
+    let now = self.clock.now();
+    let mut link = self.link(Direction::Outbound);
+    while let Some(size) = link.due_len(now) {
+      let data = match inner.allocate(size as u32) {
+        Ok(data) => data,
+        Err(AllocatePacketError::WouldBlock) => return Ok(()),
+        Err(err) => return Err(err),
+      };
+      let packet = link.pop_due(now).expect("the packet was due a moment ago");
+      unsafe { std::ptr::copy_nonoverlapping(packet.as_ptr(), data, size) };
+      inner.send(data, size as u32);
+    }
+    Ok(())
+  }
+}
+
+pub(crate) struct SimulatedBackend {
+  inner: Arc<dyn SessionBackend>,
+  shared: Arc<Shared>,
+}
+
+impl SimulatedBackend {
+  /// SAFETY: `data` and `size` have to describe a buffer previously leaked by this backend
+  unsafe fn reclaim(data: *const u8, size: u32) -> Box<[u8]> {
+    Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+      data as *mut u8,
+      size as usize,
+    ))
+  }
+}
+
+impl SessionBackend for SimulatedBackend {
+  fn receive(&self) -> Result<(*mut u8, u32), ReceivePacketError> {
+    let _ = self.shared.deliver_outbound(&*self.inner);
+    self.shared.pull_inbound(&*self.inner)?;
+    let packet = self
+      .shared
+      .link(Direction::Inbound)
+      .pop_due(self.shared.clock.now())
+      .ok_or(ReceivePacketError::WouldBlock)?;
+    let size = packet.len() as u32;
+    Ok((Box::into_raw(packet.into_boxed_slice()) as *mut u8, size))
+  }
+
+  fn release(&self, data: *const u8, size: u32) {
+    drop(unsafe { Self::reclaim(data, size) });
+  }
+
+  fn allocate(&self, size: u32) -> Result<*mut u8, AllocatePacketError> {
+    self.shared.deliver_outbound(&*self.inner)?;
+    let packet = vec![0u8; size as usize].into_boxed_slice();
+    Ok(Box::into_raw(packet) as *mut u8)
+  }
+
+  fn send(&self, data: *const u8, size: u32) {
+    let packet = unsafe { Self::reclaim(data, size) };
+    self
+      .shared
+      .link(Direction::Outbound)
+      .push(self.shared.clock.now(), packet.into_vec());
+    //Failures show up on the next allocation
+    let _ = self.shared.deliver_outbound(&*self.inner);
+  }
+
+  fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+      let _ = self.shared.deliver_outbound(&*self.inner);
+      if self.shared.pull_inbound(&*self.inner).is_err() {
+        //Let the next receive report it
+        return Ok(true);
+      }
+      let now = self.shared.clock.now();
+      let inbound = self.shared.link(Direction::Inbound).next_due();
+      if inbound.is_some_and(|due| due <= now) {
+        return Ok(true);
+      }
+      let mut slice = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+      if slice.is_some_and(|slice| slice.is_zero()) {
+        return Ok(false);
+      }
+      let outbound = self.shared.link(Direction::Outbound).next_due();
+      if let Some(due) = inbound.into_iter().chain(outbound).min() {
+        let until_due = due.saturating_sub(now).clamp(MIN_WAIT_SLICE, WAIT_SLICE);
+        slice = Some(slice.map_or(until_due, |slice| slice.min(until_due)));
+      }
+      self.inner.wait_readable(slice)?;
+    }
+  }
+
+  #[cfg(windows)]
+  fn read_wait_event(&self) -> WintunResult<winapi::shared::ntdef::HANDLE> {
+    //Signalled when packets reach the underlying ring, which can be before they are due
+    self.inner.read_wait_event()
+  }
+}
+
+/// Changes and observes the link of a [`SimulatedSession`], also after the session was split
+#[derive(Clone)]
+pub struct SimulationControl {
+  shared: Arc<Shared>,
+  inner: Weak<dyn SessionBackend>,
+}
+
+impl SimulationControl {
+  pub fn impairments(&self, direction: Direction) -> Impairments {
+    self.shared.link(direction).impairments.clone()
+  }
+  /// Applies to packets entering the link from now on, queued packets keep their schedule
+  pub fn set_impairments(&self, direction: Direction, impairments: Impairments) {
+    self.shared.link(direction).impairments = impairments;
+  }
+  pub fn stats(&self) -> SimulationStats {
+    SimulationStats {
+      inbound: self.shared.link(Direction::Inbound).stats,
+      outbound: self.shared.link(Direction::Outbound).stats,
+    }
+  }
+  /// Packets currently travelling in `direction`
+  pub fn queued(&self, direction: Direction) -> usize {
+    self.shared.link(direction).queue.len()
+  }
+  /// Moves packets along the link. Every session call does this as well, but while the session is
+  /// idle, received packets only start their trip and delayed outbound packets only reach the
+  /// adapter through this
+  pub fn poll(&self) -> WintunResult<()> {
+    let Some(inner) = self.inner.upgrade() else {
+      return Ok(());
+    };
+    self.shared.deliver_outbound(&*inner)?;
+    self.shared.pull_inbound(&*inner)?;
+    Ok(())
+  }
+}
+
+/// A [`Session`] whose packets travel over a simulated link. It dereferences to the session, so
+/// it can be used, split and converted like any other
+pub struct SimulatedSession<'adapter> {
+  session: Session<'adapter>,
+  control: SimulationControl,
+}
+
+impl<'adapter> SimulatedSession<'adapter> {
+  /// Layers the simulation over `session`, which may belong to an in-memory or a real adapter
+  pub fn new(session: Session<'adapter>, config: SimulationConfig) -> Self {
+    let shared = Arc::new(Shared {
+      clock: config.clock,
+      inbound: Mutex::new(Link::new(config.inbound, config.seed)),
+      //Different streams per direction so traffic one way doesn't change the fate of the other
+      outbound: Mutex::new(Link::new(config.outbound, !config.seed)),
+    });
+    let mut control = None;
+    let session = session.wrap_backend(|inner| {
+      let inner: Arc<dyn SessionBackend> = Arc::from(inner);
+      control = Some(SimulationControl {
+        shared: shared.clone(),
+        inner: Arc::downgrade(&inner),
+      });
+      Box::new(SimulatedBackend { inner, shared })
+    });
+    Self {
+      session,
+      control: control.expect("wrap_backend calls the closure"),
+    }
+  }
+  pub fn control(&self) -> &SimulationControl {
+    &self.control
+  }
+  pub fn into_parts(self) -> (Session<'adapter>, SimulationControl) {
+    (self.session, self.control)
+  }
+}
+
+impl<'adapter> Deref for SimulatedSession<'adapter> {
+  type Target = Session<'adapter>;
+  fn deref(&self) -> &Self::Target {
+    &self.session
+  }
+}
+
+impl<'adapter> DerefMut for SimulatedSession<'adapter> {
+  fn deref_mut(&mut self) -> &mut Self::Target {
+    &mut self.session
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync::Arc, time::Duration};
+
+  use super::{
+    BurstLoss, Delay, Direction, Impairments, LinkStats, SimulatedSession, SimulationConfig,
+  };
+  use crate::{Adapter, ManualClock, MemoryPeer, RingCapacity, Session};
+
+  fn simulate<'a>(
+    adapter: &'a Adapter,
+    seed: u64,
+    inbound: Impairments,
+    outbound: Impairments,
+  ) -> (SimulatedSession<'a>, ManualClock) {
+    let clock = ManualClock::new();
+    let session = adapter.session(RingCapacity::min()).unwrap();
+    let config = SimulationConfig {
+      seed,
+      inbound,
+      outbound,
+      clock: Arc::new(clock.clone()),
+    };
+    (SimulatedSession::new(session, config), clock)
+  }
+
+  fn receive_all(session: &Session) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| session.recv().ok().map(|packet| packet.slice().to_vec())).collect()
+  }
+
+  fn send(session: &Session, packet: &[u8]) {
+    let mut allocated = session
+      .allocate((packet.len() as u32).try_into().unwrap())
+      .unwrap();
+    allocated.mut_slice().copy_from_slice(packet);
+    allocated.send();
+  }
+
+  fn drain(peer: &MemoryPeer) -> Vec<Vec<u8>> {
+    std::iter::from_fn(|| peer.try_recv()).collect()
+  }
+
+  #[test]
+  fn delays_packets_until_due() {
+    let adapter = Adapter::create_in_memory("sim-delay", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let delay = |ms| Impairments {
+      delay: Delay::Constant(Duration::from_millis(ms)),
+      ..Impairments::default()
+    };
+    let (session, clock) = simulate(&adapter, 1, delay(20), delay(50));
+
+    peer.inject(&[0x45, 1]).unwrap();
+    assert!(session.recv().is_err_and(|err| err.is_would_block()));
+    assert!(!session.wait_readable(Some(Duration::ZERO)).unwrap());
+    clock.advance(Duration::from_millis(20));
+    assert!(session.wait_readable(Some(Duration::ZERO)).unwrap());
+    assert_eq!(receive_all(&session), [vec![0x45, 1]]);
+
+    send(&session, &[0x45, 2]);
+    session.control().poll().unwrap();
+    assert!(peer.try_recv().is_none());
+    assert_eq!(session.control().queued(Direction::Outbound), 1);
+    clock.advance(Duration::from_millis(50));
+    session.control().poll().unwrap();
+    assert_eq!(drain(&peer), [vec![0x45, 2]]);
+  }
+
+  #[test]
+  fn same_seed_same_fate() {
+    let lossy = Impairments {
+      loss: 0.3,
+      duplicate: 0.2,
+      corrupt: 0.1,
+      delay: Delay::Uniform {
+        min: Duration::ZERO,
+        max: Duration::from_millis(30),
+      },
+      ..Impairments::default()
+    };
+    let run = |name: &str, seed| {
+      let adapter = Adapter::create_in_memory(name, "tunnel_type", None).unwrap();
+      let peer = adapter.memory_peer().unwrap();
+      let (session, clock) = simulate(&adapter, seed, lossy.clone(), Impairments::default());
+      for i in 0..200u8 {
+        peer.inject(&[0x45, i, 0, 0]).unwrap();
+        session.control().poll().unwrap();
+      }
+      clock.advance(Duration::from_millis(30));
+      (receive_all(&session), session.control().stats().inbound)
+    };
+    let (packets, stats) = run("sim-seed-a", 7);
+    assert_eq!(run("sim-seed-b", 7), (packets.clone(), stats));
+    assert_ne!(run("sim-seed-c", 8).0, packets);
+
+    assert_eq!(stats.offered, 200);
+    assert_eq!(stats.delivered, packets.len() as u64);
+    assert_eq!(
+      stats.delivered,
+      stats.offered - stats.lost + stats.duplicated
+    );
+    assert!((30..90).contains(&stats.lost), "{stats:?}");
+    assert!(stats.duplicated > 0 && stats.corrupted > 0, "{stats:?}");
+    //Jitter reorders packets
+    assert!(packets.windows(2).any(|pair| pair[0][1] > pair[1][1]));
+  }
+
+  #[test]
+  fn duplicates_and_corrupts() {
+    let adapter = Adapter::create_in_memory("sim-corrupt", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let outbound = Impairments {
+      duplicate: 1.0,
+      corrupt: 1.0,
+      ..Impairments::default()
+    };
+    let (session, _clock) = simulate(&adapter, 3, Impairments::default(), outbound);
+    let packet = [0x45, 0, 0, 20, 1, 2, 3, 4];
+    send(&session, &packet);
+    let received = drain(&peer);
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0], received[1]);
+    let flipped: u32 = received[0]
+      .iter()
+      .zip(packet)
+      .map(|(a, b)| (a ^ b).count_ones())
+      .sum();
+    assert_eq!(flipped, 1);
+    assert_eq!(
+      session.control().stats().outbound,
+      LinkStats {
+        offered: 1,
+        delivered: 2,
+        duplicated: 1,
+        corrupted: 1,
+        ..LinkStats::default()
+      }
+    );
+  }
+
+  #[test]
+  fn bandwidth_and_reordering() {
+    let adapter = Adapter::create_in_memory("sim-bandwidth", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let inbound = Impairments {
+      bandwidth: Some(1000),
+      ..Impairments::default()
+    };
+    let (session, clock) = simulate(&adapter, 5, inbound, Impairments::default());
+    for i in 0..3 {
+      peer.inject(&[i; 100]).unwrap();
+    }
+    session.control().poll().unwrap();
+    //100 bytes take 100ms at 1000 bytes per second
+    let mut arrivals = Vec::new();
+    for _ in 0..3 {
+      clock.advance(Duration::from_millis(100));
+      arrivals.push(receive_all(&session).len());
+    }
+    assert_eq!(arrivals, [1, 1, 1]);
+
+    let reordering = Impairments {
+      reorder: 1.0,
+      reorder_delay: Duration::from_millis(5),
+      ..Impairments::default()
+    };
+    session
+      .control()
+      .set_impairments(Direction::Inbound, reordering);
+    peer.inject(&[1]).unwrap();
+    session.control().poll().unwrap();
+    session
+      .control()
+      .set_impairments(Direction::Inbound, Impairments::default());
+    peer.inject(&[2]).unwrap();
+    assert_eq!(receive_all(&session), [vec![2]]);
+    clock.advance(Duration::from_millis(5));
+    assert_eq!(receive_all(&session), [vec![1]]);
+    assert_eq!(session.control().stats().inbound.reordered, 1);
+  }
+
+  #[test]
+  fn burst_loss_and_overflow() {
+    let adapter = Adapter::create_in_memory("sim-burst", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let inbound = Impairments {
+      burst_loss: Some(BurstLoss {
+        enter: 1.0,
+        exit: 0.0,
+      }),
+      ..Impairments::default()
+    };
+    let (session, clock) = simulate(&adapter, 9, inbound, Impairments::default());
+    for _ in 0..10 {
+      peer.inject(&[0x45]).unwrap();
+    }
+    assert!(receive_all(&session).is_empty());
+    assert_eq!(session.control().stats().inbound.lost, 10);
+
+    session.control().set_impairments(
+      Direction::Inbound,
+      Impairments {
+        delay: Delay::Constant(Duration::from_millis(1)),
+        queue_limit: 250,
+        ..Impairments::default()
+      },
+    );
+    for _ in 0..3 {
+      peer.inject(&[0x45; 100]).unwrap();
+    }
+    session.control().poll().unwrap();
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(receive_all(&session).len(), 2);
+    assert_eq!(session.control().stats().inbound.overflowed, 1);
+  }
+
+  #[test]
+  fn termination_passes_through() {
+    let adapter = Adapter::create_in_memory("sim-terminate", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let (session, _clock) = simulate(&adapter, 0, Impairments::default(), Impairments::default());
+    let (session, control) = session.into_parts();
+    let (reader, _writer) = session.split();
+    peer.terminate();
+    assert!(reader.wait_readable(None).unwrap());
+    assert!(reader.recv().is_err_and(|err| err.is_adapter_terminating()));
+    assert!(control.poll().is_err());
+  }
+}
@@ -0,0 +1,192 @@
+//! TCP connections std can't open, from a chosen local address
+
+use std::{
+  io,
+  net::{SocketAddr, TcpStream},
+  time::Duration,
+};
+
+/// Connects to `address` from `local`, giving up after `timeout`
+#[cfg(not(windows))]
+pub(crate) fn connect_from(
+  local: SocketAddr,
+  address: SocketAddr,
+  timeout: Duration,
+) -> io::Result<TcpStream> {
+  use std::os::fd::{AsRawFd, FromRawFd};
+
+  let family = match address {
+    SocketAddr::V4(_) => libc::AF_INET,
+    SocketAddr::V6(_) => libc::AF_INET6,
+  };
+  let fd = unsafe {
+    libc::socket(
+      family,
+      libc::SOCK_STREAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+      0,
+    )
+  };
+  if fd < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  //Owned right away, so it is closed on every error
+  let stream = unsafe { TcpStream::from_raw_fd(fd) };
+  let (storage, len) = sockaddr(local);
+  if unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  let (storage, len) = sockaddr(address);
+  if unsafe { libc::connect(fd, &storage as *const _ as *const libc::sockaddr, len) } != 0 {
+    let error = io::Error::last_os_error();
+    if error.raw_os_error() != Some(libc::EINPROGRESS) {
+      return Err(error);
+    }
+    let mut poll = libc::pollfd {
+      fd: stream.as_raw_fd(),
+      events: libc::POLLOUT,
+      revents: 0,
+    };
+    let timeout = timeout.as_millis().try_into().unwrap_or(i32::MAX);
+    match unsafe { libc::poll(&mut poll, 1, timeout) } {
+      ..0 => return Err(io::Error::last_os_error()),
+      0 => return Err(io::ErrorKind::TimedOut.into()),
+      _ => {}
+    }
+    if let Some(error) = stream.take_error()? {
+      return Err(error);
+    }
+  }
+  stream.set_nonblocking(false)?;
+  Ok(stream)
+}
+
+#[cfg(not(windows))]
+fn sockaddr(address: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+  let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+  let len = match address {
+    SocketAddr::V4(address) => {
+      let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+      sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+      sockaddr.sin_port = address.port().to_be();
+      sockaddr.sin_addr.s_addr = u32::from_ne_bytes(address.ip().octets());
+      std::mem::size_of::<libc::sockaddr_in>()
+    }
+    SocketAddr::V6(address) => {
+      let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+      sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+      sockaddr.sin6_port = address.port().to_be();
+      sockaddr.sin6_flowinfo = address.flowinfo();
+      sockaddr.sin6_addr.s6_addr = address.ip().octets();
+      sockaddr.sin6_scope_id = address.scope_id();
+      std::mem::size_of::<libc::sockaddr_in6>()
+    }
+  };
+  (storage, len as libc::socklen_t)
+}
+
+/// Connects to `address` from `local`, giving up after `timeout`
+#[cfg(windows)]
+pub(crate) fn connect_from(
+  local: SocketAddr,
+  address: SocketAddr,
+  timeout: Duration,
+) -> io::Result<TcpStream> {
+  use std::os::windows::io::{AsRawSocket, FromRawSocket};
+
+  use winapi::{
+    shared::{
+      winerror::WSAEWOULDBLOCK,
+      ws2def::{AF_INET, AF_INET6, IPPROTO_TCP, SOCK_STREAM},
+    },
+    um::winsock2::{
+      bind, connect, ioctlsocket, socket, WSAGetLastError, WSAPoll, FIONBIO, INVALID_SOCKET,
+      POLLWRNORM, WSAPOLLFD,
+    },
+  };
+
+  startup();
+  let family = match address {
+    SocketAddr::V4(_) => AF_INET,
+    SocketAddr::V6(_) => AF_INET6,
+  };
+  let raw = unsafe { socket(family, SOCK_STREAM, IPPROTO_TCP as i32) };
+  if raw == INVALID_SOCKET {
+    return Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+  }
+  //Owned right away, so it is closed on every error
+  let stream = unsafe { TcpStream::from_raw_socket(raw as _) };
+  let (storage, len) = sockaddr(local);
+  if unsafe { bind(raw, &storage as *const _ as *const _, len) } != 0 {
+    return Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+  }
+  let mut nonblocking = 1;
+  if unsafe { ioctlsocket(raw, FIONBIO, &mut nonblocking) } != 0 {
+    return Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() }));
+  }
+  let (storage, len) = sockaddr(address);
+  if unsafe { connect(raw, &storage as *const _ as *const _, len) } != 0 {
+    let error = unsafe { WSAGetLastError() };
+    if error != WSAEWOULDBLOCK as i32 {
+      return Err(io::Error::from_raw_os_error(error));
+    }
+    let mut poll = WSAPOLLFD {
+      fd: stream.as_raw_socket() as _,
+      events: POLLWRNORM,
+      revents: 0,
+    };
+    let timeout = timeout.as_millis().try_into().unwrap_or(i32::MAX);
+    match unsafe { WSAPoll(&mut poll, 1, timeout) } {
+      ..0 => return Err(io::Error::from_raw_os_error(unsafe { WSAGetLastError() })),
+      0 => return Err(io::ErrorKind::TimedOut.into()),
+      _ => {}
+    }
+    if let Some(error) = stream.take_error()? {
+      return Err(error);
+    }
+  }
+  stream.set_nonblocking(false)?;
+  Ok(stream)
+}
+
+/// Initializes Winsock, which std only does once it opens a socket itself
+#[cfg(windows)]
+fn startup() {
+  use std::sync::Once;
+
+  use winapi::um::winsock2::{WSAStartup, WSADATA};
+
+  static STARTUP: Once = Once::new();
+  STARTUP.call_once(|| {
+    let mut data: WSADATA = unsafe { std::mem::zeroed() };
+    unsafe { WSAStartup(0x202, &mut data) };
+  });
+}
+
+#[cfg(windows)]
+fn sockaddr(address: SocketAddr) -> (winapi::shared::ws2def::SOCKADDR_STORAGE, i32) {
+  use winapi::shared::{
+    ws2def::{AF_INET, AF_INET6, SOCKADDR_IN, SOCKADDR_STORAGE},
+    ws2ipdef::SOCKADDR_IN6_LH,
+  };
+
+  let mut storage: SOCKADDR_STORAGE = unsafe { std::mem::zeroed() };
+  let len = match address {
+    SocketAddr::V4(address) => {
+      let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut SOCKADDR_IN) };
+      sockaddr.sin_family = AF_INET as u16;
+      sockaddr.sin_port = address.port().to_be();
+      unsafe { *sockaddr.sin_addr.S_un.S_addr_mut() = u32::from_ne_bytes(address.ip().octets()) };
+      std::mem::size_of::<SOCKADDR_IN>()
+    }
+    SocketAddr::V6(address) => {
+      let sockaddr = unsafe { &mut *(&mut storage as *mut _ as *mut SOCKADDR_IN6_LH) };
+      sockaddr.sin6_family = AF_INET6 as u16;
+      sockaddr.sin6_port = address.port().to_be();
+      sockaddr.sin6_flowinfo = address.flowinfo();
+      unsafe { *sockaddr.sin6_addr.u.Byte_mut() = address.ip().octets() };
+      unsafe { *sockaddr.u.sin6_scope_id_mut() = address.scope_id() };
+      std::mem::size_of::<SOCKADDR_IN6_LH>()
+    }
+  };
+  (storage, len as i32)
+}
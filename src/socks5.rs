@@ -0,0 +1,537 @@
+//! A SOCKS5 client (RFC 1928) with username and password authentication (RFC 1929), for
+//! relaying flows taken out of the tunnel through a proxy. [`Socks5Proxy::connect`] opens TCP
+//! connections and [`Socks5Proxy::associate`] UDP associations through the proxy
+
+use std::{
+  fmt,
+  io::{self, Read, Write},
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket},
+  time::Duration,
+};
+
+use crate::Destination;
+
+const VERSION: u8 = 5;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+const PASSWORD_VERSION: u8 = 1;
+const COMMAND_CONNECT: u8 = 1;
+const COMMAND_UDP_ASSOCIATE: u8 = 3;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Destination {
+  /// Appends the SOCKS5 form, the address type, the address and the port
+  fn encode(&self, buf: &mut Vec<u8>) -> Result<(), Socks5Error> {
+    match self {
+      Destination::Address(SocketAddr::V4(address)) => {
+        buf.push(ADDRESS_IPV4);
+        buf.extend_from_slice(&address.ip().octets());
+      }
+      Destination::Address(SocketAddr::V6(address)) => {
+        buf.push(ADDRESS_IPV6);
+        buf.extend_from_slice(&address.ip().octets());
+      }
+      Destination::Domain(domain, _) => {
+        let len = u8::try_from(domain.len()).map_err(|_| Socks5Error::DomainTooLong)?;
+        buf.extend_from_slice(&[ADDRESS_DOMAIN, len]);
+        buf.extend_from_slice(domain.as_bytes());
+      }
+    }
+    buf.extend_from_slice(&self.port().to_be_bytes());
+    Ok(())
+  }
+  /// Parses the SOCKS5 form at the start of `buf`, returning it and its length
+  fn decode(buf: &[u8]) -> Option<(Self, usize)> {
+    let (host, rest) = match *buf.first()? {
+      ADDRESS_IPV4 => {
+        let octets: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+        (Ok(IpAddr::from(octets)), 5)
+      }
+      ADDRESS_IPV6 => {
+        let octets: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+        (Ok(IpAddr::from(octets)), 17)
+      }
+      ADDRESS_DOMAIN => {
+        let len = *buf.get(1)? as usize;
+        let domain = std::str::from_utf8(buf.get(2..2 + len)?).ok()?;
+        (Err(domain.to_owned()), 2 + len)
+      }
+      _ => return None,
+    };
+    let port = u16::from_be_bytes(buf.get(rest..rest + 2)?.try_into().ok()?);
+    let destination = match host {
+      Ok(ip) => Destination::Address(SocketAddr::new(ip, port)),
+      Err(domain) => Destination::Domain(domain, port),
+    };
+    Some((destination, rest + 2))
+  }
+}
+
+#[derive(Debug)]
+pub enum Socks5Error {
+  Io(io::Error),
+  /// The proxy takes none of the offered authentication methods
+  NoAcceptableMethod,
+  AuthenticationFailed,
+  /// The proxy refused the request with this reply code
+  Rejected(u8),
+  /// The proxy answered something that isn't SOCKS5
+  Protocol,
+  /// Names longer than 255 bytes can't be sent
+  DomainTooLong,
+  /// Usernames and passwords longer than 255 bytes can't be sent
+  CredentialsTooLong,
+}
+
+impl From<io::Error> for Socks5Error {
+  fn from(error: io::Error) -> Self {
+    Socks5Error::Io(error)
+  }
+}
+
+impl fmt::Display for Socks5Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Socks5Error::Io(error) => {
+        f.write_fmt(format_args!("SOCKS5 proxy connection failed: {error}"))
+      }
+      Socks5Error::NoAcceptableMethod => {
+        f.write_str("SOCKS5 proxy accepts none of the offered authentication methods")
+      }
+      Socks5Error::AuthenticationFailed => f.write_str("SOCKS5 authentication failed"),
+      Socks5Error::Rejected(code) => {
+        let reason = match code {
+          1 => "general failure",
+          2 => "not allowed by ruleset",
+          3 => "network unreachable",
+          4 => "host unreachable",
+          5 => "connection refused",
+          6 => "TTL expired",
+          7 => "command not supported",
+          8 => "address type not supported",
+          _ => "unknown reason",
+        };
+        f.write_fmt(format_args!(
+          "SOCKS5 proxy rejected the request: {reason} ({code})"
+        ))
+      }
+      Socks5Error::Protocol => f.write_str("Invalid SOCKS5 response"),
+      Socks5Error::DomainTooLong => f.write_str("Domain name is too long for SOCKS5"),
+      Socks5Error::CredentialsTooLong => f.write_str("Username or password is too long for SOCKS5"),
+    }
+  }
+}
+
+impl std::error::Error for Socks5Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Socks5Error::Io(error) => Some(error),
+      _ => None,
+    }
+  }
+}
+
+/// A SOCKS5 proxy server to relay through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+  address: SocketAddr,
+  credentials: Option<(String, String)>,
+  timeout: Duration,
+}
+
+impl Socks5Proxy {
+  pub fn new(address: SocketAddr) -> Self {
+    Self {
+      address,
+      credentials: None,
+      timeout: DEFAULT_TIMEOUT,
+    }
+  }
+  /// Authenticates with a username and a password, each at most 255 bytes. Longer ones fail
+  /// every connection with [`Socks5Error::CredentialsTooLong`]
+  pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+    self.credentials = Some((username.to_owned(), password.to_owned()));
+    self
+  }
+  /// Gives up connecting and negotiating with the proxy after `timeout`. Defaults to 10 seconds
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+  /// Opens a TCP connection to `destination` through the proxy
+  pub fn connect(&self, destination: &Destination) -> Result<TcpStream, Socks5Error> {
+    let stream = TcpStream::connect_timeout(&self.address, self.timeout)?;
+    self.connect_over(stream, destination)
+  }
+  /// Like [`connect`](Self::connect), over a `stream` already open to the proxy, which may run
+  /// through other proxies
+  pub fn connect_over(
+    &self,
+    mut stream: TcpStream,
+    destination: &Destination,
+  ) -> Result<TcpStream, Socks5Error> {
+    self.negotiate(&mut stream)?;
+    self.request(&mut stream, COMMAND_CONNECT, destination)?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+  }
+  /// Opens a UDP association, datagrams can then be relayed to any destination through it
+  pub fn associate(&self) -> Result<Socks5Udp, Socks5Error> {
+    let mut control = TcpStream::connect_timeout(&self.address, self.timeout)?;
+    self.negotiate(&mut control)?;
+    let local = match self.address {
+      SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+      SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local)?;
+    //The address datagrams will come from isn't known behind NAT, so none is given
+    let Destination::Address(mut relay) = self.request(
+      &mut control,
+      COMMAND_UDP_ASSOCIATE,
+      &Destination::Address(local),
+    )?
+    else {
+      return Err(Socks5Error::Protocol);
+    };
+    if relay.ip().is_unspecified() {
+      relay.set_ip(self.address.ip());
+    }
+    socket.connect(relay)?;
+    control.set_read_timeout(None)?;
+    Ok(Socks5Udp {
+      _control: control,
+      socket,
+    })
+  }
+  /// Authenticates on a new connection to the proxy
+  fn negotiate(&self, stream: &mut TcpStream) -> Result<(), Socks5Error> {
+    let mut credentials = None;
+    if let Some((username, password)) = &self.credentials {
+      let mut request = vec![PASSWORD_VERSION];
+      for field in [username, password] {
+        let len = u8::try_from(field.len()).map_err(|_| Socks5Error::CredentialsTooLong)?;
+        request.push(len);
+        request.extend_from_slice(field.as_bytes());
+      }
+      credentials = Some(request);
+    }
+    stream.set_read_timeout(Some(self.timeout))?;
+    stream.set_write_timeout(Some(self.timeout))?;
+    let method = match self.credentials {
+      Some(_) => METHOD_PASSWORD,
+      None => METHOD_NONE,
+    };
+    stream.write_all(&[VERSION, 1, method])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    match reply {
+      [VERSION, METHOD_UNACCEPTABLE] => return Err(Socks5Error::NoAcceptableMethod),
+      [VERSION, chosen] if chosen == method => {}
+      _ => return Err(Socks5Error::Protocol),
+    }
+    if let Some(request) = credentials {
+      stream.write_all(&request)?;
+      stream.read_exact(&mut reply)?;
+      if reply[1] != 0 {
+        return Err(Socks5Error::AuthenticationFailed);
+      }
+    }
+    Ok(())
+  }
+  /// Sends a request and returns the address of the reply
+  fn request(
+    &self,
+    stream: &mut TcpStream,
+    command: u8,
+    destination: &Destination,
+  ) -> Result<Destination, Socks5Error> {
+    let mut request = vec![VERSION, command, 0];
+    destination.encode(&mut request)?;
+    stream.write_all(&request)?;
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != VERSION {
+      return Err(Socks5Error::Protocol);
+    }
+    if reply[1] != 0 {
+      return Err(Socks5Error::Rejected(reply[1]));
+    }
+    let mut address = vec![reply[3]];
+    let len = match reply[3] {
+      ADDRESS_IPV4 => 4,
+      ADDRESS_IPV6 => 16,
+      ADDRESS_DOMAIN => {
+        let mut len = [0];
+        stream.read_exact(&mut len)?;
+        address.push(len[0]);
+        len[0] as usize
+      }
+      _ => return Err(Socks5Error::Protocol),
+    };
+    let start = address.len();
+    address.resize(start + len + 2, 0);
+    stream.read_exact(&mut address[start..])?;
+    Destination::decode(&address)
+      .map(|(destination, _)| destination)
+      .ok_or(Socks5Error::Protocol)
+  }
+}
+
+/// A UDP association through a [`Socks5Proxy`]. It lasts as long as this value, dropping it
+/// closes the control connection that keeps it open
+pub struct Socks5Udp {
+  /// The association ends when this connection closes
+  _control: TcpStream,
+  socket: UdpSocket,
+}
+
+impl Socks5Udp {
+  /// Relays `data` to `destination`
+  pub fn send_to(&self, data: &[u8], destination: &Destination) -> Result<usize, Socks5Error> {
+    let mut datagram = Vec::with_capacity(data.len() + 22);
+    //Reserved bytes and the fragment number, fragments aren't used
+    datagram.extend_from_slice(&[0, 0, 0]);
+    destination.encode(&mut datagram)?;
+    datagram.extend_from_slice(data);
+    self.socket.send(&datagram)?;
+    Ok(data.len())
+  }
+  /// Receives a relayed datagram into `buf`, returning its length and where it came from. The
+  /// relay header is received into `buf` too, datagrams longer than `buf` without it are
+  /// truncated. Fragmented and malformed datagrams are skipped
+  pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, Destination)> {
+    loop {
+      let len = self.socket.recv(buf)?;
+      if len < 3 || buf[2] != 0 {
+        continue;
+      }
+      let Some((source, header_len)) = Destination::decode(&buf[3..len]) else {
+        continue;
+      };
+      buf.copy_within(3 + header_len..len, 0);
+      return Ok((len - 3 - header_len, source));
+    }
+  }
+  pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+    self.socket.set_nonblocking(nonblocking)
+  }
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.socket.set_read_timeout(timeout)
+  }
+  /// Address of the proxy's relay
+  pub fn relay(&self) -> io::Result<SocketAddr> {
+    self.socket.peer_addr()
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+  };
+
+  use super::{Socks5Error, Socks5Proxy};
+  use crate::Destination;
+
+  /// A SOCKS5 server for tests. It relays every CONNECT to the same target whatever was asked
+  /// for, refusing it when the target does, and echoes UDP datagrams back as if the destination had answered, recording the
+  /// commands and destinations it was asked for
+  pub(crate) struct StandIn {
+    pub address: SocketAddr,
+    pub requests: Arc<Mutex<Vec<(u8, Destination)>>>,
+  }
+
+  impl StandIn {
+    pub fn start(credentials: Option<(&'static str, &'static str)>, target: SocketAddr) -> Self {
+      let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+      let address = listener.local_addr().unwrap();
+      let requests = Arc::new(Mutex::new(Vec::new()));
+      let recorded = requests.clone();
+      thread::spawn(move || {
+        for stream in listener.incoming() {
+          let recorded = recorded.clone();
+          thread::spawn(move || serve(stream?, credentials, target, &recorded));
+        }
+        io::Result::Ok(())
+      });
+      Self { address, requests }
+    }
+    pub fn requests(&self) -> Vec<(u8, Destination)> {
+      self.requests.lock().unwrap().clone()
+    }
+  }
+
+  fn serve(
+    mut stream: TcpStream,
+    credentials: Option<(&str, &str)>,
+    target: SocketAddr,
+    requests: &Mutex<Vec<(u8, Destination)>>,
+  ) -> io::Result<()> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header)?;
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods)?;
+    let method = match credentials {
+      Some(_) => 2,
+      None => 0,
+    };
+    if !methods.contains(&method) {
+      return stream.write_all(&[5, 0xFF]);
+    }
+    stream.write_all(&[5, method])?;
+    if let Some((username, password)) = credentials {
+      let mut fields = Vec::new();
+      stream.read_exact(&mut header[..1])?;
+      for _ in 0..2 {
+        let mut len = [0];
+        stream.read_exact(&mut len)?;
+        let mut field = vec![0; len[0] as usize];
+        stream.read_exact(&mut field)?;
+        fields.push(field);
+      }
+      let accepted = fields == [username.as_bytes(), password.as_bytes()];
+      stream.write_all(&[1, !accepted as u8])?;
+      if !accepted {
+        return Ok(());
+      }
+    }
+    let mut request = [0; 4];
+    stream.read_exact(&mut request)?;
+    let mut address = vec![request[3]];
+    let len = match request[3] {
+      1 => 6,
+      4 => 18,
+      _ => {
+        let mut len = [0];
+        stream.read_exact(&mut len)?;
+        address.push(len[0]);
+        len[0] as usize + 2
+      }
+    };
+    let start = address.len();
+    address.resize(start + len, 0);
+    stream.read_exact(&mut address[start..])?;
+    let (destination, _) = Destination::decode(&address).unwrap();
+    requests.lock().unwrap().push((request[1], destination));
+    let reply = |stream: &mut TcpStream, bound: SocketAddr| {
+      let mut reply = vec![5, 0, 0];
+      Destination::Address(bound).encode(&mut reply).unwrap();
+      stream.write_all(&reply)
+    };
+    match request[1] {
+      1 => {
+        let Ok(mut upstream) = TcpStream::connect(target) else {
+          return stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        };
+        reply(&mut stream, upstream.local_addr()?)?;
+        let (mut from, mut to) = (stream.try_clone()?, upstream.try_clone()?);
+        let forward = thread::spawn(move || {
+          let _ = io::copy(&mut from, &mut to);
+          let _ = to.shutdown(Shutdown::Write);
+        });
+        let _ = io::copy(&mut upstream, &mut stream);
+        let _ = stream.shutdown(Shutdown::Write);
+        let _ = forward.join();
+        Ok(())
+      }
+      3 => {
+        let relay = UdpSocket::bind("127.0.0.1:0")?;
+        relay.set_read_timeout(Some(Duration::from_secs(5)))?;
+        reply(&mut stream, relay.local_addr()?)?;
+        let mut datagram = [0; 2048];
+        loop {
+          let (len, client) = relay.recv_from(&mut datagram)?;
+          if let Some((destination, _)) = Destination::decode(&datagram[3..len]) {
+            requests.lock().unwrap().push((request[1], destination));
+          }
+          relay.send_to(&datagram[..len], client)?;
+        }
+      }
+      _ => stream.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]),
+    }
+  }
+
+  /// A TCP server echoing what it receives until the client closes its side
+  pub(crate) fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut stream = stream?;
+        thread::spawn(move || {
+          let mut from = stream.try_clone()?;
+          io::copy(&mut from, &mut stream)?;
+          stream.shutdown(Shutdown::Write)
+        });
+      }
+      io::Result::Ok(())
+    });
+    address
+  }
+
+  #[test]
+  fn connects_with_credentials() {
+    let proxy = StandIn::start(Some(("user", "secret")), echo_server());
+    let destination = Destination::Domain("echo.test".into(), 80);
+    let mut stream = Socks5Proxy::new(proxy.address)
+      .with_credentials("user", "secret")
+      .connect(&destination)
+      .unwrap();
+    stream.write_all(b"hello").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello");
+    assert_eq!(proxy.requests(), [(1, destination.clone())]);
+
+    let result = Socks5Proxy::new(proxy.address)
+      .with_credentials("user", "wrong")
+      .connect(&destination);
+    assert!(matches!(result, Err(Socks5Error::AuthenticationFailed)));
+    let result = Socks5Proxy::new(proxy.address).connect(&destination);
+    assert!(matches!(result, Err(Socks5Error::NoAcceptableMethod)));
+    let long = Destination::Domain("a".repeat(256), 80);
+    let result = Socks5Proxy::new(proxy.address)
+      .with_credentials("user", "secret")
+      .connect(&long);
+    assert!(matches!(result, Err(Socks5Error::DomainTooLong)));
+    let result = Socks5Proxy::new(proxy.address)
+      .with_credentials(&"u".repeat(256), "secret")
+      .connect(&destination);
+    assert!(matches!(result, Err(Socks5Error::CredentialsTooLong)));
+
+    //Nothing listens on port 0
+    let closed = "127.0.0.1:0".parse().unwrap();
+    let proxy = StandIn::start(None, closed);
+    let result = Socks5Proxy::new(proxy.address).connect(&destination);
+    assert!(matches!(result, Err(Socks5Error::Rejected(5))));
+  }
+
+  #[test]
+  fn relays_udp() {
+    let proxy = StandIn::start(None, echo_server());
+    let association = Socks5Proxy::new(proxy.address).associate().unwrap();
+    association
+      .set_read_timeout(Some(Duration::from_secs(5)))
+      .unwrap();
+    let destination = Destination::Address("[2001:db8::7]:9999".parse().unwrap());
+    association.send_to(b"ping", &destination).unwrap();
+    let mut buf = [0; 1500];
+    let (len, source) = association.recv_from(&mut buf).unwrap();
+    assert_eq!((&buf[..len], source), (&b"ping"[..], destination.clone()));
+    assert_eq!(proxy.requests()[1], (3, destination));
+  }
+}
@@ -0,0 +1,531 @@
+use std::{
+  future::Future,
+  pin::Pin,
+  sync::{Arc, Mutex, MutexGuard, Weak},
+  task::{Context, Poll, Waker},
+  thread::JoinHandle,
+  time::{Duration, Instant},
+};
+
+use crate::{
+  adapter::AdapterRef,
+  session::{wait_error_to_receive, SessionCore},
+  Adapter, AllocatePacketError, DirectionStats, IpPacketSize, ReceivePacketError, RecvPacket,
+  SendPacket, WintunResult,
+};
+
+//How long the waiter thread blocks before checking whether futures were dropped
+const WAIT_SLICE: Duration = Duration::from_millis(50);
+//There is no event signalling free space in the send ring, so allocation is retried after this
+const ALLOCATE_RETRY: Duration = Duration::from_millis(1);
+
+/// Receiving half of a [`Session`](crate::Session), created by
+/// [`Session::split`](crate::Session::split)
+pub struct SessionReader<'adapter> {
+  core: Arc<SessionCore>,
+  adapter: AdapterRef<'adapter>,
+}
+
+impl<'adapter> SessionReader<'adapter> {
+  pub(crate) fn new(core: Arc<SessionCore>, adapter: AdapterRef<'adapter>) -> Self {
+    core.wakers.state().halves += 1;
+    Self { core, adapter }
+  }
+  pub fn adapter(&self) -> &Adapter {
+    &self.adapter
+  }
+  /// Counters of the packets received through this half
+  pub fn stats(&self) -> DirectionStats {
+    self.core.rx.snapshot()
+  }
+  #[cfg(windows)]
+  pub fn get_read_wait_event(&self) -> WintunResult<winapi::shared::ntdef::HANDLE> {
+    self.core.backend().read_wait_event()
+  }
+  pub fn wait_readable(&self, timeout: Option<Duration>) -> WintunResult<bool> {
+    self.core.wait_readable(timeout)
+  }
+  pub fn recv(&self) -> Result<RecvPacket<'_>, ReceivePacketError> {
+    self.core.recv()
+  }
+  /// Waits until a packet is available and receives it
+  pub fn recv_blocking(&self) -> Result<RecvPacket<'_>, ReceivePacketError> {
+    loop {
+      match self.core.recv() {
+        Err(ReceivePacketError::WouldBlock) => {}
+        result => return result,
+      }
+      self
+        .core
+        .wait_readable(None)
+        .map_err(wait_error_to_receive)?;
+    }
+  }
+  /// Like [`recv_blocking`](Self::recv_blocking) but gives up after `timeout`, returning
+  /// [`ReceivePacketError::WouldBlock`]
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<RecvPacket<'_>, ReceivePacketError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+      match self.core.recv() {
+        Err(ReceivePacketError::WouldBlock) => {}
+        result => return result,
+      }
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining.is_zero()
+        || !self
+          .core
+          .wait_readable(Some(remaining))
+          .map_err(wait_error_to_receive)?
+      {
+        return Err(ReceivePacketError::WouldBlock);
+      }
+    }
+  }
+  /// Returns a future resolving to the next packet. While the ring is empty a helper thread
+  /// shared by all futures of the session waits on the read event, so this works with any
+  /// executor
+  pub fn recv_async(&self) -> RecvFuture<'_> {
+    RecvFuture {
+      reader: self,
+      id: None,
+    }
+  }
+}
+
+/// Futures of a session waiting for a packet or for room in the send ring. A single helper
+/// thread per session wakes them, it is started by the first one and exits once none are left
+#[derive(Default)]
+pub(crate) struct Wakers(Mutex<WakerState>);
+
+#[derive(Default)]
+struct WakerState {
+  next_id: u64,
+  readers: Vec<(u64, Waker)>,
+  writers: Vec<(u64, Waker)>,
+  /// Failure of the last wait, reported to the readers instead of waiting again
+  failed: Option<ReceivePacketError>,
+  running: bool,
+  /// Halves of the session still alive
+  halves: usize,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl Wakers {
+  fn state(&self) -> MutexGuard<'_, WakerState> {
+    self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+/// Registers the waker of a pending future, or replaces it when the future already has an id,
+/// and returns the id
+fn register(core: &Arc<SessionCore>, id: Option<u64>, writer: bool, waker: &Waker) -> u64 {
+  let mut state = core.wakers.state();
+  let id = id.unwrap_or_else(|| {
+    state.next_id += 1;
+    state.next_id
+  });
+  let pending = match writer {
+    true => &mut state.writers,
+    false => &mut state.readers,
+  };
+  match pending.iter_mut().find(|(other, _)| *other == id) {
+    Some((_, registered)) => registered.clone_from(waker),
+    None => pending.push((id, waker.clone())),
+  }
+  if !state.running {
+    state.running = true;
+    //The previous thread only has to let go of the core, it must not outlive the halves either
+    if let Some(previous) = state.thread.take() {
+      let _ = previous.join();
+    }
+    let core = Arc::downgrade(core);
+    state.thread = Some(std::thread::spawn(move || wake_pending(&core)));
+  }
+  id
+}
+
+fn unregister(core: &SessionCore, id: u64) {
+  let mut state = core.wakers.state();
+  state.readers.retain(|(other, _)| *other != id);
+  state.writers.retain(|(other, _)| *other != id);
+}
+
+/// Called as a half is dropped. Futures borrow the halves, so once the last one goes none is
+/// pending and the helper thread is about to exit. It is joined so the ring ends with the last
+/// half rather than with the thread's last wait, after the adapter may already be gone
+fn release(core: &SessionCore) {
+  let thread = {
+    let mut state = core.wakers.state();
+    state.halves -= 1;
+    match state.halves {
+      0 => state.thread.take(),
+      _ => None,
+    }
+  };
+  if let Some(thread) = thread {
+    let _ = thread.join();
+  }
+}
+
+/// Body of the helper thread. Readers are woken once the ring has a packet or the wait fails,
+/// writers after every retry interval since nothing signals room in the send ring
+fn wake_pending(core: &Weak<SessionCore>) {
+  loop {
+    let Some(core) = core.upgrade() else {
+      return;
+    };
+    let (readers, writers) = {
+      let mut state = core.wakers.state();
+      if state.readers.is_empty() && state.writers.is_empty() {
+        state.running = false;
+        return;
+      }
+      (!state.readers.is_empty(), !state.writers.is_empty())
+    };
+    let timeout = match writers {
+      true => ALLOCATE_RETRY,
+      false => WAIT_SLICE,
+    };
+    let waited = match readers {
+      true => core.wait_readable(Some(timeout)),
+      false => {
+        std::thread::sleep(timeout);
+        Ok(false)
+      }
+    };
+    let mut state = core.wakers.state();
+    let mut woken = std::mem::take(&mut state.writers);
+    match waited {
+      Ok(false) => {}
+      Ok(true) => woken.append(&mut state.readers),
+      Err(err) => {
+        state.failed = Some(wait_error_to_receive(err)).filter(|err| !err.is_would_block());
+        woken.append(&mut state.readers);
+      }
+    }
+    drop(state);
+    for (_, waker) in woken {
+      waker.wake();
+    }
+  }
+}
+
+/// Future returned by [`SessionReader::recv_async`]
+pub struct RecvFuture<'reader> {
+  reader: &'reader SessionReader<'reader>,
+  /// Set once the future waits for the helper thread
+  id: Option<u64>,
+}
+
+impl<'reader> Future for RecvFuture<'reader> {
+  type Output = Result<RecvPacket<'reader>, ReceivePacketError>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let reader = self.reader;
+    match reader.recv() {
+      Err(ReceivePacketError::WouldBlock) => {}
+      result => return Poll::Ready(result),
+    }
+    if self.id.is_some() {
+      if let Some(err) = reader.core.wakers.state().failed.take() {
+        return Poll::Ready(Err(err));
+      }
+    }
+    self.id = Some(register(&reader.core, self.id, false, cx.waker()));
+    Poll::Pending
+  }
+}
+
+impl<'adapter> Drop for SessionReader<'adapter> {
+  fn drop(&mut self) {
+    release(&self.core);
+  }
+}
+
+impl<'reader> Drop for RecvFuture<'reader> {
+  fn drop(&mut self) {
+    if let Some(id) = self.id {
+      unregister(&self.reader.core, id);
+    }
+  }
+}
+
+/// Sending half of a [`Session`](crate::Session), created by
+/// [`Session::split`](crate::Session::split)
+pub struct SessionWriter<'adapter> {
+  core: Arc<SessionCore>,
+  adapter: AdapterRef<'adapter>,
+}
+
+impl<'adapter> SessionWriter<'adapter> {
+  pub(crate) fn new(core: Arc<SessionCore>, adapter: AdapterRef<'adapter>) -> Self {
+    core.wakers.state().halves += 1;
+    Self { core, adapter }
+  }
+  pub fn adapter(&self) -> &Adapter {
+    &self.adapter
+  }
+  /// Counters of the packets sent through this half
+  pub fn stats(&self) -> DirectionStats {
+    self.core.tx.snapshot()
+  }
+  pub fn allocate(&self, size: IpPacketSize) -> Result<SendPacket<'_>, AllocatePacketError> {
+    self.core.allocate(size)
+  }
+  /// Allocates a packet, retrying while the send ring is full
+  pub fn allocate_blocking(
+    &self,
+    size: IpPacketSize,
+  ) -> Result<SendPacket<'_>, AllocatePacketError> {
+    loop {
+      match self.core.allocate(size) {
+        Err(AllocatePacketError::WouldBlock) => std::thread::sleep(ALLOCATE_RETRY),
+        result => return result,
+      }
+    }
+  }
+  /// Like [`allocate_blocking`](Self::allocate_blocking) but gives up after `timeout`, returning
+  /// [`AllocatePacketError::WouldBlock`]
+  pub fn allocate_timeout(
+    &self,
+    size: IpPacketSize,
+    timeout: Duration,
+  ) -> Result<SendPacket<'_>, AllocatePacketError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+      match self.core.allocate(size) {
+        Err(AllocatePacketError::WouldBlock) if Instant::now() < deadline => {
+          std::thread::sleep(ALLOCATE_RETRY.min(deadline - Instant::now()))
+        }
+        result => return result,
+      }
+    }
+  }
+  /// Returns a future resolving to an allocated packet once the send ring has room. While it is
+  /// full the helper thread of the session retries on behalf of all waiting futures
+  pub fn allocate_async(&self, size: IpPacketSize) -> AllocateFuture<'_> {
+    AllocateFuture {
+      writer: self,
+      size,
+      id: None,
+    }
+  }
+}
+
+/// Future returned by [`SessionWriter::allocate_async`]
+pub struct AllocateFuture<'writer> {
+  writer: &'writer SessionWriter<'writer>,
+  size: IpPacketSize,
+  /// Set once the future waits for the helper thread
+  id: Option<u64>,
+}
+
+impl<'writer> Future for AllocateFuture<'writer> {
+  type Output = Result<SendPacket<'writer>, AllocatePacketError>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let writer = self.writer;
+    match writer.allocate(self.size) {
+      Err(AllocatePacketError::WouldBlock) => {
+        self.id = Some(register(&writer.core, self.id, true, cx.waker()));
+        Poll::Pending
+      }
+      result => Poll::Ready(result),
+    }
+  }
+}
+
+impl<'adapter> Drop for SessionWriter<'adapter> {
+  fn drop(&mut self) {
+    release(&self.core);
+  }
+}
+
+impl<'writer> Drop for AllocateFuture<'writer> {
+  fn drop(&mut self) {
+    if let Some(id) = self.id {
+      unregister(&self.writer.core, id);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    future::Future,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread,
+    time::{Duration, Instant},
+  };
+
+  use crate::{Adapter, IpPacketSize, RingCapacity, SessionReader, SessionWriter};
+
+  fn assert_send<T: Send>() {}
+
+  #[test]
+  fn halves_are_send() {
+    assert_send::<SessionReader<'static>>();
+    assert_send::<SessionWriter<'static>>();
+  }
+
+  //Minimal executor so the async helpers can be tested without a runtime
+  struct Signal(Mutex<bool>, Condvar);
+
+  impl Wake for Signal {
+    fn wake(self: Arc<Self>) {
+      *self.0.lock().unwrap() = true;
+      self.1.notify_one();
+    }
+  }
+
+  fn block_on<F: Future>(future: F) -> F::Output {
+    let signal = Arc::new(Signal(Mutex::new(false), Condvar::new()));
+    let waker = Waker::from(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+      if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+        return output;
+      }
+      let mut woken = signal.0.lock().unwrap();
+      while !*woken {
+        woken = signal.1.wait(woken).unwrap();
+      }
+      *woken = false;
+    }
+  }
+
+  #[test]
+  fn halves_run_on_separate_threads() {
+    let adapter = Adapter::create_in_memory("split-threads", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let (reader, writer) = adapter.into_session(RingCapacity::min()).unwrap().split();
+
+    let receiver = thread::spawn(move || {
+      for i in 0..100u8 {
+        let packet = reader.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(packet.slice(), &[0x45, i]);
+      }
+      reader.stats()
+    });
+    let sender = thread::spawn(move || {
+      for i in 0..100u8 {
+        let mut packet = writer.allocate_blocking(2.try_into().unwrap()).unwrap();
+        packet.mut_slice().copy_from_slice(&[0x60, i]);
+        packet.send();
+      }
+      writer.stats()
+    });
+    for i in 0..100u8 {
+      peer.inject(&[0x45, i]).unwrap();
+    }
+    for i in 0..100u8 {
+      assert_eq!(
+        peer.recv_timeout(Duration::from_secs(5)).unwrap(),
+        vec![0x60, i]
+      );
+    }
+    let rx = receiver.join().unwrap();
+    let tx = sender.join().unwrap();
+    assert_eq!((rx.packets, rx.bytes), (100, 200));
+    assert_eq!((tx.packets, tx.bytes), (100, 200));
+  }
+
+  #[test]
+  fn ring_ends_when_both_halves_are_dropped() {
+    let adapter = Adapter::create_in_memory("split-drop", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let (reader, writer) = adapter.session(RingCapacity::min()).unwrap().split();
+    drop(reader);
+    assert!(peer.has_session());
+    writer.allocate(4.try_into().unwrap()).unwrap().send();
+    assert_eq!(peer.try_recv().unwrap().len(), 4);
+    drop(writer);
+    assert!(!peer.has_session());
+  }
+
+  #[test]
+  fn async_receive_wakes_on_packet() {
+    let adapter = Adapter::create_in_memory("split-async", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let (reader, writer) = adapter.session(RingCapacity::min()).unwrap().split();
+    let injector = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      peer.inject(&[0x45, 0, 0, 1]).unwrap();
+    });
+    let packet = block_on(reader.recv_async()).unwrap();
+    assert_eq!(packet.slice(), &[0x45, 0, 0, 1]);
+    drop(packet);
+    injector.join().unwrap();
+    assert!(reader.stats().would_block > 0);
+    block_on(writer.allocate_async(1.try_into().unwrap()))
+      .unwrap()
+      .send();
+    assert_eq!(writer.stats().packets, 1);
+  }
+
+  #[test]
+  fn pending_futures_share_one_waiter() {
+    let adapter = Adapter::create_in_memory("split-waiter", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let (reader, writer) = adapter.session(RingCapacity::min()).unwrap().split();
+    let size = IpPacketSize::max();
+    let held: Vec<_> = std::iter::from_fn(|| writer.allocate(size).ok()).collect();
+    let signal = Arc::new(Signal(Mutex::new(false), Condvar::new()));
+    let waker = Waker::from(signal);
+    let mut cx = Context::from_waker(&waker);
+    let mut allocate = Box::pin(writer.allocate_async(size));
+    let mut receive = Box::pin(reader.recv_async());
+    for _ in 0..1000 {
+      assert!(allocate.as_mut().poll(&mut cx).is_pending());
+      assert!(receive.as_mut().poll(&mut cx).is_pending());
+    }
+    {
+      let state = writer.core.wakers.state();
+      assert_eq!((state.readers.len(), state.writers.len()), (1, 1));
+      assert!(state.running);
+    }
+    //Room frees up once the peer takes the sent packets
+    for packet in held {
+      packet.send();
+    }
+    while peer.try_recv().is_some() {}
+    assert!(block_on(allocate).is_ok());
+    drop(receive);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while writer.core.wakers.state().running {
+      assert!(Instant::now() < deadline);
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+
+  #[test]
+  fn waiter_does_not_outlive_the_halves() {
+    let adapter = Adapter::create_in_memory("split-waiter-end", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let (reader, writer) = adapter.session(RingCapacity::min()).unwrap().split();
+    let waker = Waker::from(Arc::new(Signal(Mutex::new(false), Condvar::new())));
+    let mut receive = Box::pin(reader.recv_async());
+    assert!(receive
+      .as_mut()
+      .poll(&mut Context::from_waker(&waker))
+      .is_pending());
+    //The helper thread is still in its wait when the halves go
+    thread::sleep(Duration::from_millis(10));
+    drop(receive);
+    drop(reader);
+    drop(writer);
+    assert!(!peer.has_session());
+  }
+
+  #[test]
+  fn receive_timeout_expires() {
+    let adapter = Adapter::create_in_memory("split-timeout", "tunnel_type", None).unwrap();
+    let (reader, _writer) = adapter.session(RingCapacity::min()).unwrap().split();
+    assert!(matches!(
+      reader.recv_timeout(Duration::from_millis(10)),
+      Err(err) if err.is_would_block()
+    ));
+  }
+}
@@ -0,0 +1,495 @@
+//! Keeps a tunnel running across driver failures. The supervisor owns everything needed to build
+//! the adapter and its session from scratch, so when the ring reports that the adapter is going
+//! away or that its contents are corrupted it can tear both down and rebuild them unattended
+
+use std::{
+  cell::Cell,
+  net::IpAddr,
+  sync::{
+    mpsc::{channel, Receiver, Sender},
+    Mutex,
+  },
+  time::Duration,
+};
+
+use crate::{
+  Adapter, AllocatePacketError, IpAndMaskPrefix, IpPacketSize, ReceivePacketError, RecvPacket,
+  RingCapacity, Route, SendPacket, Session, WintunError, WintunResult,
+};
+
+/// Which kind of adapter the supervisor builds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelBackend {
+  /// [`Adapter::create`]
+  Native,
+  /// [`Adapter::create_in_memory`]
+  InMemory,
+}
+
+/// Exponential backoff between recovery attempts. Attempt `n` waits `initial * 2^(n-1)`, capped
+/// at `max`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+  pub initial: Duration,
+  pub max: Duration,
+  /// Attempts made before a recovery gives up, `None` retries forever
+  pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Self {
+      initial: Duration::from_millis(100),
+      max: Duration::from_secs(30),
+      max_attempts: None,
+    }
+  }
+}
+
+impl Backoff {
+  pub fn delay(&self, attempt: u32) -> Duration {
+    let factor = 1u32
+      .checked_shl(attempt.saturating_sub(1))
+      .unwrap_or(u32::MAX);
+    self.initial.saturating_mul(factor).min(self.max)
+  }
+}
+
+/// Everything the supervisor needs to build the tunnel
+#[derive(Debug, Clone)]
+pub struct TunnelConfig {
+  pub name: String,
+  pub tunnel_type: String,
+  /// Guid of the adapter. When `None` the guid picked on the first start is reused afterwards
+  pub guid: Option<u128>,
+  pub capacity: RingCapacity,
+  pub mtu: Option<IpPacketSize>,
+  pub addresses: Vec<IpAndMaskPrefix>,
+  pub routes: Vec<Route>,
+  pub dns_servers: Vec<IpAddr>,
+  pub backend: TunnelBackend,
+  pub backoff: Backoff,
+}
+
+impl TunnelConfig {
+  pub fn new(name: impl Into<String>, tunnel_type: impl Into<String>) -> Self {
+    Self {
+      name: name.into(),
+      tunnel_type: tunnel_type.into(),
+      guid: None,
+      capacity: RingCapacity::max(),
+      mtu: None,
+      addresses: Vec::new(),
+      routes: Vec::new(),
+      dns_servers: Vec::new(),
+      backend: TunnelBackend::Native,
+      backoff: Backoff::default(),
+    }
+  }
+}
+
+/// Lifecycle notifications delivered through [`TunnelSupervisor::subscribe`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelEvent {
+  Started {
+    guid: u128,
+  },
+  /// The session failed with an error that requires rebuilding the tunnel
+  SessionLost {
+    error: WintunError,
+  },
+  Retrying {
+    attempt: u32,
+    delay: Duration,
+  },
+  RecoveryFailed {
+    attempt: u32,
+    error: WintunError,
+  },
+  Recovered {
+    attempt: u32,
+  },
+  /// [`Backoff::max_attempts`] were exhausted. The next operation starts a new recovery
+  GaveUp {
+    attempts: u32,
+  },
+  Stopped,
+}
+
+/// Owns an adapter and its session and rebuilds both when the session fails.
+///
+/// Operations that fail with `AdapterIsTerminating` or `InvalidData` return the error once and
+/// mark the tunnel as lost, the next operation then recreates the adapter with the same guid,
+/// reapplies the MTU, addresses, DNS servers and routes and starts a new session before
+/// proceeding. Other errors are returned as they are and leave the tunnel alone
+pub struct TunnelSupervisor {
+  config: TunnelConfig,
+  guid: Option<u128>,
+  session: Option<Session<'static>>,
+  lost: Cell<bool>,
+  /// Set by [`stop`](Self::stop), operations fail instead of rebuilding until the next start
+  stopped: bool,
+  subscribers: Mutex<Vec<Sender<TunnelEvent>>>,
+}
+
+impl TunnelSupervisor {
+  pub fn new(config: TunnelConfig) -> Self {
+    Self {
+      guid: config.guid,
+      config,
+      session: None,
+      lost: Cell::new(false),
+      stopped: false,
+      subscribers: Mutex::new(Vec::new()),
+    }
+  }
+  pub fn config(&self) -> &TunnelConfig {
+    &self.config
+  }
+  /// Returns a channel receiving every event emitted from now on
+  pub fn subscribe(&self) -> Receiver<TunnelEvent> {
+    let (sender, receiver) = channel();
+    self.subscribers().push(sender);
+    receiver
+  }
+  /// Builds the tunnel. Unlike recovery this does not retry, so configuration errors surface
+  /// immediately
+  pub fn start(&mut self) -> WintunResult<()> {
+    self.session = None;
+    self.stopped = false;
+    let session = self.build()?;
+    self.session = Some(session);
+    self.lost.set(false);
+    self.emit(TunnelEvent::Started {
+      guid: self.guid.unwrap_or_default(),
+    });
+    Ok(())
+  }
+  /// Ends the session and closes the adapter. Operations then fail with `AdapterIsTerminating`
+  /// until [`start`](Self::start) is called again
+  pub fn stop(&mut self) {
+    self.stopped = true;
+    if self.session.take().is_some() {
+      self.emit(TunnelEvent::Stopped);
+    }
+  }
+  pub fn is_running(&self) -> bool {
+    self.session.is_some() && !self.lost.get()
+  }
+  /// The current session, `None` while the tunnel is down
+  pub fn session(&self) -> Option<&Session<'static>> {
+    self.session.as_ref().filter(|_| !self.lost.get())
+  }
+  pub fn adapter(&self) -> Option<&Adapter> {
+    self.session().map(Session::adapter)
+  }
+  /// Receives a packet, rebuilding the tunnel first if it was lost
+  pub fn recv(&mut self) -> WintunResult<RecvPacket<'_>> {
+    self.ensure_running()?;
+    let session = self
+      .session
+      .as_ref()
+      .ok_or(WintunError::AdapterIsTerminating)?;
+    match session.recv() {
+      Err(ReceivePacketError::WouldBlock) => Err(WintunError::WouldBlock),
+      Err(error) => Err(self.fail(error.into())),
+      Ok(packet) => Ok(packet),
+    }
+  }
+  /// Allocates a packet, rebuilding the tunnel first if it was lost
+  pub fn allocate(&mut self, size: IpPacketSize) -> WintunResult<SendPacket<'_>> {
+    self.ensure_running()?;
+    let session = self
+      .session
+      .as_ref()
+      .ok_or(WintunError::AdapterIsTerminating)?;
+    match session.allocate(size) {
+      Err(AllocatePacketError::WouldBlock) => Err(WintunError::WouldBlock),
+      Err(error) => Err(self.fail(error.into())),
+      Ok(packet) => Ok(packet),
+    }
+  }
+  /// Waits for a packet like [`Session::wait_readable`], rebuilding the tunnel first if it was
+  /// lost
+  pub fn wait_readable(&mut self, timeout: Option<Duration>) -> WintunResult<bool> {
+    self.ensure_running()?;
+    let session = self
+      .session
+      .as_ref()
+      .ok_or(WintunError::AdapterIsTerminating)?;
+    session
+      .wait_readable(timeout)
+      .map_err(|error| self.fail(error))
+  }
+  /// Tears the tunnel down and rebuilds it, retrying with [`TunnelConfig::backoff`]. Fails with
+  /// `AdapterIsTerminating` after [`stop`](Self::stop)
+  pub fn recover(&mut self) -> WintunResult<()> {
+    if self.stopped {
+      return Err(WintunError::AdapterIsTerminating);
+    }
+    self.session = None;
+    self.lost.set(false);
+    let backoff = self.config.backoff;
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      let delay = backoff.delay(attempt);
+      self.emit(TunnelEvent::Retrying { attempt, delay });
+      std::thread::sleep(delay);
+      match self.build() {
+        Ok(session) => {
+          self.session = Some(session);
+          self.emit(TunnelEvent::Recovered { attempt });
+          return Ok(());
+        }
+        Err(error) => {
+          self.emit(TunnelEvent::RecoveryFailed {
+            attempt,
+            error: error.clone(),
+          });
+          if backoff.max_attempts.is_some_and(|max| attempt >= max) {
+            self.emit(TunnelEvent::GaveUp { attempts: attempt });
+            return Err(error);
+          }
+        }
+      }
+    }
+  }
+  fn ensure_running(&mut self) -> WintunResult<()> {
+    if self.session.is_none() || self.lost.get() {
+      self.recover()?;
+    }
+    Ok(())
+  }
+  /// Marks the tunnel as lost if `error` means the adapter is going away or the ring is corrupted
+  fn fail(&self, error: WintunError) -> WintunError {
+    let fatal = matches!(
+      error.root(),
+      WintunError::AdapterIsTerminating | WintunError::InvalidData
+    );
+    if fatal && !self.lost.replace(true) {
+      self.emit(TunnelEvent::SessionLost {
+        error: error.clone(),
+      });
+    }
+    error
+  }
+  fn build(&mut self) -> WintunResult<Session<'static>> {
+    let config = &self.config;
+    let mut adapter = match config.backend {
+      TunnelBackend::Native => Adapter::create(&config.name, &config.tunnel_type, self.guid),
+      TunnelBackend::InMemory => {
+        Adapter::create_in_memory(&config.name, &config.tunnel_type, self.guid)
+      }
+    }?;
+    if self.guid.is_none() {
+      self.guid = Some(adapter.get_guid()?);
+    }
+    if let Some(mtu) = config.mtu {
+      adapter.set_mtu(mtu)?;
+    }
+    for address in &config.addresses {
+      adapter.set_ip_address(*address)?;
+    }
+    if !config.dns_servers.is_empty() {
+      adapter.set_dns_servers(&config.dns_servers)?;
+    }
+    //Routes go last, Linux only accepts them once the session brought the link up
+    let session = adapter.into_session(config.capacity)?;
+    for route in &config.routes {
+      session.adapter().add_route(*route)?;
+    }
+    Ok(session)
+  }
+  fn subscribers(&self) -> std::sync::MutexGuard<'_, Vec<Sender<TunnelEvent>>> {
+    self
+      .subscribers
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+  fn emit(&self, event: TunnelEvent) {
+    self
+      .subscribers()
+      .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{net::Ipv4Addr, time::Duration};
+
+  use crate::{Adapter, IpAndMaskPrefix, Operation, RingCapacity, Route, WintunError};
+
+  use super::{Backoff, TunnelBackend, TunnelConfig, TunnelEvent, TunnelSupervisor};
+
+  fn config(name: &str) -> TunnelConfig {
+    let mut config = TunnelConfig::new(name, "tunnel_type");
+    config.backend = TunnelBackend::InMemory;
+    config.capacity = RingCapacity::min();
+    config.mtu = Some(1400.try_into().unwrap());
+    config.addresses.push(IpAndMaskPrefix::V4 {
+      ip: Ipv4Addr::new(10, 7, 0, 1),
+      prefix: 24.try_into().unwrap(),
+    });
+    config.routes.push(Route::new(IpAndMaskPrefix::V4 {
+      ip: Ipv4Addr::new(10, 70, 0, 0),
+      prefix: 16.try_into().unwrap(),
+    }));
+    config.backoff = Backoff {
+      initial: Duration::from_millis(1),
+      max: Duration::from_millis(4),
+      max_attempts: Some(3),
+    };
+    config
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_max() {
+    let backoff = Backoff {
+      initial: Duration::from_millis(10),
+      max: Duration::from_millis(50),
+      max_attempts: None,
+    };
+    let delays: Vec<_> = (1..=5).map(|attempt| backoff.delay(attempt)).collect();
+    assert_eq!(
+      delays,
+      [10, 20, 40, 50, 50].map(Duration::from_millis).to_vec()
+    );
+    assert_eq!(backoff.delay(200), Duration::from_millis(50));
+  }
+
+  #[test]
+  fn recovers_terminated_adapter() {
+    let mut supervisor = TunnelSupervisor::new(config("supervisor-terminate"));
+    let events = supervisor.subscribe();
+    supervisor.start().unwrap();
+    let peer = supervisor.adapter().unwrap().memory_peer().unwrap();
+    let guid = peer.guid();
+
+    peer.terminate();
+    assert!(matches!(
+      supervisor.recv(),
+      Err(WintunError::AdapterIsTerminating)
+    ));
+    assert!(!supervisor.is_running());
+    assert!(matches!(supervisor.recv(), Err(WintunError::WouldBlock)));
+
+    let peer = supervisor.adapter().unwrap().memory_peer().unwrap();
+    assert!(!peer.is_terminated());
+    assert_eq!(peer.guid(), guid);
+    assert_eq!(peer.mtu().unwrap().size(), 1400);
+    assert_eq!(peer.addresses(), supervisor.config().addresses);
+    assert_eq!(peer.routes(), supervisor.config().routes);
+    peer.inject(&[0x45, 1]).unwrap();
+    assert_eq!(supervisor.recv().unwrap().slice(), &[0x45, 1]);
+
+    let events: Vec<_> = events.try_iter().collect();
+    assert_eq!(
+      events,
+      vec![
+        TunnelEvent::Started { guid },
+        TunnelEvent::SessionLost {
+          error: WintunError::AdapterIsTerminating
+        },
+        TunnelEvent::Retrying {
+          attempt: 1,
+          delay: Duration::from_millis(1)
+        },
+        TunnelEvent::Recovered { attempt: 1 },
+      ]
+    );
+  }
+
+  #[test]
+  fn recovers_corrupted_ring() {
+    let mut supervisor = TunnelSupervisor::new(config("supervisor-corrupt"));
+    supervisor.start().unwrap();
+    let peer = supervisor.adapter().unwrap().memory_peer().unwrap();
+    peer.corrupt();
+    assert!(matches!(supervisor.wait_readable(None), Ok(true)));
+    assert!(matches!(supervisor.recv(), Err(WintunError::InvalidData)));
+    //Rebuilding closes the corrupted adapter
+    assert!(matches!(supervisor.recv(), Err(WintunError::WouldBlock)));
+    assert!(peer.is_terminated());
+    supervisor.allocate(1.try_into().unwrap()).unwrap().send();
+    let peer = supervisor.adapter().unwrap().memory_peer().unwrap();
+    assert_eq!(peer.try_recv().unwrap(), vec![0]);
+  }
+
+  #[test]
+  fn stays_down_after_stop() {
+    let mut supervisor = TunnelSupervisor::new(config("supervisor-stop"));
+    supervisor.start().unwrap();
+    supervisor.stop();
+    assert!(matches!(
+      supervisor.recv(),
+      Err(WintunError::AdapterIsTerminating)
+    ));
+    assert!(matches!(
+      supervisor.allocate(1.try_into().unwrap()),
+      Err(WintunError::AdapterIsTerminating)
+    ));
+    assert!(matches!(
+      supervisor.wait_readable(Some(Duration::ZERO)),
+      Err(WintunError::AdapterIsTerminating)
+    ));
+    //The adapter wasn't recreated behind the stop
+    assert!(supervisor.session().is_none());
+    assert!(Adapter::open("supervisor-stop").is_err());
+
+    supervisor.start().unwrap();
+    assert!(matches!(supervisor.recv(), Err(WintunError::WouldBlock)));
+  }
+
+  #[test]
+  fn rebuilds_only_for_lost_adapters_and_corrupted_rings() {
+    let mut supervisor = TunnelSupervisor::new(config("supervisor-other-errors"));
+    let events = supervisor.subscribe();
+    supervisor.start().unwrap();
+    let peer = supervisor.adapter().unwrap().memory_peer().unwrap();
+    let error = WintunError::InterfaceNotFound.context(Operation::StartSession, None);
+    assert_eq!(supervisor.fail(error.clone()), error);
+    assert!(supervisor.is_running());
+    assert!(matches!(supervisor.recv(), Err(WintunError::WouldBlock)));
+    assert!(!peer.is_terminated());
+
+    let error = WintunError::InvalidData.context(Operation::StartSession, None);
+    supervisor.fail(error.clone());
+    assert!(!supervisor.is_running());
+    let events: Vec<_> = events.try_iter().collect();
+    assert_eq!(events[1..], [TunnelEvent::SessionLost { error }]);
+  }
+
+  #[test]
+  fn gives_up_after_max_attempts() {
+    let mut supervisor = TunnelSupervisor::new(config("supervisor-give-up"));
+    let events = supervisor.subscribe();
+    supervisor.start().unwrap();
+    supervisor
+      .adapter()
+      .unwrap()
+      .memory_peer()
+      .unwrap()
+      .terminate();
+    assert!(supervisor.recv().is_err());
+
+    //Take the name so the supervisor can't recreate the adapter
+    let squatter = Adapter::create_in_memory("supervisor-give-up", "other", None).unwrap();
+    assert!(supervisor.recv().is_err());
+    assert!(supervisor.session().is_none());
+    let events: Vec<_> = events.try_iter().collect();
+    assert_eq!(events.last(), Some(&TunnelEvent::GaveUp { attempts: 3 }));
+    assert_eq!(
+      events
+        .iter()
+        .filter(|event| matches!(event, TunnelEvent::RecoveryFailed { .. }))
+        .count(),
+      3
+    );
+
+    squatter.close();
+    assert!(matches!(supervisor.recv(), Err(WintunError::WouldBlock)));
+    supervisor.stop();
+    assert!(!supervisor.is_running());
+  }
+}
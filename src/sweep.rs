@@ -0,0 +1,392 @@
+//! Cleanup of adapters left behind by processes that exited without closing them. Adapters are
+//! found by the tunnel type they were created with, and an optional on-disk registry records which
+//! process owns which adapter so stale ones can be told apart from ones still in use
+
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use crate::{backend, Adapter, Operation, WintunError, WintunResult};
+
+/// An adapter found by [`list_adapters`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterInfo {
+  pub name: String,
+  pub tunnel_type: String,
+  pub guid: u128,
+  pub index: u32,
+  /// Whether this is an adapter created with [`Adapter::create_in_memory`]
+  pub in_memory: bool,
+}
+
+/// Outcome of [`sweep_adapters`]
+#[derive(Debug, Default)]
+pub struct SweepReport {
+  pub kept: Vec<AdapterInfo>,
+  pub removed: Vec<AdapterInfo>,
+  pub failed: Vec<(AdapterInfo, WintunError)>,
+  /// Records of owners that are gone which couldn't be deleted, filled by
+  /// [`OwnershipRegistry::sweep`]
+  pub failed_records: Vec<(OwnerRecord, io::Error)>,
+}
+
+/// Lists the in-memory adapters of this process and the native adapters created with
+/// `tunnel_type`
+pub fn list_adapters(tunnel_type: &str) -> WintunResult<Vec<AdapterInfo>> {
+  let mut adapters = backend::memory::list(tunnel_type);
+  adapters.extend(
+    backend::native::list(tunnel_type).map_err(|err| err.context(Operation::ListAdapters, None))?,
+  );
+  Ok(adapters)
+}
+
+/// Removes every adapter of `tunnel_type` for which `keep` returns `false`. Removing an adapter
+/// that is still in use terminates its sessions.
+///
+/// On Linux TUN interfaces vanish with the last process holding them, so only interfaces kept
+/// alive by other processes are found there
+pub fn sweep_adapters(
+  tunnel_type: &str,
+  keep: impl Fn(&AdapterInfo) -> bool,
+) -> WintunResult<SweepReport> {
+  let mut report = SweepReport::default();
+  for adapter in list_adapters(tunnel_type)? {
+    if keep(&adapter) {
+      report.kept.push(adapter);
+      continue;
+    }
+    let result = if adapter.in_memory {
+      backend::memory::remove(&adapter)
+    } else {
+      backend::native::remove(&adapter)
+    };
+    match result {
+      Ok(()) => report.removed.push(adapter),
+      Err(error) => {
+        let error = error.context(Operation::RemoveAdapter, Some(&adapter.name));
+        report.failed.push((adapter, error))
+      }
+    }
+  }
+  Ok(report)
+}
+
+/// Marker stating that process `pid` owns an adapter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnerRecord {
+  pub pid: u32,
+  pub name: String,
+  pub tunnel_type: String,
+  pub guid: u128,
+}
+
+impl OwnerRecord {
+  fn serialize(&self) -> String {
+    format!(
+      "pid={}\nguid={:032x}\nname={}\ntunnel_type={}\n",
+      self.pid,
+      self.guid,
+      escape(&self.name),
+      escape(&self.tunnel_type)
+    )
+  }
+  fn parse(contents: &str) -> Option<Self> {
+    let mut pid = None;
+    let mut guid = None;
+    let mut name = None;
+    let mut tunnel_type = None;
+    for line in contents.lines() {
+      let (key, value) = line.split_once('=')?;
+      match key {
+        "pid" => pid = value.parse().ok(),
+        "guid" => guid = u128::from_str_radix(value, 16).ok(),
+        "name" => name = unescape(value),
+        "tunnel_type" => tunnel_type = unescape(value),
+        _ => {}
+      }
+    }
+    Some(Self {
+      pid: pid?,
+      guid: guid?,
+      name: name?,
+      tunnel_type: tunnel_type?,
+    })
+  }
+}
+
+/// Escapes line breaks and backslashes so a value stays on its line
+fn escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '\\' => escaped.push_str("\\\\"),
+      '\n' => escaped.push_str("\\n"),
+      '\r' => escaped.push_str("\\r"),
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+fn unescape(value: &str) -> Option<String> {
+  let mut unescaped = String::with_capacity(value.len());
+  let mut chars = value.chars();
+  while let Some(c) = chars.next() {
+    unescaped.push(match c {
+      '\\' => match chars.next()? {
+        '\\' => '\\',
+        'n' => '\n',
+        'r' => '\r',
+        _ => return None,
+      },
+      c => c,
+    });
+  }
+  Some(unescaped)
+}
+
+#[cfg(target_os = "linux")]
+fn process_is_alive(pid: u32) -> bool {
+  Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(windows)]
+fn process_is_alive(pid: u32) -> bool {
+  use winapi::um::{handleapi, minwinbase, processthreadsapi, winnt};
+
+  let process =
+    unsafe { processthreadsapi::OpenProcess(winnt::PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+  if process.is_null() {
+    //Processes of other users can't be opened but are still running
+    let error = get_last_error::Win32Error::get_last_error();
+    return error.code() == winapi::shared::winerror::ERROR_ACCESS_DENIED;
+  }
+  let mut exit_code = 0;
+  let queried = unsafe { processthreadsapi::GetExitCodeProcess(process, &mut exit_code as *mut _) };
+  unsafe { handleapi::CloseHandle(process) };
+  queried != 0 && exit_code == minwinbase::STILL_ACTIVE
+}
+
+type Liveness = Box<dyn Fn(u32) -> bool + Send + Sync>;
+
+/// Directory of lock files recording which process owns which adapter. Processes
+/// [`claim`](Self::claim) the adapters they create, and [`sweep`](Self::sweep) removes the
+/// adapters whose owner is gone
+pub struct OwnershipRegistry {
+  dir: PathBuf,
+  is_alive: Liveness,
+}
+
+impl OwnershipRegistry {
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Self {
+      dir: dir.into(),
+      is_alive: Box::new(process_is_alive),
+    }
+  }
+  /// Registry in `wintun2-owners` under the system temporary directory
+  pub fn default_location() -> Self {
+    Self::new(std::env::temp_dir().join("wintun2-owners"))
+  }
+  /// Replaces the check whether a process is still running
+  pub fn with_liveness(mut self, is_alive: impl Fn(u32) -> bool + Send + Sync + 'static) -> Self {
+    self.is_alive = Box::new(is_alive);
+    self
+  }
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+  fn path(&self, guid: u128) -> PathBuf {
+    self.dir.join(format!("{guid:032x}.owner"))
+  }
+  fn write(&self, record: &OwnerRecord) -> io::Result<()> {
+    fs::create_dir_all(&self.dir)?;
+    //Write to a temporary file first so readers never see a partial record
+    let path = self.path(record.guid);
+    let temporary = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&temporary, record.serialize())?;
+    fs::rename(&temporary, &path)
+  }
+  /// Records the current process as the owner of `adapter`
+  pub fn claim(&self, adapter: &Adapter, tunnel_type: &str) -> WintunResult<OwnerRecord> {
+    let record = OwnerRecord {
+      pid: std::process::id(),
+      name: adapter.name().to_owned(),
+      tunnel_type: tunnel_type.to_owned(),
+      guid: adapter.get_guid()?,
+    };
+    self
+      .write(&record)
+      .map_err(|err| io_error(err).context(Operation::ClaimAdapter, Some(adapter.name())))?;
+    Ok(record)
+  }
+  /// Removes the record of the adapter with `guid` if it belongs to the current process
+  pub fn release(&self, guid: u128) -> io::Result<()> {
+    match self.owner(guid) {
+      Some(record) if record.pid == std::process::id() => fs::remove_file(self.path(guid)),
+      _ => Ok(()),
+    }
+  }
+  pub fn owner(&self, guid: u128) -> Option<OwnerRecord> {
+    let contents = fs::read_to_string(self.path(guid)).ok()?;
+    OwnerRecord::parse(&contents)
+  }
+  /// All readable records, in no particular order
+  pub fn records(&self) -> io::Result<Vec<OwnerRecord>> {
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(err) => return Err(err),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+      let path = entry?.path();
+      if path
+        .extension()
+        .is_some_and(|extension| extension == "owner")
+      {
+        if let Some(record) = fs::read_to_string(&path)
+          .ok()
+          .and_then(|contents| OwnerRecord::parse(&contents))
+        {
+          records.push(record);
+        }
+      }
+    }
+    Ok(records)
+  }
+  /// An adapter is stale when its owner is no longer running. Adapters nobody claimed are never
+  /// stale, they may belong to software other than this crate
+  pub fn is_stale(&self, adapter: &AdapterInfo) -> bool {
+    self
+      .owner(adapter.guid)
+      .is_some_and(|record| !(self.is_alive)(record.pid))
+  }
+  /// Removes the stale adapters of `tunnel_type` and drops the records of owners that are gone.
+  /// Failures are collected in the report, the records of adapters that couldn't be removed are
+  /// kept so a later sweep retries them
+  pub fn sweep(&self, tunnel_type: &str) -> WintunResult<SweepReport> {
+    let mut report = sweep_adapters(tunnel_type, |adapter| !self.is_stale(adapter))?;
+    for record in self.records().map_err(io_error)? {
+      let failed = report
+        .failed
+        .iter()
+        .any(|(adapter, _)| adapter.guid == record.guid);
+      if record.tunnel_type != tunnel_type || failed || (self.is_alive)(record.pid) {
+        continue;
+      }
+      match fs::remove_file(self.path(record.guid)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => {
+          report.failed_records.push((record, err))
+        }
+        _ => {}
+      }
+    }
+    Ok(report)
+  }
+}
+
+#[cfg(windows)]
+fn io_error(error: io::Error) -> WintunError {
+  let code = error
+    .raw_os_error()
+    .unwrap_or(winapi::shared::winerror::ERROR_GEN_FAILURE as i32);
+  crate::OsError::new(code as u32).into()
+}
+
+#[cfg(not(windows))]
+fn io_error(error: io::Error) -> WintunError {
+  crate::OsError::new(error.raw_os_error().unwrap_or(libc::EIO)).into()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use crate::Adapter;
+
+  use super::{list_adapters, sweep_adapters, OwnerRecord, OwnershipRegistry};
+
+  #[test]
+  fn sweep_removes_rejected_adapters() {
+    let keep = Adapter::create_in_memory("sweep-keep", "sweep-test", None).unwrap();
+    let stale = Adapter::create_in_memory("sweep-stale", "sweep-test", None).unwrap();
+    let other = Adapter::create_in_memory("sweep-other", "other-type", None).unwrap();
+    let mut names: Vec<_> = list_adapters("sweep-test")
+      .unwrap()
+      .into_iter()
+      .map(|adapter| adapter.name)
+      .collect();
+    names.sort();
+    assert_eq!(names, ["sweep-keep", "sweep-stale"]);
+
+    let report = sweep_adapters("sweep-test", |adapter| adapter.name == "sweep-keep").unwrap();
+    assert_eq!(report.kept.len(), 1);
+    assert_eq!(report.removed.len(), 1);
+    assert_eq!(report.removed[0].name, "sweep-stale");
+    assert!(report.failed.is_empty());
+    assert!(!keep.memory_peer().unwrap().is_terminated());
+    assert!(stale.memory_peer().unwrap().is_terminated());
+    assert!(!other.memory_peer().unwrap().is_terminated());
+  }
+
+  #[test]
+  fn registry_sweeps_adapters_of_dead_owners() {
+    let dir = std::env::temp_dir().join(format!("wintun2-registry-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let registry = OwnershipRegistry::new(&dir).with_liveness(|pid| pid == std::process::id());
+
+    let ours = Adapter::create_in_memory("registry-ours", "registry-test", None).unwrap();
+    let orphan = Adapter::create_in_memory("registry-orphan", "registry-test", None).unwrap();
+    let unclaimed = Adapter::create_in_memory("registry-unclaimed", "registry-test", None).unwrap();
+    let claimed = registry.claim(&ours, "registry-test").unwrap();
+    assert_eq!(claimed.pid, std::process::id());
+    let dead = OwnerRecord {
+      pid: u32::MAX,
+      name: "registry-orphan".into(),
+      tunnel_type: "registry-test".into(),
+      guid: orphan.get_guid().unwrap(),
+    };
+    registry.write(&dead).unwrap();
+    assert_eq!(registry.owner(dead.guid), Some(dead.clone()));
+    assert_eq!(registry.records().unwrap().len(), 2);
+
+    let report = registry.sweep("registry-test").unwrap();
+    let removed: Vec<_> = report.removed.iter().map(|a| a.name.as_str()).collect();
+    assert_eq!(removed, ["registry-orphan"]);
+    assert!(report.failed_records.is_empty());
+    assert!(!ours.memory_peer().unwrap().is_terminated());
+    assert!(orphan.memory_peer().unwrap().is_terminated());
+    //Adapters nobody claimed may belong to someone else
+    assert!(!unclaimed.memory_peer().unwrap().is_terminated());
+    assert_eq!(registry.records().unwrap(), vec![claimed.clone()]);
+
+    registry.release(claimed.guid).unwrap();
+    assert!(registry.records().unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn malformed_records_are_ignored() {
+    assert!(OwnerRecord::parse("pid=12\nguid=zz\nname=a\ntunnel_type=b\n").is_none());
+    assert!(OwnerRecord::parse("pid=12\n").is_none());
+    let record = OwnerRecord {
+      pid: 12,
+      name: "name with = sign".into(),
+      tunnel_type: "type".into(),
+      guid: 0x1234,
+    };
+    assert_eq!(OwnerRecord::parse(&record.serialize()), Some(record));
+    let record = OwnerRecord {
+      pid: 12,
+      name: "name\npid=1\\n".into(),
+      tunnel_type: "type\r\nguid=0".into(),
+      guid: 0x1234,
+    };
+    let serialized = record.serialize();
+    assert_eq!(serialized.lines().count(), 4);
+    assert_eq!(OwnerRecord::parse(&serialized), Some(record));
+    assert!(OwnerRecord::parse("pid=12\nguid=1\nname=a\\x\ntunnel_type=b\n").is_none());
+  }
+}
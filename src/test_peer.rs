@@ -0,0 +1,413 @@
+//! Scripted traffic for tests of code built on [`Session`](crate::Session). A [`TestPeer`] drives
+//! the far end of an in-memory adapter: it injects packets and asserts on the packets the code
+//! under test sends
+//!
+//! ```
+//! use std::{net::{Ipv4Addr, SocketAddr}, time::Duration};
+//! use wintun2::{udp_packet, Adapter, IpProtocol, PacketMatcher, RingCapacity, TestPeer};
+//!
+//! let adapter = Adapter::create_in_memory("doc-peer", "example", None).unwrap();
+//! let peer = TestPeer::attach(&adapter).unwrap();
+//! let session = adapter.session(RingCapacity::min()).unwrap();
+//!
+//! let local = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), 4000));
+//! let remote = SocketAddr::from((Ipv4Addr::new(10, 0, 0, 2), 53));
+//! let query = udp_packet(local, remote, b"query").unwrap();
+//! let mut packet = session.allocate((query.len() as u32).try_into().unwrap()).unwrap();
+//! packet.mut_slice().copy_from_slice(&query);
+//! packet.send();
+//!
+//! let matcher = PacketMatcher::new().protocol(IpProtocol::Udp).destination(remote);
+//! peer.expect_packet(&matcher, Duration::from_secs(1)).unwrap();
+//! peer.expect_no_packet(Duration::from_millis(10)).unwrap();
+//! ```
+
+use std::{
+  collections::VecDeque,
+  fmt,
+  net::{IpAddr, SocketAddr},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use crate::{Adapter, IpPacket, IpProtocol, MemoryPeer, WintunResult};
+
+type Predicate = Box<dyn Fn(&IpPacket) -> bool + Send + Sync>;
+
+/// Describes the packets an expectation accepts. Every condition that is set has to hold, a new
+/// matcher accepts any IP packet
+#[derive(Default)]
+pub struct PacketMatcher {
+  protocol: Option<IpProtocol>,
+  source: Option<IpAddr>,
+  destination: Option<IpAddr>,
+  source_port: Option<u16>,
+  destination_port: Option<u16>,
+  payload: Option<Vec<u8>>,
+  predicates: Vec<(String, Predicate)>,
+}
+
+impl PacketMatcher {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  pub fn protocol(mut self, protocol: IpProtocol) -> Self {
+    self.protocol = Some(protocol);
+    self
+  }
+  pub fn source_ip(mut self, ip: IpAddr) -> Self {
+    self.source = Some(ip);
+    self
+  }
+  pub fn destination_ip(mut self, ip: IpAddr) -> Self {
+    self.destination = Some(ip);
+    self
+  }
+  pub fn source_port(mut self, port: u16) -> Self {
+    self.source_port = Some(port);
+    self
+  }
+  pub fn destination_port(mut self, port: u16) -> Self {
+    self.destination_port = Some(port);
+    self
+  }
+  /// Source address and port
+  pub fn source(self, address: SocketAddr) -> Self {
+    self.source_ip(address.ip()).source_port(address.port())
+  }
+  /// Destination address and port
+  pub fn destination(self, address: SocketAddr) -> Self {
+    self
+      .destination_ip(address.ip())
+      .destination_port(address.port())
+  }
+  /// The payload after the IP header, and after the transport header for TCP and UDP
+  pub fn payload(mut self, payload: impl Into<Vec<u8>>) -> Self {
+    self.payload = Some(payload.into());
+    self
+  }
+  /// Any other condition, `description` shows up in failed expectations
+  pub fn filter(
+    mut self,
+    description: impl Into<String>,
+    predicate: impl Fn(&IpPacket) -> bool + Send + Sync + 'static,
+  ) -> Self {
+    self
+      .predicates
+      .push((description.into(), Box::new(predicate)));
+    self
+  }
+  pub fn matches(&self, packet: &[u8]) -> bool {
+    let Ok(packet) = IpPacket::parse(packet) else {
+      return false;
+    };
+    self
+      .protocol
+      .is_none_or(|protocol| packet.protocol() == protocol)
+      && self.source.is_none_or(|ip| packet.source() == ip)
+      && self.destination.is_none_or(|ip| packet.destination() == ip)
+      && self
+        .source_port
+        .is_none_or(|port| packet.source_port() == Some(port))
+      && self
+        .destination_port
+        .is_none_or(|port| packet.destination_port() == Some(port))
+      && self
+        .payload
+        .as_ref()
+        .is_none_or(|payload| transport_payload(&packet) == Some(payload.as_slice()))
+      && self
+        .predicates
+        .iter()
+        .all(|(_, predicate)| predicate(&packet))
+  }
+}
+
+fn transport_payload<'a>(packet: &IpPacket<'a>) -> Option<&'a [u8]> {
+  let payload = packet.payload();
+  match packet.protocol() {
+    IpProtocol::Udp => payload.get(8..),
+    IpProtocol::Tcp => payload.get((*payload.get(12)? >> 4) as usize * 4..),
+    _ => Some(payload),
+  }
+}
+
+impl fmt::Debug for PacketMatcher {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.to_string())
+  }
+}
+
+/// Lists the conditions, like `UDP to 10.0.0.2:53`
+impl fmt::Display for PacketMatcher {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let endpoint = |ip: Option<IpAddr>, port: Option<u16>| match (ip, port) {
+      (Some(ip), Some(port)) => Some(SocketAddr::new(ip, port).to_string()),
+      (Some(ip), None) => Some(ip.to_string()),
+      (None, Some(port)) => Some(format!("port {port}")),
+      (None, None) => None,
+    };
+    let mut parts = vec![self
+      .protocol
+      .map_or("any packet".to_owned(), |protocol| protocol.to_string())];
+    if let Some(source) = endpoint(self.source, self.source_port) {
+      parts.push(format!("from {source}"));
+    }
+    if let Some(destination) = endpoint(self.destination, self.destination_port) {
+      parts.push(format!("to {destination}"));
+    }
+    if let Some(payload) = &self.payload {
+      parts.push(format!("with a {} byte payload", payload.len()));
+    }
+    for (description, _) in &self.predicates {
+      parts.push(description.clone());
+    }
+    f.write_str(&parts.join(" "))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpectError {
+  /// No matching packet arrived in time. `unmatched` are the other packets that were sent
+  Timeout {
+    expected: String,
+    unmatched: Vec<Vec<u8>>,
+  },
+  /// A packet arrived while none was expected
+  Unexpected(Vec<u8>),
+  AdapterIsTerminating,
+}
+
+impl fmt::Display for ExpectError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ExpectError::Timeout {
+        expected,
+        unmatched,
+      } => {
+        f.write_fmt(format_args!("Timed out waiting for {expected}"))?;
+        if !unmatched.is_empty() {
+          f.write_str(", got")?;
+          for packet in unmatched {
+            f.write_fmt(format_args!("\n  {}", describe(packet)))?;
+          }
+        }
+        Ok(())
+      }
+      ExpectError::Unexpected(packet) => {
+        f.write_fmt(format_args!("Unexpected packet {}", describe(packet)))
+      }
+      ExpectError::AdapterIsTerminating => f.write_str("The adapter is terminating"),
+    }
+  }
+}
+
+impl std::error::Error for ExpectError {}
+
+fn describe(packet: &[u8]) -> String {
+  match IpPacket::parse(packet) {
+    Ok(packet) => packet.to_string(),
+    Err(err) => format!("({err}, {} bytes)", packet.len()),
+  }
+}
+
+/// The far end of an in-memory adapter with assertions on the traffic. Packets that arrive while
+/// an expectation waits for a different packet are kept and checked by later expectations
+pub struct TestPeer {
+  peer: MemoryPeer,
+  unmatched: Mutex<VecDeque<Vec<u8>>>,
+}
+
+impl TestPeer {
+  /// Returns `None` for native adapters
+  pub fn attach(adapter: &Adapter) -> Option<Self> {
+    adapter.memory_peer().map(Self::new)
+  }
+  pub fn new(peer: MemoryPeer) -> Self {
+    Self {
+      peer,
+      unmatched: Mutex::new(VecDeque::new()),
+    }
+  }
+  pub fn peer(&self) -> &MemoryPeer {
+    &self.peer
+  }
+  /// Hands a packet to the adapter's session, see [`MemoryPeer::inject`]
+  pub fn inject(&self, packet: &[u8]) -> WintunResult<()> {
+    self.peer.inject(packet)
+  }
+  /// Waits up to `timeout` for a packet accepted by `matcher` and returns it
+  pub fn expect_packet(
+    &self,
+    matcher: &PacketMatcher,
+    timeout: Duration,
+  ) -> Result<Vec<u8>, ExpectError> {
+    let mut unmatched = self.lock();
+    if let Some(position) = unmatched.iter().position(|packet| matcher.matches(packet)) {
+      return Ok(
+        unmatched
+          .remove(position)
+          .expect("the position was just found"),
+      );
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      let Some(packet) = self.peer.recv_timeout(remaining) else {
+        if self.peer.is_terminated() {
+          return Err(ExpectError::AdapterIsTerminating);
+        }
+        return Err(ExpectError::Timeout {
+          expected: matcher.to_string(),
+          unmatched: unmatched.iter().cloned().collect(),
+        });
+      };
+      if matcher.matches(&packet) {
+        return Ok(packet);
+      }
+      unmatched.push_back(packet);
+    }
+  }
+  /// Fails if a packet arrives within `duration` or an earlier one was left unmatched
+  pub fn expect_no_packet(&self, duration: Duration) -> Result<(), ExpectError> {
+    let mut unmatched = self.lock();
+    if let Some(packet) = unmatched.pop_front() {
+      return Err(ExpectError::Unexpected(packet));
+    }
+    match self.peer.recv_timeout(duration) {
+      Some(packet) => Err(ExpectError::Unexpected(packet)),
+      None => Ok(()),
+    }
+  }
+  /// Takes the packets that arrived but were not matched by any expectation yet
+  pub fn take_unmatched(&self) -> Vec<Vec<u8>> {
+    let mut unmatched = self.lock();
+    unmatched.extend(std::iter::from_fn(|| self.peer.try_recv()));
+    unmatched.drain(..).collect()
+  }
+  fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Vec<u8>>> {
+    self
+      .unmatched
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+  };
+
+  use super::{ExpectError, PacketMatcher, TestPeer};
+  use crate::{udp_packet, Adapter, IpProtocol, RingCapacity, Session};
+
+  fn send(session: &Session, packet: &[u8]) {
+    let mut allocated = session
+      .allocate((packet.len() as u32).try_into().unwrap())
+      .unwrap();
+    allocated.mut_slice().copy_from_slice(packet);
+    allocated.send();
+  }
+
+  fn address(last: u8, port: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::new(10, 0, 0, last), port))
+  }
+
+  #[test]
+  fn matchers() {
+    let packet = udp_packet(address(1, 4000), address(2, 53), b"query").unwrap();
+    let matches = |matcher: PacketMatcher| matcher.matches(&packet);
+    assert!(matches(PacketMatcher::new()));
+    assert!(matches(
+      PacketMatcher::new()
+        .protocol(IpProtocol::Udp)
+        .source(address(1, 4000))
+        .destination(address(2, 53))
+        .payload(*b"query")
+    ));
+    assert!(!matches(PacketMatcher::new().protocol(IpProtocol::Tcp)));
+    assert!(!matches(PacketMatcher::new().destination_port(54)));
+    assert!(!matches(
+      PacketMatcher::new().source_ip(Ipv6Addr::LOCALHOST.into())
+    ));
+    assert!(!matches(PacketMatcher::new().payload(*b"other")));
+    assert!(!matches(
+      PacketMatcher::new().filter("with TTL 1", |packet| packet.ttl() == 1)
+    ));
+    assert!(!PacketMatcher::new().matches(&[0x45, 0]));
+    assert_eq!(
+      PacketMatcher::new()
+        .protocol(IpProtocol::Udp)
+        .destination(address(2, 53))
+        .filter("with TTL 1", |packet| packet.ttl() == 1)
+        .to_string(),
+      "UDP to 10.0.0.2:53 with TTL 1"
+    );
+  }
+
+  #[test]
+  fn expectations() {
+    let adapter = Adapter::create_in_memory("test-peer", "tunnel_type", None).unwrap();
+    let peer = TestPeer::attach(&adapter).unwrap();
+    let session = adapter.session(RingCapacity::min()).unwrap();
+
+    let ping = udp_packet(address(1, 4000), address(2, 7), b"ping").unwrap();
+    peer.inject(&ping).unwrap();
+    assert_eq!(session.recv().unwrap().slice(), ping.as_slice());
+
+    let dns = udp_packet(address(1, 4000), address(2, 53), b"query").unwrap();
+    let echo = udp_packet(address(1, 4000), address(2, 7), b"echo").unwrap();
+    send(&session, &dns);
+    send(&session, &echo);
+    //The DNS packet is skipped over and kept for later
+    let to_echo = PacketMatcher::new().destination_port(7);
+    assert_eq!(
+      peer.expect_packet(&to_echo, Duration::from_secs(1)),
+      Ok(echo)
+    );
+    match peer.expect_packet(&to_echo, Duration::from_millis(10)) {
+      Err(ExpectError::Timeout {
+        expected,
+        unmatched,
+      }) => {
+        assert_eq!(expected, "any packet to port 7");
+        assert_eq!(unmatched, std::slice::from_ref(&dns));
+      }
+      other => panic!("{other:?}"),
+    }
+    assert_eq!(
+      peer.expect_no_packet(Duration::ZERO),
+      Err(ExpectError::Unexpected(dns.clone()))
+    );
+    peer.expect_no_packet(Duration::from_millis(10)).unwrap();
+
+    send(&session, &dns);
+    assert_eq!(peer.take_unmatched(), [dns]);
+    peer.peer().terminate();
+    assert_eq!(
+      peer.expect_packet(&to_echo, Duration::from_secs(1)),
+      Err(ExpectError::AdapterIsTerminating)
+    );
+  }
+
+  #[test]
+  fn expect_waits_for_packets() {
+    let adapter = Adapter::create_in_memory("test-peer-wait", "tunnel_type", None).unwrap();
+    let peer = TestPeer::attach(&adapter).unwrap();
+    let session = adapter.session(RingCapacity::min()).unwrap();
+    let packet = udp_packet(address(1, 1), address(2, 2), &[]).unwrap();
+    std::thread::scope(|scope| {
+      scope.spawn(|| {
+        std::thread::sleep(Duration::from_millis(20));
+        send(&session, &packet);
+      });
+      let matcher = PacketMatcher::new().protocol(IpProtocol::Udp);
+      assert_eq!(
+        peer.expect_packet(&matcher, Duration::from_secs(5)),
+        Ok(packet.clone())
+      );
+    });
+  }
+}
@@ -0,0 +1,1024 @@
+//! Transparent proxying of tunnel traffic. [`Tun2Socks`] terminates the TCP connections the
+//! system opens through the tunnel in a userspace TCP stack and relays each of them, and each UDP
+//! flow, through an [`UpstreamDialer`] such as a [`Socks5Proxy`](crate::Socks5Proxy). What comes
+//! back is sent to the system as packets on the session, from the addresses the system talked to
+
+use std::{
+  collections::{hash_map::RandomState, HashMap, VecDeque},
+  hash::{BuildHasher, Hasher},
+  io::{self, Read, Write},
+  net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc,
+  },
+  thread,
+  time::Duration,
+};
+
+use smoltcp::{
+  iface::{Config, Interface, SocketHandle, SocketSet},
+  phy::{self, DeviceCapabilities, Medium},
+  socket::tcp,
+  time::Instant,
+  wire::{HardwareAddress, IpCidr},
+};
+
+use crate::{
+  udp_packet, Clock, Destination, DialError, FakeIpPool, FlowKey, FragmentError, Fragmenter,
+  IpPacket, IpPacketSize, IpProtocol, ReceivePacketError, Session, SystemClock, UpstreamDialer,
+  UpstreamUdp, WintunError, WintunResult, TCP_ACK, TCP_SYN,
+};
+
+/// Bytes buffered per TCP connection in each direction
+const TCP_BUFFER: usize = 64 * 1024;
+const MAX_TCP_FLOWS: usize = 1024;
+const MAX_UDP_FLOWS: usize = 1024;
+/// Datagrams of a UDP flow held while its upstream flow is being opened
+const MAX_QUEUED_DATAGRAMS: usize = 16;
+const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(60);
+/// MTU assumed for the interface of the session, the usual one of Ethernet
+const DEFAULT_MTU: u32 = 1500;
+/// Connections whose client stops answering keep-alives are aborted after this long
+const TCP_TIMEOUT: Duration = Duration::from_secs(120);
+const TCP_KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// Packets received per poll, so relaying keeps up while the system sends
+const PACKETS_PER_POLL: usize = 256;
+/// Longest wait for packets in [`Tun2Socks::run`], upstream sockets are polled at least this often
+const IDLE_WAIT: Duration = Duration::from_millis(5);
+/// Addresses of the stack itself, from ranges that never appear on the wire (RFC 7600, RFC 6666)
+const STACK_IPV4: Ipv4Addr = Ipv4Addr::new(192, 0, 0, 8);
+const STACK_IPV6: Ipv6Addr = Ipv6Addr::new(0x100, 0, 0, 0, 0, 0, 0, 1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tun2SocksStats {
+  pub tcp_flows: u64,
+  pub udp_flows: u64,
+  /// Flows whose upstream connection or UDP flow couldn't be opened
+  pub failed: u64,
+  /// Packets that weren't relayed, because they were malformed, fragmented, not TCP or UDP, went
+  /// to fake addresses without a name, found the tables full or the send ring full
+  pub dropped: u64,
+}
+
+/// The link between the TCP stack and the session. Packets are copied in and out so they can be
+/// inspected before the stack sees them
+#[derive(Default)]
+struct Tunnel {
+  rx: VecDeque<Vec<u8>>,
+  tx: Vec<Vec<u8>>,
+}
+
+struct TunnelRxToken(Vec<u8>);
+
+impl phy::RxToken for TunnelRxToken {
+  fn consume<R, F>(self, f: F) -> R
+  where
+    F: FnOnce(&[u8]) -> R,
+  {
+    f(&self.0)
+  }
+}
+
+struct TunnelTxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl phy::TxToken for TunnelTxToken<'_> {
+  fn consume<R, F>(self, len: usize, f: F) -> R
+  where
+    F: FnOnce(&mut [u8]) -> R,
+  {
+    let mut packet = vec![0; len];
+    let result = f(&mut packet);
+    self.0.push(packet);
+    result
+  }
+}
+
+impl phy::Device for Tunnel {
+  type RxToken<'a> = TunnelRxToken;
+  type TxToken<'a> = TunnelTxToken<'a>;
+
+  fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+    let packet = self.rx.pop_front()?;
+    Some((TunnelRxToken(packet), TunnelTxToken(&mut self.tx)))
+  }
+
+  fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+    Some(TunnelTxToken(&mut self.tx))
+  }
+
+  fn capabilities(&self) -> DeviceCapabilities {
+    let mut capabilities = DeviceCapabilities::default();
+    capabilities.medium = Medium::Ip;
+    capabilities.max_transmission_unit = IpPacketSize::max().size() as usize;
+    capabilities
+  }
+}
+
+/// A TCP connection of the system, relayed to an upstream connection
+struct TcpFlow {
+  id: u64,
+  handle: SocketHandle,
+  /// `None` until the dialer connected
+  upstream: Option<TcpStream>,
+  /// Bytes from the system not yet written upstream
+  pending: Vec<u8>,
+  /// Whether upstream closed its side and the system was sent a FIN
+  upstream_closed: bool,
+  /// Whether the system closed its side and upstream was shut down for writing
+  shut_down: bool,
+}
+
+impl TcpFlow {
+  /// Moves what is available in both directions without blocking
+  fn relay(&mut self, socket: &mut tcp::Socket) -> io::Result<()> {
+    let Some(stream) = &mut self.upstream else {
+      return Ok(());
+    };
+    loop {
+      if self.pending.is_empty() && socket.can_recv() {
+        socket
+          .recv(|data| {
+            self.pending.extend_from_slice(data);
+            (data.len(), ())
+          })
+          .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+      }
+      if self.pending.is_empty() {
+        break;
+      }
+      match stream.write(&self.pending) {
+        Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+        Ok(written) => drop(self.pending.drain(..written)),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+        Err(err) => return Err(err),
+      }
+    }
+    let closed_by_system = matches!(
+      socket.state(),
+      tcp::State::CloseWait | tcp::State::LastAck | tcp::State::Closing | tcp::State::TimeWait
+    );
+    if closed_by_system && !self.shut_down && self.pending.is_empty() && !socket.can_recv() {
+      stream.shutdown(Shutdown::Write)?;
+      self.shut_down = true;
+    }
+    while !self.upstream_closed && socket.can_send() {
+      let read = socket
+        .send(|buf| match stream.read(buf) {
+          Ok(read) => (read, Ok(read)),
+          Err(err) => (0, Err(err)),
+        })
+        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+      match read {
+        Ok(0) => {
+          self.upstream_closed = true;
+          socket.close();
+        }
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+        Err(err) => return Err(err),
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A UDP flow of the system, relayed through an upstream flow of its own
+struct UdpFlow {
+  id: u64,
+  /// `None` until the dialer opened the upstream flow
+  upstream: Option<Box<dyn UpstreamUdp>>,
+  /// Datagrams received while the upstream flow was being opened
+  queued: Vec<Vec<u8>>,
+  last_active: Duration,
+}
+
+/// Outcome of dialing a flow, which happens on a thread of its own
+enum Opened {
+  Tcp(FlowKey, u64, Result<TcpStream, DialError>),
+  Udp(FlowKey, u64, Result<Box<dyn UpstreamUdp>, DialError>),
+}
+
+/// Relays the TCP connections and UDP flows the system sends through a session to upstream ones
+/// opened by a dialer. Packets are taken from the session by [`poll`](Self::poll) or
+/// [`run`](Self::run), every connection is accepted by a userspace TCP stack whatever its
+/// destination, and its data is relayed through a connection the dialer opens to the same
+/// destination. UDP flows get an upstream flow each. With a [`FakeIpPool`], flows to fake
+/// addresses go to the names they stand for instead, so the dialer or its proxy resolves them.
+/// A [`RoutingDialer`](crate::RoutingDialer) picks the way out per flow.
+///
+/// Connections are accepted before the dialer is asked, those it can't open are reset
+pub struct Tun2Socks {
+  dialer: Arc<dyn UpstreamDialer>,
+  fake_ip: Option<FakeIpPool>,
+  udp_timeout: Duration,
+  /// Splits UDP answers that don't fit the MTU
+  fragmenter: Fragmenter,
+  clock: Arc<dyn Clock>,
+  device: Tunnel,
+  iface: Interface,
+  sockets: SocketSet<'static>,
+  tcp: HashMap<FlowKey, TcpFlow>,
+  udp: HashMap<FlowKey, UdpFlow>,
+  next_id: u64,
+  opened: mpsc::Receiver<Opened>,
+  opener: mpsc::Sender<Opened>,
+  /// Receives datagrams from upstream flows
+  scratch: Vec<u8>,
+  stats: Tun2SocksStats,
+}
+
+impl Tun2Socks {
+  pub fn new(dialer: impl UpstreamDialer + 'static) -> Self {
+    let mut device = Tunnel::default();
+    let mut config = Config::new(HardwareAddress::Ip);
+    config.random_seed = RandomState::new().build_hasher().finish();
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
+    let mut iface = Interface::new(config, &mut device, timestamp(&*clock));
+    //Accepts packets to any address routed to the stack, which is every address
+    iface.set_any_ip(true);
+    iface.update_ip_addrs(|addrs| {
+      addrs.push(IpCidr::new(STACK_IPV4.into(), 32)).unwrap();
+      addrs.push(IpCidr::new(STACK_IPV6.into(), 128)).unwrap();
+    });
+    iface
+      .routes_mut()
+      .add_default_ipv4_route(STACK_IPV4)
+      .unwrap();
+    iface
+      .routes_mut()
+      .add_default_ipv6_route(STACK_IPV6)
+      .unwrap();
+    let (opener, opened) = mpsc::channel();
+    Self {
+      dialer: Arc::new(dialer),
+      fake_ip: None,
+      udp_timeout: DEFAULT_UDP_TIMEOUT,
+      fragmenter: Fragmenter::new(IpPacketSize::try_from(DEFAULT_MTU).unwrap()),
+      clock,
+      device,
+      iface,
+      sockets: SocketSet::new(Vec::new()),
+      tcp: HashMap::new(),
+      udp: HashMap::new(),
+      next_id: 0,
+      opened,
+      opener,
+      scratch: vec![0; u16::MAX as usize],
+      stats: Tun2SocksStats::default(),
+    }
+  }
+  /// Relays flows to addresses of `pool` to the names they stand for. Flows to its addresses
+  /// without a name are refused
+  pub fn with_fake_ip(mut self, pool: FakeIpPool) -> Self {
+    self.fake_ip = Some(pool);
+    self
+  }
+  /// Closes UDP flows idle for `timeout`. Defaults to 60 seconds
+  pub fn with_udp_timeout(mut self, timeout: Duration) -> Self {
+    self.udp_timeout = timeout;
+    self
+  }
+  /// The MTU of the interface of the session, UDP answers longer than it are sent fragmented.
+  /// Defaults to 1500
+  pub fn with_mtu(mut self, mtu: IpPacketSize) -> Self {
+    self.fragmenter.set_mtu(mtu);
+    self
+  }
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+  pub fn stats(&self) -> Tun2SocksStats {
+    self.stats
+  }
+  /// Open TCP connections and UDP flows
+  pub fn flows(&self) -> (usize, usize) {
+    (self.tcp.len(), self.udp.len())
+  }
+  /// Relays what can be relayed without waiting: takes the packets waiting on `session`,
+  /// moves data between the system and upstream and sends the resulting packets
+  pub fn poll(&mut self, session: &Session<'_>) -> WintunResult<()> {
+    self.collect_opened();
+    for _ in 0..PACKETS_PER_POLL {
+      let packet = match session.recv() {
+        Ok(packet) => packet,
+        Err(ReceivePacketError::WouldBlock) => break,
+        Err(err) => return Err(err.into()),
+      };
+      self.receive(packet.slice());
+    }
+    self.relay_tcp();
+    self.relay_udp();
+    self
+      .iface
+      .poll(timestamp(&*self.clock), &mut self.device, &mut self.sockets);
+    self.flush(session)?;
+    self.reap();
+    Ok(())
+  }
+  /// Relays until `stop` is set or the adapter terminates
+  pub fn run(&mut self, session: &Session<'_>, stop: &AtomicBool) -> WintunResult<()> {
+    while !stop.load(Ordering::Relaxed) {
+      let result = self
+        .poll(session)
+        .and_then(|()| session.wait_readable(Some(IDLE_WAIT)));
+      match result {
+        Err(err) if matches!(err.root(), WintunError::AdapterIsTerminating) => return Ok(()),
+        Err(err) => return Err(err),
+        Ok(_) => {}
+      }
+    }
+    Ok(())
+  }
+  fn next_id(&mut self) -> u64 {
+    self.next_id += 1;
+    self.next_id
+  }
+  /// Where a flow to `address` is relayed to, `None` for fake addresses without a name
+  fn destination(&self, address: SocketAddr) -> Option<Destination> {
+    match &self.fake_ip {
+      Some(pool) if pool.contains(address.ip()) => pool
+        .domain(address.ip())
+        .map(|domain| Destination::Domain(domain, address.port())),
+      _ => Some(Destination::Address(address)),
+    }
+  }
+  fn collect_opened(&mut self) {
+    while let Ok(opened) = self.opened.try_recv() {
+      match opened {
+        Opened::Tcp(key, id, result) => {
+          let Some(flow) = self.tcp.get_mut(&key).filter(|flow| flow.id == id) else {
+            continue;
+          };
+          let result = result.and_then(|stream| Ok(stream.set_nonblocking(true).map(|()| stream)?));
+          match result {
+            Ok(stream) => flow.upstream = Some(stream),
+            Err(_) => {
+              self.sockets.get_mut::<tcp::Socket>(flow.handle).abort();
+              self.stats.failed += 1;
+            }
+          }
+        }
+        Opened::Udp(key, id, result) => {
+          let Some(flow) = self.udp.get_mut(&key).filter(|flow| flow.id == id) else {
+            continue;
+          };
+          let result =
+            result.and_then(|upstream| Ok(upstream.set_nonblocking(true).map(|()| upstream)?));
+          match result {
+            Ok(upstream) => {
+              for datagram in flow.queued.drain(..) {
+                if upstream.send(&datagram).is_err() {
+                  self.stats.dropped += 1;
+                }
+              }
+              flow.upstream = Some(upstream);
+            }
+            Err(_) => {
+              self.stats.failed += 1;
+              self.stats.dropped += flow.queued.len() as u64;
+              self.udp.remove(&key);
+            }
+          }
+        }
+      }
+    }
+  }
+  fn receive(&mut self, packet: &[u8]) {
+    let Ok(parsed) = IpPacket::parse(packet) else {
+      self.stats.dropped += 1;
+      return;
+    };
+    //Fragments are left to a Reassembler in front
+    match parsed.protocol() {
+      IpProtocol::Tcp if !parsed.is_fragment() => self.receive_tcp(&parsed),
+      IpProtocol::Udp if !parsed.is_fragment() => self.receive_udp(&parsed),
+      _ => self.stats.dropped += 1,
+    }
+  }
+  fn receive_tcp(&mut self, packet: &IpPacket) {
+    let key = FlowKey::of(packet);
+    let opening = packet
+      .tcp_flags()
+      .is_some_and(|flags| flags & (TCP_SYN | TCP_ACK) == TCP_SYN)
+      && !self.tcp.contains_key(&key);
+    let destination = match opening && self.tcp.len() < MAX_TCP_FLOWS {
+      true => self.destination(key.destination),
+      false => None,
+    };
+    if destination.is_some() {
+      let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER]),
+      );
+      socket.set_nagle_enabled(false);
+      socket.set_timeout(Some(TCP_TIMEOUT.into()));
+      socket.set_keep_alive(Some(TCP_KEEP_ALIVE.into()));
+      //Listens on exactly the address the system connects to, so its answers come from there
+      if socket.listen(key.destination).is_err() {
+        self.stats.dropped += 1;
+        return;
+      }
+      let flow = TcpFlow {
+        id: self.next_id(),
+        handle: self.sockets.add(socket),
+        upstream: None,
+        pending: Vec::new(),
+        upstream_closed: false,
+        shut_down: false,
+      };
+      self.tcp.insert(key, flow);
+    } else if opening {
+      //Refused by the stack with a reset, as no socket listens
+      self.stats.dropped += 1;
+    }
+    self.device.rx.push_back(packet.as_bytes().to_vec());
+    self
+      .iface
+      .poll(timestamp(&*self.clock), &mut self.device, &mut self.sockets);
+    let Some(destination) = destination else {
+      return;
+    };
+    let flow = &self.tcp[&key];
+    if self.sockets.get::<tcp::Socket>(flow.handle).state() == tcp::State::Listen {
+      //The stack didn't take the SYN
+      self.sockets.remove(flow.handle);
+      self.tcp.remove(&key);
+      return;
+    }
+    self.stats.tcp_flows += 1;
+    let (id, dialer, opener) = (flow.id, self.dialer.clone(), self.opener.clone());
+    thread::spawn(move || {
+      let _ = opener.send(Opened::Tcp(key, id, dialer.dial_tcp(&destination)));
+    });
+  }
+  fn receive_udp(&mut self, packet: &IpPacket) {
+    let key = FlowKey::of(packet);
+    let Some(data) = packet.payload().get(8..) else {
+      self.stats.dropped += 1;
+      return;
+    };
+    let now = self.clock.now();
+    if let Some(flow) = self.udp.get_mut(&key) {
+      flow.last_active = now;
+      let relayed = match &flow.upstream {
+        Some(upstream) => upstream.send(data).is_ok(),
+        None if flow.queued.len() < MAX_QUEUED_DATAGRAMS => {
+          flow.queued.push(data.to_vec());
+          true
+        }
+        None => false,
+      };
+      if !relayed {
+        self.stats.dropped += 1;
+      }
+      return;
+    }
+    let destination = match self.udp.len() < MAX_UDP_FLOWS {
+      true => self.destination(key.destination),
+      false => None,
+    };
+    let Some(destination) = destination else {
+      self.stats.dropped += 1;
+      return;
+    };
+    let id = self.next_id();
+    self.udp.insert(
+      key,
+      UdpFlow {
+        id,
+        upstream: None,
+        queued: vec![data.to_vec()],
+        last_active: now,
+      },
+    );
+    self.stats.udp_flows += 1;
+    let (dialer, opener) = (self.dialer.clone(), self.opener.clone());
+    thread::spawn(move || {
+      let _ = opener.send(Opened::Udp(key, id, dialer.bind_udp(&destination)));
+    });
+  }
+  fn relay_tcp(&mut self) {
+    for flow in self.tcp.values_mut() {
+      let socket = self.sockets.get_mut::<tcp::Socket>(flow.handle);
+      if flow.relay(socket).is_err() {
+        socket.abort();
+        flow.upstream = None;
+      }
+    }
+  }
+  fn relay_udp(&mut self) {
+    let now = self.clock.now();
+    for (key, flow) in &mut self.udp {
+      let Some(upstream) = &flow.upstream else {
+        continue;
+      };
+      //Answers come from the address the system sent to, whatever upstream says
+      while let Ok(len) = upstream.recv(&mut self.scratch) {
+        flow.last_active = now;
+        let fragments = udp_packet(key.destination, key.source, &self.scratch[..len])
+          .map_err(FragmentError::from)
+          .and_then(|packet| self.fragmenter.fragment(&packet));
+        match fragments {
+          Ok(fragments) => self.device.tx.extend(fragments),
+          Err(_) => self.stats.dropped += 1,
+        }
+      }
+    }
+    let timeout = self.udp_timeout;
+    self
+      .udp
+      .retain(|_, flow| now.saturating_sub(flow.last_active) < timeout);
+  }
+  /// Sends the packets of the stack and the UDP answers on the session
+  fn flush(&mut self, session: &Session<'_>) -> WintunResult<()> {
+    for packet in self.device.tx.drain(..) {
+      let size = IpPacketSize::try_from(packet.len() as u32)?;
+      match session.allocate(size) {
+        Ok(mut send) => {
+          send.mut_slice().copy_from_slice(&packet);
+          send.send();
+        }
+        //TCP retransmits and UDP may be lost, like on a congested link
+        Err(err) if err.is_would_block() => self.stats.dropped += 1,
+        Err(err) => return Err(err.into()),
+      }
+    }
+    Ok(())
+  }
+  /// Drops connections that are over
+  fn reap(&mut self) {
+    let sockets = &mut self.sockets;
+    self.tcp.retain(|_, flow| {
+      let state = sockets.get::<tcp::Socket>(flow.handle).state();
+      let over = matches!(state, tcp::State::Closed | tcp::State::TimeWait);
+      if over {
+        sockets.remove(flow.handle);
+      }
+      !over
+    });
+  }
+}
+
+fn timestamp(clock: &dyn Clock) -> Instant {
+  Instant::from_micros(clock.now().as_micros() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+  };
+
+  use smoltcp::{
+    iface::{Config, Interface, SocketSet},
+    phy::{self, DeviceCapabilities, Medium},
+    socket::tcp,
+    time::Instant,
+    wire::{HardwareAddress, IpCidr, Ipv4Address, Ipv6Address},
+  };
+
+  use super::{Tun2Socks, MAX_TCP_FLOWS, MAX_UDP_FLOWS};
+  use crate::{
+    socks5::tests::{echo_server, StandIn},
+    tcp_packet, udp_packet, Adapter, Destination, DialError, Direct, FakeIpPool, IpAndMaskPrefix,
+    IpPacket, IpPacketSize, IpProtocol, ManualClock, MemoryPeer, Reassembler, RingCapacity,
+    Session, Socks5Proxy, UpstreamDialer, UpstreamUdp, TCP_ACK, TCP_RST, TCP_SYN,
+  };
+
+  /// The system's end of the tunnel, a TCP stack talking through the memory peer
+  struct System<'a>(&'a MemoryPeer);
+
+  struct SystemRxToken(Vec<u8>);
+
+  impl phy::RxToken for SystemRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+      F: FnOnce(&[u8]) -> R,
+    {
+      f(&self.0)
+    }
+  }
+
+  struct SystemTxToken<'a>(&'a MemoryPeer);
+
+  impl phy::TxToken for SystemTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+      F: FnOnce(&mut [u8]) -> R,
+    {
+      let mut packet = vec![0; len];
+      let result = f(&mut packet);
+      self.0.inject(&packet).unwrap();
+      result
+    }
+  }
+
+  impl phy::Device for System<'_> {
+    type RxToken<'a>
+      = SystemRxToken
+    where
+      Self: 'a;
+    type TxToken<'a>
+      = SystemTxToken<'a>
+    where
+      Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+      let packet = self.0.try_recv()?;
+      Some((SystemRxToken(packet), SystemTxToken(self.0)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+      Some(SystemTxToken(self.0))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+      let mut capabilities = DeviceCapabilities::default();
+      capabilities.medium = Medium::Ip;
+      capabilities.max_transmission_unit = 1500;
+      capabilities
+    }
+  }
+
+  /// The next packet of `protocol` the system gets
+  fn expect(peer: &MemoryPeer, protocol: IpProtocol) -> Vec<u8> {
+    loop {
+      let packet = peer
+        .recv_timeout(Duration::from_secs(5))
+        .expect("no packet arrived");
+      if IpPacket::parse(&packet).unwrap().protocol() == protocol {
+        return packet;
+      }
+    }
+  }
+
+  /// Polls `tun2socks` until `done` holds, for at most 5 seconds
+  fn poll_until(
+    tun2socks: &mut Tun2Socks,
+    session: &Session<'_>,
+    mut done: impl FnMut(&Tun2Socks) -> bool,
+  ) {
+    for _ in 0..2500 {
+      tun2socks.poll(session).unwrap();
+      if done(tun2socks) {
+        return;
+      }
+      thread::sleep(Duration::from_millis(2));
+    }
+    panic!("gave up waiting");
+  }
+
+  /// A UDP server on `ip` echoing datagrams back
+  fn udp_echo(ip: IpAddr) -> SocketAddr {
+    let socket = UdpSocket::bind((ip, 0)).unwrap();
+    socket
+      .set_read_timeout(Some(Duration::from_secs(10)))
+      .unwrap();
+    let address = socket.local_addr().unwrap();
+    thread::spawn(move || {
+      let mut datagram = [0; u16::MAX as usize];
+      while let Ok((len, client)) = socket.recv_from(&mut datagram) {
+        let _ = socket.send_to(&datagram[..len], client);
+      }
+    });
+    address
+  }
+
+  /// Connects from `local` to `remote` with a TCP stack on the system's end, sends `data` and
+  /// closes. Returns the final state and what came back
+  fn tcp_exchange(
+    peer: &MemoryPeer,
+    local: SocketAddr,
+    remote: SocketAddr,
+    data: &[u8],
+  ) -> (tcp::State, Vec<u8>) {
+    let mut system = System(peer);
+    let mut iface = Interface::new(
+      Config::new(HardwareAddress::Ip),
+      &mut system,
+      Instant::now(),
+    );
+    iface.update_ip_addrs(|addrs| match local.ip() {
+      IpAddr::V4(ip) => addrs.push(IpCidr::new(ip.into(), 24)).unwrap(),
+      IpAddr::V6(ip) => addrs.push(IpCidr::new(ip.into(), 64)).unwrap(),
+    });
+    match local.ip() {
+      IpAddr::V4(_) => iface
+        .routes_mut()
+        .add_default_ipv4_route(Ipv4Address::new(10, 8, 0, 1))
+        .unwrap(),
+      IpAddr::V6(_) => iface
+        .routes_mut()
+        .add_default_ipv6_route(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 1))
+        .unwrap(),
+    };
+    let mut sockets = SocketSet::new(Vec::new());
+    let handle = sockets.add(tcp::Socket::new(
+      tcp::SocketBuffer::new(vec![0; 4096]),
+      tcp::SocketBuffer::new(vec![0; 4096]),
+    ));
+    sockets
+      .get_mut::<tcp::Socket>(handle)
+      .connect(iface.context(), remote, local.port())
+      .unwrap();
+    let mut received = Vec::new();
+    let mut sent = false;
+    for _ in 0..5000 {
+      iface.poll(Instant::now(), &mut system, &mut sockets);
+      let socket = sockets.get_mut::<tcp::Socket>(handle);
+      if socket.may_send() && !sent {
+        socket.send_slice(data).unwrap();
+        socket.close();
+        sent = true;
+      }
+      if socket.can_recv() {
+        socket
+          .recv(|data| {
+            received.extend_from_slice(data);
+            (data.len(), ())
+          })
+          .unwrap();
+      }
+      if matches!(socket.state(), tcp::State::TimeWait | tcp::State::Closed) {
+        break;
+      }
+      thread::sleep(Duration::from_millis(2));
+    }
+    (sockets.get::<tcp::Socket>(handle).state(), received)
+  }
+
+  /// Dials nothing until released, so flows stay open meanwhile
+  #[derive(Clone, Default)]
+  struct Held(Arc<(Mutex<bool>, Condvar)>);
+
+  impl Held {
+    fn release(&self) {
+      *self.0 .0.lock().unwrap() = true;
+      self.0 .1.notify_all();
+    }
+    fn wait(&self) -> DialError {
+      let (released, condvar) = &*self.0;
+      drop(condvar.wait_while(released.lock().unwrap(), |released| !*released));
+      DialError::Blocked
+    }
+  }
+
+  impl UpstreamDialer for Held {
+    fn dial_tcp(&self, _destination: &Destination) -> Result<TcpStream, DialError> {
+      Err(self.wait())
+    }
+    fn bind_udp(&self, _destination: &Destination) -> Result<Box<dyn UpstreamUdp>, DialError> {
+      Err(self.wait())
+    }
+  }
+
+  #[test]
+  fn relays_tcp_and_udp_through_socks5() {
+    let proxy = StandIn::start(Some(("user", "secret")), echo_server());
+    let pool = FakeIpPool::new(IpAndMaskPrefix::V4 {
+      ip: "198.18.0.0".parse().unwrap(),
+      prefix: 15.try_into().unwrap(),
+    });
+    let fake = pool.allocate("echo.test").unwrap();
+    let adapter = Adapter::create_in_memory("tun2socks", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.into_session(RingCapacity::min()).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let relay = {
+      let (stop, pool) = (stop.clone(), pool.clone());
+      let socks5 = Socks5Proxy::new(proxy.address).with_credentials("user", "secret");
+      thread::spawn(move || {
+        let mut tun2socks = Tun2Socks::new(socks5).with_fake_ip(pool);
+        tun2socks.run(&session, &stop).unwrap();
+        tun2socks.stats()
+      })
+    };
+
+    //A connection to the fake address of `echo.test`, closed after one request
+    let local = "10.8.0.2:40000".parse().unwrap();
+    let (state, echoed) = tcp_exchange(&peer, local, (fake, 80).into(), b"hello through socks");
+    assert_eq!(state, tcp::State::TimeWait);
+    assert_eq!(echoed, b"hello through socks");
+    assert_eq!(
+      proxy.requests()[0],
+      (1, Destination::Domain("echo.test".into(), 80))
+    );
+
+    //Fake addresses without a name are refused
+    let client: SocketAddr = "10.8.0.2:40001".parse().unwrap();
+    let unknown = SocketAddr::new(IpAddr::from([198, 18, 0, 200]), 80);
+    peer
+      .inject(&tcp_packet(client, unknown, TCP_SYN, &[], b"").unwrap())
+      .unwrap();
+    let reset = expect(&peer, IpProtocol::Tcp);
+    let reset = IpPacket::parse(&reset).unwrap();
+    assert_eq!(reset.source(), unknown.ip());
+    assert_ne!(reset.tcp_flags().unwrap() & TCP_RST, 0);
+
+    //A datagram is answered from the address it was sent to
+    let client: SocketAddr = "10.8.0.2:5353".parse().unwrap();
+    let remote: SocketAddr = "203.0.113.7:9999".parse().unwrap();
+    peer
+      .inject(&udp_packet(client, remote, b"ping").unwrap())
+      .unwrap();
+    let answer = expect(&peer, IpProtocol::Udp);
+    let answer = IpPacket::parse(&answer).unwrap();
+    assert_eq!(answer.source(), remote.ip());
+    assert_eq!(answer.ports(), Some((9999, 5353)));
+    assert_eq!(&answer.payload()[8..], b"ping");
+    assert!(proxy
+      .requests()
+      .contains(&(3, Destination::Address(remote))));
+
+    stop.store(true, Ordering::Relaxed);
+    let stats = relay.join().unwrap();
+    assert_eq!((stats.tcp_flows, stats.udp_flows, stats.failed), (1, 1, 0));
+  }
+
+  #[test]
+  fn long_udp_answers_are_fragmented() {
+    let adapter = Adapter::create_in_memory("tun2socks_mtu", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.into_session(RingCapacity::min()).unwrap();
+    let mtu = IpPacketSize::try_from(1280).unwrap();
+    let mut tun2socks = Tun2Socks::new(Direct::new()).with_mtu(mtu);
+    let remote = udp_echo([127, 0, 0, 1].into());
+    let client: SocketAddr = "10.8.0.2:5353".parse().unwrap();
+    let data: Vec<u8> = (0..3000).map(|byte| byte as u8).collect();
+    peer
+      .inject(&udp_packet(client, remote, &data).unwrap())
+      .unwrap();
+    let mut reassembler = Reassembler::new();
+    let mut answer = None;
+    poll_until(&mut tun2socks, &session, |_| {
+      while let Some(packet) = peer.try_recv() {
+        assert!(packet.len() <= mtu.size() as usize);
+        answer = answer.take().or(reassembler.push(&packet).unwrap());
+      }
+      answer.is_some()
+    });
+    let answer = answer.unwrap();
+    let answer = IpPacket::parse(&answer).unwrap();
+    assert_eq!(answer.source(), remote.ip());
+    assert_eq!(answer.ports(), Some((remote.port(), 5353)));
+    assert_eq!(&answer.payload()[8..], data);
+    assert_eq!(tun2socks.stats().dropped, 0);
+  }
+
+  #[test]
+  fn relays_ipv6_flows() {
+    let proxy = StandIn::start(None, echo_server());
+    let adapter = Adapter::create_in_memory("tun2socks_ipv6", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.into_session(RingCapacity::min()).unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let relay = {
+      let stop = stop.clone();
+      let socks5 = Socks5Proxy::new(proxy.address);
+      thread::spawn(move || {
+        let mut tun2socks = Tun2Socks::new(socks5);
+        tun2socks.run(&session, &stop).unwrap();
+        tun2socks.stats()
+      })
+    };
+
+    let remote: SocketAddr = "[2001:db8::7]:80".parse().unwrap();
+    let local = "[fd00::2]:40000".parse().unwrap();
+    let (state, echoed) = tcp_exchange(&peer, local, remote, b"hello over ipv6");
+    assert_eq!(state, tcp::State::TimeWait);
+    assert_eq!(echoed, b"hello over ipv6");
+    assert_eq!(proxy.requests()[0], (1, Destination::Address(remote)));
+
+    let client: SocketAddr = "[fd00::2]:5353".parse().unwrap();
+    let remote: SocketAddr = "[2001:db8::7]:9999".parse().unwrap();
+    peer
+      .inject(&udp_packet(client, remote, b"ping").unwrap())
+      .unwrap();
+    let answer = expect(&peer, IpProtocol::Udp);
+    let answer = IpPacket::parse(&answer).unwrap();
+    assert_eq!(
+      (answer.source(), answer.destination()),
+      (remote.ip(), client.ip())
+    );
+    assert_eq!(&answer.payload()[8..], b"ping");
+
+    stop.store(true, Ordering::Relaxed);
+    let stats = relay.join().unwrap();
+    assert_eq!((stats.tcp_flows, stats.udp_flows, stats.failed), (1, 1, 0));
+  }
+
+  #[test]
+  fn failed_dials_reset_the_connection() {
+    //Nothing listens on port 0
+    let closed = "127.0.0.1:0".parse().unwrap();
+    let refusing = StandIn::start(None, closed);
+    let authenticating = StandIn::start(Some(("user", "secret")), echo_server());
+    let dialers = [
+      Socks5Proxy::new(refusing.address),
+      Socks5Proxy::new(authenticating.address).with_credentials("user", "wrong"),
+    ];
+    for (at, socks5) in dialers.into_iter().enumerate() {
+      let adapter = Adapter::create_in_memory("tun2socks_failed", "tunnel_type", None).unwrap();
+      let peer = adapter.memory_peer().unwrap();
+      let session = adapter.into_session(RingCapacity::min()).unwrap();
+      let mut tun2socks = Tun2Socks::new(socks5);
+      let client = SocketAddr::from(([10, 8, 0, 2], 40000 + at as u16));
+      let remote: SocketAddr = "203.0.113.7:80".parse().unwrap();
+      peer
+        .inject(&tcp_packet(client, remote, TCP_SYN, &[], b"").unwrap())
+        .unwrap();
+      poll_until(&mut tun2socks, &session, |tun2socks| {
+        tun2socks.stats().failed == 1
+      });
+      //Accepted first, then reset
+      let accepted = expect(&peer, IpProtocol::Tcp);
+      let accepted = IpPacket::parse(&accepted).unwrap();
+      assert_eq!(
+        accepted.tcp_flags().unwrap() & (TCP_SYN | TCP_ACK),
+        TCP_SYN | TCP_ACK
+      );
+      poll_until(&mut tun2socks, &session, |tun2socks| {
+        tun2socks.flows() == (0, 0)
+      });
+      let reset = expect(&peer, IpProtocol::Tcp);
+      let reset = IpPacket::parse(&reset).unwrap();
+      assert_eq!(reset.source(), remote.ip());
+      assert_ne!(reset.tcp_flags().unwrap() & TCP_RST, 0);
+      assert_eq!(tun2socks.stats().tcp_flows, 1);
+    }
+  }
+
+  #[test]
+  fn idle_udp_flows_expire() {
+    let adapter = Adapter::create_in_memory("tun2socks_udp", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.into_session(RingCapacity::min()).unwrap();
+    let clock = ManualClock::new();
+    let mut tun2socks = Tun2Socks::new(Direct::new())
+      .with_clock(Arc::new(clock.clone()))
+      .with_udp_timeout(Duration::from_secs(10));
+    let remote = udp_echo([127, 0, 0, 1].into());
+    let client: SocketAddr = "10.8.0.2:5353".parse().unwrap();
+    let exchange = |tun2socks: &mut Tun2Socks| {
+      peer
+        .inject(&udp_packet(client, remote, b"ping").unwrap())
+        .unwrap();
+      poll_until(tun2socks, &session, |_| peer.try_recv().is_some());
+    };
+
+    exchange(&mut tun2socks);
+    clock.advance(Duration::from_secs(9));
+    exchange(&mut tun2socks);
+    //The second datagram kept the flow alive
+    clock.advance(Duration::from_secs(9));
+    tun2socks.poll(&session).unwrap();
+    assert_eq!(tun2socks.flows(), (0, 1));
+    clock.advance(Duration::from_secs(1));
+    tun2socks.poll(&session).unwrap();
+    assert_eq!(tun2socks.flows(), (0, 0));
+    //Later datagrams open a new flow
+    exchange(&mut tun2socks);
+    assert_eq!(tun2socks.stats().udp_flows, 2);
+  }
+
+  #[test]
+  fn flow_tables_are_capped() {
+    let adapter = Adapter::create_in_memory("tun2socks_caps", "tunnel_type", None).unwrap();
+    let peer = adapter.memory_peer().unwrap();
+    let session = adapter.into_session(RingCapacity::min()).unwrap();
+    let held = Held::default();
+    let mut tun2socks = Tun2Socks::new(held.clone());
+    let remote: SocketAddr = "203.0.113.7:80".parse().unwrap();
+    let client = |port: usize| SocketAddr::from(([10, 8, 0, 2], 1000 + port as u16));
+    let flows = MAX_TCP_FLOWS.max(MAX_UDP_FLOWS) + 1;
+    for batch in (0..flows).collect::<Vec<_>>().chunks(128) {
+      for &port in batch {
+        if port <= MAX_TCP_FLOWS {
+          let syn = tcp_packet(client(port), remote, TCP_SYN, &[], b"").unwrap();
+          peer.inject(&syn).unwrap();
+        }
+        if port <= MAX_UDP_FLOWS {
+          peer
+            .inject(&udp_packet(client(port), remote, b"ping").unwrap())
+            .unwrap();
+        }
+      }
+      tun2socks.poll(&session).unwrap();
+      while peer.try_recv().is_some() {}
+    }
+    assert_eq!(tun2socks.flows(), (MAX_TCP_FLOWS, MAX_UDP_FLOWS));
+    let stats = tun2socks.stats();
+    assert_eq!(stats.tcp_flows, MAX_TCP_FLOWS as u64);
+    assert_eq!(stats.udp_flows, MAX_UDP_FLOWS as u64);
+    assert_eq!(stats.dropped, 2);
+    held.release();
+  }
+}
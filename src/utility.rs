@@ -44,41 +44,38 @@ pub(crate) fn interface_luid_to_guid(luid: u64) -> Result<GUID, Win32Error> {
   Ok(guid)
 }
 
-pub(crate) fn guid_be_to_ne(mut guid: GUID) -> GUID {
-  guid.Data1 = guid.Data1.to_be();
-  guid.Data2 = guid.Data2.to_be();
-  guid.Data3 = guid.Data3.to_be();
-  #[cfg(target_endian = "big")]
-  guid.Data4.reverse();
-  guid
-}
-
+/// Converts from the crate's guid representation, see [`crate::parse_guid`]
 pub(crate) fn guid_from_u128(guid: u128) -> GUID {
-  unsafe { std::mem::transmute(guid) }
-}
-pub(crate) fn guid_to_u128(guid: GUID) -> u128 {
-  unsafe { std::mem::transmute(guid) }
-}
-pub(crate) struct GuidParseError<'data> {
-  pub invalid_data: &'data [u8],
-  pub error: chomp::prelude::Error<u8>
-}
-impl<'data> std::fmt::Debug for GuidParseError<'data> {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-      f.write_fmt(format_args!("GUID string contained invalid data: '{:?}'. Actual error: {}", self.invalid_data, self.error))
+  GUID {
+    Data1: (guid >> 96) as u32,
+    Data2: (guid >> 80) as u16,
+    Data3: (guid >> 64) as u16,
+    Data4: (guid as u64).to_be_bytes(),
   }
 }
-impl<'data> std::fmt::Display for GuidParseError<'data> {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-      f.write_fmt(format_args!("{:?}", self))
-  }
-}
-impl<'data> std::error::Error for GuidParseError<'data> {}
-fn parse_chunks(src: &str) -> Result<guid_parser::Chunks, GuidParseError> {
-  chomp::parse_only(guid_parser::chunks, src.as_bytes()).map_err(|(invalid_data, error)| GuidParseError{invalid_data, error})
+pub(crate) fn guid_to_u128(guid: GUID) -> u128 {
+  (guid.Data1 as u128) << 96
+    | (guid.Data2 as u128) << 80
+    | (guid.Data3 as u128) << 64
+    | u64::from_be_bytes(guid.Data4) as u128
 }
 
-/// Parse a source string as a GUID, and return the GUID as a sequence of bytes.
-pub(crate) fn parse_guid(src: &str) -> Result<[u8; 16], GuidParseError> {
-  parse_chunks(src).map(|chunks| chunks.to_bytes())
+#[cfg(test)]
+mod tests {
+  use super::{guid_from_u128, guid_to_u128};
+
+  const GUID: u128 = 0x0A1B2C3D_4E5F_6071_8293_A4B5C6D7E8F9;
+
+  #[test]
+  fn guid_layout_is_pinned() {
+    let guid = guid_from_u128(GUID);
+    assert_eq!(
+      (guid.Data1, guid.Data2, guid.Data3, guid.Data4),
+      (0x0A1B2C3D, 0x4E5F, 0x6071, [0x82, 0x93, 0xA4, 0xB5, 0xC6, 0xD7, 0xE8, 0xF9])
+    );
+    assert_eq!(guid_to_u128(guid), GUID);
+    //What earlier versions passed for the same adapter
+    let native: u128 = unsafe { std::mem::transmute(guid) };
+    assert_eq!(native, crate::guid_to_native_layout(GUID));
+  }
 }
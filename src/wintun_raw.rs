@@ -1,7 +1,9 @@
 #![allow(non_snake_case, non_camel_case_types)]
 
+#[cfg(windows)]
 use std::os::windows::raw::HANDLE;
 
+#[cfg(windows)]
 use winapi::shared::{basetsd::DWORD64, guiddef::GUID, ifdef::NET_LUID, ntdef::LPCWSTR, minwindef::BYTE};
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -23,10 +25,13 @@ pub const WINTUN_LOGGER_LEVEL_WINTUN_LOG_INFO: WINTUN_LOGGER_LEVEL = 0;
 pub const WINTUN_LOGGER_LEVEL_WINTUN_LOG_WARN: WINTUN_LOGGER_LEVEL = 1;
 #[doc = "< Error"]
 pub const WINTUN_LOGGER_LEVEL_WINTUN_LOG_ERR: WINTUN_LOGGER_LEVEL = 2;
+#[cfg(windows)]
 pub type WINTUN_LOGGER_CALLBACK =
   Option<extern "C" fn(level: WINTUN_LOGGER_LEVEL, timestamp: DWORD64, message: LPCWSTR)>;
+#[cfg(windows)]
 pub type DWORD = std::ffi::c_ulong;
 
+#[cfg(windows)]
 #[link(name = "wintun", kind = "static")]
 extern "C" {
   pub fn WintunCreateAdapter(
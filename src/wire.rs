@@ -0,0 +1,611 @@
+//! Just enough of IPv4, IPv6, TCP and UDP to look into the packets crossing a session and to
+//! build packets for tests
+
+use std::{
+  fmt,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::MAX_IP_PACKET_SIZE;
+
+/// Lengths of the headers without options or extension headers
+pub(crate) const IPV4_HEADER_LEN: usize = 20;
+pub(crate) const IPV6_HEADER_LEN: usize = 40;
+pub(crate) const UDP_HEADER_LEN: usize = 8;
+pub(crate) const TCP_HEADER_LEN: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+
+/// Bits of [`IpPacket::tcp_flags`]
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+pub(crate) const ICMP_ECHO_REPLY: u8 = 0;
+pub(crate) const ICMP_ECHO_REQUEST: u8 = 8;
+pub(crate) const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub(crate) const ICMPV6_ECHO_REPLY: u8 = 129;
+/// Destination unreachable, source quench, redirect, time exceeded and parameter problem
+pub(crate) const ICMP_ERRORS: [u8; 5] = [3, 4, 5, 11, 12];
+/// Destination unreachable, packet too big, time exceeded and parameter problem
+pub(crate) const ICMPV6_ERRORS: [u8; 4] = [1, 2, 3, 4];
+/// Type, code, checksum and 4 bytes that differ per message
+pub(crate) const ICMP_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IpProtocol {
+  Icmp,
+  Tcp,
+  Udp,
+  Icmpv6,
+  Other(u8),
+}
+
+impl From<u8> for IpProtocol {
+  fn from(value: u8) -> Self {
+    match value {
+      1 => IpProtocol::Icmp,
+      6 => IpProtocol::Tcp,
+      17 => IpProtocol::Udp,
+      58 => IpProtocol::Icmpv6,
+      other => IpProtocol::Other(other),
+    }
+  }
+}
+
+impl From<IpProtocol> for u8 {
+  fn from(value: IpProtocol) -> Self {
+    match value {
+      IpProtocol::Icmp => 1,
+      IpProtocol::Tcp => 6,
+      IpProtocol::Udp => 17,
+      IpProtocol::Icmpv6 => 58,
+      IpProtocol::Other(other) => other,
+    }
+  }
+}
+
+impl fmt::Display for IpProtocol {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      IpProtocol::Icmp => f.write_str("ICMP"),
+      IpProtocol::Tcp => f.write_str("TCP"),
+      IpProtocol::Udp => f.write_str("UDP"),
+      IpProtocol::Icmpv6 => f.write_str("ICMPv6"),
+      IpProtocol::Other(other) => f.write_fmt(format_args!("protocol {other}")),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+  /// The packet is shorter than its headers say
+  Truncated,
+  UnknownVersion(u8),
+  InvalidHeaderLength,
+  /// Source and destination of a packet to build are of different address families
+  MixedFamilies,
+  /// A packet to build would be larger than [`MAX_IP_PACKET_SIZE`]
+  TooLong,
+}
+
+impl fmt::Display for WireError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WireError::Truncated => f.write_str("Packet is shorter than its headers"),
+      WireError::UnknownVersion(version) => {
+        f.write_fmt(format_args!("Unknown IP version {version}"))
+      }
+      WireError::InvalidHeaderLength => f.write_str("Invalid IP header length"),
+      WireError::MixedFamilies => f.write_str("Source and destination are of different families"),
+      WireError::TooLong => f.write_fmt(format_args!(
+        "Packet would be longer than {MAX_IP_PACKET_SIZE} bytes"
+      )),
+    }
+  }
+}
+
+impl std::error::Error for WireError {}
+
+/// A parsed view of an IPv4 or IPv6 packet
+#[derive(Debug, Clone, Copy)]
+pub struct IpPacket<'a> {
+  /// The packet up to the length given in its header
+  data: &'a [u8],
+  /// Length of the IP header including IPv6 extension headers
+  header_len: usize,
+  protocol: IpProtocol,
+  /// In units of 8 bytes
+  fragment_offset: u16,
+  more_fragments: bool,
+}
+
+impl<'a> IpPacket<'a> {
+  pub fn parse(data: &'a [u8]) -> Result<Self, WireError> {
+    let version = data.first().ok_or(WireError::Truncated)? >> 4;
+    match version {
+      4 => Self::parse_v4(data),
+      6 => Self::parse_v6(data),
+      version => Err(WireError::UnknownVersion(version)),
+    }
+  }
+  fn parse_v4(data: &'a [u8]) -> Result<Self, WireError> {
+    if data.len() < IPV4_HEADER_LEN {
+      return Err(WireError::Truncated);
+    }
+    let header_len = (data[0] & 0xF) as usize * 4;
+    let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if header_len < IPV4_HEADER_LEN || total_len < header_len {
+      return Err(WireError::InvalidHeaderLength);
+    }
+    if total_len > data.len() {
+      return Err(WireError::Truncated);
+    }
+    let flags_and_offset = u16::from_be_bytes([data[6], data[7]]);
+    Ok(Self {
+      data: &data[..total_len],
+      header_len,
+      protocol: data[9].into(),
+      fragment_offset: flags_and_offset & 0x1FFF,
+      more_fragments: flags_and_offset & 0x2000 != 0,
+    })
+  }
+  fn parse_v6(data: &'a [u8]) -> Result<Self, WireError> {
+    if data.len() < IPV6_HEADER_LEN {
+      return Err(WireError::Truncated);
+    }
+    let total_len = IPV6_HEADER_LEN + u16::from_be_bytes([data[4], data[5]]) as usize;
+    if total_len > data.len() {
+      return Err(WireError::Truncated);
+    }
+    let data = &data[..total_len];
+    let mut next_header = data[6];
+    let mut header_len = IPV6_HEADER_LEN;
+    let mut fragment_offset = 0;
+    let mut more_fragments = false;
+    loop {
+      let extension = data.get(header_len..header_len + 8);
+      match (next_header, extension) {
+        //Hop-by-hop options, routing and destination options
+        (0 | 43 | 60, Some(extension)) => {
+          next_header = extension[0];
+          header_len += (extension[1] as usize + 1) * 8;
+        }
+        (44, Some(extension)) => {
+          let offset_and_flags = u16::from_be_bytes([extension[2], extension[3]]);
+          next_header = extension[0];
+          header_len += 8;
+          fragment_offset = offset_and_flags >> 3;
+          more_fragments = offset_and_flags & 1 != 0;
+        }
+        (0 | 43 | 44 | 60, None) => return Err(WireError::Truncated),
+        _ => break,
+      }
+    }
+    if header_len > data.len() {
+      return Err(WireError::Truncated);
+    }
+    Ok(Self {
+      data,
+      header_len,
+      protocol: next_header.into(),
+      fragment_offset,
+      more_fragments,
+    })
+  }
+  pub fn version(&self) -> u8 {
+    self.data[0] >> 4
+  }
+  pub fn source(&self) -> IpAddr {
+    match self.version() {
+      4 => IpAddr::V4(Ipv4Addr::from(
+        <[u8; 4]>::try_from(&self.data[12..16]).unwrap(),
+      )),
+      _ => IpAddr::V6(Ipv6Addr::from(
+        <[u8; 16]>::try_from(&self.data[8..24]).unwrap(),
+      )),
+    }
+  }
+  pub fn destination(&self) -> IpAddr {
+    match self.version() {
+      4 => IpAddr::V4(Ipv4Addr::from(
+        <[u8; 4]>::try_from(&self.data[16..20]).unwrap(),
+      )),
+      _ => IpAddr::V6(Ipv6Addr::from(
+        <[u8; 16]>::try_from(&self.data[24..40]).unwrap(),
+      )),
+    }
+  }
+  /// Protocol of the payload, after any IPv6 extension headers
+  pub fn protocol(&self) -> IpProtocol {
+    self.protocol
+  }
+  /// Time to live or hop limit
+  pub fn ttl(&self) -> u8 {
+    match self.version() {
+      4 => self.data[8],
+      _ => self.data[7],
+    }
+  }
+  pub fn is_fragment(&self) -> bool {
+    self.fragment_offset != 0 || self.more_fragments
+  }
+  /// Where the data of a fragment goes in its datagram, in units of 8 bytes. 0 for the first
+  /// fragment and for whole packets
+  pub fn fragment_offset(&self) -> u16 {
+    self.fragment_offset
+  }
+  /// The whole packet, without bytes past the length given in the header
+  pub fn as_bytes(&self) -> &'a [u8] {
+    self.data
+  }
+  pub fn header(&self) -> &'a [u8] {
+    &self.data[..self.header_len]
+  }
+  pub fn payload(&self) -> &'a [u8] {
+    &self.data[self.header_len..]
+  }
+  /// Source and destination port of TCP and UDP packets. `None` for other protocols and for
+  /// fragments other than the first
+  pub fn ports(&self) -> Option<(u16, u16)> {
+    if !matches!(self.protocol, IpProtocol::Tcp | IpProtocol::Udp) || self.fragment_offset != 0 {
+      return None;
+    }
+    let ports = self.payload().get(..4)?;
+    Some((
+      u16::from_be_bytes([ports[0], ports[1]]),
+      u16::from_be_bytes([ports[2], ports[3]]),
+    ))
+  }
+  /// Flags of TCP segments, unless the packet is a later fragment
+  pub fn tcp_flags(&self) -> Option<u8> {
+    if self.protocol != IpProtocol::Tcp || self.fragment_offset != 0 {
+      return None;
+    }
+    self.payload().get(13).copied()
+  }
+  /// Type of ICMP and ICMPv6 messages, unless the packet is a later fragment
+  pub fn icmp_type(&self) -> Option<u8> {
+    if !matches!(self.protocol, IpProtocol::Icmp | IpProtocol::Icmpv6) || self.fragment_offset != 0
+    {
+      return None;
+    }
+    self.payload().first().copied()
+  }
+  pub fn source_port(&self) -> Option<u16> {
+    self.ports().map(|(source, _)| source)
+  }
+  pub fn destination_port(&self) -> Option<u16> {
+    self.ports().map(|(_, destination)| destination)
+  }
+}
+
+/// Summarizes the packet as `UDP 10.0.0.1:53 -> 10.0.0.2:4000, 48 bytes`
+impl fmt::Display for IpPacket<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_fmt(format_args!("{} ", self.protocol))?;
+    match self.ports() {
+      Some((source, destination)) => f.write_fmt(format_args!(
+        "{} -> {}",
+        SocketAddr::new(self.source(), source),
+        SocketAddr::new(self.destination(), destination)
+      ))?,
+      None => f.write_fmt(format_args!("{} -> {}", self.source(), self.destination()))?,
+    }
+    f.write_fmt(format_args!(", {} bytes", self.data.len()))
+  }
+}
+
+/// One's complement sum of `data` as 16 bit words, added to `initial`
+fn sum(data: &[u8], initial: u32) -> u32 {
+  let mut chunks = data.chunks_exact(2);
+  let mut sum = initial;
+  for chunk in &mut chunks {
+    sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+  }
+  if let [last] = chunks.remainder() {
+    sum += (*last as u32) << 8;
+  }
+  sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+  while sum > 0xFFFF {
+    sum = (sum & 0xFFFF) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+/// The internet checksum (RFC 1071) of `data`
+pub fn checksum(data: &[u8]) -> u16 {
+  fold(sum(data, 0))
+}
+
+/// Updates an internet checksum after `old` was replaced with `new` in the covered data, without
+/// touching the rest of it (RFC 1624). Both have to be as long and start at an even offset
+pub fn update_checksum(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+  //HC' = ~(~HC + ~m + m') word by word, which is what folding the sum computes
+  let mut sum = !checksum as u32;
+  for (old, new) in old.chunks(2).zip(new.chunks(2)) {
+    let word = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes.get(1).copied().unwrap_or(0)]);
+    sum += !word(old) as u32 + word(new) as u32;
+  }
+  fold(sum)
+}
+
+/// Checksum of a TCP, UDP or ICMPv6 `segment` including the pseudo header. The segment's own
+/// checksum field has to be zero or the result is the value to verify against zero
+pub fn transport_checksum(
+  source: IpAddr,
+  destination: IpAddr,
+  protocol: IpProtocol,
+  segment: &[u8],
+) -> u16 {
+  let mut pseudo = match (source, destination) {
+    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+      sum(&destination.octets(), sum(&source.octets(), 0))
+    }
+    (source, destination) => sum(&ip_octets(destination), sum(&ip_octets(source), 0)),
+  };
+  pseudo += u8::from(protocol) as u32 + segment.len() as u32;
+  fold(sum(segment, pseudo))
+}
+
+fn ip_octets(ip: IpAddr) -> [u8; 16] {
+  match ip {
+    IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+    IpAddr::V6(ip) => ip.octets(),
+  }
+}
+
+/// Wraps `payload` into an IP header with a TTL of 64. Checksums inside the payload are left as
+/// they are
+pub fn ip_packet(
+  source: IpAddr,
+  destination: IpAddr,
+  protocol: IpProtocol,
+  payload: &[u8],
+) -> Result<Vec<u8>, WireError> {
+  match (source, destination) {
+    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+      let total_len = IPV4_HEADER_LEN + payload.len();
+      if total_len > MAX_IP_PACKET_SIZE as usize {
+        return Err(WireError::TooLong);
+      }
+      let mut packet = Vec::with_capacity(total_len);
+      packet.extend_from_slice(&[0x45, 0]);
+      packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+      //Identification, flags and fragment offset
+      packet.extend_from_slice(&[0, 0, 0, 0]);
+      packet.extend_from_slice(&[DEFAULT_TTL, protocol.into(), 0, 0]);
+      packet.extend_from_slice(&source.octets());
+      packet.extend_from_slice(&destination.octets());
+      let header_checksum = checksum(&packet);
+      packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+      packet.extend_from_slice(payload);
+      Ok(packet)
+    }
+    (IpAddr::V6(source), IpAddr::V6(destination)) => {
+      if IPV6_HEADER_LEN + payload.len() > MAX_IP_PACKET_SIZE as usize {
+        return Err(WireError::TooLong);
+      }
+      let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + payload.len());
+      packet.extend_from_slice(&[0x60, 0, 0, 0]);
+      packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+      packet.extend_from_slice(&[protocol.into(), DEFAULT_TTL]);
+      packet.extend_from_slice(&source.octets());
+      packet.extend_from_slice(&destination.octets());
+      packet.extend_from_slice(payload);
+      Ok(packet)
+    }
+    _ => Err(WireError::MixedFamilies),
+  }
+}
+
+/// Builds a complete TCP packet with valid checksums. `options` are padded to a multiple of 4
+/// bytes, sequence and acknowledgment numbers are zero and the window is 65535
+pub fn tcp_packet(
+  source: SocketAddr,
+  destination: SocketAddr,
+  flags: u8,
+  options: &[u8],
+  payload: &[u8],
+) -> Result<Vec<u8>, WireError> {
+  let options_len = options.len().div_ceil(4) * 4;
+  if options_len > 40 {
+    return Err(WireError::InvalidHeaderLength);
+  }
+  let header_len = TCP_HEADER_LEN + options_len;
+  let mut segment = Vec::with_capacity(header_len + payload.len());
+  segment.extend_from_slice(&source.port().to_be_bytes());
+  segment.extend_from_slice(&destination.port().to_be_bytes());
+  segment.extend_from_slice(&[0; 8]);
+  segment.extend_from_slice(&[(header_len as u8 / 4) << 4, flags, 0xFF, 0xFF]);
+  segment.extend_from_slice(&[0; 4]);
+  segment.extend_from_slice(options);
+  segment.resize(header_len, 0);
+  segment.extend_from_slice(payload);
+  let checksum = transport_checksum(source.ip(), destination.ip(), IpProtocol::Tcp, &segment);
+  segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+  ip_packet(source.ip(), destination.ip(), IpProtocol::Tcp, &segment)
+}
+
+/// Builds a complete UDP packet with valid checksums
+pub fn udp_packet(
+  source: SocketAddr,
+  destination: SocketAddr,
+  payload: &[u8],
+) -> Result<Vec<u8>, WireError> {
+  let len = UDP_HEADER_LEN + payload.len();
+  if len > u16::MAX as usize {
+    return Err(WireError::TooLong);
+  }
+  let mut segment = Vec::with_capacity(len);
+  segment.extend_from_slice(&source.port().to_be_bytes());
+  segment.extend_from_slice(&destination.port().to_be_bytes());
+  segment.extend_from_slice(&(len as u16).to_be_bytes());
+  segment.extend_from_slice(&[0, 0]);
+  segment.extend_from_slice(payload);
+  let checksum = match transport_checksum(source.ip(), destination.ip(), IpProtocol::Udp, &segment)
+  {
+    //Zero means no checksum in UDP, so a computed zero is sent as all ones
+    0 => 0xFFFF,
+    checksum => checksum,
+  };
+  segment[6..8].copy_from_slice(&checksum.to_be_bytes());
+  ip_packet(source.ip(), destination.ip(), IpProtocol::Udp, &segment)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+  use super::{
+    checksum, ip_packet, tcp_packet, transport_checksum, udp_packet, update_checksum, IpPacket,
+    IpProtocol, WireError, TCP_SYN,
+  };
+
+  #[test]
+  fn udp_roundtrip() {
+    let source = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 5353);
+    let destination = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 53);
+    let bytes = udp_packet(source, destination, b"query").unwrap();
+    let packet = IpPacket::parse(&bytes).unwrap();
+    assert_eq!(packet.version(), 4);
+    assert_eq!(packet.source(), source.ip());
+    assert_eq!(packet.destination(), destination.ip());
+    assert_eq!(packet.protocol(), IpProtocol::Udp);
+    assert_eq!(packet.ports(), Some((5353, 53)));
+    assert_eq!(packet.ttl(), 64);
+    assert_eq!(&packet.payload()[8..], b"query");
+    assert_eq!(checksum(packet.header()), 0);
+    assert_eq!(
+      transport_checksum(
+        packet.source(),
+        packet.destination(),
+        packet.protocol(),
+        packet.payload()
+      ),
+      0
+    );
+    assert_eq!(
+      packet.to_string(),
+      "UDP 10.0.0.1:5353 -> 10.0.0.2:53, 33 bytes"
+    );
+
+    let source = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 1);
+    let bytes = udp_packet(source, source, &[]).unwrap();
+    let packet = IpPacket::parse(&bytes).unwrap();
+    assert_eq!(packet.version(), 6);
+    assert_eq!(packet.source(), IpAddr::V6(Ipv6Addr::LOCALHOST));
+    assert_eq!(packet.ports(), Some((1, 1)));
+    assert_eq!(
+      transport_checksum(source.ip(), source.ip(), IpProtocol::Udp, packet.payload()),
+      0
+    );
+  }
+
+  #[test]
+  fn checksum_reference() {
+    //Example header from RFC 1071 style walkthroughs, checksum 0xB861
+    let header = [
+      0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xC0, 0xA8, 0x00,
+      0x01, 0xC0, 0xA8, 0x00, 0xC7,
+    ];
+    assert_eq!(checksum(&header), 0xB861);
+    assert_eq!(checksum(&[0xFF]), 0x00FF);
+  }
+
+  #[test]
+  fn incremental_checksums() {
+    let mut data: Vec<u8> = (0..64u8).map(|byte| byte.wrapping_mul(37)).collect();
+    for (offset, new) in [
+      (12, [10, 0, 0, 1]),
+      (0, [0xFF, 0xFF, 0xFF, 0xFF]),
+      (60, [0, 0, 0, 0]),
+    ] {
+      let before = checksum(&data);
+      let old = data[offset..offset + 4].to_vec();
+      data[offset..offset + 4].copy_from_slice(&new);
+      assert_eq!(update_checksum(before, &old, &new), checksum(&data));
+    }
+  }
+
+  #[test]
+  fn tcp_roundtrip() {
+    let source = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 40000);
+    let destination = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 443);
+    let bytes = tcp_packet(source, destination, TCP_SYN, &[2, 4, 5, 0xB4, 1], b"x").unwrap();
+    let packet = IpPacket::parse(&bytes).unwrap();
+    assert_eq!(packet.ports(), Some((40000, 443)));
+    assert_eq!(packet.tcp_flags(), Some(TCP_SYN));
+    assert_eq!(packet.payload().len(), 28 + 1);
+    assert_eq!(packet.payload()[12] >> 4, 7);
+    let segment = packet.payload();
+    assert_eq!(
+      transport_checksum(source.ip(), destination.ip(), IpProtocol::Tcp, segment),
+      0
+    );
+  }
+
+  #[test]
+  fn ipv6_extension_headers_and_fragments() {
+    let source = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    //Hop-by-hop options followed by a fragment header with offset 1 in front of UDP
+    let mut payload = vec![44, 0, 0, 0, 0, 0, 0, 0];
+    payload.extend_from_slice(&[17, 0, 0, 8, 0, 0, 0, 1]);
+    payload.extend_from_slice(&[0, 53, 0, 53]);
+    let mut bytes = ip_packet(source, source, IpProtocol::Other(0), &payload).unwrap();
+    let packet = IpPacket::parse(&bytes).unwrap();
+    assert_eq!(packet.protocol(), IpProtocol::Udp);
+    assert_eq!(packet.header().len(), 56);
+    assert!(packet.is_fragment());
+    assert_eq!(packet.ports(), None);
+    //Offset zero: the first fragment carries the ports
+    bytes[50..52].copy_from_slice(&[0, 1]);
+    assert_eq!(IpPacket::parse(&bytes).unwrap().ports(), Some((53, 53)));
+    bytes.truncate(50);
+    bytes[4..6].copy_from_slice(&10u16.to_be_bytes());
+    assert_eq!(IpPacket::parse(&bytes).unwrap_err(), WireError::Truncated);
+  }
+
+  #[test]
+  fn malformed_packets() {
+    assert_eq!(IpPacket::parse(&[]).unwrap_err(), WireError::Truncated);
+    assert_eq!(
+      IpPacket::parse(&[0x50]).unwrap_err(),
+      WireError::UnknownVersion(5)
+    );
+    let mut bytes = ip_packet(
+      Ipv4Addr::LOCALHOST.into(),
+      Ipv4Addr::LOCALHOST.into(),
+      IpProtocol::Tcp,
+      &[0; 2],
+    )
+    .unwrap();
+    //Ports are cut off
+    assert_eq!(IpPacket::parse(&bytes).unwrap().ports(), None);
+    bytes[0] = 0x44;
+    assert_eq!(
+      IpPacket::parse(&bytes).unwrap_err(),
+      WireError::InvalidHeaderLength
+    );
+    bytes[0] = 0x45;
+    bytes[3] = 30;
+    assert_eq!(IpPacket::parse(&bytes).unwrap_err(), WireError::Truncated);
+    //Bytes past the total length are ignored
+    bytes[3] = 20;
+    assert!(IpPacket::parse(&bytes).unwrap().payload().is_empty());
+    assert_eq!(
+      ip_packet(
+        Ipv4Addr::LOCALHOST.into(),
+        Ipv6Addr::LOCALHOST.into(),
+        IpProtocol::Udp,
+        &[]
+      )
+      .unwrap_err(),
+      WireError::MixedFamilies
+    );
+  }
+}